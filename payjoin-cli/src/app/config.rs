@@ -3,7 +3,8 @@ use std::path::PathBuf;
 use anyhow::Result;
 use config::builder::DefaultState;
 use config::{ConfigError, File, FileFormat};
-use payjoin::bitcoin::FeeRate;
+use payjoin::bitcoin::address::NetworkUnchecked;
+use payjoin::bitcoin::{Address, Amount, FeeRate};
 use payjoin::Version;
 use serde::Deserialize;
 use url::Url;
@@ -23,6 +24,89 @@ pub struct BitcoindConfig {
     pub rpcpassword: String,
 }
 
+/// A local descriptor wallet which uses `[bitcoind]` only as a block source and broadcaster
+#[derive(Clone, Deserialize)]
+pub struct BdkConfig {
+    pub descriptor: String,
+    pub change_descriptor: String,
+    #[serde(default = "default_bdk_wallet_path")]
+    pub wallet_path: PathBuf,
+    /// The block height to start scanning from when the wallet is first created
+    #[serde(default)]
+    pub start_height: u32,
+}
+
+fn default_bdk_wallet_path() -> PathBuf { PathBuf::from("bdk-wallet.dat") }
+
+// Descriptors may hold private keys, so keep them out of the logs
+impl std::fmt::Debug for BdkConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BdkConfig")
+            .field("wallet_path", &self.wallet_path)
+            .field("start_height", &self.start_height)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Sign PSBTs outside of payjoin-cli, e.g. with a hardware wallet, exchanging them through files
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExternalSignerConfig {
+    #[serde(default = "default_psbt_dir")]
+    pub psbt_dir: PathBuf,
+}
+
+fn default_psbt_dir() -> PathBuf { PathBuf::from("psbts") }
+
+/// How the receiver handles the payjoins it is sent
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReceiverConfig {
+    /// Contribute one of the wallet's coins to each payjoin, rather than only adjusting outputs
+    pub contribute_inputs: bool,
+    pub coin_selection: CoinSelection,
+    /// The smallest coin to contribute, in sats
+    pub min_utxo_value: Option<Amount>,
+    /// The largest coin to contribute, in sats
+    pub max_utxo_value: Option<Amount>,
+    /// The confirmations a coin needs before it is contributed
+    pub min_confirmations: u32,
+    /// Skip checking that the Original can be broadcast. The check guards against senders
+    /// probing the wallet's coins, which only matters when URIs are handed out automatically.
+    pub interactive: bool,
+    /// Forward received payments to one of these addresses, e.g. cold storage, instead of a new
+    /// wallet address when the sender allows output substitution
+    pub forward_to: Vec<Address<NetworkUnchecked>>,
+    /// How long BIP77 payjoin URIs stay valid, in seconds
+    pub expiration_secs: Option<u64>,
+}
+
+impl Default for ReceiverConfig {
+    fn default() -> Self {
+        Self {
+            contribute_inputs: true,
+            coin_selection: CoinSelection::default(),
+            min_utxo_value: None,
+            max_utxo_value: None,
+            min_confirmations: 1,
+            interactive: false,
+            forward_to: vec![],
+            expiration_secs: None,
+        }
+    }
+}
+
+/// Which eligible coin the receiver contributes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoinSelection {
+    /// A coin which avoids the unnecessary input heuristic, as chosen by
+    /// `try_preserving_privacy`, or else the first eligible coin
+    #[default]
+    Privacy,
+    LargestFirst,
+    SmallestFirst,
+}
+
 #[cfg(feature = "v1")]
 #[derive(Debug, Clone, Deserialize)]
 pub struct V1Config {
@@ -37,6 +121,19 @@ pub struct V2Config {
     pub ohttp_keys: Option<payjoin::OhttpKeys>,
     pub ohttp_relays: Vec<Url>,
     pub pj_directory: Url,
+    /// SOCKS5 proxy (e.g. a local Tor daemon) through which all HTTP traffic is routed
+    pub socks_proxy: Option<Url>,
+}
+
+/// Programs notified of every saved session event
+#[cfg(feature = "v2")]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    /// URLs each notification is POSTed to as JSON
+    pub webhooks: Vec<Url>,
+    /// Executables run with each notification as JSON on stdin
+    pub scripts: Vec<PathBuf>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -56,6 +153,11 @@ pub struct Config {
     pub db_path: PathBuf,
     pub max_fee_rate: Option<FeeRate>,
     pub bitcoind: BitcoindConfig,
+    pub bdk: Option<BdkConfig>,
+    pub external_signer: Option<ExternalSignerConfig>,
+    pub receiver: ReceiverConfig,
+    #[cfg(feature = "v2")]
+    pub hooks: HooksConfig,
     #[serde(skip)]
     pub version: Option<VersionConfig>,
     #[cfg(feature = "_manual-tls")]
@@ -144,6 +246,27 @@ impl Config {
             db_path: built_config.get("db_path")?,
             max_fee_rate: built_config.get("max_fee_rate").ok(),
             bitcoind: built_config.get("bitcoind")?,
+            bdk: match built_config.get("bdk") {
+                Ok(bdk) => Some(bdk),
+                Err(ConfigError::NotFound(_)) => None,
+                Err(e) => return Err(e),
+            },
+            external_signer: match built_config.get("external_signer") {
+                Ok(external_signer) => Some(external_signer),
+                Err(ConfigError::NotFound(_)) => None,
+                Err(e) => return Err(e),
+            },
+            receiver: match built_config.get("receiver") {
+                Ok(receiver) => receiver,
+                Err(ConfigError::NotFound(_)) => ReceiverConfig::default(),
+                Err(e) => return Err(e),
+            },
+            #[cfg(feature = "v2")]
+            hooks: match built_config.get("hooks") {
+                Ok(hooks) => hooks,
+                Err(ConfigError::NotFound(_)) => HooksConfig::default(),
+                Err(e) => return Err(e),
+            },
             version: None,
             #[cfg(feature = "_manual-tls")]
             root_certificate: built_config.get("root_certificate").ok(),
@@ -194,6 +317,16 @@ impl Config {
             }
         }
 
+        if let (Some(min), Some(max)) =
+            (config.receiver.min_utxo_value, config.receiver.max_utxo_value)
+        {
+            if min > max {
+                return Err(ConfigError::Message(
+                    "receiver.min_utxo_value may not exceed receiver.max_utxo_value".to_owned(),
+                ));
+            }
+        }
+
         if config.version.is_none() {
             return Err(ConfigError::Message(
                 "No valid version configuration found for the specified mode".to_string(),
@@ -271,7 +404,8 @@ fn add_v2_defaults(config: Builder, cli: &Cli) -> Result<Builder, ConfigError> {
     // Set default values
     let config = config
         .set_default("v2.pj_directory", "https://payjo.in")?
-        .set_default("v2.ohttp_keys", None::<String>)?;
+        .set_default("v2.ohttp_keys", None::<String>)?
+        .set_default("v2.socks_proxy", None::<String>)?;
 
     // Override config values with command line arguments if applicable
     let pj_directory = cli.pj_directory.as_ref().map(|s| s.as_str());
@@ -280,11 +414,13 @@ fn add_v2_defaults(config: Builder, cli: &Cli) -> Result<Builder, ConfigError> {
         .ohttp_relays
         .as_ref()
         .map(|urls| urls.iter().map(|url| url.as_str()).collect::<Vec<_>>());
+    let socks_proxy = cli.socks_proxy.as_ref().map(|s| s.as_str());
 
     config
         .set_override_option("v2.pj_directory", pj_directory)?
         .set_override_option("v2.ohttp_keys", ohttp_keys)?
-        .set_override_option("v2.ohttp_relays", ohttp_relays)
+        .set_override_option("v2.ohttp_relays", ohttp_relays)?
+        .set_override_option("v2.socks_proxy", socks_proxy)
 }
 
 /// Handles configuration overrides based on CLI subcommands
@@ -328,7 +464,14 @@ fn handle_subcommands(config: Builder, cli: &Cli) -> Result<Builder, ConfigError
             Ok(config)
         }
         #[cfg(feature = "v2")]
-        Commands::Resume => Ok(config),
+        Commands::Resume { .. } => Ok(config),
+        #[cfg(feature = "v2")]
+        Commands::Daemon { .. } => Ok(config),
+        #[cfg(feature = "v2")]
+        Commands::Show { .. } => Ok(config),
+        Commands::SignImport { .. } => Ok(config),
+        #[cfg(feature = "v2")]
+        Commands::Cancel { .. } => Ok(config),
         #[cfg(feature = "v2")]
         Commands::History => Ok(config),
     }
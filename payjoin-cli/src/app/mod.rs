@@ -1,15 +1,27 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use payjoin::bitcoin::address::NetworkUnchecked;
 use payjoin::bitcoin::psbt::Psbt;
-use payjoin::bitcoin::{self, Address, Amount, FeeRate};
+use payjoin::bitcoin::transaction::InputWeightPrediction;
+use payjoin::bitcoin::{self, Address, Amount, FeeRate, ScriptBuf, Weight};
+use payjoin::receive::InputPair;
 use tokio::signal;
 use tokio::sync::watch;
 
 pub mod config;
+pub mod share;
 pub mod wallet;
-use crate::app::config::Config;
-use crate::app::wallet::BitcoindWallet;
+use crate::app::config::{CoinSelection, Config, ReceiverConfig};
+use crate::app::share::ShareOptions;
+use crate::app::wallet::PayjoinWallet;
+use crate::output::{self, Event};
+
+// input script: 0x160014{20-byte-key-hash} = 23 bytes
+// witness: <signature> <pubkey> = 72, 33 bytes
+const NESTED_P2WPKH_MAX: InputWeightPrediction = InputWeightPrediction::from_slice(23, &[72, 33]);
 
 #[cfg(feature = "v1")]
 pub(crate) mod v1;
@@ -21,27 +33,103 @@ pub trait App: Send + Sync {
     async fn new(config: Config) -> Result<Self>
     where
         Self: Sized;
-    fn wallet(&self) -> BitcoindWallet;
-    async fn send_payjoin(&self, bip21: &str, fee_rate: FeeRate) -> Result<()>;
-    async fn receive_payjoin(&self, amount: Amount) -> Result<()>;
+    fn wallet(&self) -> Arc<dyn PayjoinWallet>;
+    /// Pay `bip21` with a payjoin, and `batch` in the same transaction
+    async fn send_payjoin(
+        &self,
+        bip21: &str,
+        fee_rate: FeeRate,
+        batch: &[(Address<NetworkUnchecked>, Amount)],
+    ) -> Result<()>;
+    /// Receive `amount` with a payjoin, sharing the Payjoin URI as set by `share`
+    async fn receive_payjoin(&self, amount: Amount, share: &ShareOptions) -> Result<()>;
     #[cfg(feature = "v2")]
-    async fn resume_payjoins(&self) -> Result<()>;
+    async fn resume_payjoins(
+        &self,
+        session_id: Option<i64>,
+        role: Option<crate::output::Role>,
+    ) -> Result<()>;
     #[cfg(feature = "v2")]
     async fn history(&self) -> Result<()>;
+    #[cfg(feature = "v2")]
+    async fn show(&self, session_id: i64, role: Option<crate::output::Role>) -> Result<()>;
+    #[cfg(feature = "v2")]
+    async fn daemon(&self, listen: std::net::SocketAddr) -> Result<()>;
+    #[cfg(feature = "v2")]
+    async fn cancel(
+        &self,
+        session_id: i64,
+        role: Option<crate::output::Role>,
+        broadcast_fallback: bool,
+    ) -> Result<()>;
+    /// Deliver the hook notifications which are due, before exiting
+    async fn flush_hooks(&self) {}
 
     fn create_original_psbt(
         &self,
         address: &Address,
         amount: Amount,
         fee_rate: FeeRate,
+        batch: &[(Address<NetworkUnchecked>, Amount)],
     ) -> Result<Psbt> {
         // wallet_create_funded_psbt requires a HashMap<address: String, Amount>
-        let mut outputs = HashMap::with_capacity(1);
+        let mut outputs = HashMap::with_capacity(1 + batch.len());
         outputs.insert(address.to_string(), amount);
+        if !batch.is_empty() {
+            let network = self.wallet().network()?;
+            for (batch_address, batch_amount) in batch {
+                let batch_address = batch_address.clone().require_network(network)?;
+                if outputs.insert(batch_address.to_string(), *batch_amount).is_some() {
+                    return Err(anyhow!("{batch_address} is paid more than once"));
+                }
+            }
+        }
 
         self.wallet().create_psbt(outputs, fee_rate, true)
     }
 
+    /// How the receiver may pay for its input out of the change of an Original PSBT which also
+    /// pays `batch`
+    fn fee_contribution(
+        &self,
+        psbt: &Psbt,
+        address: &Address,
+        batch: &[(Address<NetworkUnchecked>, Amount)],
+        fee_rate: FeeRate,
+    ) -> Result<FeeContribution> {
+        if batch.is_empty() {
+            return Ok(FeeContribution::Recommended);
+        }
+
+        let recipients = std::iter::once(address.script_pubkey())
+            .chain(batch.iter().map(|(address, _)| address.assume_checked_ref().script_pubkey()))
+            .collect::<Vec<ScriptBuf>>();
+        let mut change_index = None;
+        for (index, txout) in psbt.unsigned_tx.output.iter().enumerate() {
+            if !recipients.contains(&txout.script_pubkey)
+                && self.wallet().is_mine(&txout.script_pubkey)?
+            {
+                change_index = Some(index);
+                break;
+            }
+        }
+        let Some(change_index) = change_index else {
+            return Ok(FeeContribution::None);
+        };
+
+        // Like the payjoin crate, expect the receiver's input to be like our first one
+        let prediction = match psbt.inputs.first().and_then(|input| input.witness_utxo.as_ref()) {
+            Some(txout) if txout.script_pubkey.is_p2wpkh() => InputWeightPrediction::P2WPKH_MAX,
+            Some(txout) if txout.script_pubkey.is_p2sh() => NESTED_P2WPKH_MAX,
+            Some(txout) if txout.script_pubkey.is_p2tr() =>
+                InputWeightPrediction::P2TR_KEY_DEFAULT_SIGHASH,
+            _ => InputWeightPrediction::P2TR_KEY_NON_DEFAULT_SIGHASH,
+        };
+        // The outpoint, sequence and empty script_sig length of the input
+        let input_weight = prediction.weight() + Weight::from_non_witness_data_size(32 + 4 + 4 + 1);
+        Ok(FeeContribution::FromChange { max: fee_rate * input_weight, change_index })
+    }
+
     fn process_pj_response(&self, psbt: Psbt) -> Result<bitcoin::Txid> {
         tracing::trace!("Proposed psbt: {psbt:#?}");
 
@@ -50,18 +138,105 @@ pub trait App: Send + Sync {
 
         let txid = self.wallet().broadcast_tx(&tx)?;
 
-        println!("Payjoin sent. TXID: {txid}");
+        output::emit(Event::TxBroadcast { txid });
         Ok(txid)
     }
 }
 
+/// Where the receiver may take the fee for its input from
+pub(crate) enum FeeContribution {
+    /// Let the payjoin crate pick the first output which isn't the payee's. Only right when the
+    /// Original PSBT pays nobody else.
+    Recommended,
+    /// Up to `max` from our change output at `change_index`, leaving other recipients untouched
+    FromChange { max: Amount, change_index: usize },
+    /// There is no change output to take it from
+    None,
+}
+
+/// The coins the `[receiver]` policy allows contributing, for `try_preserving_privacy` to
+/// choose from. Unless it chooses, only the preferred coin is offered.
+pub(crate) fn candidate_inputs(
+    policy: &ReceiverConfig,
+    wallet: &dyn PayjoinWallet,
+) -> Result<Vec<InputPair>> {
+    if !policy.contribute_inputs {
+        return Ok(vec![]);
+    }
+    let mut utxos = wallet
+        .list_unspent()?
+        .into_iter()
+        .filter(|utxo| {
+            utxo.confirmations >= policy.min_confirmations
+                && policy.min_utxo_value.is_none_or(|min| utxo.value >= min)
+                && policy.max_utxo_value.is_none_or(|max| utxo.value <= max)
+        })
+        .collect::<Vec<_>>();
+    match policy.coin_selection {
+        CoinSelection::Privacy => {}
+        CoinSelection::LargestFirst => {
+            utxos.sort_by_key(|utxo| Reverse(utxo.value));
+            utxos.truncate(1);
+        }
+        CoinSelection::SmallestFirst => {
+            utxos.sort_by_key(|utxo| utxo.value);
+            utxos.truncate(1);
+        }
+    }
+    if utxos.is_empty() {
+        output::info("No coins match the receiver policy, so none are contributed");
+    }
+    Ok(utxos.into_iter().map(|utxo| utxo.input).collect())
+}
+
+/// The script to forward a received payment to, if the `[receiver]` policy forwards payments.
+/// `seed` picks one of the addresses, spreading payments across them.
+pub(crate) fn forward_script(
+    policy: &ReceiverConfig,
+    wallet: &dyn PayjoinWallet,
+    seed: u64,
+) -> Result<Option<ScriptBuf>> {
+    if policy.forward_to.is_empty() {
+        return Ok(None);
+    }
+    let address = &policy.forward_to[(seed % policy.forward_to.len() as u64) as usize];
+    let address = address.clone().require_network(wallet.network()?)?;
+    Ok(Some(address.script_pubkey()))
+}
+
 #[cfg(feature = "_manual-tls")]
 fn http_agent(config: &Config) -> Result<reqwest::Client> {
-    Ok(http_agent_builder(config.root_certificate.as_ref())?.build()?)
+    Ok(with_socks_proxy(http_agent_builder(config.root_certificate.as_ref())?, config)?.build()?)
 }
 
 #[cfg(not(feature = "_manual-tls"))]
-fn http_agent(_config: &Config) -> Result<reqwest::Client> { Ok(reqwest::Client::new()) }
+fn http_agent(config: &Config) -> Result<reqwest::Client> {
+    Ok(with_socks_proxy(reqwest::ClientBuilder::new(), config)?.build()?)
+}
+
+/// Route all HTTP traffic through the configured SOCKS5 proxy, if any
+#[cfg(feature = "v2")]
+fn with_socks_proxy(
+    builder: reqwest::ClientBuilder,
+    config: &Config,
+) -> Result<reqwest::ClientBuilder> {
+    match config.v2().ok().and_then(|v2| v2.socks_proxy.as_ref()) {
+        Some(socks_proxy) => match socks_proxy.scheme() {
+            "socks5" | "socks5h" => Ok(builder.proxy(reqwest::Proxy::all(socks_proxy.as_str())?)),
+            scheme =>
+                Err(anyhow::anyhow!("Unsupported socks_proxy scheme {scheme}, expected socks5h")),
+        },
+        None => Ok(builder),
+    }
+}
+
+#[cfg(not(feature = "v2"))]
+fn with_socks_proxy(
+    builder: reqwest::ClientBuilder,
+    _config: &Config,
+) -> Result<reqwest::ClientBuilder> {
+    Ok(builder)
+}
 
 #[cfg(feature = "_manual-tls")]
 fn http_agent_builder(
@@ -77,9 +252,168 @@ fn http_agent_builder(
     Ok(builder)
 }
 
+/// The user interrupted a command, e.g. with Ctrl-C
+#[cfg_attr(not(feature = "v2"), allow(dead_code))]
+#[derive(Debug)]
+pub(crate) struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { write!(f, "Interrupted") }
+}
+
+impl std::error::Error for Interrupted {}
+
 async fn handle_interrupt(tx: watch::Sender<()>) {
     if let Err(e) = signal::ctrl_c().await {
         eprintln!("Error setting up Ctrl-C handler: {e}");
     }
     let _ = tx.send(());
 }
+
+#[cfg(test)]
+mod tests {
+    use payjoin::bitcoin::hashes::Hash;
+    use payjoin::bitcoin::{Network, OutPoint, Script, Transaction, TxOut, Txid, WPubkeyHash};
+
+    use super::*;
+    use crate::app::wallet::Utxo;
+
+    /// A wallet holding coins of the given values and confirmations
+    struct StubWallet {
+        coins: Vec<(u64, u32)>,
+        network: Network,
+    }
+
+    fn input(sats: u64) -> InputPair {
+        let txout = TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+        };
+        let outpoint = OutPoint { txid: Txid::all_zeros(), vout: sats as u32 };
+        InputPair::new_p2wpkh(txout, outpoint, None).expect("valid p2wpkh input")
+    }
+
+    impl PayjoinWallet for StubWallet {
+        fn create_psbt(&self, _: HashMap<String, Amount>, _: FeeRate, _: bool) -> Result<Psbt> {
+            unimplemented!()
+        }
+
+        fn create_unsigned_psbt(
+            &self,
+            _: HashMap<String, Amount>,
+            _: FeeRate,
+            _: bool,
+        ) -> Result<Psbt> {
+            unimplemented!()
+        }
+
+        fn process_psbt(&self, _: &Psbt) -> Result<Psbt> { unimplemented!() }
+
+        fn can_broadcast(&self, _: &Transaction) -> Result<bool> { unimplemented!() }
+
+        fn broadcast_tx(&self, _: &Transaction) -> Result<Txid> { unimplemented!() }
+
+        fn is_mine(&self, _: &Script) -> Result<bool> { unimplemented!() }
+
+        #[cfg(feature = "v2")]
+        fn is_outpoint_spent(&self, _: &OutPoint) -> Result<bool> { unimplemented!() }
+
+        #[cfg(feature = "v2")]
+        fn unlock_unspent(&self, _: &[OutPoint]) -> Result<()> { unimplemented!() }
+
+        #[cfg(feature = "v2")]
+        fn get_raw_transaction(&self, _: &Txid) -> Result<Option<Transaction>> { unimplemented!() }
+
+        fn get_new_address(&self) -> Result<Address> { unimplemented!() }
+
+        fn list_unspent(&self) -> Result<Vec<Utxo>> {
+            Ok(self
+                .coins
+                .iter()
+                .map(|&(sats, confirmations)| Utxo {
+                    input: input(sats),
+                    value: Amount::from_sat(sats),
+                    confirmations,
+                })
+                .collect())
+        }
+
+        fn network(&self) -> Result<Network> { Ok(self.network) }
+    }
+
+    fn wallet() -> StubWallet {
+        StubWallet {
+            coins: vec![(5_000, 0), (10_000, 1), (20_000, 3), (40_000, 6)],
+            network: Network::Regtest,
+        }
+    }
+
+    #[test]
+    fn test_candidate_inputs_filters_by_policy() {
+        let policy = ReceiverConfig::default();
+        assert_eq!(
+            candidate_inputs(&policy, &wallet()).expect("candidates"),
+            vec![input(10_000), input(20_000), input(40_000)]
+        );
+
+        let policy = ReceiverConfig {
+            min_confirmations: 0,
+            min_utxo_value: Some(Amount::from_sat(10_000)),
+            max_utxo_value: Some(Amount::from_sat(20_000)),
+            ..ReceiverConfig::default()
+        };
+        assert_eq!(
+            candidate_inputs(&policy, &wallet()).expect("candidates"),
+            vec![input(10_000), input(20_000)]
+        );
+
+        let policy = ReceiverConfig { min_confirmations: 7, ..ReceiverConfig::default() };
+        assert!(candidate_inputs(&policy, &wallet()).expect("candidates").is_empty());
+
+        let policy = ReceiverConfig { contribute_inputs: false, ..ReceiverConfig::default() };
+        assert!(candidate_inputs(&policy, &wallet()).expect("candidates").is_empty());
+    }
+
+    #[test]
+    fn test_candidate_inputs_coin_selection() {
+        let policy = ReceiverConfig {
+            coin_selection: CoinSelection::LargestFirst,
+            ..ReceiverConfig::default()
+        };
+        assert_eq!(candidate_inputs(&policy, &wallet()).expect("candidates"), vec![input(40_000)]);
+
+        let policy = ReceiverConfig {
+            coin_selection: CoinSelection::SmallestFirst,
+            ..ReceiverConfig::default()
+        };
+        assert_eq!(candidate_inputs(&policy, &wallet()).expect("candidates"), vec![input(10_000)]);
+    }
+
+    #[test]
+    fn test_forward_script() {
+        let addresses: Vec<Address> = (1..=2)
+            .map(|i| {
+                let script = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([i; 20]));
+                Address::from_script(&script, Network::Regtest).expect("p2wpkh address")
+            })
+            .collect();
+
+        assert_eq!(forward_script(&ReceiverConfig::default(), &wallet(), 0).expect("script"), None);
+
+        let policy = ReceiverConfig {
+            forward_to: addresses.iter().map(|a| a.as_unchecked().clone()).collect(),
+            ..ReceiverConfig::default()
+        };
+        assert_eq!(
+            forward_script(&policy, &wallet(), 0).expect("script"),
+            Some(addresses[0].script_pubkey())
+        );
+        assert_eq!(
+            forward_script(&policy, &wallet(), 3).expect("script"),
+            Some(addresses[1].script_pubkey())
+        );
+
+        let mainnet = StubWallet { network: Network::Bitcoin, ..wallet() };
+        assert!(forward_script(&policy, &mainnet, 0).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! Sharing a Payjoin URI with the sender: BIP21 labels and messages, and QR codes for phone
+//! wallets.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use payjoin::PjUri;
+use qrcode::render::{svg, unicode};
+use qrcode::QrCode;
+
+use crate::output;
+
+/// Size of a QR code written to a file, in pixels
+const QR_FILE_SIZE: u32 = 512;
+
+/// The QR code file types this build can write
+#[cfg(feature = "png")]
+pub const QR_FILE_TYPES: &str = ".png or .svg";
+#[cfg(not(feature = "png"))]
+pub const QR_FILE_TYPES: &str = ".svg";
+
+/// How a receiver presents its Payjoin URI
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ShareOptions {
+    /// A BIP21 label for the receiver, shown by the sender's wallet
+    #[arg(long = "label")]
+    pub label: Option<String>,
+
+    /// A BIP21 message describing the payment, shown by the sender's wallet
+    #[arg(long = "message")]
+    pub message: Option<String>,
+
+    /// Also write the QR code of the Payjoin URI to this .svg file, or .png file if built
+    /// with the `png` feature
+    #[arg(long = "qr", value_parser = crate::cli::parse_qr_path)]
+    pub qr: Option<PathBuf>,
+}
+
+impl ShareOptions {
+    /// Add the label and message to `uri`
+    pub(crate) fn apply<'a>(&'a self, mut uri: PjUri<'a>) -> PjUri<'a> {
+        uri.label = self.label.as_deref().map(Into::into);
+        uri.message = self.message.as_deref().map(Into::into);
+        uri
+    }
+
+    /// Print `uri` as a QR code, unless printing JSON, and write it to the QR file if any
+    pub(crate) fn show_qr(&self, uri: &PjUri) -> Result<()> {
+        let code = QrCode::new(qr_data(uri)).context("Failed to encode the Payjoin URI")?;
+        if !output::is_json() {
+            // Inverted, since terminals are usually dark
+            let qr = code
+                .render::<unicode::Dense1x2>()
+                .dark_color(unicode::Dense1x2::Light)
+                .light_color(unicode::Dense1x2::Dark)
+                .build();
+            println!("{qr}");
+        }
+        if let Some(path) = &self.qr {
+            write_qr(&code, path)?;
+            output::info(format!("Wrote the QR code to {}", path.display()));
+        }
+        Ok(())
+    }
+}
+
+/// The URI with its scheme and a bech32 address in uppercase, so that QR codes can store them in
+/// the denser alphanumeric mode like the already uppercase BIP77 `pj` parameter. Base58
+/// addresses are case sensitive, so `to_qr_uri` leaves them alone.
+fn qr_data(uri: &PjUri) -> String {
+    let uri_str = uri.to_string();
+    match uri_str.strip_prefix(&format!("bitcoin:{}", uri.address)) {
+        Some(params) => format!("{}{params}", uri.address.to_qr_uri()),
+        None => uri_str,
+    }
+}
+
+fn write_qr(code: &QrCode, path: &Path) -> Result<()> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("svg") => {
+            let svg =
+                code.render::<svg::Color>().min_dimensions(QR_FILE_SIZE, QR_FILE_SIZE).build();
+            std::fs::write(path, svg)
+        }
+        #[cfg(feature = "png")]
+        Some("png") => code
+            .render::<image::Luma<u8>>()
+            .min_dimensions(QR_FILE_SIZE, QR_FILE_SIZE)
+            .build()
+            .save(path)
+            .map_err(std::io::Error::other),
+        _ => return Err(anyhow!("{} is not a {QR_FILE_TYPES} file", path.display())),
+    }
+    .with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -11,20 +11,23 @@ use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
+use payjoin::bitcoin::address::NetworkUnchecked;
 use payjoin::bitcoin::psbt::Psbt;
-use payjoin::bitcoin::{Amount, FeeRate};
+use payjoin::bitcoin::{Address, Amount, FeeRate};
 use payjoin::receive::v1::{PayjoinProposal, UncheckedOriginalPayload};
 use payjoin::receive::Error;
 use payjoin::send::v1::SenderBuilder;
-use payjoin::{ImplementationError, IntoUrl, Uri, UriExt};
+use payjoin::{ImplementationError, IntoUrl, OutputSubstitution, PjUri, Uri, UriExt};
 use tokio::net::TcpListener;
 use tokio::sync::watch;
 
-use super::config::Config;
-use super::wallet::BitcoindWallet;
+use super::config::{Config, ReceiverConfig};
+use super::share::ShareOptions;
+use super::wallet::{self, PayjoinWallet};
 use super::App as AppTrait;
-use crate::app::{handle_interrupt, http_agent};
+use crate::app::{candidate_inputs, forward_script, handle_interrupt, http_agent, FeeContribution};
 use crate::db::Database;
+use crate::output::{self, Event};
 
 struct Headers<'a>(&'a hyper::HeaderMap);
 impl payjoin::receive::v1::Headers for Headers<'_> {
@@ -37,7 +40,7 @@ impl payjoin::receive::v1::Headers for Headers<'_> {
 pub(crate) struct App {
     config: Config,
     db: Arc<Database>,
-    wallet: BitcoindWallet,
+    wallet: Arc<dyn PayjoinWallet>,
     interrupt: watch::Receiver<()>,
 }
 
@@ -47,7 +50,7 @@ impl AppTrait for App {
         let db = Arc::new(Database::create(&config.db_path)?);
         let (interrupt_tx, interrupt_rx) = watch::channel(());
         tokio::spawn(handle_interrupt(interrupt_tx));
-        let wallet = BitcoindWallet::new(&config.bitcoind).await?;
+        let wallet = wallet::from_config(&config, interrupt_rx.clone()).await?;
         let app = Self { config, db, wallet, interrupt: interrupt_rx };
         app.wallet()
             .network()
@@ -55,22 +58,33 @@ impl AppTrait for App {
         Ok(app)
     }
 
-    fn wallet(&self) -> BitcoindWallet { self.wallet.clone() }
+    fn wallet(&self) -> Arc<dyn PayjoinWallet> { self.wallet.clone() }
 
-    async fn send_payjoin(&self, bip21: &str, fee_rate: FeeRate) -> Result<()> {
+    async fn send_payjoin(
+        &self,
+        bip21: &str,
+        fee_rate: FeeRate,
+        batch: &[(Address<NetworkUnchecked>, Amount)],
+    ) -> Result<()> {
         let uri =
             Uri::try_from(bip21).map_err(|e| anyhow!("Failed to create URI from BIP21: {}", e))?;
         let uri = uri.assume_checked();
         let uri = uri.check_pj_supported().map_err(|_| anyhow!("URI does not support Payjoin"))?;
         let amount = uri.amount.ok_or_else(|| anyhow!("please specify the amount in the Uri"))?;
-        let psbt = self.create_original_psbt(&uri.address, amount, fee_rate)?;
-        let (req, ctx) = SenderBuilder::new(psbt, uri.clone())
-            .build_recommended(fee_rate)
-            .with_context(|| "Failed to build payjoin request")?
-            .create_v1_post_request();
+        let psbt = self.create_original_psbt(&uri.address, amount, fee_rate, batch)?;
+        let fee_contribution = self.fee_contribution(&psbt, &uri.address, batch, fee_rate)?;
+        let builder = SenderBuilder::new(psbt, uri.clone());
+        let (req, ctx) = match fee_contribution {
+            FeeContribution::Recommended => builder.build_recommended(fee_rate),
+            FeeContribution::FromChange { max, change_index } =>
+                builder.build_with_additional_fee(max, Some(change_index), fee_rate, true),
+            FeeContribution::None => builder.build_non_incentivizing(fee_rate),
+        }
+        .with_context(|| "Failed to build payjoin request")?
+        .create_v1_post_request();
         let http = http_agent(&self.config)?;
         let body = String::from_utf8(req.body.clone()).unwrap();
-        println!("Sending fallback request to {}", &req.url);
+        output::info(format!("Sending fallback request to {}", req.url));
         let response = http
             .post(req.url)
             .header("Content-Type", req.content_type)
@@ -81,11 +95,11 @@ impl AppTrait for App {
         let fallback_tx = Psbt::from_str(&body)
             .map_err(|e| anyhow!("Failed to load PSBT from base64: {}", e))?
             .extract_tx()?;
-        println!("Sent fallback transaction txid: {}", fallback_tx.compute_txid());
-        println!(
-            "Sent fallback transaction hex: {:#}",
-            payjoin::bitcoin::consensus::encode::serialize_hex(&fallback_tx)
-        );
+        output::emit(Event::OriginalPosted {
+            session_id: None,
+            fallback_txid: Some(fallback_tx.compute_txid()),
+            fallback_tx: Some(payjoin::bitcoin::consensus::encode::serialize_hex(&fallback_tx)),
+        });
         let psbt = ctx.process_response(&response.bytes().await?).map_err(|e| {
             tracing::debug!("Error processing response: {e:?}");
             anyhow!("Failed to process response {e}")
@@ -96,19 +110,23 @@ impl AppTrait for App {
     }
 
     #[allow(clippy::incompatible_msrv)]
-    async fn receive_payjoin(&self, amount: Amount) -> Result<()> {
+    async fn receive_payjoin(&self, amount: Amount, share: &ShareOptions) -> Result<()> {
         let mut interrupt = self.interrupt.clone();
         tokio::select! {
-            res = self.start_http_server(amount) => { res?; }
+            res = self.start_http_server(amount, share) => { res?; }
             _ = interrupt.changed() => {
-                println!("Interrupted.");
+                output::info("Interrupted.");
             }
         }
         Ok(())
     }
 
     #[cfg(feature = "v2")]
-    async fn resume_payjoins(&self) -> Result<()> {
+    async fn resume_payjoins(
+        &self,
+        _session_id: Option<i64>,
+        _role: Option<crate::output::Role>,
+    ) -> Result<()> {
         unimplemented!("resume_payjoins not implemented for v1");
     }
 
@@ -116,10 +134,35 @@ impl AppTrait for App {
     async fn history(&self) -> Result<()> {
         unimplemented!("history not implemented for v1");
     }
+
+    #[cfg(feature = "v2")]
+    async fn show(&self, _session_id: i64, _role: Option<crate::output::Role>) -> Result<()> {
+        Err(anyhow!("show requires BIP77 (v2)"))
+    }
+
+    #[cfg(feature = "v2")]
+    async fn daemon(&self, _listen: SocketAddr) -> Result<()> {
+        Err(anyhow!("daemon requires BIP77 (v2)"))
+    }
+
+    #[cfg(feature = "v2")]
+    async fn cancel(
+        &self,
+        _session_id: i64,
+        _role: Option<crate::output::Role>,
+        _broadcast_fallback: bool,
+    ) -> Result<()> {
+        Err(anyhow!("cancel requires BIP77 (v2)"))
+    }
 }
 
 impl App {
-    fn construct_payjoin_uri(&self, amount: Amount, endpoint: impl IntoUrl) -> Result<String> {
+    fn construct_payjoin_uri<'a>(
+        &self,
+        amount: Amount,
+        endpoint: impl IntoUrl,
+        share: &'a ShareOptions,
+    ) -> Result<PjUri<'a>> {
         let pj_receiver_address = self.wallet.get_new_address()?;
 
         let mut pj_uri = payjoin::receive::v1::build_v1_pj_uri(
@@ -129,10 +172,10 @@ impl App {
         )?;
         pj_uri.amount = Some(amount);
 
-        Ok(pj_uri.to_string())
+        Ok(share.apply(pj_uri))
     }
 
-    async fn start_http_server(&self, amount: Amount) -> Result<()> {
+    async fn start_http_server(&self, amount: Amount, share: &ShareOptions) -> Result<()> {
         let port = self.config.v1()?.port;
         let addr = SocketAddr::from(([0, 0, 0, 0], port));
         let listener = TcpListener::bind(addr).await?;
@@ -147,12 +190,13 @@ impl App {
                 .expect("setting port must succeed");
         }
 
-        let pj_uri_string = self.construct_payjoin_uri(amount, endpoint.as_str())?;
-        println!(
-            "Listening at {}. Configured to accept payjoin at BIP 21 Payjoin Uri:",
-            listener.local_addr()?
-        );
-        println!("{pj_uri_string}");
+        let pj_uri = self.construct_payjoin_uri(amount, endpoint.as_str(), share)?;
+        output::emit(Event::UriCreated {
+            session_id: None,
+            uri: pj_uri.to_string(),
+            listening_at: Some(listener.local_addr()?),
+        });
+        share.show_qr(&pj_uri)?;
 
         let app = self.clone();
 
@@ -307,10 +351,10 @@ impl App {
         let payjoin_proposal = self.process_v1_proposal(proposal)?;
         let psbt = payjoin_proposal.psbt();
         let body = psbt.to_string();
-        println!(
-            "Responded with Payjoin proposal {}",
-            psbt.clone().extract_tx_unchecked_fee_rate().compute_txid()
-        );
+        output::emit(Event::ProposalSent {
+            session_id: None,
+            txid: psbt.clone().extract_tx_unchecked_fee_rate().compute_txid(),
+        });
         Ok(Response::new(full(body)))
     }
 
@@ -321,15 +365,19 @@ impl App {
         let wallet = self.wallet();
 
         // Receive Check 1: Can Broadcast
-        let proposal = proposal.check_broadcast_suitability(None, |tx| {
-            wallet
-                .can_broadcast(tx)
-                .map_err(|e| ImplementationError::from(e.into_boxed_dyn_error()))
-        })?;
+        let proposal = if self.config.receiver.interactive {
+            proposal.assume_interactive_receiver()
+        } else {
+            proposal.check_broadcast_suitability(None, |tx| {
+                wallet
+                    .can_broadcast(tx)
+                    .map_err(|e| ImplementationError::from(e.into_boxed_dyn_error()))
+            })?
+        };
         tracing::trace!("check1");
 
         // in a payment processor where the sender could go offline, this is where you schedule to broadcast the original_tx
-        let _to_broadcast_in_failure_case = proposal.extract_tx_to_schedule_broadcast();
+        let to_broadcast_in_failure_case = proposal.extract_tx_to_schedule_broadcast();
 
         // Receive Check 2: receiver can't sign for proposal inputs
         let proposal = proposal.check_inputs_not_owned(&mut |input| {
@@ -349,21 +397,35 @@ impl App {
                 .map_err(|e| ImplementationError::from(e.into_boxed_dyn_error()))
         })?;
 
+        // Spread forwarded payments across the forwarding addresses by the Original's txid
+        let txid = to_broadcast_in_failure_case.compute_txid();
+        let seed = u64::from_le_bytes(
+            payjoin::bitcoin::hashes::Hash::as_byte_array(&txid)[..8]
+                .try_into()
+                .expect("txid is 32 bytes"),
+        );
+        let forward =
+            forward_script(&self.config.receiver, wallet.as_ref(), seed).map_err(|e| {
+                Error::Implementation(ImplementationError::from(e.into_boxed_dyn_error()))
+            })?;
+        let receiver_script = match forward {
+            Some(script) if payjoin.output_substitution() == OutputSubstitution::Enabled => script,
+            _ => self
+                .wallet
+                .get_new_address()
+                .map_err(|e| {
+                    Error::Implementation(ImplementationError::from(e.into_boxed_dyn_error()))
+                })?
+                .script_pubkey(),
+        };
         let payjoin = payjoin
-            .substitute_receiver_script(
-                &self
-                    .wallet
-                    .get_new_address()
-                    .map_err(|e| {
-                        Error::Implementation(ImplementationError::from(e.into_boxed_dyn_error()))
-                    })?
-                    .script_pubkey(),
-            )
+            .substitute_receiver_script(&receiver_script)
             .map_err(|e| Error::Implementation(ImplementationError::new(e)))?
             .commit_outputs();
 
-        let wants_fee_range = try_contributing_inputs(payjoin.clone(), &self.wallet)
-            .map_err(Error::Implementation)?;
+        let wants_fee_range =
+            try_contributing_inputs(payjoin.clone(), &*self.wallet, &self.config.receiver)
+                .map_err(Error::Implementation)?;
         let provisional_payjoin =
             wants_fee_range.apply_fee_range(None, self.config.max_fee_rate)?;
 
@@ -378,10 +440,14 @@ impl App {
 
 fn try_contributing_inputs(
     payjoin: payjoin::receive::v1::WantsInputs,
-    wallet: &BitcoindWallet,
+    wallet: &dyn PayjoinWallet,
+    policy: &ReceiverConfig,
 ) -> Result<payjoin::receive::v1::WantsFeeRange, ImplementationError> {
-    let candidate_inputs =
-        wallet.list_unspent().map_err(|e| ImplementationError::from(e.into_boxed_dyn_error()))?;
+    let candidate_inputs = candidate_inputs(policy, wallet)
+        .map_err(|e| ImplementationError::from(e.into_boxed_dyn_error()))?;
+    if candidate_inputs.is_empty() {
+        return Ok(payjoin.commit_inputs());
+    }
 
     let selected_input =
         payjoin.try_preserving_privacy(candidate_inputs).map_err(ImplementationError::new)?;
@@ -0,0 +1,375 @@
+//! A long running daemon which keeps sessions progressing in the background and exposes a
+//! localhost HTTP API to control them.
+//!
+//! Requests must carry `Authorization: Bearer <token>`, with the token that the daemon writes
+//! to a cookie file next to the database on start, like bitcoind's `.cookie`.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full, Limited, StreamBody};
+use hyper::body::{Bytes, Frame, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use payjoin::bitcoin::hashes::cmp::fixed_time_eq;
+use payjoin::bitcoin::hex::DisplayHex;
+use payjoin::bitcoin::secp256k1::rand;
+use payjoin::bitcoin::Amount;
+use payjoin::receive::v2::ReceiveSession;
+use payjoin::send::v2::SendSession;
+use payjoin::PjParam;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::AbortHandle;
+
+use super::{parse_pj_uri, replay_receiver_event_log, replay_sender_event_log, App};
+use crate::app::share::ShareOptions;
+use crate::cli::fee_rate_from_sat_per_vb;
+use crate::db::v2::{ReceiverPersister, SenderPersister};
+use crate::output::{self, ErrorCode, Event, Role};
+
+/// The name of the file holding the API token, in the same directory as the database
+const COOKIE_FILE: &str = "payjoin-daemon.cookie";
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+#[derive(Clone)]
+struct Daemon {
+    app: App,
+    token: Arc<String>,
+    /// Sessions being processed in the background
+    tasks: Arc<Mutex<HashMap<(Role, i64), AbortHandle>>>,
+}
+
+#[derive(Deserialize)]
+struct ReceiveRequest {
+    /// The amount to receive in satoshis
+    amount: u64,
+    label: Option<String>,
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SendRequest {
+    bip21: String,
+    /// Fee rate in sat/vB
+    fee_rate: f32,
+}
+
+/// Resume all active sessions and serve the control API on `listen` until interrupted
+pub(crate) async fn run(app: App, listen: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    if !listen.ip().is_loopback() {
+        tracing::warn!("The daemon API is reachable beyond this host at {listen}");
+    }
+    let cookie = Cookie::create(&app.config.db_path)?;
+    let daemon = Daemon {
+        app,
+        token: Arc::new(cookie.token.clone()),
+        tasks: Arc::new(Mutex::new(HashMap::new())),
+    };
+    daemon.resume_sessions()?;
+    output::info(format!(
+        "Daemon API listening at {}. Authenticate with the token in {}",
+        listener.local_addr()?,
+        cookie.path.display()
+    ));
+
+    let mut interrupt = daemon.app.interrupt.clone();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::error!("Failed to accept connection: {e}");
+                        continue;
+                    }
+                };
+                let daemon = daemon.clone();
+                tokio::spawn(async move {
+                    let _ = http1::Builder::new()
+                        .serve_connection(
+                            TokioIo::new(stream),
+                            service_fn(move |req| daemon.clone().handle_request(req)),
+                        )
+                        .await;
+                });
+            }
+            _ = interrupt.changed() => {
+                output::info("Interrupted. Active sessions will resume when the daemon restarts.");
+                break;
+            }
+        }
+    }
+
+    for (_, task) in daemon.tasks.lock().expect("Lock should not be poisoned").drain() {
+        task.abort();
+    }
+    Ok(())
+}
+
+impl Daemon {
+    fn resume_sessions(&self) -> Result<()> {
+        let db = &self.app.db;
+        for session_id in db.get_recv_session_ids()? {
+            let persister = ReceiverPersister::from_id(db.clone(), session_id);
+            let (session, _) = replay_receiver_event_log(&persister)
+                .map_err(|e| anyhow!("Failed to replay receiver event log: {:?}", e))?;
+            self.spawn_receiver(session, persister);
+        }
+        for session_id in db.get_send_session_ids()? {
+            let persister = SenderPersister::from_id(db.clone(), session_id);
+            let (session, _) = replay_sender_event_log(&persister)
+                .map_err(|e| anyhow!("Failed to replay sender event log: {:?}", e))?;
+            self.spawn_sender(session, persister);
+        }
+        Ok(())
+    }
+
+    fn spawn_receiver(&self, session: ReceiveSession, persister: ReceiverPersister) {
+        let session_id = **persister.session_id();
+        let app = self.app.clone();
+        self.spawn(Role::Receiver, session_id, async move {
+            app.process_receiver_session(session, &persister).await
+        });
+    }
+
+    fn spawn_sender(&self, session: SendSession, persister: SenderPersister) {
+        let session_id = **persister.session_id();
+        let app = self.app.clone();
+        self.spawn(Role::Sender, session_id, async move {
+            app.process_sender_session(session, &persister).await
+        });
+    }
+
+    /// Process a session in the background, unless it is already being processed
+    fn spawn(
+        &self,
+        role: Role,
+        session_id: i64,
+        session: impl Future<Output = Result<()>> + Send + 'static,
+    ) {
+        let mut tasks = self.tasks.lock().expect("Lock should not be poisoned");
+        if tasks.contains_key(&(role, session_id)) {
+            return;
+        }
+        let finished = self.tasks.clone();
+        let task = tokio::spawn(async move {
+            if let Err(e) = session.await {
+                output::emit(Event::SessionFailed {
+                    session_id,
+                    role,
+                    code: ErrorCode::from(&e),
+                    message: format!("{e:#}"),
+                });
+            }
+            finished.lock().expect("Lock should not be poisoned").remove(&(role, session_id));
+        });
+        tasks.insert((role, session_id), task.abort_handle());
+    }
+
+    async fn handle_request(self, req: Request<Incoming>) -> Result<Response<Body>, Infallible> {
+        tracing::trace!("Received {} request to {}", req.method(), req.uri().path());
+        if !self.is_authorized(&req) {
+            return Ok(error_response(StatusCode::UNAUTHORIZED, None, "Invalid bearer token"));
+        }
+
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+        let response = match (&method, segments.as_slice()) {
+            (&Method::POST, ["", "receive"]) => self.receive(req).await,
+            (&Method::POST, ["", "send"]) => self.send(req).await,
+            (&Method::GET, ["", "sessions"]) => self.sessions(),
+            (&Method::POST, ["", "sessions", role, session_id, "cancel"]) =>
+                self.cancel(role, session_id),
+            (&Method::GET, ["", "events"]) => Ok(events()),
+            _ => Ok(error_response(StatusCode::NOT_FOUND, None, "Not found")),
+        };
+        Ok(response.unwrap_or_else(|e| {
+            tracing::error!("Error handling request: {e:#}");
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Some(ErrorCode::from(&e)),
+                format!("{e:#}"),
+            )
+        }))
+    }
+
+    fn is_authorized(&self, req: &Request<Incoming>) -> bool {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| {
+                token.len() == self.token.len()
+                    && fixed_time_eq(token.as_bytes(), self.token.as_bytes())
+            })
+    }
+
+    async fn receive(&self, req: Request<Incoming>) -> Result<Response<Body>> {
+        let request: ReceiveRequest = match read_json(req).await {
+            Ok(request) => request,
+            Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, None, e)),
+        };
+        let share = ShareOptions { label: request.label, message: request.message, qr: None };
+        let (session, persister) =
+            self.app.create_receive_session(Amount::from_sat(request.amount), &share).await?;
+        let response = json!({
+            "session_id": **persister.session_id(),
+            "uri": share.apply(session.pj_uri()).to_string(),
+        });
+        self.spawn_receiver(ReceiveSession::Initialized(session), persister);
+        Ok(json_response(StatusCode::OK, response))
+    }
+
+    async fn send(&self, req: Request<Incoming>) -> Result<Response<Body>> {
+        let request: SendRequest = match read_json(req).await {
+            Ok(request) => request,
+            Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, None, e)),
+        };
+        let uri = match parse_pj_uri(&request.bip21) {
+            Ok(uri) => uri,
+            Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, None, e)),
+        };
+        let Some(amount) = uri.amount else {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                None,
+                "please specify the amount in the Uri",
+            ));
+        };
+        let PjParam::V2(pj_param) = uri.extras.pj_param() else {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                None,
+                "The daemon only sends to BIP77 payjoin URIs",
+            ));
+        };
+        let (session, persister) = self.app.get_or_create_send_session(
+            pj_param,
+            &uri.address,
+            amount,
+            fee_rate_from_sat_per_vb(request.fee_rate),
+            &[],
+        )?;
+        let response = json!({ "session_id": **persister.session_id() });
+        self.spawn_sender(session, persister);
+        Ok(json_response(StatusCode::OK, response))
+    }
+
+    fn sessions(&self) -> Result<Response<Body>> {
+        Ok(json_response(StatusCode::OK, serde_json::to_value(self.app.history_entries()?)?))
+    }
+
+    fn cancel(&self, role: &str, session_id: &str) -> Result<Response<Body>> {
+        let (Ok(role), Ok(session_id)) = (role.parse::<Role>(), session_id.parse::<i64>()) else {
+            return Ok(error_response(StatusCode::NOT_FOUND, None, "Not found"));
+        };
+        if let Some(task) =
+            self.tasks.lock().expect("Lock should not be poisoned").remove(&(role, session_id))
+        {
+            task.abort();
+        }
+        if !self.app.cancel_session(role, session_id)? {
+            return Ok(error_response(
+                StatusCode::NOT_FOUND,
+                None,
+                format!("No active {} session {session_id}", role.as_str().to_lowercase()),
+            ));
+        }
+        Ok(json_response(StatusCode::OK, json!({ "session_id": session_id, "role": role })))
+    }
+}
+
+/// Stream every event as newline delimited JSON until the client disconnects
+fn events() -> Response<Body> {
+    let events = futures::stream::unfold(output::subscribe(), |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) =>
+                    return Some((
+                        Ok::<_, Infallible>(Frame::data(Bytes::from(event + "\n"))),
+                        events,
+                    )),
+                Err(RecvError::Lagged(missed)) =>
+                    tracing::warn!("Event stream subscriber missed {missed} events"),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(UnsyncBoxBody::new(StreamBody::new(events)))
+        .expect("response should be valid")
+}
+
+async fn read_json<T: DeserializeOwned>(req: Request<Incoming>) -> Result<T> {
+    let body = Limited::new(req.into_body(), MAX_REQUEST_SIZE)
+        .collect()
+        .await
+        .map_err(|e| anyhow!("Failed to read request body: {e}"))?
+        .to_bytes();
+    serde_json::from_slice(&body).map_err(|e| anyhow!("Invalid request: {e}"))
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())).boxed_unsync())
+        .expect("response should be valid")
+}
+
+fn error_response(
+    status: StatusCode,
+    code: Option<ErrorCode>,
+    message: impl fmt::Display,
+) -> Response<Body> {
+    json_response(status, json!({ "error": message.to_string(), "code": code }))
+}
+
+/// The API token, written to a file only the current user can read, and removed on shutdown
+struct Cookie {
+    path: PathBuf,
+    token: String,
+}
+
+impl Cookie {
+    fn create(db_path: &Path) -> Result<Self> {
+        let path = db_path.with_file_name(COOKIE_FILE);
+        let token = rand::random::<[u8; 32]>().to_lower_hex_string();
+
+        // Recreate the file so that it can't keep permissions from an earlier one
+        let _ = std::fs::remove_file(&path);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&path)
+            .and_then(|mut file| file.write_all(token.as_bytes()))
+            .map_err(|e| anyhow!("Failed to write {}: {e}", path.display()))?;
+        Ok(Self { path, token })
+    }
+}
+
+impl Drop for Cookie {
+    fn drop(&mut self) { let _ = std::fs::remove_file(&self.path); }
+}
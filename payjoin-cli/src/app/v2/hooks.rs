@@ -0,0 +1,435 @@
+//! Notifying other programs of session events through webhooks and hook scripts, e.g. to credit
+//! an order once its payjoin completes.
+//!
+//! A notification of every saved session event is queued in the database's outbox for each hook,
+//! in the same transaction as the event, and only removed once the hook accepts it. Failed
+//! deliveries are retried with exponential backoff, also on later runs, so every hook sees each
+//! event at least once and in order.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use payjoin::bitcoin::{Psbt, ScriptBuf, Txid};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use super::show::{outcome_name, split_event};
+use crate::app::config::HooksConfig;
+use crate::db::{now, Database};
+use crate::output::Role;
+
+/// How long a hook may take to accept a notification
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to keep delivering notifications before exiting
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(60);
+/// The delay before the first retry, doubled with every failed delivery
+const INITIAL_BACKOFF_SECS: i64 = 5;
+/// The longest delay between retries
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+
+/// Where notifications are delivered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HookTarget {
+    /// POST each notification as JSON to the URL
+    Webhook(Url),
+    /// Run the executable with each notification as JSON on stdin
+    Script(PathBuf),
+}
+
+impl HookTarget {
+    pub(crate) fn from_config(config: &HooksConfig) -> Vec<Self> {
+        let webhooks = config.webhooks.iter().cloned().map(HookTarget::Webhook);
+        let scripts = config.scripts.iter().cloned().map(HookTarget::Script);
+        webhooks.chain(scripts).collect()
+    }
+
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            HookTarget::Webhook(_) => "webhook",
+            HookTarget::Script(_) => "script",
+        }
+    }
+
+    pub(crate) fn target(&self) -> String {
+        match self {
+            HookTarget::Webhook(url) => url.to_string(),
+            HookTarget::Script(path) => path.to_string_lossy().into_owned(),
+        }
+    }
+
+    async fn deliver(&self, http: &reqwest::Client, payload: &str) -> Result<()> {
+        match self {
+            HookTarget::Webhook(url) => {
+                http.post(url.clone())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(payload.to_owned())
+                    .timeout(DELIVERY_TIMEOUT)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(())
+            }
+            HookTarget::Script(path) => {
+                // Keep stdout for our own output
+                let mut child = tokio::process::Command::new(path)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .with_context(|| format!("Failed to run {}", path.display()))?;
+                let mut stdin = child.stdin.take().expect("stdin is piped");
+                stdin.write_all(payload.as_bytes()).await?;
+                drop(stdin);
+                let status = tokio::time::timeout(DELIVERY_TIMEOUT, child.wait())
+                    .await
+                    .map_err(|_| anyhow!("{} timed out", path.display()))??;
+                if !status.success() {
+                    return Err(anyhow!("{} exited with {status}", path.display()));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What hooks are told about a saved session event
+#[derive(Debug, Serialize)]
+pub(crate) struct Notification {
+    /// The ID of the saved event, to recognize a notification which is delivered again
+    event_id: i64,
+    session_id: i64,
+    role: Role,
+    /// The `SessionEvent` variant, e.g. `FinalizedProposal` or `Closed`
+    event: String,
+    /// The session's latest transaction: the Original, or the payjoin once it is proposed
+    txid: Option<Txid>,
+    /// The amount paid to the receiver in sats, if known
+    amount: Option<u64>,
+    /// How the session ended, on `Closed` events
+    outcome: Option<String>,
+    /// When the event was saved, in seconds since the Unix epoch
+    created_at: u64,
+}
+
+impl Notification {
+    /// Describe the last of a session's `events`, which are in the order they were saved, with
+    /// what the earlier ones tell about its transaction
+    pub(crate) fn new(event_id: i64, session_id: i64, role: Role, events: &[(u64, Value)]) -> Self {
+        let mut notification = Self {
+            event_id,
+            session_id,
+            role,
+            event: "Unknown".to_owned(),
+            txid: None,
+            amount: None,
+            outcome: None,
+            created_at: 0,
+        };
+        for (created_at, event) in events {
+            let (name, data) = split_event(event);
+            match (role, name.as_str()) {
+                (Role::Sender, "Created") => {
+                    let psbt_ctx = &data["psbt_ctx"];
+                    if let Some(original) = psbt(&psbt_ctx["original_psbt"]) {
+                        notification.txid = Some(original.unsigned_tx.compute_txid());
+                        if let Ok(payee) =
+                            serde_json::from_value::<ScriptBuf>(psbt_ctx["payee"].clone())
+                        {
+                            let amount = original
+                                .unsigned_tx
+                                .output
+                                .iter()
+                                .filter(|txout| txout.script_pubkey == payee)
+                                .map(|txout| txout.value.to_sat())
+                                .sum();
+                            notification.amount = Some(amount);
+                        }
+                    }
+                }
+                (Role::Receiver, "Created") => notification.amount = data["amount"].as_u64(),
+                (Role::Receiver, "RetrievedOriginalPayload") =>
+                    if let Some(original) = psbt(&data["original"]["psbt"]) {
+                        notification.txid = Some(original.unsigned_tx.compute_txid());
+                    },
+                (Role::Sender, "ReceivedProposalPsbt") | (Role::Receiver, "FinalizedProposal") =>
+                    if let Some(payjoin) = psbt(data) {
+                        notification.txid = Some(payjoin.unsigned_tx.compute_txid());
+                    },
+                (_, "Closed") => notification.outcome = Some(outcome_name(data).to_owned()),
+                _ => {}
+            }
+            notification.event = name;
+            notification.created_at = *created_at;
+        }
+        notification
+    }
+}
+
+fn psbt(psbt: &Value) -> Option<Psbt> { serde_json::from_value(psbt.clone()).ok() }
+
+/// Delivers the notifications queued in the outbox
+pub(crate) struct Hooks {
+    db: Arc<Database>,
+    http: reqwest::Client,
+    /// Keeps the background task and the final flush from delivering a notification twice
+    delivering: tokio::sync::Mutex<()>,
+}
+
+impl Hooks {
+    pub(crate) fn new(db: Arc<Database>) -> Result<Self> {
+        // Webhooks are the operator's own services, so they aren't reached through socks_proxy
+        let http = reqwest::Client::builder().build()?;
+        Ok(Self { db, http, delivering: tokio::sync::Mutex::new(()) })
+    }
+
+    /// Deliver notifications as they are queued or due for a retry, for as long as the process
+    /// runs
+    pub(crate) async fn run(self: Arc<Self>) {
+        loop {
+            let next_attempt_at = match self.deliver_due().await {
+                Ok(next_attempt_at) => next_attempt_at,
+                Err(e) => {
+                    tracing::warn!("Failed to deliver hook notifications: {e:#}");
+                    Some(now() + INITIAL_BACKOFF_SECS)
+                }
+            };
+            let wait = match next_attempt_at {
+                Some(next_attempt_at) => (next_attempt_at - now()).clamp(1, MAX_BACKOFF_SECS),
+                None => MAX_BACKOFF_SECS,
+            };
+            tokio::select! {
+                _ = self.db.outbox_ready() => {}
+                _ = tokio::time::sleep(Duration::from_secs(wait as u64)) => {}
+            }
+        }
+    }
+
+    /// Give the notifications which are due a last chance to be delivered before exiting.
+    /// Whatever is left is delivered on a later run.
+    pub(crate) async fn flush(&self) {
+        match tokio::time::timeout(FLUSH_TIMEOUT, self.deliver_due()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => tracing::warn!("Failed to deliver hook notifications: {e:#}"),
+            Err(_) => tracing::warn!("Timed out delivering hook notifications"),
+        }
+    }
+
+    /// Try to deliver every notification which is due. A hook only gets a notification once all
+    /// earlier ones for it were delivered, so that it sees events in order. Returns when the next
+    /// retry is due, if any.
+    async fn deliver_due(&self) -> Result<Option<i64>> {
+        let _delivering = self.delivering.lock().await;
+        let mut next_attempt_at: Option<i64> = None;
+        // Hooks with an earlier notification waiting for a retry
+        let mut waiting = HashSet::new();
+        for entry in self.db.get_queued_notifications()? {
+            let key = (entry.kind.clone(), entry.target.clone());
+            if waiting.contains(&key) {
+                continue;
+            }
+            if entry.next_attempt_at > now() {
+                next_attempt_at = Some(
+                    next_attempt_at
+                        .map_or(entry.next_attempt_at, |next| next.min(entry.next_attempt_at)),
+                );
+                waiting.insert(key);
+                continue;
+            }
+            let Some(hook) = self
+                .db
+                .hooks()
+                .iter()
+                .find(|hook| hook.kind() == entry.kind && hook.target() == entry.target)
+            else {
+                tracing::warn!(
+                    "Dropping a notification for the {} {}, which is no longer configured",
+                    entry.kind,
+                    entry.target
+                );
+                self.db.delete_notification(entry.id)?;
+                continue;
+            };
+            match hook.deliver(&self.http, &entry.payload).await {
+                Ok(()) => self.db.delete_notification(entry.id)?,
+                Err(e) => {
+                    let attempts = entry.attempts + 1;
+                    tracing::warn!(
+                        "Failed to notify the {} {} (attempt {attempts}): {e:#}",
+                        entry.kind,
+                        entry.target
+                    );
+                    let backoff = INITIAL_BACKOFF_SECS
+                        .saturating_mul(1 << (attempts - 1).min(20))
+                        .min(MAX_BACKOFF_SECS);
+                    let retry_at = now() + backoff;
+                    self.db.reschedule_notification(entry.id, attempts, retry_at)?;
+                    next_attempt_at =
+                        Some(next_attempt_at.map_or(retry_at, |next| next.min(retry_at)));
+                    waiting.insert(key);
+                }
+            }
+        }
+        Ok(next_attempt_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    use payjoin::bitcoin::absolute::LockTime;
+    use payjoin::bitcoin::hashes::Hash;
+    use payjoin::bitcoin::transaction::Version;
+    use payjoin::bitcoin::{Amount, Transaction, TxOut, WPubkeyHash};
+    use rusqlite::params;
+    use serde_json::json;
+
+    use super::*;
+
+    fn script(byte: u8) -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([byte; 20]))
+    }
+
+    /// A PSBT paying `sats` to each of `outputs`
+    fn psbt(outputs: &[(u8, u64)]) -> Psbt {
+        Psbt::from_unsigned_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: outputs
+                .iter()
+                .map(|&(byte, sats)| TxOut {
+                    value: Amount::from_sat(sats),
+                    script_pubkey: script(byte),
+                })
+                .collect(),
+        })
+        .expect("unsigned tx")
+    }
+
+    #[test]
+    fn test_receiver_notification() {
+        let original = psbt(&[(1, 54_321), (2, 1_000)]);
+        let payjoin = psbt(&[(1, 64_321)]);
+        let events = vec![
+            (1, json!({ "Created": { "amount": 54_321 } })),
+            (2, json!({ "RetrievedOriginalPayload": { "original": { "psbt": original } } })),
+            (3, json!({ "FinalizedProposal": payjoin })),
+            (4, json!({ "Closed": { "Success": [] } })),
+        ];
+
+        let notification = Notification::new(9, 1, Role::Receiver, &events[..2]);
+        assert_eq!(notification.event, "RetrievedOriginalPayload");
+        assert_eq!(notification.txid, Some(original.unsigned_tx.compute_txid()));
+        assert_eq!(notification.amount, Some(54_321));
+        assert_eq!(notification.created_at, 2);
+
+        let notification = Notification::new(11, 1, Role::Receiver, &events);
+        assert_eq!(
+            serde_json::to_value(&notification).expect("serializable"),
+            json!({
+                "event_id": 11,
+                "session_id": 1,
+                "role": "receiver",
+                "event": "Closed",
+                "txid": payjoin.unsigned_tx.compute_txid(),
+                "amount": 54_321,
+                "outcome": "Success",
+                "created_at": 4,
+            })
+        );
+    }
+
+    #[test]
+    fn test_sender_notification() {
+        let original = psbt(&[(1, 54_321), (2, 1_000), (1, 1)]);
+        let events = vec![(
+            1,
+            json!({ "Created": { "psbt_ctx": { "original_psbt": original, "payee": script(1) } } }),
+        )];
+
+        let notification = Notification::new(1, 2, Role::Sender, &events);
+        assert_eq!(notification.event, "Created");
+        assert_eq!(notification.txid, Some(original.unsigned_tx.compute_txid()));
+        assert_eq!(notification.amount, Some(54_322));
+        assert_eq!(notification.outcome, None);
+    }
+
+    /// A hook script which counts its runs and records what it is sent, and fails while a `fail`
+    /// file exists next to it
+    fn hook_script(dir: &Path) -> PathBuf {
+        let path = dir.join("hook.sh");
+        std::fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\ncd {}\necho >> runs\n[ -e fail ] && exit 1\ncat >> delivered\necho >> delivered\n",
+                dir.display()
+            ),
+        )
+        .expect("write hook script");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .expect("make hook script executable");
+        path
+    }
+
+    fn queue(db: &Database, hook: &HookTarget, payload: &str) {
+        db.get_connection()
+            .expect("connection")
+            .execute(
+                "INSERT INTO hook_outbox (kind, target, payload, next_attempt_at, created_at) VALUES (?1, ?2, ?3, ?4, ?4)",
+                params![hook.kind(), hook.target(), payload, now()],
+            )
+            .expect("queue notification");
+    }
+
+    fn runs(dir: &Path) -> usize {
+        std::fs::read_to_string(dir.join("runs")).map_or(0, |runs| runs.lines().count())
+    }
+
+    #[tokio::test]
+    async fn test_deliver_due_retries_in_order_with_backoff() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let hook = HookTarget::Script(hook_script(dir.path()));
+        let db = Arc::new(
+            Database::create(dir.path().join("payjoin.sqlite"))
+                .expect("database")
+                .with_hooks(vec![hook.clone()]),
+        );
+        let hooks = Hooks::new(db.clone()).expect("hooks");
+        queue(&db, &hook, "first");
+        queue(&db, &hook, "second");
+        let removed = HookTarget::Webhook("http://127.0.0.1:1/removed".parse().expect("url"));
+        queue(&db, &removed, "dropped");
+
+        std::fs::write(dir.path().join("fail"), "").expect("write fail");
+        let retry_at = hooks.deliver_due().await.expect("delivery attempted").expect("a retry");
+        assert_eq!(runs(dir.path()), 1, "the second notification waits for the first");
+        assert!((retry_at - now() - INITIAL_BACKOFF_SECS).abs() <= 1);
+        let queued = db.get_queued_notifications().expect("queued");
+        assert_eq!(queued.len(), 2, "notifications for removed hooks are dropped");
+        assert_eq!((queued[0].attempts, queued[0].next_attempt_at), (1, retry_at));
+
+        assert_eq!(hooks.deliver_due().await.expect("nothing due"), Some(retry_at));
+        assert_eq!(runs(dir.path()), 1);
+
+        db.reschedule_notification(queued[0].id, 1, now()).expect("reschedule");
+        let retry_at = hooks.deliver_due().await.expect("delivery attempted").expect("a retry");
+        assert!((retry_at - now() - 2 * INITIAL_BACKOFF_SECS).abs() <= 1, "backoff doubles");
+
+        std::fs::remove_file(dir.path().join("fail")).expect("remove fail");
+        db.reschedule_notification(queued[0].id, 2, now()).expect("reschedule");
+        assert_eq!(hooks.deliver_due().await.expect("delivered"), None);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("delivered")).expect("delivered"),
+            "first\nsecond\n"
+        );
+        assert!(db.get_queued_notifications().expect("queued").is_empty());
+    }
+}
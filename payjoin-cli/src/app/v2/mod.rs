@@ -1,33 +1,49 @@
 use std::fmt;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
+use payjoin::bitcoin::address::NetworkUnchecked;
 use payjoin::bitcoin::consensus::encode::serialize_hex;
-use payjoin::bitcoin::{Amount, FeeRate};
-use payjoin::persist::OptionalTransitionOutcome;
+use payjoin::bitcoin::{Address, Amount, FeeRate, Txid};
+use payjoin::persist::{OptionalTransitionOutcome, PersistedError, SessionPersister};
 use payjoin::receive::v2::{
     replay_event_log as replay_receiver_event_log, HasReplyableError, Initialized,
     MaybeInputsOwned, MaybeInputsSeen, Monitor, OutputsUnknown, PayjoinProposal,
     ProvisionalProposal, ReceiveSession, Receiver, ReceiverBuilder,
-    SessionOutcome as ReceiverSessionOutcome, UncheckedOriginalPayload, WantsFeeRange, WantsInputs,
-    WantsOutputs,
+    SessionEvent as ReceiverSessionEvent, SessionOutcome as ReceiverSessionOutcome,
+    UncheckedOriginalPayload, WantsFeeRange, WantsInputs, WantsOutputs,
 };
+use payjoin::receive::ProtocolError;
 use payjoin::send::v2::{
     replay_event_log as replay_sender_event_log, PollingForProposal, SendSession, Sender,
-    SenderBuilder, SessionOutcome as SenderSessionOutcome, WithReplyKey,
+    SenderBuilder, SessionEvent as SenderSessionEvent, SessionOutcome as SenderSessionOutcome,
+    WithReplyKey,
 };
-use payjoin::{ImplementationError, PjParam, Uri};
+use payjoin::{ImplementationError, OhttpKeysCache, OutputSubstitution, PjParam, PjUri, Uri};
+use serde::Serialize;
 use tokio::sync::watch;
 
 use super::config::Config;
-use super::wallet::BitcoindWallet;
+use super::share::ShareOptions;
+use super::wallet::{self, PayjoinWallet};
 use super::App as AppTrait;
-use crate::app::v2::ohttp::{unwrap_ohttp_keys_or_else_fetch, RelayManager};
-use crate::app::{handle_interrupt, http_agent};
+use crate::app::v2::hooks::{HookTarget, Hooks};
+use crate::app::v2::ohttp::{
+    refresh_ohttp_keys, unwrap_ohttp_keys_or_else_fetch, RelayManager, OHTTP_KEYS_CACHE_TTL,
+};
+use crate::app::{
+    candidate_inputs, forward_script, handle_interrupt, http_agent, FeeContribution, Interrupted,
+};
 use crate::db::v2::{ReceiverPersister, SenderPersister, SessionId};
 use crate::db::Database;
+use crate::output::{self, Event, Role};
 
+mod daemon;
+pub(crate) mod hooks;
 mod ohttp;
+mod show;
 
 const W_ID: usize = 12;
 const W_ROLE: usize = 25;
@@ -38,13 +54,17 @@ const W_STATUS: usize = 15;
 pub(crate) struct App {
     config: Config,
     db: Arc<Database>,
-    wallet: BitcoindWallet,
+    wallet: Arc<dyn PayjoinWallet>,
     interrupt: watch::Receiver<()>,
     relay_manager: Arc<Mutex<RelayManager>>,
+    ohttp_keys_cache: Arc<OhttpKeysCache>,
+    hooks: Option<Arc<Hooks>>,
 }
 
 trait StatusText {
     fn status_text(&self) -> &'static str;
+    /// The name of the session's typestate
+    fn typestate(&self) -> &'static str;
 }
 
 impl StatusText for SendSession {
@@ -60,6 +80,15 @@ impl StatusText for SendSession {
             },
         }
     }
+
+    fn typestate(&self) -> &'static str {
+        match self {
+            SendSession::WithReplyKey(_) => "WithReplyKey",
+            SendSession::PollingForProposal(_) => "PollingForProposal",
+            SendSession::ProposalReceived(_) => "ProposalReceived",
+            SendSession::Closed(_) => "Closed",
+        }
+    }
 }
 
 impl StatusText for ReceiveSession {
@@ -86,6 +115,24 @@ impl StatusText for ReceiveSession {
             },
         }
     }
+
+    fn typestate(&self) -> &'static str {
+        match self {
+            ReceiveSession::Initialized(_) => "Initialized",
+            ReceiveSession::UncheckedOriginalPayload(_) => "UncheckedOriginalPayload",
+            ReceiveSession::MaybeInputsOwned(_) => "MaybeInputsOwned",
+            ReceiveSession::MaybeInputsSeen(_) => "MaybeInputsSeen",
+            ReceiveSession::OutputsUnknown(_) => "OutputsUnknown",
+            ReceiveSession::WantsOutputs(_) => "WantsOutputs",
+            ReceiveSession::WantsInputs(_) => "WantsInputs",
+            ReceiveSession::WantsFeeRange(_) => "WantsFeeRange",
+            ReceiveSession::ProvisionalProposal(_) => "ProvisionalProposal",
+            ReceiveSession::PayjoinProposal(_) => "PayjoinProposal",
+            ReceiveSession::HasReplyableError(_) => "HasReplyableError",
+            ReceiveSession::Monitor(_) => "Monitor",
+            ReceiveSession::Closed(_) => "Closed",
+        }
+    }
 }
 
 fn print_header() {
@@ -95,19 +142,6 @@ fn print_header() {
     );
 }
 
-enum Role {
-    Sender,
-    Receiver,
-}
-impl Role {
-    fn as_str(&self) -> &'static str {
-        match self {
-            Role::Sender => "Sender",
-            Role::Receiver => "Receiver",
-        }
-    }
-}
-
 struct SessionHistoryRow<Status> {
     session_id: SessionId,
     role: Role,
@@ -116,6 +150,9 @@ struct SessionHistoryRow<Status> {
     error_message: Option<String>,
 }
 
+/// The rows of every send and receive session
+type SessionHistory = (Vec<SessionHistoryRow<SendSession>>, Vec<SessionHistoryRow<ReceiveSession>>);
+
 impl<Status: StatusText> fmt::Display for SessionHistoryRow<Status> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -135,31 +172,102 @@ impl<Status: StatusText> fmt::Display for SessionHistoryRow<Status> {
     }
 }
 
+/// A row of the history table, as printed with `--json`
+#[derive(Serialize)]
+struct HistoryEntry {
+    session_id: i64,
+    role: Role,
+    completed_at: Option<u64>,
+    status: &'static str,
+    error: Option<String>,
+}
+
+impl<Status: StatusText> From<&SessionHistoryRow<Status>> for HistoryEntry {
+    fn from(row: &SessionHistoryRow<Status>) -> Self {
+        Self {
+            session_id: *row.session_id,
+            role: row.role,
+            completed_at: row.completed_at,
+            status: row.status.status_text(),
+            error: row.error_message.clone(),
+        }
+    }
+}
+
+/// The role of the session `session_id`, given whether a send and a receive session have that
+/// ID, or `None` if neither matches `role`
+fn session_role(
+    session_id: i64,
+    role: Option<Role>,
+    is_sender: bool,
+    is_receiver: bool,
+) -> Result<Option<Role>> {
+    match (role, is_sender, is_receiver) {
+        (Some(Role::Sender), true, _) | (None, true, false) => Ok(Some(Role::Sender)),
+        (Some(Role::Receiver), _, true) | (None, false, true) => Ok(Some(Role::Receiver)),
+        (None, true, true) =>
+            Err(anyhow!("Session {session_id} is both a send and a receive session, pass --role")),
+        _ => Ok(None),
+    }
+}
+
+fn parse_pj_uri(bip21: &str) -> Result<PjUri<'_>> {
+    use payjoin::UriExt;
+    Uri::try_from(bip21)
+        .map_err(|e| anyhow!("Failed to create URI from BIP21: {}", e))?
+        .assume_checked()
+        .check_pj_supported()
+        .map_err(|_| anyhow!("URI does not support Payjoin"))
+}
+
 #[async_trait::async_trait]
 impl AppTrait for App {
     async fn new(config: Config) -> Result<Self> {
-        let db = Arc::new(Database::create(&config.db_path)?);
+        let db = Arc::new(
+            Database::create(&config.db_path)?.with_hooks(HookTarget::from_config(&config.hooks)),
+        );
         let relay_manager = Arc::new(Mutex::new(RelayManager::new()));
         let (interrupt_tx, interrupt_rx) = watch::channel(());
         tokio::spawn(handle_interrupt(interrupt_tx));
-        let wallet = BitcoindWallet::new(&config.bitcoind).await?;
-        let app = Self { config, db, wallet, interrupt: interrupt_rx, relay_manager };
+        let wallet = wallet::from_config(&config, interrupt_rx.clone()).await?;
+        let hooks = if db.hooks().is_empty() {
+            None
+        } else {
+            let hooks = Arc::new(Hooks::new(db.clone())?);
+            tokio::spawn(hooks.clone().run());
+            Some(hooks)
+        };
+        let app = Self {
+            config,
+            db,
+            wallet,
+            interrupt: interrupt_rx,
+            relay_manager,
+            ohttp_keys_cache: Arc::new(OhttpKeysCache::new(OHTTP_KEYS_CACHE_TTL)),
+            hooks,
+        };
         app.wallet()
             .network()
             .context("Failed to connect to bitcoind. Check config RPC connection.")?;
         Ok(app)
     }
 
-    fn wallet(&self) -> BitcoindWallet { self.wallet.clone() }
+    fn wallet(&self) -> Arc<dyn PayjoinWallet> { self.wallet.clone() }
+
+    async fn flush_hooks(&self) {
+        if let Some(hooks) = &self.hooks {
+            hooks.flush().await;
+        }
+    }
 
     #[allow(clippy::incompatible_msrv)]
-    async fn send_payjoin(&self, bip21: &str, fee_rate: FeeRate) -> Result<()> {
-        use payjoin::UriExt;
-        let uri = Uri::try_from(bip21)
-            .map_err(|e| anyhow!("Failed to create URI from BIP21: {}", e))?
-            .assume_checked()
-            .check_pj_supported()
-            .map_err(|_| anyhow!("URI does not support Payjoin"))?;
+    async fn send_payjoin(
+        &self,
+        bip21: &str,
+        fee_rate: FeeRate,
+        batch: &[(Address<NetworkUnchecked>, Amount)],
+    ) -> Result<()> {
+        let uri = parse_pj_uri(bip21)?;
         let address = uri.address;
         let amount = uri.amount.ok_or_else(|| anyhow!("please specify the amount in the Uri"))?;
         match uri.extras.pj_param() {
@@ -167,19 +275,25 @@ impl AppTrait for App {
             PjParam::V1(pj_param) => {
                 use std::str::FromStr;
 
-                let psbt = self.create_original_psbt(&address, amount, fee_rate)?;
-                let (req, ctx) = payjoin::send::v1::SenderBuilder::from_parts(
+                let psbt = self.create_original_psbt(&address, amount, fee_rate, batch)?;
+                let fee_contribution = self.fee_contribution(&psbt, &address, batch, fee_rate)?;
+                let builder = payjoin::send::v1::SenderBuilder::from_parts(
                     psbt,
                     pj_param,
                     &address,
                     Some(amount),
-                )
-                .build_recommended(fee_rate)
+                );
+                let (req, ctx) = match fee_contribution {
+                    FeeContribution::Recommended => builder.build_recommended(fee_rate),
+                    FeeContribution::FromChange { max, change_index } =>
+                        builder.build_with_additional_fee(max, Some(change_index), fee_rate, true),
+                    FeeContribution::None => builder.build_non_incentivizing(fee_rate),
+                }
                 .with_context(|| "Failed to build payjoin request")?
                 .create_v1_post_request();
                 let http = http_agent(&self.config)?;
                 let body = String::from_utf8(req.body.clone()).unwrap();
-                println!("Sending fallback request to {}", &req.url);
+                output::info(format!("Sending fallback request to {}", req.url));
                 let response = http
                     .post(req.url)
                     .header("Content-Type", req.content_type)
@@ -190,11 +304,11 @@ impl AppTrait for App {
                 let fallback_tx = payjoin::bitcoin::Psbt::from_str(&body)
                     .map_err(|e| anyhow!("Failed to load PSBT from base64: {}", e))?
                     .extract_tx()?;
-                println!("Sent fallback transaction txid: {}", fallback_tx.compute_txid());
-                println!(
-                    "Sent fallback transaction hex: {:#}",
-                    payjoin::bitcoin::consensus::encode::serialize_hex(&fallback_tx)
-                );
+                output::emit(Event::OriginalPosted {
+                    session_id: None,
+                    fallback_txid: Some(fallback_tx.compute_txid()),
+                    fallback_tx: Some(serialize_hex(&fallback_tx)),
+                });
                 let psbt = ctx.process_response(&response.bytes().await?).map_err(|e| {
                     tracing::debug!("Error processing response: {e:?}");
                     anyhow!("Failed to process response {e}")
@@ -204,46 +318,14 @@ impl AppTrait for App {
                 Ok(())
             }
             PjParam::V2(pj_param) => {
-                let receiver_pubkey = pj_param.receiver_pubkey();
-                let sender_state =
-                    self.db.get_send_session_ids()?.into_iter().find_map(|session_id| {
-                        let session_receiver_pubkey = self
-                            .db
-                            .get_send_session_receiver_pk(&session_id)
-                            .expect("Receiver pubkey should exist if session id exists");
-                        if session_receiver_pubkey == *receiver_pubkey {
-                            let sender_persister =
-                                SenderPersister::from_id(self.db.clone(), session_id);
-                            let (send_session, _) = replay_sender_event_log(&sender_persister)
-                                .map_err(|e| anyhow!("Failed to replay sender event log: {:?}", e))
-                                .ok()?;
-
-                            Some((send_session, sender_persister))
-                        } else {
-                            None
-                        }
-                    });
-
-                let (sender_state, persister) = match sender_state {
-                    Some((sender_state, persister)) => (sender_state, persister),
-                    None => {
-                        let persister =
-                            SenderPersister::new(self.db.clone(), receiver_pubkey.clone())?;
-                        let psbt = self.create_original_psbt(&address, amount, fee_rate)?;
-                        let sender =
-                            SenderBuilder::from_parts(psbt, pj_param, &address, Some(amount))
-                                .build_recommended(fee_rate)?
-                                .save(&persister)?;
-
-                        (SendSession::WithReplyKey(sender), persister)
-                    }
-                };
+                let (sender_state, persister) =
+                    self.get_or_create_send_session(pj_param, &address, amount, fee_rate, batch)?;
                 let mut interrupt = self.interrupt.clone();
                 tokio::select! {
                     _ = self.process_sender_session(sender_state, &persister) => return Ok(()),
                     _ = interrupt.changed() => {
-                        println!("Interrupted. Call `send` with the same arguments to resume this session or `resume` to resume all sessions.");
-                        return Err(anyhow!("Interrupted"))
+                        output::info("Interrupted. Call `send` with the same arguments to resume this session or `resume` to resume all sessions.");
+                        return Err(Interrupted.into())
                     }
                 }
             }
@@ -251,37 +333,37 @@ impl AppTrait for App {
         }
     }
 
-    async fn receive_payjoin(&self, amount: Amount) -> Result<()> {
-        let address = self.wallet().get_new_address()?;
-        let ohttp_keys =
-            unwrap_ohttp_keys_or_else_fetch(&self.config, None, self.relay_manager.clone())
-                .await?
-                .ohttp_keys;
-        let persister = ReceiverPersister::new(self.db.clone())?;
-        let session =
-            ReceiverBuilder::new(address, self.config.v2()?.pj_directory.as_str(), ohttp_keys)?
-                .with_amount(amount)
-                .with_max_fee_rate(self.config.max_fee_rate.unwrap_or(FeeRate::BROADCAST_MIN))
-                .build()
-                .save(&persister)?;
-
-        println!("Receive session established");
-        let pj_uri = session.pj_uri();
-        println!("Request Payjoin by sharing this Payjoin Uri:");
-        println!("{pj_uri}");
-
-        self.process_receiver_session(ReceiveSession::Initialized(session.clone()), &persister)
-            .await?;
+    async fn receive_payjoin(&self, amount: Amount, share: &ShareOptions) -> Result<()> {
+        let (session, persister) = self.create_receive_session(amount, share).await?;
+        share.show_qr(&share.apply(session.pj_uri()))?;
+        self.process_receiver_session(ReceiveSession::Initialized(session), &persister).await?;
         Ok(())
     }
 
     #[allow(clippy::incompatible_msrv)]
-    async fn resume_payjoins(&self) -> Result<()> {
-        let recv_session_ids = self.db.get_recv_session_ids()?;
-        let send_session_ids = self.db.get_send_session_ids()?;
+    async fn resume_payjoins(&self, session_id: Option<i64>, role: Option<Role>) -> Result<()> {
+        let mut recv_session_ids = match role {
+            Some(Role::Sender) => vec![],
+            _ => self.db.get_recv_session_ids()?,
+        };
+        let mut send_session_ids = match role {
+            Some(Role::Receiver) => vec![],
+            _ => self.db.get_send_session_ids()?,
+        };
+        if let Some(session_id) = session_id {
+            recv_session_ids.retain(|id| **id == session_id);
+            send_session_ids.retain(|id| **id == session_id);
+            session_role(
+                session_id,
+                role,
+                !send_session_ids.is_empty(),
+                !recv_session_ids.is_empty(),
+            )?
+            .ok_or_else(|| anyhow!("No active session {session_id}"))?;
+        }
 
         if recv_session_ids.is_empty() && send_session_ids.is_empty() {
-            println!("No sessions to resume.");
+            output::info("No sessions to resume.");
             return Ok(());
         }
 
@@ -316,10 +398,10 @@ impl AppTrait for App {
                     let _ = task.await;
                 }
             } => {
-                println!("All resumed sessions completed.");
+                output::info("All resumed sessions completed.");
             }
             _ = interrupt.changed() => {
-                println!("Resumed sessions were interrupted.");
+                output::info("Resumed sessions were interrupted.");
             }
         }
         Ok(())
@@ -327,7 +409,114 @@ impl AppTrait for App {
 
     #[cfg(feature = "v2")]
     async fn history(&self) -> Result<()> {
+        if output::is_json() {
+            println!("{}", serde_json::to_string(&self.history_entries()?)?);
+            return Ok(());
+        }
+
+        let (send_rows, recv_rows) = self.session_history()?;
         print_header();
+        // Print receiver and sender rows separately
+        for row in send_rows {
+            println!("{row}");
+        }
+        for row in recv_rows {
+            println!("{row}");
+        }
+
+        Ok(())
+    }
+
+    async fn daemon(&self, listen: SocketAddr) -> Result<()> {
+        daemon::run(self.clone(), listen).await
+    }
+
+    async fn cancel(
+        &self,
+        session_id: i64,
+        role: Option<Role>,
+        broadcast_fallback: bool,
+    ) -> Result<()> {
+        let role = session_role(
+            session_id,
+            role,
+            self.active_send_session(session_id)?.is_some(),
+            self.active_receive_session(session_id)?.is_some(),
+        )?
+        .ok_or_else(|| anyhow!("No active session {session_id}"))?;
+
+        if broadcast_fallback {
+            if role != Role::Receiver {
+                return Err(anyhow!(
+                    "Only receive sessions have a fallback transaction to broadcast"
+                ));
+            }
+            let txid = self.broadcast_fallback(session_id)?;
+            output::emit(Event::FallbackBroadcast { session_id, txid });
+        } else {
+            self.cancel_session(role, session_id)?;
+            output::emit(Event::SessionCancelled { session_id, role });
+        }
+        Ok(())
+    }
+
+    async fn show(&self, session_id: i64, role: Option<Role>) -> Result<()> {
+        let send_events = self.db.get_session_events(Role::Sender, session_id)?;
+        let recv_events = self.db.get_session_events(Role::Receiver, session_id)?;
+        let role =
+            session_role(session_id, role, !send_events.is_empty(), !recv_events.is_empty())?
+                .ok_or_else(|| anyhow!("No session {session_id}"))?;
+        let events = match role {
+            Role::Sender => send_events,
+            Role::Receiver => recv_events,
+        };
+
+        let (state, uri) = match role {
+            Role::Sender => {
+                let persister =
+                    SenderPersister::from_id(self.db.clone(), SessionId::new(session_id));
+                match replay_sender_event_log(&persister) {
+                    Ok((state, history)) => (
+                        state.typestate().to_owned(),
+                        Some(PjParam::V2(history.pj_param().clone()).endpoint()),
+                    ),
+                    Err(e) => (e.to_string(), None),
+                }
+            }
+            Role::Receiver => {
+                let persister =
+                    ReceiverPersister::from_id(self.db.clone(), SessionId::new(session_id));
+                match replay_receiver_event_log(&persister) {
+                    Ok((state, history)) =>
+                        (state.typestate().to_owned(), Some(history.pj_uri().to_string())),
+                    Err(e) => (e.to_string(), None),
+                }
+            }
+        };
+        let timeline = show::SessionTimeline {
+            session_id,
+            role,
+            state,
+            uri,
+            expiration: events.first().and_then(|(_, created)| show::expiration(role, created)),
+            completed_at: self.db.get_session_completed_at(role, session_id)?,
+            events: events
+                .iter()
+                .map(|(created_at, event)| show::TimelineEntry::new(role, *created_at, event))
+                .collect(),
+        };
+
+        if output::is_json() {
+            println!("{}", serde_json::to_string(&timeline)?);
+        } else {
+            print!("{timeline}");
+        }
+        Ok(())
+    }
+}
+
+impl App {
+    fn session_history(&self) -> Result<SessionHistory> {
         let mut send_rows = vec![];
         let mut recv_rows = vec![];
         self.db.get_send_session_ids()?.into_iter().for_each(|session_id| {
@@ -438,19 +627,190 @@ impl AppTrait for App {
             },
         );
 
-        // Print receiver and sender rows separately
-        for row in send_rows {
-            println!("{row}");
+        Ok((send_rows, recv_rows))
+    }
+
+    /// The session history as printed with `--json`
+    fn history_entries(&self) -> Result<Vec<HistoryEntry>> {
+        let (send_rows, recv_rows) = self.session_history()?;
+        Ok(send_rows
+            .iter()
+            .map(HistoryEntry::from)
+            .chain(recv_rows.iter().map(HistoryEntry::from))
+            .collect())
+    }
+
+    /// Close an active session as cancelled, so that it is no longer resumed, and unlock the
+    /// coins the wallet locked for it. Returns `false` if there is no such active session.
+    pub(crate) fn cancel_session(&self, role: Role, session_id: i64) -> Result<bool> {
+        match role {
+            Role::Sender => {
+                let Some(persister) = self.active_send_session(session_id)? else {
+                    return Ok(false);
+                };
+                self.unlock_original_inputs(&persister);
+                persister.save_event(SenderSessionEvent::Closed(SenderSessionOutcome::Cancel))?;
+                persister.close()?;
+            }
+            Role::Receiver => {
+                let Some(persister) = self.active_receive_session(session_id)? else {
+                    return Ok(false);
+                };
+                persister
+                    .save_event(ReceiverSessionEvent::Closed(ReceiverSessionOutcome::Cancel))?;
+                persister.close()?;
+            }
         }
-        for row in recv_rows {
-            println!("{row}");
+        Ok(true)
+    }
+
+    /// Broadcast the fallback transaction of an active receive session which got an Original
+    /// PSBT, and close the session
+    fn broadcast_fallback(&self, session_id: i64) -> Result<Txid> {
+        let persister = self
+            .active_receive_session(session_id)?
+            .ok_or_else(|| anyhow!("No active receive session {session_id}"))?;
+        let (_, history) = replay_receiver_event_log(&persister)
+            .map_err(|e| anyhow!("Failed to replay receiver event log: {:?}", e))?;
+        let fallback_tx = history.fallback_tx().ok_or_else(|| {
+            anyhow!("Receive session {session_id} has no Original PSBT to broadcast")
+        })?;
+        let txid = self.wallet.broadcast_tx(&fallback_tx)?;
+        persister.save_event(ReceiverSessionEvent::Closed(
+            ReceiverSessionOutcome::FallbackBroadcasted,
+        ))?;
+        persister.close()?;
+        Ok(txid)
+    }
+
+    /// Unlock the inputs of a send session's Original PSBT. Failing to is only logged, since
+    /// Bitcoin Core forgets its locks on restart anyway.
+    fn unlock_original_inputs(&self, persister: &SenderPersister) {
+        let outpoints = match replay_sender_event_log(persister) {
+            Ok((_, history)) => history
+                .fallback_tx()
+                .input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                tracing::warn!(
+                    "Not unlocking inputs of send session {}: {e:?}",
+                    persister.session_id()
+                );
+                return;
+            }
+        };
+        if let Err(e) = self.wallet.unlock_unspent(&outpoints) {
+            tracing::warn!(
+                "Failed to unlock inputs of send session {}: {e}",
+                persister.session_id()
+            );
         }
+    }
 
-        Ok(())
+    fn active_send_session(&self, session_id: i64) -> Result<Option<SenderPersister>> {
+        Ok(self
+            .db
+            .get_send_session_ids()?
+            .into_iter()
+            .find(|id| **id == session_id)
+            .map(|id| SenderPersister::from_id(self.db.clone(), id)))
+    }
+
+    fn active_receive_session(&self, session_id: i64) -> Result<Option<ReceiverPersister>> {
+        Ok(self
+            .db
+            .get_recv_session_ids()?
+            .into_iter()
+            .find(|id| **id == session_id)
+            .map(|id| ReceiverPersister::from_id(self.db.clone(), id)))
+    }
+
+    /// Resume the send session paying `pj_param` if there is one, or else start a new one which
+    /// also pays `batch`
+    fn get_or_create_send_session(
+        &self,
+        pj_param: &payjoin::uri::v2::PjParam,
+        address: &Address,
+        amount: Amount,
+        fee_rate: FeeRate,
+        batch: &[(Address<NetworkUnchecked>, Amount)],
+    ) -> Result<(SendSession, SenderPersister)> {
+        let receiver_pubkey = pj_param.receiver_pubkey();
+        let sender_state = self.db.get_send_session_ids()?.into_iter().find_map(|session_id| {
+            let session_receiver_pubkey = self
+                .db
+                .get_send_session_receiver_pk(&session_id)
+                .expect("Receiver pubkey should exist if session id exists");
+            if session_receiver_pubkey == *receiver_pubkey {
+                let sender_persister = SenderPersister::from_id(self.db.clone(), session_id);
+                let (send_session, _) = replay_sender_event_log(&sender_persister)
+                    .map_err(|e| anyhow!("Failed to replay sender event log: {:?}", e))
+                    .ok()?;
+
+                Some((send_session, sender_persister))
+            } else {
+                None
+            }
+        });
+
+        match sender_state {
+            Some((sender_state, persister)) => Ok((sender_state, persister)),
+            None => {
+                let psbt = self.create_original_psbt(address, amount, fee_rate, batch)?;
+                let fee_contribution = self.fee_contribution(&psbt, address, batch, fee_rate)?;
+                // Only start the session once the Original is signed, which may take a while
+                // with an external signer, so an interrupted send leaves no empty session behind
+                let persister = SenderPersister::new(self.db.clone(), receiver_pubkey.clone())?;
+                let builder = SenderBuilder::from_parts(psbt, pj_param, address, Some(amount));
+                let sender = match fee_contribution {
+                    FeeContribution::Recommended => builder.build_recommended(fee_rate)?,
+                    FeeContribution::FromChange { max, change_index } => builder
+                        .build_with_additional_fee(max, Some(change_index), fee_rate, true)?,
+                    FeeContribution::None => builder.build_non_incentivizing(fee_rate)?,
+                }
+                .save(&persister)?;
+
+                Ok((SendSession::WithReplyKey(sender), persister))
+            }
+        }
+    }
+
+    /// Start a receive session and emit its BIP21 URI
+    async fn create_receive_session(
+        &self,
+        amount: Amount,
+        share: &ShareOptions,
+    ) -> Result<(Receiver<Initialized>, ReceiverPersister)> {
+        let address = self.wallet().get_new_address()?;
+        let ohttp_keys = unwrap_ohttp_keys_or_else_fetch(
+            &self.config,
+            None,
+            self.relay_manager.clone(),
+            &self.ohttp_keys_cache,
+        )
+        .await?
+        .ohttp_keys;
+        let persister = ReceiverPersister::new(self.db.clone())?;
+        let mut builder =
+            ReceiverBuilder::new(address, self.config.v2()?.pj_directory.as_str(), ohttp_keys)?
+                .with_amount(amount)
+                .with_max_fee_rate(self.config.max_fee_rate.unwrap_or(FeeRate::BROADCAST_MIN));
+        if let Some(expiration_secs) = self.config.receiver.expiration_secs {
+            builder = builder.with_expiration(Duration::from_secs(expiration_secs));
+        }
+        let session = builder.build().save(&persister)?;
+
+        output::emit(Event::UriCreated {
+            session_id: Some(**persister.session_id()),
+            uri: share.apply(session.pj_uri()).to_string(),
+            listening_at: None,
+        });
+
+        Ok((session, persister))
     }
-}
 
-impl App {
     async fn process_sender_session(
         &self,
         session: SendSession,
@@ -479,7 +839,11 @@ impl App {
             self.unwrap_relay_or_else_fetch(Some(&sender.endpoint())).await?.as_str(),
         )?;
         let response = self.post_request(req).await?;
-        println!("Posted original proposal...");
+        output::emit(Event::OriginalPosted {
+            session_id: Some(**persister.session_id()),
+            fallback_txid: None,
+            fallback_tx: None,
+        });
         let sender = sender.process_response(&response.bytes().await?, ctx).save(persister)?;
         self.get_proposed_payjoin_psbt(sender, persister).await
     }
@@ -499,17 +863,17 @@ impl App {
             let res = session.process_response(&response.bytes().await?, ctx).save(persister);
             match res {
                 Ok(OptionalTransitionOutcome::Progress(psbt)) => {
-                    println!("Proposal received. Processing...");
+                    output::emit(Event::ProposalReceived { session_id: **persister.session_id() });
                     self.process_pj_response(psbt)?;
                     return Ok(());
                 }
                 Ok(OptionalTransitionOutcome::Stasis(current_state)) => {
-                    println!("No response yet.");
+                    output::emit(Event::NoProposalYet { session_id: **persister.session_id() });
                     session = current_state;
                     continue;
                 }
                 Err(re) => {
-                    println!("{re}");
+                    output::info(re.to_string());
                     tracing::debug!("{re:?}");
                     return Err(anyhow!("Response error").context(re));
                 }
@@ -528,14 +892,15 @@ impl App {
         let mut session = session;
         loop {
             let (req, context) = session.create_poll_request(ohttp_relay.as_str())?;
-            println!("Polling receive request...");
+            output::info("Polling receive request...");
             let ohttp_response = self.post_request(req).await?;
             let state_transition = session
+                .clone()
                 .process_response(ohttp_response.bytes().await?.to_vec().as_slice(), context)
                 .save(persister);
             match state_transition {
                 Ok(OptionalTransitionOutcome::Progress(next_state)) => {
-                    println!("Got a request from the sender. Responding with a Payjoin proposal.");
+                    output::emit(Event::OriginalReceived { session_id: **persister.session_id() });
                     return Ok(next_state);
                 }
                 Ok(OptionalTransitionOutcome::Stasis(current_state)) => {
@@ -549,11 +914,12 @@ impl App {
 
     async fn process_receiver_session(
         &self,
-        session: ReceiveSession,
+        mut session: ReceiveSession,
         persister: &ReceiverPersister,
     ) -> Result<()> {
-        let res = {
-            match session {
+        let mut refreshed = false;
+        loop {
+            let res = match session {
                 ReceiveSession::Initialized(proposal) =>
                     self.read_from_directory(proposal, persister).await,
                 ReceiveSession::UncheckedOriginalPayload(proposal) =>
@@ -579,9 +945,28 @@ impl App {
                 ReceiveSession::Monitor(proposal) =>
                     self.monitor_payjoin_proposal(proposal, persister).await,
                 ReceiveSession::Closed(_) => return Err(anyhow!("Session closed")),
+            };
+            match res {
+                // Fresh keys which are rejected too won't be fixed by refreshing again
+                Err(e) if is_ohttp_key_rejection(&e) && !refreshed => {
+                    output::info("Directory rejected the OHTTP keys. Refreshing OHTTP keys...");
+                    // The failed state may have progressed since it was dispatched
+                    let (current, history) = replay_receiver_event_log(persister)
+                        .map_err(|e| anyhow!("Failed to replay receiver event log: {:?}", e))?;
+                    let ohttp_keys = refresh_ohttp_keys(
+                        &self.config,
+                        history.directory(),
+                        self.relay_manager.clone(),
+                        &self.ohttp_keys_cache,
+                    )
+                    .await?
+                    .ohttp_keys;
+                    session = current.refresh_ohttp_keys(ohttp_keys).save(persister)?;
+                    refreshed = true;
+                }
+                res => return res,
             }
-        };
-        res
+        }
     }
 
     #[allow(clippy::incompatible_msrv)]
@@ -594,8 +979,8 @@ impl App {
         let receiver = tokio::select! {
             res = self.long_poll_fallback(session, persister) => res,
             _ = interrupt.changed() => {
-                println!("Interrupted. Call the `resume` command to resume all sessions.");
-                return Err(anyhow!("Interrupted"));
+                output::info("Interrupted. Call the `resume` command to resume all sessions.");
+                return Err(Interrupted.into());
             }
         }?;
         self.check_proposal(receiver, persister).await
@@ -607,16 +992,22 @@ impl App {
         persister: &ReceiverPersister,
     ) -> Result<()> {
         let wallet = self.wallet();
-        let proposal = proposal
-            .check_broadcast_suitability(None, |tx| {
-                wallet
-                    .can_broadcast(tx)
-                    .map_err(|e| ImplementationError::from(e.into_boxed_dyn_error()))
-            })
-            .save(persister)?;
+        let proposal = if self.config.receiver.interactive {
+            proposal.assume_interactive_receiver().save(persister)?
+        } else {
+            proposal
+                .check_broadcast_suitability(None, |tx| {
+                    wallet
+                        .can_broadcast(tx)
+                        .map_err(|e| ImplementationError::from(e.into_boxed_dyn_error()))
+                })
+                .save(persister)?
+        };
 
-        println!("Fallback transaction received. Consider broadcasting this to get paid if the Payjoin fails:");
-        println!("{}", serialize_hex(&proposal.extract_tx_to_schedule_broadcast()));
+        output::emit(Event::FallbackReceived {
+            session_id: **persister.session_id(),
+            fallback_tx: serialize_hex(&proposal.extract_tx_to_schedule_broadcast()),
+        });
         self.check_inputs_not_owned(proposal, persister).await
     }
 
@@ -670,6 +1061,17 @@ impl App {
         proposal: Receiver<WantsOutputs>,
         persister: &ReceiverPersister,
     ) -> Result<()> {
+        let wallet = self.wallet();
+        let session_id = **persister.session_id() as u64;
+        let proposal = match forward_script(&self.config.receiver, wallet.as_ref(), session_id)? {
+            Some(script) if proposal.output_substitution() == OutputSubstitution::Enabled =>
+                proposal.substitute_receiver_script(&script)?,
+            Some(_) => {
+                output::info("The sender disabled output substitution, so the payment is kept");
+                proposal
+            }
+            None => proposal,
+        };
         let proposal = proposal.commit_outputs().save(persister)?;
         self.contribute_inputs(proposal, persister).await
     }
@@ -680,11 +1082,15 @@ impl App {
         persister: &ReceiverPersister,
     ) -> Result<()> {
         let wallet = self.wallet();
-        let candidate_inputs = wallet.list_unspent()?;
+        let candidate_inputs = candidate_inputs(&self.config.receiver, wallet.as_ref())?;
 
-        let selected_input = proposal.try_preserving_privacy(candidate_inputs)?;
-        let proposal =
-            proposal.contribute_inputs(vec![selected_input])?.commit_inputs().save(persister)?;
+        let proposal = if candidate_inputs.is_empty() {
+            proposal
+        } else {
+            let selected_input = proposal.try_preserving_privacy(candidate_inputs)?;
+            proposal.contribute_inputs(vec![selected_input])?
+        };
+        let proposal = proposal.commit_inputs().save(persister)?;
         self.apply_fee_range(proposal, persister).await
     }
 
@@ -724,10 +1130,10 @@ impl App {
         let res = self.post_request(req).await?;
         let payjoin_psbt = proposal.psbt().clone();
         let session = proposal.process_response(&res.bytes().await?, ohttp_ctx).save(persister)?;
-        println!(
-            "Response successful. Watch mempool for successful Payjoin. TXID: {}",
-            payjoin_psbt.extract_tx_unchecked_fee_rate().compute_txid()
-        );
+        output::emit(Event::ProposalSent {
+            session_id: Some(**persister.session_id()),
+            txid: payjoin_psbt.extract_tx_unchecked_fee_rate().compute_txid(),
+        });
 
         return self.monitor_payjoin_proposal(session, persister).await;
     }
@@ -765,9 +1171,14 @@ impl App {
         let ohttp_relay = match selected_relay {
             Some(relay) => relay,
             None =>
-                unwrap_ohttp_keys_or_else_fetch(&self.config, directory, self.relay_manager.clone())
-                    .await?
-                    .relay_url,
+                unwrap_ohttp_keys_or_else_fetch(
+                    &self.config,
+                    directory,
+                    self.relay_manager.clone(),
+                    &self.ohttp_keys_cache,
+                )
+                .await?
+                .relay_url,
         };
         Ok(ohttp_relay)
     }
@@ -791,9 +1202,10 @@ impl App {
             Err(e) => return Err(anyhow!("Failed to get error response bytes: {}", e)),
         };
 
-        if let Err(e) = session.process_error_response(&err_bytes, err_ctx).save(persister) {
-            return Err(anyhow!("Failed to process error response: {}", e));
-        }
+        session
+            .process_error_response(&err_bytes, err_ctx)
+            .save(persister)
+            .context("Failed to process error response")?;
 
         Ok(())
     }
@@ -809,6 +1221,15 @@ impl App {
     }
 }
 
+/// Whether a receiver request failed because the directory rejected the session's OHTTP keys
+fn is_ohttp_key_rejection(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<PersistedError<ProtocolError, crate::db::error::Error>>()
+            .and_then(PersistedError::api_error_ref),
+        Some(ProtocolError::V2(session_error)) if session_error.is_ohttp_key_rejection()
+    )
+}
+
 fn map_reqwest_err(e: reqwest::Error) -> anyhow::Error {
     match e.status() {
         Some(status_code) => anyhow!("HTTP request failed: {} {}", status_code, e),
@@ -1,8 +1,11 @@
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
+use payjoin::OhttpKeysCache;
 
 use super::Config;
+use crate::output;
 
 #[derive(Debug, Clone)]
 pub struct RelayManager {
@@ -22,6 +25,9 @@ impl RelayManager {
     pub fn get_failed_relays(&self) -> Vec<url::Url> { self.failed_relays.clone() }
 }
 
+/// How long fetched OHTTP keys are reused. Keys the directory rejects are refetched sooner.
+pub(crate) const OHTTP_KEYS_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
 pub(crate) struct ValidatedOhttpKeys {
     pub(crate) ohttp_keys: payjoin::OhttpKeys,
     pub(crate) relay_url: url::Url,
@@ -31,25 +37,39 @@ pub(crate) async fn unwrap_ohttp_keys_or_else_fetch(
     config: &Config,
     directory: Option<url::Url>,
     relay_manager: Arc<Mutex<RelayManager>>,
+    cache: &OhttpKeysCache,
 ) -> Result<ValidatedOhttpKeys> {
     if let Some(ohttp_keys) = config.v2()?.ohttp_keys.clone() {
-        println!("Using OHTTP Keys from config");
+        output::info("Using OHTTP Keys from config");
         return Ok(ValidatedOhttpKeys {
             ohttp_keys,
             relay_url: config.v2()?.ohttp_relays[0].clone(),
         });
     } else {
-        println!("Bootstrapping private network transport over Oblivious HTTP");
-        let fetched_keys = fetch_ohttp_keys(config, directory, relay_manager).await?;
+        output::info("Bootstrapping private network transport over Oblivious HTTP");
+        let fetched_keys = fetch_ohttp_keys(config, directory, relay_manager, cache).await?;
 
         Ok(fetched_keys)
     }
 }
 
+/// Fetch fresh OHTTP keys in place of those the directory rejected, bypassing the cache.
+pub(crate) async fn refresh_ohttp_keys(
+    config: &Config,
+    directory: url::Url,
+    relay_manager: Arc<Mutex<RelayManager>>,
+    cache: &OhttpKeysCache,
+) -> Result<ValidatedOhttpKeys> {
+    cache.invalidate(&directory);
+    fetch_ohttp_keys(config, Some(directory), relay_manager, cache).await
+}
+
+/// Fetch the directory's OHTTP keys through a relay, unless they are still cached.
 async fn fetch_ohttp_keys(
     config: &Config,
     directory: Option<url::Url>,
     relay_manager: Arc<Mutex<RelayManager>>,
+    cache: &OhttpKeysCache,
 ) -> Result<ValidatedOhttpKeys> {
     use payjoin::bitcoin::secp256k1::rand::prelude::SliceRandom;
     let payjoin_directory = directory.unwrap_or(config.v2()?.pj_directory.clone());
@@ -77,6 +97,19 @@ async fn fetch_ohttp_keys(
             .expect("Lock should not be poisoned")
             .set_selected_relay(selected_relay.clone());
 
+        if let Some(ohttp_keys) = cache.get(&payjoin_directory) {
+            return Ok(ValidatedOhttpKeys { ohttp_keys, relay_url: selected_relay });
+        }
+
+        if let Some(socks_proxy) = config.v2()?.socks_proxy.clone() {
+            // The SOCKS proxy already hides our IP address from the directory, so bootstrap
+            // through it directly and keep the selected relay for OHTTP requests only.
+            let ohttp_keys =
+                fetch_ohttp_keys_via_socks_proxy(config, &socks_proxy, &payjoin_directory).await?;
+            cache.insert(&payjoin_directory, ohttp_keys.clone());
+            return Ok(ValidatedOhttpKeys { ohttp_keys, relay_url: selected_relay });
+        }
+
         let ohttp_keys = {
             #[cfg(feature = "_manual-tls")]
             {
@@ -101,8 +134,10 @@ async fn fetch_ohttp_keys(
         };
 
         match ohttp_keys {
-            Ok(keys) =>
-                return Ok(ValidatedOhttpKeys { ohttp_keys: keys, relay_url: selected_relay }),
+            Ok(keys) => {
+                cache.insert(&payjoin_directory, keys.clone());
+                return Ok(ValidatedOhttpKeys { ohttp_keys: keys, relay_url: selected_relay });
+            }
             Err(payjoin::io::Error::UnexpectedStatusCode(e)) => {
                 return Err(payjoin::io::Error::UnexpectedStatusCode(e).into());
             }
@@ -116,3 +151,94 @@ async fn fetch_ohttp_keys(
         }
     }
 }
+
+async fn fetch_ohttp_keys_via_socks_proxy(
+    #[allow(unused_variables)] config: &Config,
+    socks_proxy: &url::Url,
+    payjoin_directory: &url::Url,
+) -> Result<payjoin::OhttpKeys> {
+    #[cfg(feature = "_manual-tls")]
+    if let Some(cert_path) = config.root_certificate.as_ref() {
+        let cert_der = std::fs::read(cert_path)?;
+        return Ok(payjoin::io::fetch_ohttp_keys_via_socks_proxy_with_cert(
+            socks_proxy.as_str(),
+            payjoin_directory.as_str(),
+            cert_der,
+        )
+        .await?);
+    }
+    Ok(payjoin::io::fetch_ohttp_keys_via_socks_proxy(
+        socks_proxy.as_str(),
+        payjoin_directory.as_str(),
+    )
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use payjoin::bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use payjoin::OhttpKeys;
+
+    use super::*;
+    use crate::app::config::{
+        BitcoindConfig, HooksConfig, ReceiverConfig, V2Config, VersionConfig,
+    };
+
+    fn ohttp_keys() -> OhttpKeys {
+        let secret_key = SecretKey::from_slice(&[1u8; 32]).expect("valid key");
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key);
+        // A key ID followed by the gateway's compressed public key
+        let bytes = [&[1u8][..], &public_key.serialize()].concat();
+        OhttpKeys::try_from(&bytes[..]).expect("valid keys")
+    }
+
+    /// A config whose only relay can't be reached
+    fn config() -> Config {
+        Config {
+            db_path: "payjoin.sqlite".into(),
+            max_fee_rate: None,
+            bitcoind: BitcoindConfig {
+                rpchost: url::Url::parse("http://127.0.0.1:18443").expect("valid url"),
+                cookie: None,
+                rpcuser: "bitcoin".to_owned(),
+                rpcpassword: "bitcoin".to_owned(),
+            },
+            bdk: None,
+            external_signer: None,
+            receiver: ReceiverConfig::default(),
+            hooks: HooksConfig::default(),
+            version: Some(VersionConfig::V2(V2Config {
+                ohttp_keys: None,
+                ohttp_relays: vec![url::Url::parse("http://127.0.0.1:1").expect("valid url")],
+                pj_directory: url::Url::parse("https://directory.example").expect("valid url"),
+                socks_proxy: None,
+            })),
+            #[cfg(feature = "_manual-tls")]
+            root_certificate: None,
+            #[cfg(feature = "_manual-tls")]
+            certificate_key: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_uses_cache_and_refresh_bypasses_it() {
+        let config = config();
+        let directory = config.v2().expect("v2 config").pj_directory.clone();
+        let ohttp_keys = ohttp_keys();
+        let cache = OhttpKeysCache::new(OHTTP_KEYS_CACHE_TTL);
+        cache.insert(&directory, ohttp_keys.clone());
+        let relay_manager = Arc::new(Mutex::new(RelayManager::new()));
+
+        let bootstrapped =
+            unwrap_ohttp_keys_or_else_fetch(&config, None, relay_manager.clone(), &cache)
+                .await
+                .expect("cached keys need no relay");
+        assert_eq!(bootstrapped.ohttp_keys, ohttp_keys);
+
+        assert!(
+            refresh_ohttp_keys(&config, directory.clone(), relay_manager, &cache).await.is_err(),
+            "refreshing must fetch new keys rather than reuse the rejected ones"
+        );
+        assert!(cache.get(&directory).is_none());
+    }
+}
@@ -0,0 +1,188 @@
+//! The event log of a session, replayed into a timeline for `show`.
+//!
+//! Events are read in their stored JSON form, since most of what they hold is private to the
+//! payjoin crate. Secret keys are never printed, only whether a reply key is present.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use payjoin::bitcoin::Psbt;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::output::Role;
+
+#[derive(Serialize)]
+pub(super) struct SessionTimeline {
+    pub session_id: i64,
+    pub role: Role,
+    /// The typestate the event log replays to, or why it can't be replayed
+    pub state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<u64>,
+    pub events: Vec<TimelineEntry>,
+}
+
+#[derive(Serialize)]
+pub(super) struct TimelineEntry {
+    created_at: u64,
+    event: String,
+    #[serde(flatten)]
+    details: BTreeMap<&'static str, String>,
+}
+
+impl TimelineEntry {
+    pub fn new(role: Role, created_at: u64, event: &Value) -> Self {
+        let (name, data) = split_event(event);
+        let mut details = BTreeMap::new();
+        match role {
+            Role::Sender => sender_details(&mut details, &name, data),
+            Role::Receiver => receiver_details(&mut details, &name, data),
+        }
+        Self { created_at, event: name, details }
+    }
+}
+
+/// The name of a stored event's variant, and its data
+pub(super) fn split_event(event: &Value) -> (String, &Value) {
+    match event {
+        Value::String(name) => (name.clone(), &Value::Null),
+        Value::Object(map) => match map.iter().next() {
+            Some((name, data)) => (name.clone(), data),
+            None => ("Unknown".to_owned(), &Value::Null),
+        },
+        _ => ("Unknown".to_owned(), &Value::Null),
+    }
+}
+
+/// The name of a stored `SessionOutcome`'s variant
+pub(super) fn outcome_name(outcome: &Value) -> &str {
+    match outcome {
+        Value::String(name) => name.as_str(),
+        Value::Object(map) => map.keys().next().map(String::as_str).unwrap_or("Unknown"),
+        _ => "Unknown",
+    }
+}
+
+/// The expiration of the session's Payjoin URI, from its first event
+pub(super) fn expiration(role: Role, created: &Value) -> Option<u64> {
+    let context = created.get("Created")?;
+    match role {
+        Role::Sender => context.get("pj_param")?.get("V2")?.get("expiration")?.as_u64(),
+        Role::Receiver => context.get("expiration")?.as_u64(),
+    }
+}
+
+fn sender_details(details: &mut BTreeMap<&'static str, String>, name: &str, data: &Value) {
+    match name {
+        "Created" => {
+            let psbt_ctx = &data["psbt_ctx"];
+            psbt_details(details, &psbt_ctx["original_psbt"]);
+            if let Some(min_fee_rate) = psbt_ctx["min_fee_rate"].as_u64() {
+                details.insert("min_fee_rate", fee_rate(min_fee_rate));
+            }
+            if let Some(output_substitution) = psbt_ctx["output_substitution"].as_str() {
+                details.insert("output_substitution", output_substitution.to_owned());
+            }
+            details.insert("reply_key", presence(&data["reply_key"]));
+        }
+        "ReceivedProposalPsbt" => psbt_details(details, data),
+        "Closed" => outcome_details(details, data),
+        _ => {}
+    }
+}
+
+fn receiver_details(details: &mut BTreeMap<&'static str, String>, name: &str, data: &Value) {
+    match name {
+        "Created" => {
+            if let Some(address) = data["address"].as_str() {
+                details.insert("address", address.to_owned());
+            }
+            if let Some(amount) = data["amount"].as_u64() {
+                details.insert("amount", format!("{amount} sat"));
+            }
+            if let Some(max_fee_rate) = data["max_fee_rate"].as_u64() {
+                details.insert("max_fee_rate", fee_rate(max_fee_rate));
+            }
+            details.insert("reply_key", presence(&data["reply_key"]));
+        }
+        "RetrievedOriginalPayload" => {
+            psbt_details(details, &data["original"]["psbt"]);
+            details.insert("reply_key", presence(&data["reply_key"]));
+        }
+        "IdentifiedReceiverOutputs" =>
+            if let Some(vouts) = data.as_array() {
+                let vouts = vouts.iter().map(Value::to_string).collect::<Vec<_>>();
+                details.insert("receiver_outputs", vouts.join(", "));
+            },
+        "CommittedOutputs" | "CommittedInputs" =>
+            if let Some(count) = data.as_array().map(Vec::len) {
+                details.insert("count", count.to_string());
+            },
+        "AppliedFeeRange" => psbt_details(details, &data["payjoin_psbt"]),
+        "FinalizedProposal" => psbt_details(details, data),
+        "GotReplyableError" => {
+            details.insert("error", data.to_string());
+        }
+        "Closed" => outcome_details(details, data),
+        _ => {}
+    }
+}
+
+fn outcome_details(details: &mut BTreeMap<&'static str, String>, outcome: &Value) {
+    details.insert("outcome", outcome_name(outcome).to_owned());
+}
+
+/// The PSBT and its fee. The fee rate is only known once every input is finalized, since the
+/// size of the missing signatures can't be known.
+fn psbt_details(details: &mut BTreeMap<&'static str, String>, psbt: &Value) {
+    let Ok(psbt) = serde_json::from_value::<Psbt>(psbt.clone()) else {
+        return;
+    };
+    if let Ok(fee) = psbt.fee() {
+        details.insert("fee", format!("{} sat", fee.to_sat()));
+        let finalized = psbt
+            .inputs
+            .iter()
+            .all(|input| input.final_script_sig.is_some() || input.final_script_witness.is_some());
+        if finalized {
+            let vsize = psbt.clone().extract_tx_unchecked_fee_rate().vsize();
+            details.insert("fee_rate", format!("{:.2} sat/vB", fee.to_sat() as f64 / vsize as f64));
+        }
+    }
+    details.insert("psbt", psbt.to_string());
+}
+
+/// A fee rate serialized in sat/kwu, in sat/vB
+fn fee_rate(sat_per_kwu: u64) -> String {
+    format!("{:.2} sat/vB", sat_per_kwu as f64 * 4.0 / 1000.0)
+}
+
+fn presence(key: &Value) -> String { if key.is_null() { "absent" } else { "present" }.to_owned() }
+
+impl fmt::Display for SessionTimeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} session {}: {}", self.role.as_str(), self.session_id, self.state)?;
+        if let Some(uri) = &self.uri {
+            writeln!(f, "Payjoin URI: {uri}")?;
+        }
+        if let Some(expiration) = self.expiration {
+            writeln!(f, "Expires at: {expiration}")?;
+        }
+        match self.completed_at {
+            None => writeln!(f, "Completed at: Not Completed")?,
+            Some(secs) => writeln!(f, "Completed at: {secs}")?,
+        }
+        for entry in &self.events {
+            writeln!(f, "\n{} {}", entry.created_at, entry.event)?;
+            for (name, value) in &entry.details {
+                writeln!(f, "    {name}: {value}")?;
+            }
+        }
+        Ok(())
+    }
+}
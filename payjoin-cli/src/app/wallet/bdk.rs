@@ -0,0 +1,266 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use bdk_bitcoind_rpc::bitcoincore_rpc::{Auth, Client, RpcApi};
+use bdk_bitcoind_rpc::Emitter;
+use bdk_wallet::chain::ChainPosition;
+use bdk_wallet::file_store::Store;
+use bdk_wallet::{ChangeSet, KeychainKind, LocalOutput, PersistedWallet, SignOptions, Wallet};
+use payjoin::bitcoin::psbt::{Input, Psbt};
+use payjoin::bitcoin::{
+    Address, Amount, FeeRate, Network, OutPoint, Script, Transaction, TxIn, Txid,
+};
+use payjoin::receive::InputPair;
+
+use super::{PayjoinWallet, Utxo};
+use crate::app::config::{BdkConfig, BitcoindConfig};
+
+const DB_MAGIC: &[u8] = b"payjoin-cli bdk wallet";
+
+/// Implementation of PayjoinWallet for a local descriptor wallet. The node behind `[bitcoind]`
+/// is only used as a block source and to broadcast transactions, so it needs no wallet of its own.
+#[derive(Clone)]
+pub struct BdkWallet {
+    inner: Arc<Mutex<Inner>>,
+    rpc: Arc<Client>,
+    start_height: u32,
+}
+
+struct Inner {
+    wallet: PersistedWallet<Store<ChangeSet>>,
+    db: Store<ChangeSet>,
+    /// Coins spent by pending payjoins. Like Bitcoin Core's `lockunspent`, these locks only last
+    /// as long as the process.
+    locked: HashSet<OutPoint>,
+}
+
+impl BdkWallet {
+    pub fn new(bitcoind: &BitcoindConfig, config: &BdkConfig) -> Result<Self> {
+        let auth = match &bitcoind.cookie {
+            Some(cookie) if cookie.as_os_str().is_empty() =>
+                return Err(anyhow!(
+                    "Cookie authentication enabled but no cookie path provided in config.toml"
+                )),
+            Some(cookie) => Auth::CookieFile(cookie.clone()),
+            None => Auth::UserPass(bitcoind.rpcuser.clone(), bitcoind.rpcpassword.clone()),
+        };
+        let rpc = Client::new(bitcoind.rpchost.as_str(), auth)
+            .context("Failed to create bitcoind RPC client")?;
+        let network = tokio::task::block_in_place(|| rpc.get_blockchain_info())
+            .context("Failed to get blockchain info")?
+            .chain;
+
+        let mut db = Store::<ChangeSet>::open_or_create_new(DB_MAGIC, &config.wallet_path)
+            .map_err(|e| anyhow!("Failed to open wallet file: {e}"))?;
+        let loaded = Wallet::load()
+            .descriptor(KeychainKind::External, Some(config.descriptor.clone()))
+            .descriptor(KeychainKind::Internal, Some(config.change_descriptor.clone()))
+            .extract_keys()
+            .check_network(network)
+            .load_wallet(&mut db)
+            .map_err(|e| anyhow!("Failed to load wallet: {e}"))?;
+        let wallet = match loaded {
+            Some(wallet) => wallet,
+            None => Wallet::create(config.descriptor.clone(), config.change_descriptor.clone())
+                .network(network)
+                .create_wallet(&mut db)
+                .map_err(|e| anyhow!("Failed to create wallet: {e}"))?,
+        };
+
+        let wallet = Self {
+            inner: Arc::new(Mutex::new(Inner { wallet, db, locked: HashSet::new() })),
+            rpc: Arc::new(rpc),
+            start_height: config.start_height,
+        };
+        wallet.sync()?;
+        Ok(wallet)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("Lock should not be poisoned")
+    }
+
+    fn build_psbt(
+        &self,
+        outputs: HashMap<String, Amount>,
+        fee_rate: FeeRate,
+        lock_unspent: bool,
+        sign: bool,
+    ) -> Result<Psbt> {
+        self.sync()?;
+        let mut inner = self.lock();
+        let Inner { wallet, locked, .. } = &mut *inner;
+        let network = wallet.network();
+
+        let mut builder = wallet.build_tx();
+        for (address, amount) in outputs {
+            let address = Address::from_str(&address)?.require_network(network)?;
+            builder.add_recipient(address.script_pubkey(), amount);
+        }
+        builder.fee_rate(fee_rate).unspendable(locked.iter().copied().collect());
+        let mut psbt = builder.finish().map_err(|e| anyhow!("Failed to create PSBT: {e}"))?;
+
+        if sign {
+            let finalized = wallet
+                .sign(&mut psbt, SignOptions::default())
+                .map_err(|e| anyhow!("Failed to sign PSBT: {e}"))?;
+            if !finalized {
+                return Err(anyhow!("Failed to sign all inputs, is the descriptor missing keys?"));
+            }
+        }
+        if lock_unspent {
+            locked.extend(psbt.unsigned_tx.input.iter().map(|txin| txin.previous_output));
+        }
+        inner.persist()?;
+        Ok(psbt)
+    }
+
+    /// Catch up with the node's chain tip and mempool
+    fn sync(&self) -> Result<()> {
+        let mut inner = self.lock();
+        tokio::task::block_in_place(|| {
+            let tip = inner.wallet.latest_checkpoint();
+            let mut emitter = Emitter::new(self.rpc.as_ref(), tip, self.start_height);
+            while let Some(event) =
+                emitter.next_block().context("Failed to fetch block from bitcoind")?
+            {
+                inner
+                    .wallet
+                    .apply_block_connected_to(
+                        &event.block,
+                        event.block_height(),
+                        event.connected_to(),
+                    )
+                    .map_err(|e| anyhow!("Failed to apply block: {e}"))?;
+            }
+            let mempool = emitter.mempool().context("Failed to fetch mempool from bitcoind")?;
+            inner.wallet.apply_unconfirmed_txs(mempool);
+            inner.persist()
+        })
+    }
+}
+
+impl Inner {
+    fn persist(&mut self) -> Result<()> {
+        self.wallet.persist(&mut self.db).map_err(|e| anyhow!("Failed to persist wallet: {e}"))?;
+        Ok(())
+    }
+}
+
+impl PayjoinWallet for BdkWallet {
+    /// Locked coins are only excluded from coin selection by this process
+    fn create_psbt(
+        &self,
+        outputs: HashMap<String, Amount>,
+        fee_rate: FeeRate,
+        lock_unspent: bool,
+    ) -> Result<Psbt> {
+        self.build_psbt(outputs, fee_rate, lock_unspent, true)
+    }
+
+    fn create_unsigned_psbt(
+        &self,
+        outputs: HashMap<String, Amount>,
+        fee_rate: FeeRate,
+        lock_unspent: bool,
+    ) -> Result<Psbt> {
+        self.build_psbt(outputs, fee_rate, lock_unspent, false)
+    }
+
+    fn process_psbt(&self, psbt: &Psbt) -> Result<Psbt> {
+        let mut psbt = psbt.clone();
+        let sign_options = SignOptions { trust_witness_utxo: true, ..Default::default() };
+        self.lock()
+            .wallet
+            .sign(&mut psbt, sign_options)
+            .map_err(|e| anyhow!("Failed to sign PSBT: {e}"))?;
+        Ok(psbt)
+    }
+
+    fn can_broadcast(&self, tx: &Transaction) -> Result<bool> {
+        let mempool_results = tokio::task::block_in_place(|| self.rpc.test_mempool_accept(&[tx]))?;
+        mempool_results
+            .first()
+            .map(|result| result.allowed)
+            .ok_or_else(|| anyhow!("No mempool results returned on broadcast check"))
+    }
+
+    fn broadcast_tx(&self, tx: &Transaction) -> Result<Txid> {
+        let txid = tokio::task::block_in_place(|| self.rpc.send_raw_transaction(tx))
+            .context("Failed to broadcast transaction")?;
+        let seen_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut inner = self.lock();
+        inner.wallet.apply_unconfirmed_txs([(tx.clone(), seen_at)]);
+        inner.persist()?;
+        Ok(txid)
+    }
+
+    fn is_mine(&self, script: &Script) -> Result<bool> {
+        Ok(self.lock().wallet.is_mine(script.to_owned()))
+    }
+
+    #[cfg(feature = "v2")]
+    fn is_outpoint_spent(&self, outpoint: &OutPoint) -> Result<bool> {
+        // Outputs spent in the mempool are considered spent for our purposes
+        let txout = tokio::task::block_in_place(|| {
+            self.rpc.get_tx_out(&outpoint.txid, outpoint.vout, Some(true))
+        })?;
+        Ok(txout.is_none())
+    }
+
+    #[cfg(feature = "v2")]
+    fn unlock_unspent(&self, outpoints: &[OutPoint]) -> Result<()> {
+        let mut inner = self.lock();
+        for outpoint in outpoints {
+            inner.locked.remove(outpoint);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "v2")]
+    fn get_raw_transaction(&self, txid: &Txid) -> Result<Option<Transaction>> {
+        self.sync()?;
+        Ok(self.lock().wallet.get_tx(*txid).map(|tx| tx.tx_node.tx.as_ref().clone()))
+    }
+
+    fn get_new_address(&self) -> Result<Address> {
+        let mut inner = self.lock();
+        let address = inner.wallet.reveal_next_address(KeychainKind::External).address;
+        inner.persist()?;
+        Ok(address)
+    }
+
+    fn list_unspent(&self) -> Result<Vec<Utxo>> {
+        self.sync()?;
+        let inner = self.lock();
+        let tip = inner.wallet.latest_checkpoint().height();
+        inner
+            .wallet
+            .list_unspent()
+            .filter(|utxo| !inner.locked.contains(&utxo.outpoint))
+            .map(|utxo| {
+                let confirmations = match &utxo.chain_position {
+                    ChainPosition::Confirmed { anchor, .. } =>
+                        tip.saturating_sub(anchor.block_id.height) + 1,
+                    ChainPosition::Unconfirmed { .. } => 0,
+                };
+                Ok(Utxo {
+                    value: utxo.txout.value,
+                    confirmations,
+                    input: input_pair_from_local_output(utxo)?,
+                })
+            })
+            .collect()
+    }
+
+    fn network(&self) -> Result<Network> { Ok(self.lock().wallet.network()) }
+}
+
+fn input_pair_from_local_output(utxo: LocalOutput) -> Result<InputPair> {
+    let psbtin = Input { witness_utxo: Some(utxo.txout), ..Default::default() };
+    let txin = TxIn { previous_output: utxo.outpoint, ..Default::default() };
+    InputPair::new(txin, psbtin, None).map_err(|e| anyhow!("Unsupported wallet input: {e}"))
+}
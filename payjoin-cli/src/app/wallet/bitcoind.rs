@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use bitcoind_async_client::traits::{Broadcaster, Reader, Signer, Wallet};
+use bitcoind_async_client::types::{
+    CreateRawTransactionOutput, ListUnspent, WalletCreateFundedPsbtOptions,
+};
+use bitcoind_async_client::{Auth, Client as AsyncBitcoinRpc};
+use payjoin::bitcoin::psbt::{Input, Psbt};
+use payjoin::bitcoin::{
+    Address, Amount, FeeRate, Network, OutPoint, Script, ScriptBuf, Transaction, TxIn, TxOut, Txid,
+};
+use payjoin::receive::InputPair;
+
+use super::{PayjoinWallet, Utxo};
+use crate::app::config::BitcoindConfig;
+
+/// Implementation of PayjoinWallet for a Bitcoin Core wallet using async RPC client
+#[derive(Clone)]
+pub struct BitcoindWallet {
+    rpc: Arc<AsyncBitcoinRpc>,
+    #[cfg(feature = "v2")]
+    raw_rpc: RawRpc,
+}
+
+impl BitcoindWallet {
+    pub async fn new(config: &BitcoindConfig) -> Result<Self> {
+        let (user, password) = rpc_credentials(config)?;
+        let rpc = AsyncBitcoinRpc::new(
+            config.rpchost.to_string(),
+            Auth::UserPass(user.clone(), password.clone()),
+            None,
+            None,
+        )?;
+
+        Ok(Self {
+            rpc: Arc::new(rpc),
+            #[cfg(feature = "v2")]
+            raw_rpc: RawRpc::new(config.rpchost.clone(), user, password),
+        })
+    }
+}
+
+/// The RPC user and password, read from the cookie file if one is configured
+fn rpc_credentials(config: &BitcoindConfig) -> Result<(String, String)> {
+    match &config.cookie {
+        Some(cookie) if cookie.as_os_str().is_empty() =>
+            Err(anyhow!("Cookie authentication enabled but no cookie path provided in config.toml")),
+        Some(cookie) => {
+            let contents = std::fs::read_to_string(cookie)
+                .with_context(|| format!("Failed to read cookie file {}", cookie.display()))?;
+            parse_cookie(&contents)
+                .ok_or_else(|| anyhow!("Invalid cookie file {}", cookie.display()))
+        }
+        None => Ok((config.rpcuser.clone(), config.rpcpassword.clone())),
+    }
+}
+
+/// Split a `<user>:<password>` cookie
+fn parse_cookie(cookie: &str) -> Option<(String, String)> {
+    let (user, password) = cookie.trim().split_once(':')?;
+    Some((user.to_owned(), password.to_owned()))
+}
+
+/// JSON-RPC calls the async client has no method for
+///
+/// `bitcoind_async_client` doesn't wrap `listlockunspent` or `lockunspent`, and its generic
+/// `call` is private, so those go over this plain HTTP client with the same credentials.
+#[cfg(feature = "v2")]
+#[derive(Clone)]
+struct RawRpc {
+    http: reqwest::Client,
+    url: url::Url,
+    user: String,
+    password: String,
+}
+
+#[cfg(feature = "v2")]
+impl RawRpc {
+    fn new(url: url::Url, user: String, password: String) -> Self {
+        Self { http: reqwest::Client::new(), url, user, password }
+    }
+
+    async fn call<T: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<T> {
+        let request = serde_json::json!({
+            "jsonrpc": "1.0",
+            "id": "payjoin-cli",
+            "method": method,
+            "params": params,
+        });
+        // bitcoind answers RPC errors with an error status and a JSON body, so parse it regardless
+        let response: serde_json::Value = self
+            .http
+            .post(self.url.clone())
+            .basic_auth(&self.user, Some(&self.password))
+            .json(&request)
+            .send()
+            .await?
+            .json()
+            .await?;
+        if !response["error"].is_null() {
+            return Err(anyhow!("{method} failed: {}", response["error"]));
+        }
+        Ok(serde_json::from_value(response["result"].clone())?)
+    }
+}
+
+impl PayjoinWallet for BitcoindWallet {
+    fn create_psbt(
+        &self,
+        outputs: HashMap<String, Amount>,
+        fee_rate: FeeRate,
+        lock_unspent: bool,
+    ) -> Result<Psbt> {
+        let funded = self.create_unsigned_psbt(outputs, fee_rate, lock_unspent)?;
+
+        let processed = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async {
+                self.rpc.wallet_process_psbt(&funded.to_string(), None, None, None).await
+            })
+        })?
+        .psbt
+        .expect("should have processed valid PSBT");
+
+        Ok(processed)
+    }
+
+    fn create_unsigned_psbt(
+        &self,
+        outputs: HashMap<String, Amount>,
+        fee_rate: FeeRate,
+        lock_unspent: bool,
+    ) -> Result<Psbt> {
+        let fee_sat_per_vb = fee_rate.to_sat_per_vb_ceil();
+        tracing::debug!("Fee rate sat/vb: {}", fee_sat_per_vb);
+
+        let options = WalletCreateFundedPsbtOptions {
+            fee_rate: Some(fee_sat_per_vb as f64),
+            lock_unspents: Some(lock_unspent),
+            replaceable: None,
+            conf_target: None,
+        };
+
+        // Sync wrapper around async call - use tokio handle to avoid deadlock
+        let result = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async {
+                self.rpc
+                    .wallet_create_funded_psbt(
+                        &[], // inputs
+                        &outputs
+                            .iter()
+                            .map(|(k, v)| CreateRawTransactionOutput::AddressAmount {
+                                address: k.clone(),
+                                amount: v.to_btc(),
+                            })
+                            .collect::<Vec<_>>(),
+                        None, // locktime
+                        Some(options),
+                        None,
+                    )
+                    .await
+            })
+        })?;
+
+        Ok(Psbt::from_str(&result.psbt.to_string())?)
+    }
+
+    /// Does not include bip32 derivations in the PSBT
+    fn process_psbt(&self, psbt: &Psbt) -> Result<Psbt> {
+        let psbt_str = psbt.to_string();
+        let processed = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async {
+                self.rpc.wallet_process_psbt(&psbt_str, Some(true), None, None).await
+            })
+        })?;
+        processed.psbt.ok_or_else(|| anyhow!("Insane PSBT"))
+    }
+
+    fn can_broadcast(&self, tx: &Transaction) -> Result<bool> {
+        let mempool_results = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current()
+                .block_on(async { self.rpc.test_mempool_accept(tx).await })
+        })?;
+
+        mempool_results
+            .first()
+            .map(|result| result.reject_reason.is_none())
+            .ok_or_else(|| anyhow!("No mempool results returned on broadcast check"))
+    }
+
+    fn broadcast_tx(&self, tx: &Transaction) -> Result<Txid> {
+        tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current()
+                .block_on(async { self.rpc.send_raw_transaction(tx).await })
+        })
+        .context("Failed to broadcast transaction")
+    }
+
+    fn is_mine(&self, script: &Script) -> Result<bool> {
+        if let Ok(address) = Address::from_script(script, self.network()?) {
+            let info = tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current()
+                    .block_on(async { self.rpc.get_address_info(&address).await })
+            })
+            .context("Failed to get address info")?;
+            Ok(info.is_mine.unwrap_or(false))
+        } else {
+            Ok(false)
+        }
+    }
+
+    #[cfg(feature = "v2")]
+    fn is_outpoint_spent(&self, outpoint: &OutPoint) -> Result<bool> {
+        let _ = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current()
+                // Note: explicitly ignore txouts in the mempool. Those should be considered spent for our purposes
+                .block_on(async {
+                    match self.rpc.get_tx_out(&outpoint.txid, outpoint.vout, false).await {
+                        Ok(_) => Ok(true),
+                        Err(e) =>
+                            if e.is_missing_or_invalid_input() {
+                                Ok(false)
+                            } else {
+                                Err(e)
+                            },
+                    }
+                })
+        })?;
+        Ok(true)
+    }
+
+    #[cfg(feature = "v2")]
+    fn unlock_unspent(&self, outpoints: &[OutPoint]) -> Result<()> {
+        #[derive(serde::Deserialize)]
+        struct LockedOutPoint {
+            txid: Txid,
+            vout: u32,
+        }
+
+        tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async {
+                // lockunspent rejects coins which aren't locked, e.g. after bitcoind restarted
+                let locked: Vec<LockedOutPoint> =
+                    self.raw_rpc.call("listlockunspent", serde_json::json!([])).await?;
+                let unlock = locked
+                    .into_iter()
+                    .filter(|o| outpoints.contains(&OutPoint { txid: o.txid, vout: o.vout }))
+                    .map(|o| serde_json::json!({ "txid": o.txid, "vout": o.vout }))
+                    .collect::<Vec<_>>();
+                if !unlock.is_empty() {
+                    let _: bool =
+                        self.raw_rpc.call("lockunspent", serde_json::json!([true, unlock])).await?;
+                }
+                Ok(())
+            })
+        })
+    }
+
+    #[cfg(feature = "v2")]
+    fn get_raw_transaction(&self, txid: &Txid) -> Result<Option<payjoin::bitcoin::Transaction>> {
+        let raw_tx = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async {
+                match self.rpc.get_transaction(txid).await {
+                    Ok(tx) => Ok(Some(tx.hex)),
+                    Err(e) =>
+                        if e.is_tx_not_found() {
+                            Ok(None)
+                        } else {
+                            Err(e)
+                        },
+                }
+            })
+        })?;
+        Ok(raw_tx)
+    }
+
+    fn get_new_address(&self) -> Result<Address> {
+        let addr = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async { self.rpc.get_new_address().await })
+        })
+        .context("Failed to get new address")?;
+        Ok(addr)
+    }
+
+    fn list_unspent(&self) -> Result<Vec<Utxo>> {
+        let unspent = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current()
+                .block_on(async { self.rpc.list_unspent(Some(0), None, None, None, None).await })
+        })
+        .context("Failed to list unspent")?;
+        Ok(unspent
+            .into_iter()
+            .map(|utxo| Utxo {
+                value: utxo.amount,
+                confirmations: utxo.confirmations,
+                input: input_pair_from_corepc(utxo),
+            })
+            .collect())
+    }
+
+    fn network(&self) -> Result<Network> {
+        tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async { self.rpc.network().await })
+        })
+        .map_err(|_| anyhow!("Failed to get blockchain info"))
+    }
+}
+
+pub fn input_pair_from_corepc(utxo: ListUnspent) -> InputPair {
+    let psbtin = Input {
+        // NOTE: non_witness_utxo is not necessary because bitcoin-cli always supplies
+        // witness_utxo, even for non-witness inputs
+        witness_utxo: Some(TxOut {
+            value: utxo.amount,
+            script_pubkey: ScriptBuf::from_hex(&utxo.script_pubkey).expect("Valid script"),
+        }),
+        redeem_script: None,
+        witness_script: None, // Not available in this version
+        ..Default::default()
+    };
+    let txin = TxIn {
+        previous_output: OutPoint { txid: utxo.txid, vout: utxo.vout },
+        ..Default::default()
+    };
+    InputPair::new(txin, psbtin, None).expect("Input pair should be valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn config(cookie: Option<PathBuf>) -> BitcoindConfig {
+        BitcoindConfig {
+            rpchost: "http://127.0.0.1:18443".parse().expect("valid url"),
+            cookie,
+            rpcuser: "bitcoin".to_owned(),
+            rpcpassword: "hunter2".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_parse_cookie() {
+        assert_eq!(
+            parse_cookie("__cookie__:s3cret\n"),
+            Some(("__cookie__".to_owned(), "s3cret".to_owned()))
+        );
+        assert_eq!(
+            parse_cookie("user:pass:word"),
+            Some(("user".to_owned(), "pass:word".to_owned()))
+        );
+        assert_eq!(parse_cookie("s3cret"), None);
+    }
+
+    #[test]
+    fn test_rpc_credentials() {
+        let dir = tempfile::tempdir().expect("tempdir");
+
+        assert_eq!(
+            rpc_credentials(&config(None)).expect("user and password"),
+            ("bitcoin".to_owned(), "hunter2".to_owned())
+        );
+        assert!(rpc_credentials(&config(Some(PathBuf::new()))).is_err());
+        assert!(rpc_credentials(&config(Some(dir.path().join("missing")))).is_err());
+
+        let cookie = dir.path().join(".cookie");
+        std::fs::write(&cookie, "__cookie__:s3cret").expect("write cookie");
+        assert_eq!(
+            rpc_credentials(&config(Some(cookie.clone()))).expect("cookie"),
+            ("__cookie__".to_owned(), "s3cret".to_owned())
+        );
+
+        std::fs::write(&cookie, "s3cret").expect("write cookie");
+        let error = rpc_credentials(&config(Some(cookie))).expect_err("invalid cookie").to_string();
+        assert!(!error.contains("s3cret"), "error leaks the cookie: {error}");
+    }
+
+    /// Answer one JSON-RPC request with `status` and `body`, returning the request received
+    #[cfg(feature = "v2")]
+    async fn serve_once(
+        status: &'static str,
+        body: &'static str,
+    ) -> (url::Url, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let url = format!("http://{}", listener.local_addr().expect("local addr"))
+            .parse()
+            .expect("valid url");
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.expect("accept");
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            loop {
+                let n = stream.read(&mut buf).await.expect("read");
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body_read)) = text.split_once("\r\n\r\n") {
+                    let content_length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|len| len.trim().parse::<usize>().expect("content length"))
+                        })
+                        .unwrap_or(0);
+                    if body_read.len() >= content_length {
+                        break;
+                    }
+                }
+                assert_ne!(n, 0, "connection closed before the request was read");
+            }
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.expect("write");
+            String::from_utf8(request).expect("utf8 request")
+        });
+        (url, handle)
+    }
+
+    #[cfg(feature = "v2")]
+    #[tokio::test]
+    async fn test_raw_rpc_call() {
+        let (url, request) =
+            serve_once("200 OK", r#"{"result":true,"error":null,"id":"payjoin-cli"}"#).await;
+        let rpc = RawRpc::new(url, "user".to_owned(), "pass".to_owned());
+        let unlocked: bool =
+            rpc.call("lockunspent", serde_json::json!([true, []])).await.expect("call succeeds");
+        assert!(unlocked);
+        let request = request.await.expect("request");
+        assert!(request.to_ascii_lowercase().contains("authorization: basic dxnlcjpwyxnz"));
+        assert!(request.contains(r#""method":"lockunspent""#));
+
+        let (url, _) = serve_once(
+            "500 Internal Server Error",
+            r#"{"result":null,"error":{"code":-8,"message":"Invalid parameter"},"id":"payjoin-cli"}"#,
+        )
+        .await;
+        let rpc = RawRpc::new(url, "user".to_owned(), "pass".to_owned());
+        let error = rpc
+            .call::<bool>("lockunspent", serde_json::json!([true, []]))
+            .await
+            .expect_err("rpc error");
+        assert!(error.to_string().contains("Invalid parameter"));
+    }
+}
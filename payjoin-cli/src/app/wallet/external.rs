@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use bdk_wallet::miniscript::psbt::PsbtExt;
+use payjoin::bitcoin::psbt::Psbt;
+use payjoin::bitcoin::secp256k1::Secp256k1;
+use payjoin::bitcoin::{Address, Amount, FeeRate, Network, Script, Transaction, Txid};
+use tokio::sync::watch;
+
+use super::{PayjoinWallet, Utxo};
+use crate::app::config::ExternalSignerConfig;
+use crate::app::Interrupted;
+use crate::output::{self, Event};
+
+/// How often to look for a signed PSBT
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A wallet whose PSBTs are signed outside of payjoin-cli, e.g. by a hardware wallet or an
+/// offline machine. Everything but signing is left to the wrapped wallet, which may be watch-only.
+///
+/// A PSBT to sign is written to `<psbt_dir>/<txid>.psbt`, where `<txid>` is the id of its
+/// unsigned transaction, and signing blocks until the signed copy appears at
+/// `<psbt_dir>/<txid>.signed.psbt`, which `sign-import` writes. Payjoin proposals are persisted
+/// before they are signed, so after an interruption `resume` waits for the same file. An Original
+/// PSBT is signed before its send session starts, so an interrupted `send` starts over.
+pub struct ExternalSigner {
+    wallet: Arc<dyn PayjoinWallet>,
+    psbt_dir: PathBuf,
+    interrupt: watch::Receiver<()>,
+}
+
+impl ExternalSigner {
+    pub fn new(
+        wallet: Arc<dyn PayjoinWallet>,
+        config: &ExternalSignerConfig,
+        interrupt: watch::Receiver<()>,
+    ) -> Self {
+        Self { wallet, psbt_dir: config.psbt_dir.clone(), interrupt }
+    }
+
+    /// Write `psbt` for the signer and wait until its signed copy is imported
+    fn sign(&self, psbt: &Psbt) -> Result<Psbt> {
+        let txid = psbt.unsigned_tx.compute_txid();
+        let unsigned_path = unsigned_path(&self.psbt_dir, &txid);
+        let signed_path = signed_path(&self.psbt_dir, &txid);
+        std::fs::create_dir_all(&self.psbt_dir)
+            .with_context(|| format!("Failed to create {}", self.psbt_dir.display()))?;
+        std::fs::write(&unsigned_path, psbt.to_string())
+            .with_context(|| format!("Failed to write {}", unsigned_path.display()))?;
+        output::emit(Event::PsbtToSign {
+            txid,
+            path: unsigned_path.clone(),
+            signed_path: signed_path.clone(),
+            psbt: psbt.to_string(),
+        });
+
+        let mut interrupt = self.interrupt.clone();
+        let signed = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async {
+                loop {
+                    match read_signed(&signed_path, &txid) {
+                        Ok(Some(signed)) => return Ok(signed),
+                        Ok(None) => {}
+                        // The file may still be being written, so try again
+                        Err(e) => tracing::warn!("{e:#}"),
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                        _ = interrupt.changed() => return Err(anyhow::Error::from(Interrupted)),
+                    }
+                }
+            })
+        })?;
+
+        let _ = std::fs::remove_file(&unsigned_path);
+        let _ = std::fs::remove_file(&signed_path);
+        finalize(psbt, signed)
+    }
+}
+
+/// Merge the signer's PSBT into ours, since signers may drop fields like the spent outputs, and
+/// finalize the inputs it signed but didn't finalize, as hardware wallets commonly do
+fn finalize(psbt: &Psbt, signed: Psbt) -> Result<Psbt> {
+    let mut psbt = psbt.clone();
+    psbt.combine(signed).map_err(|e| anyhow!("Failed to combine signed PSBT: {e}"))?;
+    let secp = Secp256k1::verification_only();
+    for index in 0..psbt.inputs.len() {
+        let input = &psbt.inputs[index];
+        let signed = !input.partial_sigs.is_empty()
+            || input.tap_key_sig.is_some()
+            || !input.tap_script_sigs.is_empty();
+        let finalized = input.final_script_sig.is_some() || input.final_script_witness.is_some();
+        if signed && !finalized {
+            psbt.finalize_inp_mut(&secp, index)
+                .map_err(|e| anyhow!("Failed to finalize input {index}: {e}"))?;
+        }
+    }
+    Ok(psbt)
+}
+
+/// Hand a signed PSBT to the session waiting for it. Returns the path it was saved to.
+pub fn import(config: &ExternalSignerConfig, signed: &Psbt) -> Result<PathBuf> {
+    let txid = signed.unsigned_tx.compute_txid();
+    if !unsigned_path(&config.psbt_dir, &txid).exists() {
+        return Err(anyhow!("No PSBT with transaction {txid} is waiting to be signed"));
+    }
+    // Write to a temporary file first so a waiting session never reads a partial PSBT
+    let signed_path = signed_path(&config.psbt_dir, &txid);
+    let tmp_path = signed_path.with_extension("tmp");
+    std::fs::write(&tmp_path, signed.to_string())
+        .with_context(|| format!("Failed to write {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, &signed_path)
+        .with_context(|| format!("Failed to write {}", signed_path.display()))?;
+    Ok(signed_path)
+}
+
+fn unsigned_path(psbt_dir: &Path, txid: &Txid) -> PathBuf { psbt_dir.join(format!("{txid}.psbt")) }
+
+fn signed_path(psbt_dir: &Path, txid: &Txid) -> PathBuf {
+    psbt_dir.join(format!("{txid}.signed.psbt"))
+}
+
+fn read_signed(path: &Path, txid: &Txid) -> Result<Option<Psbt>> {
+    let signed = match std::fs::read_to_string(path) {
+        Ok(signed) => signed,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let signed = Psbt::from_str(signed.trim())
+        .with_context(|| format!("Failed to parse signed PSBT {}", path.display()))?;
+    if signed.unsigned_tx.compute_txid() != *txid {
+        return Err(anyhow!("{} signs a different transaction", path.display()));
+    }
+    Ok(Some(signed))
+}
+
+impl PayjoinWallet for ExternalSigner {
+    fn create_psbt(
+        &self,
+        outputs: HashMap<String, Amount>,
+        fee_rate: FeeRate,
+        lock_unspent: bool,
+    ) -> Result<Psbt> {
+        let psbt = self.wallet.create_unsigned_psbt(outputs, fee_rate, lock_unspent)?;
+        self.sign(&psbt)
+    }
+
+    fn create_unsigned_psbt(
+        &self,
+        outputs: HashMap<String, Amount>,
+        fee_rate: FeeRate,
+        lock_unspent: bool,
+    ) -> Result<Psbt> {
+        self.wallet.create_unsigned_psbt(outputs, fee_rate, lock_unspent)
+    }
+
+    fn process_psbt(&self, psbt: &Psbt) -> Result<Psbt> { self.sign(psbt) }
+
+    fn can_broadcast(&self, tx: &Transaction) -> Result<bool> { self.wallet.can_broadcast(tx) }
+
+    fn broadcast_tx(&self, tx: &Transaction) -> Result<Txid> { self.wallet.broadcast_tx(tx) }
+
+    fn is_mine(&self, script: &Script) -> Result<bool> { self.wallet.is_mine(script) }
+
+    #[cfg(feature = "v2")]
+    fn is_outpoint_spent(&self, outpoint: &payjoin::bitcoin::OutPoint) -> Result<bool> {
+        self.wallet.is_outpoint_spent(outpoint)
+    }
+
+    #[cfg(feature = "v2")]
+    fn unlock_unspent(&self, outpoints: &[payjoin::bitcoin::OutPoint]) -> Result<()> {
+        self.wallet.unlock_unspent(outpoints)
+    }
+
+    #[cfg(feature = "v2")]
+    fn get_raw_transaction(&self, txid: &Txid) -> Result<Option<Transaction>> {
+        self.wallet.get_raw_transaction(txid)
+    }
+
+    fn get_new_address(&self) -> Result<Address> { self.wallet.get_new_address() }
+
+    fn list_unspent(&self) -> Result<Vec<Utxo>> { self.wallet.list_unspent() }
+
+    fn network(&self) -> Result<Network> { self.wallet.network() }
+}
+
+#[cfg(test)]
+mod tests {
+    use payjoin::bitcoin::absolute::LockTime;
+    use payjoin::bitcoin::hashes::Hash;
+    use payjoin::bitcoin::secp256k1::{Message, SecretKey};
+    use payjoin::bitcoin::sighash::{EcdsaSighashType, SighashCache};
+    use payjoin::bitcoin::transaction::Version;
+    use payjoin::bitcoin::{
+        ecdsa, CompressedPublicKey, OutPoint, PublicKey, ScriptBuf, Sequence, TxIn, TxOut, Witness,
+    };
+
+    use super::*;
+
+    const SPENT: Amount = Amount::from_sat(100_000);
+
+    fn secret_key() -> SecretKey { SecretKey::from_slice(&[1; 32]).expect("valid key") }
+
+    /// An unsigned PSBT spending one P2WPKH coin of [`secret_key`]
+    fn unsigned_psbt(vout: u32) -> Psbt {
+        let secp = Secp256k1::new();
+        let public_key = CompressedPublicKey(secret_key().public_key(&secp));
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint { txid: Txid::all_zeros(), vout },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(90_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&public_key.wpubkey_hash()),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).expect("unsigned tx");
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: SPENT,
+            script_pubkey: ScriptBuf::new_p2wpkh(&public_key.wpubkey_hash()),
+        });
+        psbt
+    }
+
+    /// A signer's copy of `psbt`, signed but not finalized and without the spent output
+    fn signed_psbt(psbt: &Psbt) -> Psbt {
+        let secp = Secp256k1::new();
+        let public_key = CompressedPublicKey(secret_key().public_key(&secp));
+        let script_pubkey = ScriptBuf::new_p2wpkh(&public_key.wpubkey_hash());
+        let sighash = SighashCache::new(&psbt.unsigned_tx)
+            .p2wpkh_signature_hash(0, &script_pubkey, SPENT, EcdsaSighashType::All)
+            .expect("sighash");
+        let signature = ecdsa::Signature {
+            signature: secp.sign_ecdsa(&Message::from(sighash), &secret_key()),
+            sighash_type: EcdsaSighashType::All,
+        };
+        let mut signed = Psbt::from_unsigned_tx(psbt.unsigned_tx.clone()).expect("unsigned tx");
+        signed.inputs[0].partial_sigs.insert(PublicKey::from(public_key), signature);
+        signed
+    }
+
+    #[test]
+    fn test_finalize_signed_inputs() {
+        let psbt = unsigned_psbt(0);
+
+        let finalized = finalize(&psbt, signed_psbt(&psbt)).expect("finalized");
+        assert!(finalized.inputs[0].final_script_witness.is_some());
+        assert!(finalized.inputs[0].witness_utxo.is_some(), "spent output should be kept");
+        assert!(finalized.inputs[0].partial_sigs.is_empty());
+
+        let unsigned = Psbt::from_unsigned_tx(psbt.unsigned_tx.clone()).expect("unsigned tx");
+        let not_finalized = finalize(&psbt, unsigned).expect("nothing to finalize");
+        assert!(not_finalized.inputs[0].final_script_witness.is_none());
+
+        assert!(finalize(&psbt, signed_psbt(&unsigned_psbt(1))).is_err());
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let config = ExternalSignerConfig { psbt_dir: dir.path().to_path_buf() };
+        let psbt = unsigned_psbt(0);
+        let txid = psbt.unsigned_tx.compute_txid();
+        let signed = signed_psbt(&psbt);
+
+        assert!(import(&config, &signed).is_err(), "no PSBT is waiting to be signed");
+        assert_eq!(read_signed(&signed_path(dir.path(), &txid), &txid).expect("not yet"), None);
+
+        std::fs::write(unsigned_path(dir.path(), &txid), psbt.to_string()).expect("write psbt");
+        let path = import(&config, &signed).expect("imported");
+        assert_eq!(path, signed_path(dir.path(), &txid));
+        assert_eq!(read_signed(&path, &txid).expect("signed"), Some(signed));
+
+        let other_txid = unsigned_psbt(1).unsigned_tx.compute_txid();
+        assert!(read_signed(&path, &other_txid).is_err(), "signs a different transaction");
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use payjoin::bitcoin::psbt::Psbt;
+use payjoin::bitcoin::{Address, Amount, FeeRate, Network, Script, Transaction, Txid};
+use payjoin::receive::InputPair;
+use tokio::sync::watch;
+
+use crate::app::config::Config;
+
+mod bdk;
+mod bitcoind;
+pub mod external;
+
+pub use bdk::BdkWallet;
+pub use bitcoind::BitcoindWallet;
+pub use external::ExternalSigner;
+
+/// The wallet operations needed to send and receive payjoins
+pub trait PayjoinWallet: Send + Sync {
+    /// Create a signed PSBT with the given outputs and fee rate, optionally locking the spent
+    /// coins so they aren't selected again while the payjoin is pending
+    fn create_psbt(
+        &self,
+        outputs: HashMap<String, Amount>,
+        fee_rate: FeeRate,
+        lock_unspent: bool,
+    ) -> Result<Psbt>;
+
+    /// Like [`PayjoinWallet::create_psbt`], but leave the PSBT unsigned for an external signer
+    fn create_unsigned_psbt(
+        &self,
+        outputs: HashMap<String, Amount>,
+        fee_rate: FeeRate,
+        lock_unspent: bool,
+    ) -> Result<Psbt>;
+
+    /// Process a PSBT, validating and signing inputs owned by this wallet
+    fn process_psbt(&self, psbt: &Psbt) -> Result<Psbt>;
+
+    /// Check whether the node's mempool would accept a transaction
+    fn can_broadcast(&self, tx: &Transaction) -> Result<bool>;
+
+    /// Broadcast a raw transaction
+    fn broadcast_tx(&self, tx: &Transaction) -> Result<Txid>;
+
+    /// Check if a script belongs to this wallet
+    fn is_mine(&self, script: &Script) -> Result<bool>;
+
+    #[cfg(feature = "v2")]
+    fn is_outpoint_spent(&self, outpoint: &payjoin::bitcoin::OutPoint) -> Result<bool>;
+
+    /// Release coins locked by [`PayjoinWallet::create_psbt`], ignoring any which aren't locked
+    #[cfg(feature = "v2")]
+    fn unlock_unspent(&self, outpoints: &[payjoin::bitcoin::OutPoint]) -> Result<()>;
+
+    /// Look up a transaction relevant to this wallet, or `None` if it is unknown
+    #[cfg(feature = "v2")]
+    fn get_raw_transaction(&self, txid: &Txid) -> Result<Option<Transaction>>;
+
+    /// Get a new address from the wallet
+    fn get_new_address(&self) -> Result<Address>;
+
+    /// List unspent UTXOs, including unconfirmed ones
+    fn list_unspent(&self) -> Result<Vec<Utxo>>;
+
+    /// Get the network this wallet is operating on
+    fn network(&self) -> Result<Network>;
+}
+
+/// An unspent coin which the receiver may contribute to a payjoin
+pub struct Utxo {
+    pub input: InputPair,
+    pub value: Amount,
+    /// How deep the coin is buried in the chain, 0 while it is unconfirmed
+    pub confirmations: u32,
+}
+
+/// Open the configured wallet: a local descriptor wallet if `[bdk]` is configured, or else the
+/// Bitcoin Core wallet behind `[bitcoind]`. If `[external_signer]` is configured, its PSBTs are
+/// signed externally, and waiting for a signature stops on `interrupt`.
+pub async fn from_config(
+    config: &Config,
+    interrupt: watch::Receiver<()>,
+) -> Result<Arc<dyn PayjoinWallet>> {
+    let wallet: Arc<dyn PayjoinWallet> = match &config.bdk {
+        Some(bdk) => Arc::new(BdkWallet::new(&config.bitcoind, bdk)?),
+        None => Arc::new(BitcoindWallet::new(&config.bitcoind).await?),
+    };
+    match &config.external_signer {
+        Some(external_signer) =>
+            Ok(Arc::new(ExternalSigner::new(wallet, external_signer, interrupt))),
+        None => Ok(wallet),
+    }
+}
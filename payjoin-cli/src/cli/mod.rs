@@ -1,11 +1,19 @@
-use std::path::PathBuf;
+#[cfg(feature = "v2")]
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use clap::{value_parser, Parser, Subcommand};
+use payjoin::bitcoin::address::NetworkUnchecked;
 use payjoin::bitcoin::amount::ParseAmountError;
-use payjoin::bitcoin::{Amount, FeeRate};
+use payjoin::bitcoin::{Address, Amount, FeeRate, Psbt};
 use serde::Deserialize;
 use url::Url;
 
+use crate::app::share::ShareOptions;
+#[cfg(feature = "v2")]
+use crate::output::Role;
+
 #[derive(Debug, Clone, Deserialize, Parser)]
 pub struct Flags {
     #[arg(long = "bip77", help = "Use BIP77 (v2) protocol (default)", action = clap::ArgAction::SetTrue)]
@@ -28,6 +36,9 @@ pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
 
+    #[arg(long, global = true, help = "Print newline-delimited JSON events instead of text")]
+    pub json: bool,
+
     #[arg(long, short = 'd', help = "Sets a custom database path")]
     pub db_path: Option<PathBuf>,
 
@@ -76,6 +87,10 @@ pub struct Cli {
     #[arg(long = "pj-directory", help = "The directory to store payjoin requests", value_parser = value_parser!(Url))]
     pub pj_directory: Option<Url>,
 
+    #[cfg(feature = "v2")]
+    #[arg(long = "socks-proxy", help = "A socks5h:// proxy URL, e.g. Tor, to route all HTTP traffic through", value_parser = value_parser!(Url))]
+    pub socks_proxy: Option<Url>,
+
     #[cfg(feature = "_manual-tls")]
     #[arg(long = "root-certificate", help = "Specify a TLS certificate to be added as a root", value_parser = value_parser!(PathBuf))]
     pub root_certificate: Option<PathBuf>,
@@ -96,6 +111,16 @@ pub enum Commands {
         /// Fee rate in sat/vB
         #[arg(required = true, short, long = "fee-rate", value_parser = parse_fee_rate_in_sat_per_vb)]
         fee_rate: FeeRate,
+
+        /// Another output to pay in the same transaction, as `<address>:<amount in sats>`. May
+        /// be repeated.
+        #[arg(long = "output", value_parser = parse_output)]
+        outputs: Vec<(Address<NetworkUnchecked>, Amount)>,
+
+        /// A CSV file of other outputs to pay in the same transaction, one
+        /// `<address>,<amount in sats>` per line
+        #[arg(long = "outputs-csv", value_parser = value_parser!(PathBuf))]
+        outputs_csv: Option<PathBuf>,
     },
     /// Receive a payjoin payment
     Receive {
@@ -126,21 +151,182 @@ pub enum Commands {
         /// The path to the ohttp keys file
         #[arg(long = "ohttp-keys", value_parser = value_parser!(PathBuf))]
         ohttp_keys: Option<PathBuf>,
+
+        // Boxed to keep `Commands` small
+        #[command(flatten)]
+        share: Box<ShareOptions>,
     },
     /// Resume pending payjoins (BIP77/v2 only)
     #[cfg(feature = "v2")]
-    Resume,
+    Resume {
+        /// Only resume the session with this ID, as shown by `history`
+        #[arg(long = "session")]
+        session_id: Option<i64>,
+        /// Only resume `send` or `receive` sessions
+        #[arg(long = "role")]
+        role: Option<Role>,
+    },
     #[cfg(feature = "v2")]
     /// Show payjoin session history
     History,
+    /// Show the event timeline of a payjoin session (BIP77/v2 only)
+    #[cfg(feature = "v2")]
+    Show {
+        /// The session ID, as shown by `history`
+        session_id: i64,
+        /// Whether the session is a `send` or `receive` session, if the ID is used by both
+        #[arg(long = "role")]
+        role: Option<Role>,
+    },
+    /// Keep payjoin sessions running in the background, controlled by a local HTTP API
+    /// (BIP77/v2 only)
+    #[cfg(feature = "v2")]
+    Daemon {
+        /// The address to serve the API on
+        #[arg(long = "listen", default_value = "127.0.0.1:3001")]
+        listen: SocketAddr,
+    },
+    /// Import a PSBT signed by an external signer, continuing the session waiting for it
+    SignImport {
+        /// The signed PSBT, as base64 or a file containing it, or `-` to read it from stdin
+        psbt: String,
+    },
+    /// Cancel an active payjoin session so that it is no longer resumed (BIP77/v2 only)
+    #[cfg(feature = "v2")]
+    Cancel {
+        /// The session ID, as shown by `history`
+        session_id: i64,
+        /// Whether the session is a `send` or `receive` session, if the ID is used by both
+        #[arg(long = "role")]
+        role: Option<Role>,
+        /// Broadcast the fallback transaction of a receive session which got an Original PSBT,
+        /// to get paid without a payjoin
+        #[arg(long = "broadcast-fallback")]
+        broadcast_fallback: bool,
+    },
 }
 
 pub fn parse_amount_in_sat(s: &str) -> Result<Amount, ParseAmountError> {
     Amount::from_str_in(s, payjoin::bitcoin::Denomination::Satoshi)
 }
 
+/// Read a PSBT given as base64, or as a file or `-` for stdin holding it in binary or base64
+pub fn read_psbt(psbt: &str) -> anyhow::Result<Psbt> {
+    if let Ok(psbt) = Psbt::from_str(psbt.trim()) {
+        return Ok(psbt);
+    }
+    let bytes = if psbt == "-" {
+        let mut bytes = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin(), &mut bytes)?;
+        bytes
+    } else {
+        std::fs::read(psbt).map_err(|e| anyhow::anyhow!("Failed to read {psbt}: {e}"))?
+    };
+    match Psbt::deserialize(&bytes) {
+        Ok(psbt) => Ok(psbt),
+        Err(_) => Ok(Psbt::from_str(std::str::from_utf8(&bytes)?.trim())?),
+    }
+}
+
+/// Parse an `<address>:<amount in sats>` output
+pub fn parse_output(s: &str) -> Result<(Address<NetworkUnchecked>, Amount), String> {
+    let (address, amount) =
+        s.rsplit_once(':').ok_or_else(|| format!("expected <address>:<amount>, got {s}"))?;
+    parse_address_and_amount(address, amount)
+}
+
+/// Read `<address>,<amount in sats>` outputs from a CSV file, which may start with a header line
+pub fn read_outputs_csv(path: &Path) -> anyhow::Result<Vec<(Address<NetworkUnchecked>, Amount)>> {
+    let csv = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {e}", path.display()))?;
+    let mut outputs = Vec::new();
+    for (index, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (index == 0 && line.eq_ignore_ascii_case("address,amount")) {
+            continue;
+        }
+        let (address, amount) = line.split_once(',').ok_or_else(|| {
+            anyhow::anyhow!("{}:{}: expected <address>,<amount>", path.display(), index + 1)
+        })?;
+        outputs.push(
+            parse_address_and_amount(address.trim(), amount.trim())
+                .map_err(|e| anyhow::anyhow!("{}:{}: {e}", path.display(), index + 1))?,
+        );
+    }
+    Ok(outputs)
+}
+
+fn parse_address_and_amount(
+    address: &str,
+    amount: &str,
+) -> Result<(Address<NetworkUnchecked>, Amount), String> {
+    let address = address.parse().map_err(|e| format!("invalid address {address}: {e}"))?;
+    let amount =
+        parse_amount_in_sat(amount).map_err(|e| format!("invalid amount {amount}: {e}"))?;
+    Ok((address, amount))
+}
+
+pub fn parse_qr_path(s: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(s);
+    match path.extension().and_then(|extension| extension.to_str()) {
+        #[cfg(feature = "png")]
+        Some("png") => Ok(path),
+        Some("svg") => Ok(path),
+        _ => Err(format!("expected a {} file", crate::app::share::QR_FILE_TYPES)),
+    }
+}
+
 pub fn parse_fee_rate_in_sat_per_vb(s: &str) -> Result<FeeRate, std::num::ParseFloatError> {
-    let fee_rate_sat_per_vb: f32 = s.parse()?;
+    Ok(fee_rate_from_sat_per_vb(s.parse()?))
+}
+
+pub fn fee_rate_from_sat_per_vb(fee_rate_sat_per_vb: f32) -> FeeRate {
     let fee_rate_sat_per_kwu = fee_rate_sat_per_vb * 250.0_f32;
-    Ok(FeeRate::from_sat_per_kwu(fee_rate_sat_per_kwu.ceil() as u64))
+    FeeRate::from_sat_per_kwu(fee_rate_sat_per_kwu.ceil() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+    const OTHER_ADDRESS: &str = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2";
+
+    fn output(address: &str, sats: u64) -> (Address<NetworkUnchecked>, Amount) {
+        (address.parse().expect("valid address"), Amount::from_sat(sats))
+    }
+
+    #[test]
+    fn test_parse_output() {
+        assert_eq!(parse_output(&format!("{ADDRESS}:1000")), Ok(output(ADDRESS, 1000)));
+        assert!(parse_output(ADDRESS).is_err());
+        assert!(parse_output(&format!("{ADDRESS}:")).is_err());
+        assert!(parse_output(&format!("{ADDRESS}:0.5")).is_err());
+        assert!(parse_output("not-an-address:1000").is_err());
+    }
+
+    #[test]
+    fn test_read_outputs_csv() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("outputs.csv");
+
+        std::fs::write(&path, format!("address,amount\n{ADDRESS}, 1000\n\n{OTHER_ADDRESS},2000\n"))
+            .expect("write csv");
+        assert_eq!(
+            read_outputs_csv(&path).expect("valid csv"),
+            vec![output(ADDRESS, 1000), output(OTHER_ADDRESS, 2000)]
+        );
+
+        std::fs::write(&path, format!("{ADDRESS},1000")).expect("write csv");
+        assert_eq!(
+            read_outputs_csv(&path).expect("csv without header"),
+            vec![output(ADDRESS, 1000)]
+        );
+
+        std::fs::write(&path, format!("{ADDRESS},1000\n{OTHER_ADDRESS}")).expect("write csv");
+        let error = read_outputs_csv(&path).expect_err("missing amount").to_string();
+        assert!(error.ends_with("outputs.csv:2: expected <address>,<amount>"), "{error}");
+
+        assert!(read_outputs_csv(&dir.path().join("missing.csv")).is_err());
+    }
 }
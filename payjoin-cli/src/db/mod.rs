@@ -16,7 +16,15 @@ pub(crate) fn now() -> i64 {
 
 pub(crate) const DB_PATH: &str = "payjoin.sqlite";
 
-pub(crate) struct Database(Pool<SqliteConnectionManager>);
+pub(crate) struct Database {
+    pool: Pool<SqliteConnectionManager>,
+    /// Where notifications of saved session events are queued for
+    #[cfg(feature = "v2")]
+    hooks: Vec<crate::app::v2::hooks::HookTarget>,
+    /// Woken whenever a notification is queued
+    #[cfg(feature = "v2")]
+    outbox_ready: tokio::sync::Notify,
+}
 
 impl Database {
     pub(crate) fn create(path: impl AsRef<Path>) -> Result<Self> {
@@ -27,7 +35,13 @@ impl Database {
         let conn = pool.get()?;
         Self::init_schema(&conn)?;
 
-        Ok(Self(pool))
+        Ok(Self {
+            pool,
+            #[cfg(feature = "v2")]
+            hooks: vec![],
+            #[cfg(feature = "v2")]
+            outbox_ready: tokio::sync::Notify::new(),
+        })
     }
 
     fn init_schema(conn: &Connection) -> Result<()> {
@@ -81,11 +95,24 @@ impl Database {
             [],
         )?;
 
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS hook_outbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                target TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
         Ok(())
     }
 
     pub(crate) fn get_connection(&self) -> Result<r2d2::PooledConnection<SqliteConnectionManager>> {
-        Ok(self.0.get()?)
+        Ok(self.pool.get()?)
     }
     /// Inserts the input and returns true if the input was seen before, false otherwise.
     pub(crate) fn insert_input_seen_before(&self, input: OutPoint) -> Result<bool> {
@@ -7,10 +7,16 @@ use payjoin::HpkePublicKey;
 use rusqlite::params;
 
 use super::*;
+use crate::app::v2::hooks::{HookTarget, Notification};
+use crate::output::{self, Event, Role};
 
 #[derive(Debug, Clone)]
 pub(crate) struct SessionId(i64);
 
+impl SessionId {
+    pub fn new(id: i64) -> Self { Self(id) }
+}
+
 impl core::ops::Deref for SessionId {
     type Target = i64;
     fn deref(&self) -> &Self::Target { &self.0 }
@@ -41,6 +47,8 @@ impl SenderPersister {
     }
 
     pub fn from_id(db: Arc<Database>, id: SessionId) -> Self { Self { db, session_id: id } }
+
+    pub fn session_id(&self) -> &SessionId { &self.session_id }
 }
 
 impl SessionPersister for SenderPersister {
@@ -51,14 +59,10 @@ impl SessionPersister for SenderPersister {
         &self,
         event: SenderSessionEvent,
     ) -> std::result::Result<(), Self::InternalStorageError> {
-        let conn = self.db.get_connection()?;
-        let event_data = serde_json::to_string(&event).map_err(Error::Serialize)?;
-
-        conn.execute(
-            "INSERT INTO send_session_events (session_id, event_data, created_at) VALUES (?1, ?2, ?3)",
-            params![*self.session_id, event_data, now()],
-        )?;
+        let event_data = serde_json::to_value(&event).map_err(Error::Serialize)?;
+        self.db.insert_session_event(Role::Sender, *self.session_id, &event_data)?;
 
+        output::emit(Event::session_state(*self.session_id, Role::Sender, &event_data));
         Ok(())
     }
 
@@ -120,6 +124,8 @@ impl ReceiverPersister {
     }
 
     pub fn from_id(db: Arc<Database>, id: SessionId) -> Self { Self { db, session_id: id } }
+
+    pub fn session_id(&self) -> &SessionId { &self.session_id }
 }
 
 impl SessionPersister for ReceiverPersister {
@@ -130,14 +136,10 @@ impl SessionPersister for ReceiverPersister {
         &self,
         event: ReceiverSessionEvent,
     ) -> std::result::Result<(), Self::InternalStorageError> {
-        let conn = self.db.get_connection()?;
-        let event_data = serde_json::to_string(&event).map_err(Error::Serialize)?;
-
-        conn.execute(
-            "INSERT INTO receive_session_events (session_id, event_data, created_at) VALUES (?1, ?2, ?3)",
-            params![*self.session_id, event_data, now()],
-        )?;
+        let event_data = serde_json::to_value(&event).map_err(Error::Serialize)?;
+        self.db.insert_session_event(Role::Receiver, *self.session_id, &event_data)?;
 
+        output::emit(Event::session_state(*self.session_id, Role::Receiver, &event_data));
         Ok(())
     }
 
@@ -267,4 +269,164 @@ impl Database {
         }
         Ok(session_ids)
     }
+
+    /// Every event of a session with the time it was saved, in the order they were saved.
+    /// Empty if there is no such session.
+    pub(crate) fn get_session_events(
+        &self,
+        role: Role,
+        session_id: i64,
+    ) -> Result<Vec<(u64, serde_json::Value)>> {
+        session_events(&*self.get_connection()?, role, session_id)
+    }
+
+    /// When a session was closed, or `None` if it is still active
+    pub(crate) fn get_session_completed_at(
+        &self,
+        role: Role,
+        session_id: i64,
+    ) -> Result<Option<u64>> {
+        let conn = self.get_connection()?;
+        let completed_at = conn.query_row(
+            match role {
+                Role::Sender => "SELECT completed_at FROM send_sessions WHERE session_id = ?1",
+                Role::Receiver => "SELECT completed_at FROM receive_sessions WHERE session_id = ?1",
+            },
+            params![session_id],
+            |row| row.get(0),
+        )?;
+        Ok(completed_at)
+    }
+
+    /// Queue notifications of saved session events for `hooks`
+    pub(crate) fn with_hooks(mut self, hooks: Vec<HookTarget>) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    pub(crate) fn hooks(&self) -> &[HookTarget] { &self.hooks }
+
+    /// Save a session event, and queue a notification of it for every hook in the same
+    /// transaction, so that none is lost if payjoin-cli stops before delivering it
+    fn insert_session_event(
+        &self,
+        role: Role,
+        session_id: i64,
+        event_data: &serde_json::Value,
+    ) -> Result<()> {
+        let mut conn = self.get_connection()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            match role {
+                Role::Sender =>
+                    "INSERT INTO send_session_events (session_id, event_data, created_at) VALUES (?1, ?2, ?3)",
+                Role::Receiver =>
+                    "INSERT INTO receive_session_events (session_id, event_data, created_at) VALUES (?1, ?2, ?3)",
+            },
+            params![session_id, event_data.to_string(), now()],
+        )?;
+        if self.hooks.is_empty() {
+            return Ok(tx.commit()?);
+        }
+
+        let event_id = tx.last_insert_rowid();
+        let events = session_events(&tx, role, session_id)?;
+        let notification = Notification::new(event_id, session_id, role, &events);
+        let payload = serde_json::to_string(&notification).map_err(Error::Serialize)?;
+        for hook in &self.hooks {
+            tx.execute(
+                "INSERT INTO hook_outbox (kind, target, payload, next_attempt_at, created_at) VALUES (?1, ?2, ?3, ?4, ?4)",
+                params![hook.kind(), hook.target(), payload, now()],
+            )?;
+        }
+        tx.commit()?;
+        self.outbox_ready.notify_one();
+        Ok(())
+    }
+
+    /// The queued notifications, oldest first
+    pub(crate) fn get_queued_notifications(&self) -> Result<Vec<OutboxEntry>> {
+        let conn = self.get_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT id, kind, target, payload, attempts, next_attempt_at FROM hook_outbox ORDER BY id ASC",
+        )?;
+        let entry_rows = stmt.query_map([], |row| {
+            Ok(OutboxEntry {
+                id: row.get(0)?,
+                kind: row.get(1)?,
+                target: row.get(2)?,
+                payload: row.get(3)?,
+                attempts: row.get(4)?,
+                next_attempt_at: row.get(5)?,
+            })
+        })?;
+
+        let mut entries = Vec::new();
+        for entry_row in entry_rows {
+            entries.push(entry_row?);
+        }
+        Ok(entries)
+    }
+
+    pub(crate) fn delete_notification(&self, id: i64) -> Result<()> {
+        let conn = self.get_connection()?;
+        conn.execute("DELETE FROM hook_outbox WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Record a failed delivery and when to try again
+    pub(crate) fn reschedule_notification(
+        &self,
+        id: i64,
+        attempts: u32,
+        next_attempt_at: i64,
+    ) -> Result<()> {
+        let conn = self.get_connection()?;
+        conn.execute(
+            "UPDATE hook_outbox SET attempts = ?1, next_attempt_at = ?2 WHERE id = ?3",
+            params![attempts, next_attempt_at, id],
+        )?;
+        Ok(())
+    }
+
+    /// Wait until a notification is queued
+    pub(crate) async fn outbox_ready(&self) { self.outbox_ready.notified().await }
+}
+
+/// A notification waiting in the outbox for delivery to a hook
+pub(crate) struct OutboxEntry {
+    pub id: i64,
+    pub kind: String,
+    pub target: String,
+    /// The [`Notification`] as JSON
+    pub payload: String,
+    /// The failed deliveries so far
+    pub attempts: u32,
+    /// When to deliver it, in seconds since the Unix epoch
+    pub next_attempt_at: i64,
+}
+
+fn session_events(
+    conn: &Connection,
+    role: Role,
+    session_id: i64,
+) -> Result<Vec<(u64, serde_json::Value)>> {
+    let mut stmt = conn.prepare(match role {
+        Role::Sender =>
+            "SELECT created_at, event_data FROM send_session_events WHERE session_id = ?1 ORDER BY id ASC",
+        Role::Receiver =>
+            "SELECT created_at, event_data FROM receive_session_events WHERE session_id = ?1 ORDER BY id ASC",
+    })?;
+    let event_rows = stmt.query_map(params![session_id], |row| {
+        let created_at: u64 = row.get(0)?;
+        let event_data: String = row.get(1)?;
+        Ok((created_at, event_data))
+    })?;
+
+    let mut events = Vec::new();
+    for event_row in event_rows {
+        let (created_at, event_data) = event_row?;
+        events.push((created_at, serde_json::from_str(&event_data).map_err(Error::Deserialize)?));
+    }
+    Ok(events)
 }
@@ -3,24 +3,58 @@ use app::config::Config;
 use app::App as AppTrait;
 use clap::Parser;
 use cli::{Cli, Commands};
+use output::{ErrorCode, Event};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
 mod app;
 mod cli;
 mod db;
+mod output;
 
 #[cfg(not(any(feature = "v1", feature = "v2")))]
 compile_error!("At least one of the features ['v1', 'v2'] must be enabled");
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    output::set_json(cli.json);
+
     let env_filter =
         EnvFilter::builder().with_default_directive(LevelFilter::INFO.into()).from_env_lossy();
-    tracing_subscriber::fmt().with_target(true).with_level(true).with_env_filter(env_filter).init();
+    let subscriber =
+        tracing_subscriber::fmt().with_target(true).with_level(true).with_env_filter(env_filter);
+    if cli.json {
+        // Keep stdout for JSON events
+        subscriber.with_writer(std::io::stderr).init();
+    } else {
+        subscriber.init();
+    }
 
-    let cli = Cli::parse();
-    let config = Config::new(&cli)?;
+    if let Err(e) = run(&cli).await {
+        if output::is_json() {
+            output::emit(Event::Error { code: ErrorCode::from(&e), message: format!("{e:#}") });
+            std::process::exit(1);
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn run(cli: &Cli) -> Result<()> {
+    let config = Config::new(cli)?;
+
+    // Importing a signed PSBT only hands it over to the session waiting for it
+    if let Commands::SignImport { psbt } = &cli.command {
+        let external_signer = config
+            .external_signer
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No [external_signer] is configured"))?;
+        let psbt = cli::read_psbt(psbt)?;
+        let path = app::wallet::external::import(external_signer, &psbt)?;
+        output::emit(Event::PsbtImported { txid: psbt.unsigned_tx.compute_txid(), path });
+        return Ok(());
+    }
 
     #[allow(clippy::if_same_then_else)]
     let app: Box<dyn AppTrait> = if cli.flags.bip78.unwrap_or(false) {
@@ -60,22 +94,44 @@ async fn main() -> Result<()> {
         }
     };
 
-    match &cli.command {
-        Commands::Send { bip21, fee_rate } => {
-            app.send_payjoin(bip21, *fee_rate).await?;
-        }
-        Commands::Receive { amount, .. } => {
-            app.receive_payjoin(*amount).await?;
-        }
-        #[cfg(feature = "v2")]
-        Commands::Resume => {
-            app.resume_payjoins().await?;
-        }
-        #[cfg(feature = "v2")]
-        Commands::History => {
-            app.history().await?;
-        }
-    };
-
-    Ok(())
+    let result = async {
+        match &cli.command {
+            Commands::Send { bip21, fee_rate, outputs, outputs_csv } => {
+                let mut batch = outputs.clone();
+                if let Some(outputs_csv) = outputs_csv {
+                    batch.extend(cli::read_outputs_csv(outputs_csv)?);
+                }
+                app.send_payjoin(bip21, *fee_rate, &batch).await?;
+            }
+            Commands::Receive { amount, share, .. } => {
+                app.receive_payjoin(*amount, share).await?;
+            }
+            #[cfg(feature = "v2")]
+            Commands::Resume { session_id, role } => {
+                app.resume_payjoins(*session_id, *role).await?;
+            }
+            #[cfg(feature = "v2")]
+            Commands::History => {
+                app.history().await?;
+            }
+            #[cfg(feature = "v2")]
+            Commands::Daemon { listen } => {
+                app.daemon(*listen).await?;
+            }
+            Commands::SignImport { .. } => unreachable!("handled before connecting to the wallet"),
+            #[cfg(feature = "v2")]
+            Commands::Show { session_id, role } => {
+                app.show(*session_id, *role).await?;
+            }
+            #[cfg(feature = "v2")]
+            Commands::Cancel { session_id, role, broadcast_fallback } => {
+                app.cancel(*session_id, *role, *broadcast_fallback).await?;
+            }
+        };
+        Ok::<(), anyhow::Error>(())
+    }
+    .await;
+    // Deliver the notifications of the command's session events before exiting
+    app.flush_hooks().await;
+    result
 }
@@ -0,0 +1,353 @@
+//! User facing output, printed either as human readable text or, with `--json`, as newline
+//! delimited JSON events for automation.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use payjoin::bitcoin::Txid;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// How many events a slow subscriber may fall behind before it misses some
+const EVENT_BUFFER: usize = 256;
+
+/// Print JSON events instead of text for the rest of the process
+pub(crate) fn set_json(json: bool) { JSON.store(json, Ordering::Relaxed) }
+
+pub(crate) fn is_json() -> bool { JSON.load(Ordering::Relaxed) }
+
+/// Print an event to stdout in the selected format, and send it to any subscribers
+pub(crate) fn emit(event: Event) {
+    let subscribers = subscribers();
+    if is_json() || subscribers.receiver_count() > 0 {
+        match serde_json::to_string(&event) {
+            Ok(json) => {
+                if is_json() {
+                    println!("{json}");
+                }
+                let _ = subscribers.send(json);
+            }
+            Err(e) => tracing::error!("Failed to serialize {event:?}: {e}"),
+        }
+    }
+    if !is_json() {
+        if let Some(text) = event.text() {
+            println!("{text}");
+        }
+    }
+}
+
+/// Receive every event emitted from now on as a line of JSON
+#[cfg(feature = "v2")]
+pub(crate) fn subscribe() -> broadcast::Receiver<String> { subscribers().subscribe() }
+
+fn subscribers() -> &'static broadcast::Sender<String> {
+    static SUBSCRIBERS: OnceLock<broadcast::Sender<String>> = OnceLock::new();
+    SUBSCRIBERS.get_or_init(|| broadcast::channel(EVENT_BUFFER).0)
+}
+
+/// Print a line of progress text which has no structured equivalent
+pub(crate) fn info(message: impl Into<String>) { emit(Event::Info { message: message.into() }) }
+
+#[cfg_attr(not(feature = "v2"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Role {
+    Sender,
+    Receiver,
+}
+
+#[cfg(feature = "v2")]
+impl std::str::FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sender" | "send" => Ok(Role::Sender),
+            "receiver" | "receive" => Ok(Role::Receiver),
+            _ => Err(anyhow::anyhow!("Unknown role {s}, expected send or receive")),
+        }
+    }
+}
+
+impl Role {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Role::Sender => "Sender",
+            Role::Receiver => "Receiver",
+        }
+    }
+}
+
+// Some events are only emitted by BIP77 sessions
+#[cfg_attr(not(feature = "v2"), allow(dead_code))]
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    Info {
+        message: String,
+    },
+    /// A receiver is ready for the BIP21 URI to be shared with the sender
+    UriCreated {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_id: Option<i64>,
+        uri: String,
+        /// The address a BIP78 receiver listens on
+        #[serde(skip_serializing_if = "Option::is_none")]
+        listening_at: Option<SocketAddr>,
+    },
+    /// The sender posted the Original PSBT, whose fallback transaction is known to the receiver
+    OriginalPosted {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_id: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fallback_txid: Option<Txid>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fallback_tx: Option<String>,
+    },
+    /// The receiver got the Original PSBT from the sender
+    OriginalReceived {
+        session_id: i64,
+    },
+    /// The receiver checked that the fallback transaction can be broadcast
+    FallbackReceived {
+        session_id: i64,
+        fallback_tx: String,
+    },
+    /// The receiver responded with a Payjoin Proposal
+    ProposalSent {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        session_id: Option<i64>,
+        txid: Txid,
+    },
+    /// The sender got a Payjoin Proposal from the receiver
+    ProposalReceived {
+        session_id: i64,
+    },
+    /// The sender has not got a Payjoin Proposal yet
+    NoProposalYet {
+        session_id: i64,
+    },
+    /// The sender signed and broadcast the payjoin transaction
+    TxBroadcast {
+        txid: Txid,
+    },
+    /// A receiver broadcast the fallback transaction instead of finishing the payjoin
+    FallbackBroadcast {
+        session_id: i64,
+        txid: Txid,
+    },
+    /// A session was closed at the user's request
+    SessionCancelled {
+        session_id: i64,
+        role: Role,
+    },
+    /// A PSBT was written for an external signer, and its session waits until it is signed
+    PsbtToSign {
+        txid: Txid,
+        path: PathBuf,
+        signed_path: PathBuf,
+        psbt: String,
+    },
+    /// A signed PSBT was handed to the session waiting for it
+    PsbtImported {
+        txid: Txid,
+        path: PathBuf,
+    },
+    /// A session stopped with an error before it was closed
+    SessionFailed {
+        session_id: i64,
+        role: Role,
+        code: ErrorCode,
+        message: String,
+    },
+    /// An event was appended to a session's event log
+    SessionState {
+        session_id: i64,
+        role: Role,
+        state: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        outcome: Option<String>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl Event {
+    /// The event as human readable text, or `None` if it is only useful to automation
+    fn text(&self) -> Option<String> {
+        match self {
+            Event::Info { message } => Some(message.clone()),
+            Event::UriCreated { uri, listening_at: Some(addr), .. } => Some(format!(
+                "Listening at {addr}. Configured to accept payjoin at BIP 21 Payjoin Uri:\n{uri}"
+            )),
+            Event::UriCreated { uri, listening_at: None, .. } => Some(format!(
+                "Receive session established\nRequest Payjoin by sharing this Payjoin Uri:\n{uri}"
+            )),
+            Event::OriginalPosted { fallback_txid: Some(txid), fallback_tx: Some(tx), .. } =>
+                Some(format!(
+                    "Sent fallback transaction txid: {txid}\nSent fallback transaction hex: {tx}"
+                )),
+            Event::OriginalPosted { .. } => Some("Posted original proposal...".to_owned()),
+            Event::OriginalReceived { .. } => Some(
+                "Got a request from the sender. Responding with a Payjoin proposal.".to_owned(),
+            ),
+            Event::FallbackReceived { fallback_tx, .. } => Some(format!(
+                "Fallback transaction received. Consider broadcasting this to get paid if the Payjoin fails:\n{fallback_tx}"
+            )),
+            Event::ProposalSent { txid, .. } => Some(format!(
+                "Response successful. Watch mempool for successful Payjoin. TXID: {txid}"
+            )),
+            Event::ProposalReceived { .. } => Some("Proposal received. Processing...".to_owned()),
+            Event::NoProposalYet { .. } => Some("No response yet.".to_owned()),
+            Event::TxBroadcast { txid } => Some(format!("Payjoin sent. TXID: {txid}")),
+            Event::FallbackBroadcast { session_id, txid } => Some(format!(
+                "Broadcast fallback transaction of receive session {session_id}. TXID: {txid}"
+            )),
+            Event::SessionCancelled { session_id, role } =>
+                Some(format!("Cancelled {} session {session_id}", role.as_str().to_lowercase())),
+            Event::PsbtToSign { path, signed_path, psbt, .. } => Some(format!(
+                "Wrote a PSBT to sign to {}. Sign it externally, then import it with `payjoin-cli sign-import` or save it to {}:\n{psbt}",
+                path.display(),
+                signed_path.display()
+            )),
+            Event::PsbtImported { txid, path } =>
+                Some(format!("Imported signed PSBT for {txid} to {}", path.display())),
+            Event::SessionFailed { session_id, role, message, .. } =>
+                Some(format!("{} session {session_id} failed: {message}", role.as_str())),
+            Event::SessionState { .. } => None,
+            Event::Error { message, .. } => Some(message.clone()),
+        }
+    }
+
+    /// A session state change, from the serialized session event that caused it
+    #[cfg(feature = "v2")]
+    pub(crate) fn session_state(session_id: i64, role: Role, event: &serde_json::Value) -> Self {
+        let state = variant_name(event).unwrap_or("Unknown").to_owned();
+        let outcome = match event {
+            serde_json::Value::Object(map) if state == "Closed" =>
+                map.get("Closed").and_then(variant_name).map(str::to_owned),
+            _ => None,
+        };
+        Event::SessionState { session_id, role, state, outcome }
+    }
+}
+
+/// The name of an externally tagged enum variant, without its data, which may hold secrets
+#[cfg(feature = "v2")]
+fn variant_name(value: &serde_json::Value) -> Option<&str> {
+    match value {
+        serde_json::Value::String(name) => Some(name),
+        serde_json::Value::Object(map) => map.keys().next().map(String::as_str),
+        _ => None,
+    }
+}
+
+/// A stable classification of the error a command failed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorCode {
+    Config,
+    Database,
+    Http,
+    Protocol,
+    Interrupted,
+    Other,
+}
+
+impl From<&anyhow::Error> for ErrorCode {
+    fn from(e: &anyhow::Error) -> Self {
+        for cause in e.chain() {
+            if cause.is::<config::ConfigError>() {
+                return ErrorCode::Config;
+            }
+            if cause.is::<crate::db::error::Error>() {
+                return ErrorCode::Database;
+            }
+            if cause.is::<reqwest::Error>() {
+                return ErrorCode::Http;
+            }
+            if cause.is::<payjoin::send::BuildSenderError>()
+                || cause.is::<payjoin::send::ResponseError>()
+                || cause.is::<payjoin::receive::Error>()
+            {
+                return ErrorCode::Protocol;
+            }
+            if cause.is::<crate::app::Interrupted>() {
+                return ErrorCode::Interrupted;
+            }
+        }
+        ErrorCode::Other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn test_error_code_from_cause_chain() {
+        let config = anyhow::Error::new(config::ConfigError::Message("missing".to_owned()));
+        assert_eq!(ErrorCode::from(&config), ErrorCode::Config);
+
+        let database =
+            anyhow::Error::new(crate::db::error::Error::Rusqlite(rusqlite::Error::InvalidQuery));
+        assert_eq!(
+            ErrorCode::from(&database.context("Failed to load sessions")),
+            ErrorCode::Database
+        );
+
+        let interrupted: anyhow::Result<()> = Err(crate::app::Interrupted.into());
+        let interrupted = interrupted.context("Receive session interrupted").unwrap_err();
+        assert_eq!(ErrorCode::from(&interrupted), ErrorCode::Interrupted);
+
+        assert_eq!(ErrorCode::from(&anyhow!("something else")), ErrorCode::Other);
+    }
+
+    #[test]
+    fn test_event_json() {
+        let event = Event::Error { code: ErrorCode::Http, message: "timed out".to_owned() };
+        assert_eq!(
+            serde_json::to_value(&event).expect("serializable"),
+            serde_json::json!({ "event": "error", "code": "http", "message": "timed out" })
+        );
+
+        let event =
+            Event::UriCreated { session_id: None, uri: "bitcoin:".to_owned(), listening_at: None };
+        assert_eq!(
+            serde_json::to_value(&event).expect("serializable"),
+            serde_json::json!({ "event": "uri_created", "uri": "bitcoin:" })
+        );
+    }
+
+    #[cfg(feature = "v2")]
+    #[test]
+    fn test_session_state() {
+        let state = |event| match Event::session_state(1, Role::Receiver, &event) {
+            Event::SessionState { state, outcome, .. } => (state, outcome),
+            event => panic!("unexpected {event:?}"),
+        };
+
+        assert_eq!(
+            state(serde_json::json!({ "RetrievedOriginalPayload": { "secret": "psbt" } })),
+            ("RetrievedOriginalPayload".to_owned(), None)
+        );
+        assert_eq!(
+            state(serde_json::json!({ "Closed": { "Success": ["secret"] } })),
+            ("Closed".to_owned(), Some("Success".to_owned()))
+        );
+        assert_eq!(
+            state(serde_json::json!({ "Closed": "Cancel" })),
+            ("Closed".to_owned(), Some("Cancel".to_owned()))
+        );
+        assert_eq!(state(serde_json::json!(null)), ("Unknown".to_owned(), None));
+    }
+}
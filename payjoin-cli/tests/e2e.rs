@@ -196,6 +196,131 @@ mod e2e {
         Ok(())
     }
 
+    /// Read JSON events from `child_stdout` until one named `event` is found and returned,
+    /// asserting every line is JSON
+    async fn wait_for_json_event(
+        child_stdout: &mut tokio::process::ChildStdout,
+        event: &str,
+    ) -> Option<serde_json::Value> {
+        let line = wait_for_stdout_match(child_stdout, |line| {
+            let json: serde_json::Value =
+                serde_json::from_str(line).expect("--json should only print JSON lines");
+            json["event"] == event
+        })
+        .await?;
+        serde_json::from_str(&line).ok()
+    }
+
+    #[cfg(feature = "v1")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn send_receive_payjoin_v1_json() -> Result<(), BoxError> {
+        use payjoin_test_utils::local_cert_key;
+
+        let (bitcoind, _sender, _receiver) = init_bitcoind_sender_receiver(None, None)?;
+        let temp_dir = tempdir()?;
+        let receiver_rpchost = format!("http://{}/wallet/receiver", bitcoind.params.rpc_socket);
+        let sender_rpchost = format!("http://{}/wallet/sender", bitcoind.params.rpc_socket);
+        let cookie_file = &bitcoind.params.cookie_file;
+        let payjoin_cli = env!("CARGO_BIN_EXE_payjoin-cli");
+
+        let cert = local_cert_key();
+        let cert_path = &temp_dir.path().join("localhost.crt");
+        tokio::fs::write(cert_path, cert.cert.der().to_vec()).await?;
+        let key_path = &temp_dir.path().join("localhost.key");
+        tokio::fs::write(key_path, cert.signing_key.serialize_der()).await?;
+
+        let mut cli_receiver = Command::new(payjoin_cli)
+            .arg("--json")
+            .arg("--root-certificate")
+            .arg(cert_path)
+            .arg("--certificate-key")
+            .arg(key_path)
+            .arg("--bip78")
+            .arg("--rpchost")
+            .arg(&receiver_rpchost)
+            .arg("--cookie-file")
+            .arg(cookie_file)
+            .arg("--db-path")
+            .arg(temp_dir.path().join("receiver_db"))
+            .arg("receive")
+            .arg(RECEIVE_SATS)
+            .arg("--port")
+            .arg("0")
+            .arg("--pj-endpoint")
+            .arg("https://localhost")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .expect("Failed to execute payjoin-cli");
+        let mut receiver_stdout =
+            cli_receiver.stdout.take().expect("Failed to take stdout of child process");
+        let uri_created = wait_for_json_event(&mut receiver_stdout, "uri_created")
+            .await
+            .expect("receiver should emit uri_created");
+        let bip21 = uri_created["uri"].as_str().expect("uri_created should have a uri");
+        assert!(uri_created["listening_at"].is_string());
+
+        let mut cli_sender = Command::new(payjoin_cli)
+            .arg("--json")
+            .arg("--root-certificate")
+            .arg(cert_path)
+            .arg("--bip78")
+            .arg("--rpchost")
+            .arg(&sender_rpchost)
+            .arg("--cookie-file")
+            .arg(cookie_file)
+            .arg("--db-path")
+            .arg(temp_dir.path().join("sender_db"))
+            .arg("send")
+            .arg(bip21)
+            .arg("--fee-rate")
+            .arg("1")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .expect("Failed to execute payjoin-cli");
+        let mut sender_stdout =
+            cli_sender.stdout.take().expect("Failed to take stdout of child process");
+        let tx_broadcast = tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            wait_for_json_event(&mut sender_stdout, "tx_broadcast"),
+        )
+        .await?
+        .expect("sender should emit tx_broadcast");
+        assert!(tx_broadcast["txid"].is_string());
+
+        terminate(cli_receiver).await.expect("Failed to kill payjoin-cli");
+        terminate(cli_sender).await.expect("Failed to kill payjoin-cli");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn json_error_has_code() -> Result<(), BoxError> {
+        let temp_dir = tempdir()?;
+        tokio::fs::write(temp_dir.path().join("config.toml"), "not = [valid toml").await?;
+
+        let mut cli = Command::new(env!("CARGO_BIN_EXE_payjoin-cli"))
+            .current_dir(temp_dir.path())
+            .arg("--json")
+            .arg("--db-path")
+            .arg(temp_dir.path().join("db"))
+            .arg("send")
+            .arg("bitcoin:")
+            .arg("--fee-rate")
+            .arg("1")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .expect("Failed to execute payjoin-cli");
+        let mut stdout = cli.stdout.take().expect("Failed to take stdout of child process");
+        let error =
+            wait_for_json_event(&mut stdout, "error").await.expect("payjoin-cli should emit error");
+        assert_eq!(error["code"], "config");
+        assert!(error["message"].is_string());
+        assert!(!cli.wait().await?.success());
+        Ok(())
+    }
+
     #[cfg(feature = "v2")]
     #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
     async fn send_receive_payjoin_v2() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
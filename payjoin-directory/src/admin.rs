@@ -0,0 +1,343 @@
+//! Maintenance tasks run by the `payjoin-directory` subcommands.
+//!
+//! Apart from `prune`, these don't modify mailbox storage, so they can be run
+//! while the directory is serving.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, bail, Result};
+use payjoin::directory::ShortId;
+use tokio::{fs, io};
+
+use crate::config::{Config, DbBackend};
+use crate::db::at_rest::{self, AtRest};
+use crate::db::files::{TMP_DIR, XOR_FILE};
+use crate::db::mailboxes::{self, MailboxPolicy, MailboxStats};
+use crate::db::sqlite::SqliteStorage;
+use crate::db::{Db as _, Storage};
+use crate::{key_config, FilesDb, SqliteDb};
+
+/// Create the OHTTP key file in `dir`, or replace existing keys if `rotate`.
+pub fn keygen(dir: &Path, rotate: bool) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let ohttp_config = key_config::gen_ohttp_server_config()?;
+    if !key_config::key_path(dir).exists() {
+        key_config::persist_new_key_config(ohttp_config, dir)
+    } else if rotate {
+        key_config::rotate_key_config(ohttp_config, dir)
+    } else {
+        bail!("OHTTP keys already exist in {}, pass --rotate to replace them", dir.display())
+    }
+}
+
+/// Stored mailboxes as shown by `inspect`
+#[derive(Debug)]
+pub struct Inspection {
+    stats: MailboxStats,
+    show_ids: bool,
+    at: SystemTime,
+}
+
+impl Inspection {
+    pub fn stats(&self) -> &MailboxStats { &self.stats }
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stats = &self.stats;
+        let read = stats.read();
+        writeln!(f, "Mailboxes: {} ({} read, {} unread)", stats.len(), read, stats.len() - read)?;
+        writeln!(f, "Stored bytes: {}", stats.stored_bytes())?;
+        match stats.oldest() {
+            Some(oldest) => writeln!(f, "Oldest entry: {}s ago", age(self.at, oldest))?,
+            None => writeln!(f, "Oldest entry: none")?,
+        }
+        if self.show_ids {
+            for entry in &stats.entries {
+                write!(
+                    f,
+                    "{} {} bytes, created {}s ago",
+                    entry.id,
+                    entry.size,
+                    age(self.at, entry.created)
+                )?;
+                match entry.read {
+                    Some(read) => writeln!(f, ", read {}s ago", age(self.at, read))?,
+                    None => writeln!(f, ", unread")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Summarize the stored mailboxes. Mailbox IDs are only listed if `show_ids`.
+pub async fn inspect(config: &Config, show_ids: bool) -> Result<Inspection> {
+    let stats = match config.db_backend {
+        DbBackend::Files => {
+            let dir = existing(config.storage_dir.clone())?;
+            FilesDb::open(Duration::ZERO, dir).await?.stats().await
+        }
+        DbBackend::Sqlite => {
+            let path = existing(config.sqlite_path())?;
+            SqliteDb::init(Duration::ZERO, path).await?.stats().await
+        }
+        DbBackend::Memory => bail!("The memory backend has no storage to inspect"),
+    };
+    Ok(Inspection { stats, show_ids, at: SystemTime::now() })
+}
+
+/// Mailboxes removed by `prune`
+#[derive(Debug)]
+pub struct PruneReport {
+    before: MailboxStats,
+    after: MailboxStats,
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Pruned {} mailboxes ({} bytes), {} remain",
+            self.before.len() - self.after.len(),
+            self.before.stored_bytes() - self.after.stored_bytes(),
+            self.after.len(),
+        )
+    }
+}
+
+/// Prune expired mailboxes according to the configured policy. The directory
+/// must not be running, since it would not notice the removals.
+pub async fn prune(config: &Config) -> Result<PruneReport> {
+    let policy = config.mailbox_policy.clone();
+    match config.db_backend {
+        DbBackend::Files => {
+            let dir = existing(config.storage_dir.clone())?;
+            // Pruning doesn't read payloads, so there's no need for the key
+            let at_rest = AtRest { key: None, secure_delete: config.secure_delete };
+            prune_db(FilesDb::init_with(Duration::ZERO, dir, at_rest).await?, policy).await
+        }
+        DbBackend::Sqlite => {
+            let path = existing(config.sqlite_path())?;
+            prune_db(SqliteDb::init(Duration::ZERO, path).await?, policy).await
+        }
+        DbBackend::Memory => bail!("The memory backend has no storage to prune"),
+    }
+}
+
+async fn prune_db<S: Storage>(db: mailboxes::Db<S>, policy: MailboxPolicy) -> Result<PruneReport> {
+    let db = db.with_policy(policy).await;
+    let before = db.stats().await;
+    db.prune().await?;
+    db.flush().await?;
+    let after = db.stats().await;
+    Ok(PruneReport { before, after })
+}
+
+/// A problem with the storage directory found by `verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// Mailboxes are stored, but the pattern needed to read them is missing
+    MissingXorPattern,
+    /// An empty pattern leaves payloads unobfuscated
+    EmptyXorPattern,
+    /// The temp path is not a directory, so the directory can't start
+    TmpNotADirectory,
+    /// Temp files from interrupted writes, or from writes still in progress
+    LeftoverTempFiles(usize),
+    /// Encryption is configured but these mailboxes are only obfuscated. They
+    /// are encrypted on the next start.
+    UnencryptedMailboxes(usize),
+    /// A problem reported by SQLite's integrity check
+    Integrity(String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingXorPattern =>
+                write!(f, "{XOR_FILE} is missing, stored mailboxes can't be read"),
+            Self::EmptyXorPattern => write!(f, "{XOR_FILE} is empty, payloads are not obfuscated"),
+            Self::TmpNotADirectory => write!(f, "{TMP_DIR} is not a directory"),
+            Self::LeftoverTempFiles(count) =>
+                write!(f, "{count} files in {TMP_DIR} from interrupted or ongoing writes"),
+            Self::UnencryptedMailboxes(count) =>
+                write!(f, "{count} mailboxes are not encrypted yet"),
+            Self::Integrity(problem) => write!(f, "Integrity check failed: {problem}"),
+        }
+    }
+}
+
+/// The problems found by `verify`
+#[derive(Debug)]
+pub struct Verification {
+    problems: Vec<Problem>,
+}
+
+impl Verification {
+    pub fn problems(&self) -> &[Problem] { &self.problems }
+
+    pub fn is_ok(&self) -> bool { self.problems.is_empty() }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return writeln!(f, "No problems found");
+        }
+        for problem in &self.problems {
+            writeln!(f, "{problem}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the storage directory for problems, without modifying it.
+pub async fn verify(config: &Config) -> Result<Verification> {
+    let problems = match config.db_backend {
+        DbBackend::Files => {
+            let dir = existing(config.storage_dir.clone())?;
+            verify_files(&dir, config.storage_key.is_some()).await?
+        }
+        DbBackend::Sqlite => SqliteStorage::init(existing(config.sqlite_path())?)
+            .await?
+            .integrity_check()
+            .await?
+            .into_iter()
+            .map(Problem::Integrity)
+            .collect(),
+        DbBackend::Memory => bail!("The memory backend has no storage to verify"),
+    };
+    Ok(Verification { problems })
+}
+
+async fn verify_files(dir: &Path, encrypted: bool) -> io::Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+
+    match fs::metadata(dir.join(XOR_FILE)).await {
+        Ok(metadata) if metadata.len() == 0 => problems.push(Problem::EmptyXorPattern),
+        Ok(_) => {}
+        // A new pattern is generated on start, which is only a problem for
+        // mailboxes obfuscated with the lost one
+        Err(e) if e.kind() == io::ErrorKind::NotFound =>
+            if has_mailboxes(dir).await? {
+                problems.push(Problem::MissingXorPattern);
+            },
+        Err(e) => return Err(e),
+    }
+
+    match fs::metadata(dir.join(TMP_DIR)).await {
+        Ok(metadata) if !metadata.is_dir() => problems.push(Problem::TmpNotADirectory),
+        Ok(_) => {
+            let mut count = 0;
+            let mut entries = fs::read_dir(dir.join(TMP_DIR)).await?;
+            while entries.next_entry().await?.is_some() {
+                count += 1;
+            }
+            if count > 0 {
+                problems.push(Problem::LeftoverTempFiles(count));
+            }
+        }
+        // Created on start
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    if encrypted {
+        let mut unencrypted = 0;
+        for path in mailbox_paths(dir).await? {
+            let contents = fs::read(path).await?;
+            if at_rest::created_at(&contents).is_none() {
+                unencrypted += 1;
+            }
+        }
+        if unencrypted > 0 {
+            problems.push(Problem::UnencryptedMailboxes(unencrypted));
+        }
+    }
+
+    Ok(problems)
+}
+
+async fn has_mailboxes(dir: &Path) -> io::Result<bool> { Ok(!mailbox_paths(dir).await?.is_empty()) }
+
+async fn mailbox_paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_str().is_some_and(|name| name.parse::<ShortId>().is_ok()) {
+            paths.push(entry.path());
+        }
+    }
+    Ok(paths)
+}
+
+fn existing(path: PathBuf) -> Result<PathBuf> {
+    if path.exists() {
+        Ok(path)
+    } else {
+        Err(anyhow!("No mailbox storage at {}", path.display()))
+    }
+}
+
+fn age(at: SystemTime, then: SystemTime) -> u64 {
+    at.duration_since(then).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_inspect_and_verify_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = FilesDb::init(Duration::ZERO, dir.path().to_owned()).await?;
+        db.post_v2_payload(&ShortId([1u8; 8]), b"payload".to_vec()).await?;
+        assert!(verify_files(dir.path(), false).await?.is_empty());
+
+        fs::write(dir.path().join(TMP_DIR).join("interrupted"), b"junk").await?;
+        assert_eq!(verify_files(dir.path(), false).await?, vec![Problem::LeftoverTempFiles(1)]);
+
+        let stats = FilesDb::open(Duration::ZERO, dir.path().to_owned()).await?.stats().await;
+        assert_eq!((stats.len(), stats.read(), stats.stored_bytes()), (1, 0, 7));
+        assert!(
+            dir.path().join(TMP_DIR).join("interrupted").exists(),
+            "opening storage for inspection must not clear temp files"
+        );
+
+        assert_eq!(
+            verify_files(dir.path(), true).await?,
+            vec![Problem::LeftoverTempFiles(1), Problem::UnencryptedMailboxes(1)]
+        );
+
+        fs::remove_file(dir.path().join(XOR_FILE)).await?;
+        assert_eq!(
+            verify_files(dir.path(), false).await?,
+            vec![Problem::MissingXorPattern, Problem::LeftoverTempFiles(1)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune_offline() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = FilesDb::init(Duration::ZERO, dir.path().to_owned()).await?;
+        db.post_v2_payload(&ShortId([1u8; 8]), b"payload".to_vec()).await?;
+        drop(db);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let policy = MailboxPolicy {
+            read_ttl: Duration::from_millis(5),
+            unread_ttl_at_capacity: Duration::from_millis(10),
+            unread_ttl_below_capacity: Duration::from_millis(20),
+            ..MailboxPolicy::default()
+        };
+        let report =
+            prune_db(FilesDb::init(Duration::ZERO, dir.path().to_owned()).await?, policy).await?;
+        assert_eq!((report.before.len(), report.after.len()), (1, 0));
+        assert!(!has_mailboxes(dir.path()).await?, "the expired mailbox is removed");
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 use std::env;
 use std::path::PathBuf;
 
-use clap::{value_parser, Parser};
+use clap::{value_parser, Parser, Subcommand};
 
 #[derive(Debug, Parser)]
 #[command(
@@ -10,6 +10,9 @@ use clap::{value_parser, Parser};
     long_about = None,
 )]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     #[arg(
         long,
         short = 'p',
@@ -17,7 +20,7 @@ pub struct Cli {
         default_value = "8080",
         help = "The port to bind"
     )]
-    pub port: u16, // TODO tokio_listener::ListenerAddressLFlag
+    pub port: u16,
 
     #[arg(
         long,
@@ -25,7 +28,35 @@ pub struct Cli {
         default_value = "9090",
         help = "The port to bind for prometheus metrics export"
     )]
-    pub metrics_port: u16, // TODO tokio_listener::ListenerAddressLFlag
+    pub metrics_port: u16,
+
+    #[arg(
+        long,
+        env = "PJ_LISTEN",
+        help = "Where to listen instead of --port: HOST:PORT, unix:PATH, sd-listen or sd-listen:NAME"
+    )]
+    pub listen: Option<String>,
+
+    #[arg(
+        long,
+        env = "PJ_METRICS_LISTEN",
+        help = "Where to listen for metrics export instead of --metrics-port, as for --listen"
+    )]
+    pub metrics_listen: Option<String>,
+
+    #[arg(
+        long = "unix-socket-mode",
+        env = "PJ_UNIX_SOCKET_MODE",
+        help = "Octal permissions of Unix sockets created by the directory [default: 660]"
+    )]
+    pub unix_socket_mode: Option<String>,
+
+    #[arg(
+        long,
+        env = "PJ_H2C",
+        help = "Also accept HTTP/2 with prior knowledge on connections without TLS"
+    )]
+    pub h2c: bool,
 
     #[arg(
         long,
@@ -35,19 +66,210 @@ pub struct Cli {
     )]
     pub timeout: u64,
 
+    #[arg(
+        long = "shutdown-timeout-secs",
+        env = "PJ_SHUTDOWN_TIMEOUT_SECS",
+        default_value = "10",
+        help = "How long to wait for open connections to close when shutting down"
+    )]
+    pub shutdown_timeout: u64,
+
     #[arg(
         long = "storage-dir",
         env = "PJ_STORAGE_DIR",
+        global = true,
         help = "A directory for writing mailbox data."
     )]
-    pub storage_dir: PathBuf,
+    pub storage_dir: Option<PathBuf>,
+
+    #[arg(
+        long = "db-backend",
+        env = "PJ_DB_BACKEND",
+        global = true,
+        default_value = "files",
+        value_parser = ["files", "memory", "sqlite"],
+        help = "The mailbox storage backend"
+    )]
+    pub db_backend: String,
+
+    #[arg(
+        long = "storage-key-file",
+        env = "PJ_STORAGE_KEY_FILE",
+        conflicts_with = "ephemeral_storage_key",
+        help = "A file with at least 32 secret bytes to derive the mailbox encryption key from"
+    )]
+    pub storage_key_file: Option<PathBuf>,
+
+    #[arg(
+        long = "ephemeral-storage-key",
+        env = "PJ_EPHEMERAL_STORAGE_KEY",
+        help = "Encrypt mailboxes with a key held only in memory, losing them on restart"
+    )]
+    pub ephemeral_storage_key: bool,
+
+    #[arg(
+        long = "secure-delete",
+        env = "PJ_SECURE_DELETE",
+        help = "Overwrite mailbox files before removing them"
+    )]
+    pub secure_delete: bool,
+
+    #[arg(
+        long = "max-mailboxes",
+        env = "PJ_MAX_MAILBOXES",
+        help = "The maximum number of pending or populated mailboxes"
+    )]
+    pub max_mailboxes: Option<usize>,
+
+    #[arg(
+        long = "max-storage-bytes",
+        env = "PJ_MAX_STORAGE_BYTES",
+        help = "The maximum total size of stored mailbox payloads, in bytes"
+    )]
+    pub max_storage_bytes: Option<u64>,
+
+    #[arg(
+        long = "unread-ttl-secs",
+        env = "PJ_UNREAD_TTL_SECS",
+        help = "How long unread mailboxes are kept while below capacity"
+    )]
+    pub unread_ttl: Option<u64>,
+
+    #[arg(
+        long = "unread-ttl-at-capacity-secs",
+        env = "PJ_UNREAD_TTL_AT_CAPACITY_SECS",
+        help = "How long unread mailboxes are kept once at capacity"
+    )]
+    pub unread_ttl_at_capacity: Option<u64>,
+
+    #[arg(
+        long = "read-ttl-secs",
+        env = "PJ_READ_TTL_SECS",
+        help = "How long mailboxes are kept after they are first read"
+    )]
+    pub read_ttl: Option<u64>,
+
+    #[arg(
+        long = "prune-interval-secs",
+        env = "PJ_PRUNE_INTERVAL_SECS",
+        help = "The longest time between pruning runs triggered by requests"
+    )]
+    pub prune_interval: Option<u64>,
+
+    #[arg(
+        long = "prune-every",
+        env = "PJ_PRUNE_EVERY",
+        help = "The most requests served between pruning runs"
+    )]
+    pub prune_every: Option<usize>,
+
+    #[arg(
+        long = "v1-rate-limit-per-sec",
+        env = "PJ_V1_RATE_LIMIT_PER_SEC",
+        help = "The sustained rate of v1 fallback requests allowed per source address"
+    )]
+    pub v1_rate_limit: Option<f64>,
+
+    #[arg(
+        long = "v1-rate-limit-burst",
+        env = "PJ_V1_RATE_LIMIT_BURST",
+        help = "The burst of v1 fallback requests allowed per source address"
+    )]
+    pub v1_rate_limit_burst: Option<u32>,
+
+    #[arg(
+        long = "ohttp-rate-limit-per-sec",
+        env = "PJ_OHTTP_RATE_LIMIT_PER_SEC",
+        help = "The sustained rate of OHTTP requests allowed per relay connection"
+    )]
+    pub ohttp_rate_limit: Option<f64>,
+
+    #[arg(
+        long = "ohttp-rate-limit-burst",
+        env = "PJ_OHTTP_RATE_LIMIT_BURST",
+        help = "The burst of OHTTP requests allowed per relay connection"
+    )]
+    pub ohttp_rate_limit_burst: Option<u32>,
+
+    #[arg(
+        long = "token-issuance-rate-limit-per-sec",
+        env = "PJ_TOKEN_ISSUANCE_RATE_LIMIT_PER_SEC",
+        help = "The sustained rate of access token issuance requests allowed per source address"
+    )]
+    pub token_issuance_rate_limit: Option<f64>,
+
+    #[arg(
+        long = "token-issuance-rate-limit-burst",
+        env = "PJ_TOKEN_ISSUANCE_RATE_LIMIT_BURST",
+        help = "The burst of access token issuance requests allowed per source address"
+    )]
+    pub token_issuance_rate_limit_burst: Option<u32>,
+
+    #[arg(
+        long = "require-access-tokens",
+        env = "PJ_REQUIRE_ACCESS_TOKENS",
+        help = "Require an anonymous access token for every v2 mailbox write"
+    )]
+    pub require_access_tokens: bool,
+
+    #[arg(
+        long = "access-token-key-rotation-secs",
+        env = "PJ_ACCESS_TOKEN_KEY_ROTATION_SECS",
+        help = "How often the access token issuer key rotates"
+    )]
+    pub access_token_key_rotation: Option<u64>,
+
+    #[arg(
+        long = "max-long-polls",
+        env = "PJ_MAX_LONG_POLLS",
+        help = "The maximum number of concurrent long polling requests"
+    )]
+    pub max_long_polls: Option<usize>,
 
     #[arg(
         long = "ohttp-keys",
         env = "PJ_OHTTP_KEY_DIR",
+        global = true,
         help = "The ohttp key config file path",
         default_value = "ohttp_keys",
         value_parser = value_parser!(PathBuf)
     )]
     pub ohttp_keys: PathBuf,
+
+    #[arg(
+        long = "relay-port",
+        env = "PJ_RELAY_PORT",
+        help = "The port to bind for an OHTTP relay served alongside the directory"
+    )]
+    pub relay_port: Option<u16>,
+
+    #[arg(
+        long = "relay-gateways",
+        env = "PJ_RELAY_GATEWAYS",
+        value_delimiter = ',',
+        help = "The OHTTP gateway origins the relay may forward to, comma-separated"
+    )]
+    pub relay_gateways: Vec<String>,
+}
+
+/// Maintenance tasks run instead of serving
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Create the OHTTP key file, or replace it with --rotate")]
+    Keygen {
+        #[arg(long, help = "Replace existing keys, invalidating those cached by clients")]
+        rotate: bool,
+    },
+
+    #[command(about = "Show mailbox storage occupancy")]
+    Inspect {
+        #[arg(long = "show-ids", help = "List every stored mailbox by its ID")]
+        show_ids: bool,
+    },
+
+    #[command(about = "Prune expired mailboxes from storage while the directory is stopped")]
+    Prune,
+
+    #[command(about = "Check the storage directory for problems")]
+    Verify,
 }
@@ -9,14 +9,202 @@ use serde::Deserialize;
 type Builder = config::builder::ConfigBuilder<DefaultState>;
 
 use crate::cli::Cli;
+use crate::db::at_rest::StorageKey;
+use crate::db::mailboxes::MailboxPolicy;
+use crate::listener::ListenAddr;
+use crate::rate_limit::{RateLimit, RateLimits};
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct Config {
-    pub listen_addr: String,         // TODO tokio_listener::ListenerAddressLFlag
-    pub metrics_listen_addr: String, // TODO tokio_listener::ListenerAddressLFlag
+    pub listen_addr: ListenAddr,
+    pub metrics_listen_addr: ListenAddr,
+    /// Permissions of Unix sockets created for the listen addresses
+    pub unix_socket_mode: u32,
+    /// Whether to accept HTTP/2 with prior knowledge on connections without TLS
+    pub h2c: bool,
     pub timeout: Duration,
+    pub shutdown_timeout: Duration,
     pub storage_dir: PathBuf,
+    pub db_backend: DbBackend,
+    /// Where the key for encrypting mailbox files comes from, if they are
+    /// encrypted
+    pub storage_key: Option<StorageKeySource>,
+    /// Whether to overwrite mailbox files before removing them
+    pub secure_delete: bool,
+    pub mailbox_policy: MailboxPolicy,
+    pub rate_limits: RateLimits,
+    /// How often the access token issuer key rotates, if mailbox writes
+    /// require access tokens
+    pub access_token_key_rotation: Option<Duration>,
     pub ohttp_keys: PathBuf, // TODO OhttpConfig struct with rotation params, etc
+    pub relay: Option<RelayConfig>,
+}
+
+/// Where mailboxes are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DbBackend {
+    /// XOR-obfuscated files in `storage_dir`
+    Files,
+    /// Process memory only, lost on restart
+    Memory,
+    /// A SQLite database in `storage_dir`
+    Sqlite,
+}
+
+/// Where the key for encrypting mailbox files comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageKeySource {
+    /// A random key held in memory, so mailboxes don't survive restarts
+    Ephemeral,
+    /// A key derived from the contents of a file
+    File(PathBuf),
+}
+
+impl StorageKeySource {
+    pub fn load(&self) -> std::io::Result<StorageKey> {
+        match self {
+            Self::Ephemeral => Ok(StorageKey::generate()),
+            Self::File(path) => StorageKey::from_file(path),
+        }
+    }
+}
+
+/// Protection of mailbox files at rest as configured
+#[derive(Debug, Clone, Default, Deserialize)]
+struct StorageEncryptionConfig {
+    key_file: Option<PathBuf>,
+    #[serde(default)]
+    ephemeral_key: bool,
+    #[serde(default)]
+    secure_delete: bool,
+}
+
+impl StorageEncryptionConfig {
+    fn into_key_source(self) -> Result<Option<StorageKeySource>, ConfigError> {
+        match (self.key_file, self.ephemeral_key) {
+            (Some(_), true) => Err(ConfigError::Message(
+                "Storage encryption takes either a key file or an ephemeral key".to_string(),
+            )),
+            (Some(path), false) => Ok(Some(StorageKeySource::File(path))),
+            (None, true) => Ok(Some(StorageKeySource::Ephemeral)),
+            (None, false) => Ok(None),
+        }
+    }
+}
+
+/// Mailbox limits as configured, in seconds and bytes. Unset values fall back
+/// to the [`MailboxPolicy`] defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+struct MailboxesConfig {
+    capacity: Option<usize>,
+    max_bytes: Option<u64>,
+    unread_ttl_secs: Option<u64>,
+    unread_ttl_at_capacity_secs: Option<u64>,
+    read_ttl_secs: Option<u64>,
+    prune_interval_secs: Option<u64>,
+    prune_every: Option<usize>,
+}
+
+impl MailboxesConfig {
+    fn into_policy(self) -> Result<MailboxPolicy, ConfigError> {
+        let default = MailboxPolicy::default();
+        let policy = MailboxPolicy {
+            capacity: self.capacity.unwrap_or(default.capacity),
+            max_bytes: self.max_bytes.or(default.max_bytes),
+            unread_ttl_at_capacity: self
+                .unread_ttl_at_capacity_secs
+                .map_or(default.unread_ttl_at_capacity, Duration::from_secs),
+            unread_ttl_below_capacity: self
+                .unread_ttl_secs
+                .map_or(default.unread_ttl_below_capacity, Duration::from_secs),
+            read_ttl: self.read_ttl_secs.map_or(default.read_ttl, Duration::from_secs),
+            prune_interval: self
+                .prune_interval_secs
+                .map_or(default.prune_interval, Duration::from_secs),
+            prune_every: self.prune_every.unwrap_or(default.prune_every),
+        };
+        policy
+            .validate()
+            .map_err(|e| ConfigError::Message(format!("Invalid mailbox policy: {e}")))?;
+        Ok(policy)
+    }
+}
+
+/// Rate limits as configured. A limit is enabled by setting its rate, and its
+/// burst defaults to one second's worth of requests.
+#[derive(Debug, Clone, Default, Deserialize)]
+struct RateLimitsConfig {
+    v1_per_second: Option<f64>,
+    v1_burst: Option<u32>,
+    ohttp_per_second: Option<f64>,
+    ohttp_burst: Option<u32>,
+    token_issuance_per_second: Option<f64>,
+    token_issuance_burst: Option<u32>,
+    max_long_poll_waiters: Option<usize>,
+}
+
+impl RateLimitsConfig {
+    fn into_limits(self) -> Result<RateLimits, ConfigError> {
+        Ok(RateLimits {
+            v1_per_address: rate_limit("v1", self.v1_per_second, self.v1_burst)?,
+            ohttp_per_connection: rate_limit("ohttp", self.ohttp_per_second, self.ohttp_burst)?,
+            token_issuance_per_address: rate_limit(
+                "token issuance",
+                self.token_issuance_per_second,
+                self.token_issuance_burst,
+            )?,
+            max_long_poll_waiters: self.max_long_poll_waiters,
+        })
+    }
+}
+
+fn rate_limit(
+    name: &str,
+    per_second: Option<f64>,
+    burst: Option<u32>,
+) -> Result<Option<RateLimit>, ConfigError> {
+    match (per_second, burst) {
+        (None, None) => Ok(None),
+        (None, Some(_)) =>
+            Err(ConfigError::Message(format!("{name} rate limit burst requires a rate"))),
+        (Some(per_second), _) if !per_second.is_finite() || per_second < 0.0 =>
+            Err(ConfigError::Message(format!("Invalid {name} rate limit: {per_second}"))),
+        (Some(per_second), burst) => Ok(Some(RateLimit {
+            per_second,
+            burst: burst.unwrap_or((per_second.ceil() as u32).max(1)),
+        })),
+    }
+}
+
+/// Access token requirements as configured
+#[derive(Debug, Clone, Default, Deserialize)]
+struct AccessTokensConfig {
+    #[serde(default)]
+    required: bool,
+    key_rotation_secs: Option<u64>,
+}
+
+impl AccessTokensConfig {
+    fn into_key_rotation(self) -> Result<Option<Duration>, ConfigError> {
+        if !self.required {
+            return Ok(None);
+        }
+        match self.key_rotation_secs {
+            Some(0) => Err(ConfigError::Message(
+                "Access token key rotation interval must be nonzero".to_string(),
+            )),
+            Some(secs) => Ok(Some(Duration::from_secs(secs))),
+            None => Ok(Some(crate::tokens::DEFAULT_KEY_ROTATION_INTERVAL)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RelayConfig {
+    pub listen_addr: String,
+    #[serde(default)]
+    pub gateways: Vec<String>,
 }
 
 impl Config {
@@ -29,24 +217,130 @@ impl Config {
 
         let built_config = config.build()?;
 
+        let db_backend = built_config.get("db_backend")?;
+        let storage_encryption = built_config
+            .get::<Option<StorageEncryptionConfig>>("storage_encryption")?
+            .unwrap_or_default();
+        let secure_delete = storage_encryption.secure_delete;
+        let storage_key = storage_encryption.into_key_source()?;
+        if (storage_key.is_some() || secure_delete) && db_backend != DbBackend::Files {
+            return Err(ConfigError::Message(
+                "Storage encryption and secure delete require the files backend".to_string(),
+            ));
+        }
+
         Ok(Config {
-            listen_addr: built_config.get("listen_addr")?,
-            metrics_listen_addr: built_config.get("metrics_listen_addr")?,
+            listen_addr: listen_addr(built_config.get("listen_addr")?)?,
+            metrics_listen_addr: listen_addr(built_config.get("metrics_listen_addr")?)?,
+            unix_socket_mode: unix_socket_mode(built_config.get("unix_socket_mode")?)?,
+            h2c: built_config.get("h2c")?,
             timeout: Duration::from_secs(built_config.get("timeout")?),
+            shutdown_timeout: Duration::from_secs(built_config.get("shutdown_timeout")?),
             storage_dir: built_config.get("storage_dir")?,
+            db_backend,
+            storage_key,
+            secure_delete,
+            mailbox_policy: built_config
+                .get::<Option<MailboxesConfig>>("mailboxes")?
+                .unwrap_or_default()
+                .into_policy()?,
+            rate_limits: built_config
+                .get::<Option<RateLimitsConfig>>("rate_limits")?
+                .unwrap_or_default()
+                .into_limits()?,
+            access_token_key_rotation: built_config
+                .get::<Option<AccessTokensConfig>>("access_tokens")?
+                .unwrap_or_default()
+                .into_key_rotation()?,
             ohttp_keys: built_config.get("ohttp_keys")?,
+            relay: built_config.get("relay")?,
         })
     }
 }
 
+impl Config {
+    /// The database file of the SQLite backend
+    pub fn sqlite_path(&self) -> PathBuf { self.storage_dir.join("mailboxes.sqlite") }
+}
+
+fn listen_addr(addr: String) -> Result<ListenAddr, ConfigError> {
+    addr.parse().map_err(|e| ConfigError::Message(format!("Invalid listen address {addr}: {e}")))
+}
+
+/// Parse octal permissions such as `660`
+fn unix_socket_mode(mode: String) -> Result<u32, ConfigError> {
+    u32::from_str_radix(&mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| ConfigError::Message(format!("Invalid Unix socket mode: {mode}")))
+}
+
 fn add_defaults(config: Builder, cli: &Cli) -> Result<Builder, ConfigError> {
     config
-        .set_override_option("listen_addr", Some(format!("[::]:{}", cli.port)))?
-        .set_override_option(
-            "metrics_listen_addr",
-            Some(format!("localhost:{}", cli.metrics_port)),
+        .set_default("listen_addr", format!("[::]:{}", cli.port))?
+        .set_override_option("listen_addr", cli.listen.clone())?
+        .set_default("metrics_listen_addr", format!("localhost:{}", cli.metrics_port))?
+        .set_override_option("metrics_listen_addr", cli.metrics_listen.clone())?
+        .set_default(
+            "unix_socket_mode",
+            format!("{:o}", crate::listener::DEFAULT_UNIX_SOCKET_MODE),
         )?
+        .set_override_option("unix_socket_mode", cli.unix_socket_mode.clone())?
+        .set_default("h2c", false)?
+        .set_override_option("h2c", cli.h2c.then_some(true))?
         .set_override_option("timeout", Some(cli.timeout))?
+        .set_override_option("shutdown_timeout", Some(cli.shutdown_timeout))?
         .set_override_option("ohttp_keys", Some(cli.ohttp_keys.to_string_lossy().into_owned()))?
-        .set_override_option("storage_dir", Some(cli.storage_dir.to_string_lossy().into_owned()))
+        .set_override_option(
+            "storage_dir",
+            cli.storage_dir.as_ref().map(|dir| dir.to_string_lossy().into_owned()),
+        )?
+        .set_override_option("db_backend", Some(cli.db_backend.as_str()))?
+        .set_default("storage_encryption", None::<String>)?
+        .set_override_option(
+            "storage_encryption.key_file",
+            cli.storage_key_file.as_ref().map(|path| path.to_string_lossy().into_owned()),
+        )?
+        .set_override_option(
+            "storage_encryption.ephemeral_key",
+            cli.ephemeral_storage_key.then_some(true),
+        )?
+        .set_override_option("storage_encryption.secure_delete", cli.secure_delete.then_some(true))?
+        .set_default("mailboxes", None::<String>)?
+        .set_override_option("mailboxes.capacity", cli.max_mailboxes.map(|n| n as u64))?
+        .set_override_option("mailboxes.max_bytes", cli.max_storage_bytes)?
+        .set_override_option("mailboxes.unread_ttl_secs", cli.unread_ttl)?
+        .set_override_option("mailboxes.unread_ttl_at_capacity_secs", cli.unread_ttl_at_capacity)?
+        .set_override_option("mailboxes.read_ttl_secs", cli.read_ttl)?
+        .set_override_option("mailboxes.prune_interval_secs", cli.prune_interval)?
+        .set_override_option("mailboxes.prune_every", cli.prune_every.map(|n| n as u64))?
+        .set_default("rate_limits", None::<String>)?
+        .set_override_option("rate_limits.v1_per_second", cli.v1_rate_limit)?
+        .set_override_option("rate_limits.v1_burst", cli.v1_rate_limit_burst)?
+        .set_override_option("rate_limits.ohttp_per_second", cli.ohttp_rate_limit)?
+        .set_override_option("rate_limits.ohttp_burst", cli.ohttp_rate_limit_burst)?
+        .set_override_option(
+            "rate_limits.token_issuance_per_second",
+            cli.token_issuance_rate_limit,
+        )?
+        .set_override_option(
+            "rate_limits.token_issuance_burst",
+            cli.token_issuance_rate_limit_burst,
+        )?
+        .set_override_option(
+            "rate_limits.max_long_poll_waiters",
+            cli.max_long_polls.map(|n| n as u64),
+        )?
+        .set_default("access_tokens", None::<String>)?
+        .set_override_option("access_tokens.required", cli.require_access_tokens.then_some(true))?
+        .set_override_option("access_tokens.key_rotation_secs", cli.access_token_key_rotation)?
+        .set_default("relay", None::<String>)?
+        .set_override_option(
+            "relay.listen_addr",
+            cli.relay_port.map(|port| format!("[::]:{port}")),
+        )?
+        .set_override_option(
+            "relay.gateways",
+            (!cli.relay_gateways.is_empty()).then(|| cli.relay_gateways.clone()),
+        )
 }
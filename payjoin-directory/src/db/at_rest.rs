@@ -0,0 +1,177 @@
+//! Encryption of mailbox files at rest.
+//!
+//! Encrypted files start with a header holding the creation time and a random
+//! nonce, followed by the XChaCha20-Poly1305 ciphertext of the payload. The
+//! mailbox ID and creation time are authenticated, so that files can't be
+//! swapped between mailboxes or have their TTL extended.
+
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use payjoin::directory::ShortId;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use tokio::io;
+
+const MAGIC: &[u8; 4] = b"PJE\x01";
+const CREATED_LEN: usize = 8;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+/// The plaintext part of the header, which is enough to read the creation time
+pub(crate) const TIMESTAMP_LEN: usize = MAGIC.len() + CREATED_LEN;
+const HEADER_LEN: usize = TIMESTAMP_LEN + NONCE_LEN;
+
+/// How much larger an encrypted file is than its payload
+pub(crate) const OVERHEAD: u64 = (HEADER_LEN + TAG_LEN) as u64;
+
+/// Key file contents are hashed with this key, so that the file may hold any
+/// high entropy secret
+const KEY_DERIVATION_TAG: &[u8] = b"payjoin-directory mailbox encryption";
+
+/// The minimum length of a key file
+const MIN_KEY_MATERIAL_LEN: usize = 32;
+
+/// A key for encrypting mailbox files
+#[derive(Clone)]
+pub struct StorageKey([u8; 32]);
+
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("StorageKey(..)") }
+}
+
+impl StorageKey {
+    /// Generate a key that only lives in memory, so that mailboxes stored
+    /// before a restart can no longer be read.
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    /// Derive a key from the contents of a key file, e.g. one provisioned by
+    /// a key management service.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let material = std::fs::read(path)?;
+        if material.len() < MIN_KEY_MATERIAL_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Storage key file must hold at least {MIN_KEY_MATERIAL_LEN} bytes"),
+            ));
+        }
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(KEY_DERIVATION_TAG);
+        engine.input(&material);
+        Ok(Self(hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()))
+    }
+
+    pub(crate) fn cipher(&self) -> Cipher {
+        Cipher(XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&self.0)))
+    }
+}
+
+/// Protection of mailbox files at rest, beyond XOR obfuscation
+#[derive(Debug, Clone, Default)]
+pub struct AtRest {
+    /// Encrypt payloads with this key. XOR-obfuscated files are encrypted on
+    /// start, and those encrypted with another key are removed.
+    pub key: Option<StorageKey>,
+    /// Overwrite mailbox files before removing them. This does not reach
+    /// copies kept by copy-on-write filesystems or SSD wear leveling.
+    pub secure_delete: bool,
+}
+
+pub(crate) struct Cipher(XChaCha20Poly1305);
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str("Cipher(..)") }
+}
+
+impl Cipher {
+    /// Encrypt the payload of mailbox `id` created at `created`.
+    pub(crate) fn seal(&self, id: &ShortId, created: SystemTime, payload: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::with_capacity(payload.len() + OVERHEAD as usize);
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&to_nanos(created).to_be_bytes());
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        sealed.extend_from_slice(&nonce);
+
+        let aad = aad(id, &sealed[..TIMESTAMP_LEN]);
+        let ciphertext = self
+            .0
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: payload, aad: &aad })
+            .expect("encryption should not fail for payloads of any mailbox size");
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt the payload of mailbox `id` and its creation time, or `None`
+    /// if `sealed` was not encrypted for this mailbox with this key.
+    pub(crate) fn open(&self, id: &ShortId, sealed: &[u8]) -> Option<(SystemTime, Vec<u8>)> {
+        let created = created_at(sealed)?;
+        let (header, ciphertext) = sealed.split_at_checked(HEADER_LEN)?;
+        let nonce = XNonce::from_slice(&header[TIMESTAMP_LEN..]);
+        let aad = aad(id, &header[..TIMESTAMP_LEN]);
+        let payload = self.0.decrypt(nonce, Payload { msg: ciphertext, aad: &aad }).ok()?;
+        Some((created, payload))
+    }
+}
+
+/// Read the creation time from the start of an encrypted file, or `None` if
+/// the file is not encrypted.
+pub(crate) fn created_at(sealed: &[u8]) -> Option<SystemTime> {
+    let timestamp = sealed.get(..TIMESTAMP_LEN)?.strip_prefix(MAGIC)?;
+    let nanos = u64::from_be_bytes(timestamp.try_into().expect("timestamp length is fixed"));
+    Some(UNIX_EPOCH + Duration::from_nanos(nanos))
+}
+
+fn aad(id: &ShortId, timestamp: &[u8]) -> Vec<u8> { [&id.0[..], timestamp].concat() }
+
+fn to_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).expect("system clock before unix epoch").as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let cipher = StorageKey::generate().cipher();
+        let id = ShortId([1u8; 8]);
+        let created = SystemTime::now();
+        let sealed = cipher.seal(&id, created, b"payload");
+        assert_eq!(sealed.len() as u64, 7 + OVERHEAD);
+        assert_eq!(created_at(&sealed), Some(created));
+        assert_eq!(cipher.open(&id, &sealed), Some((created, b"payload".to_vec())));
+
+        assert!(cipher.open(&ShortId([2u8; 8]), &sealed).is_none(), "bound to the mailbox ID");
+        assert!(StorageKey::generate().cipher().open(&id, &sealed).is_none(), "bound to the key");
+        let mut tampered = sealed.clone();
+        tampered[MAGIC.len()] ^= 1;
+        assert!(cipher.open(&id, &tampered).is_none(), "the creation time is authenticated");
+        assert!(created_at(b"PJE").is_none());
+    }
+
+    #[test]
+    fn test_key_file() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("storage.key");
+        std::fs::write(&path, [7u8; 16])?;
+        assert!(StorageKey::from_file(&path).is_err(), "short key material is rejected");
+
+        std::fs::write(&path, [7u8; 32])?;
+        let sealed = StorageKey::from_file(&path)?.cipher().seal(
+            &ShortId([1u8; 8]),
+            SystemTime::now(),
+            b"payload",
+        );
+        let reloaded = StorageKey::from_file(&path)?.cipher();
+        assert!(reloaded.open(&ShortId([1u8; 8]), &sealed).is_some(), "keys are deterministic");
+        Ok(())
+    }
+}
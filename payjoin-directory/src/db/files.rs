@@ -1,70 +1,55 @@
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use futures::future::{self, FutureExt};
 use payjoin::directory::ShortId;
 use rand::rngs::OsRng;
 use rand::RngCore;
 use tokio::fs::{self, File};
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::sync::{oneshot, Mutex};
-use tracing::trace;
+use tracing::info;
 
-use super::Db as DbTrait;
+use super::at_rest::{self, AtRest, Cipher};
+use super::{mailboxes, Storage};
 
-/// The maximum number of pending or populated mailbox entries.
-///
-/// Defaults to around 2e6, for a generous upper bound rounded up from ~2
-/// mailboxes/tx, ~4K txs/block, and ~144 blocks/24h.
-const DEFAULT_CAPACITY: usize = 1 << (1 + 12 + 8);
+/// Mailboxes stored as XOR-obfuscated or encrypted files in a directory
+pub type Db = mailboxes::Db<DiskStorage>;
 
-const DEFAULT_UNREAD_TTL_AT_CAPACITY: Duration = Duration::from_secs(60 * 60 * 24); // 1 day
-const DEFAULT_UNREAD_TTL_BELOW_CAPACITY: Duration = Duration::from_secs(60 * 60 * 24 * 7); // 1 week
+/// The random pattern payloads are obfuscated with
+pub(crate) const XOR_FILE: &str = "xor.dat";
 
-/// How long read messages should be kept in mailboxes. Defaults to a 10 minute
-/// grace period from first read attempt, in case of intermittent network or
-/// relay errors.
-const DEFAULT_READ_TTL: Duration = Duration::from_secs(60 * 10); // 10 minutes
+/// Where payloads are written before being linked into the storage directory
+pub(crate) const TMP_DIR: &str = "tmp";
 
-#[derive(Debug)]
-struct V2WaitMapEntry {
-    receiver: future::Shared<oneshot::Receiver<Arc<Vec<u8>>>>,
-    sender: oneshot::Sender<Arc<Vec<u8>>>, // TODO [u8; 7168]
-}
+impl Db {
+    pub async fn init(timeout: Duration, path: PathBuf) -> io::Result<Self> {
+        Self::init_with(timeout, path, AtRest::default()).await
+    }
 
-#[derive(Debug)]
-struct V1WaitMapEntry {
-    payload: Arc<Vec<u8>>,
-    sender: oneshot::Sender<Vec<u8>>,
-}
+    /// Initialize storage with stronger protection of mailbox files at rest.
+    pub async fn init_with(timeout: Duration, path: PathBuf, at_rest: AtRest) -> io::Result<Self> {
+        Self::new(timeout, DiskStorage::init(path, at_rest).await?).await
+    }
 
-#[derive(Debug)]
-pub(crate) struct Mailboxes {
-    capacity: usize,
-    persistent_storage: DiskStorage,
-    pending_v1: HashMap<ShortId, V1WaitMapEntry>,
-    pending_v2: HashMap<ShortId, V2WaitMapEntry>,
-    insert_order: VecDeque<(SystemTime, ShortId)>,
-    read_order: VecDeque<(SystemTime, ShortId)>,
-    read_mailbox_ids: HashSet<ShortId>,
-    unread_ttl_below_capacity: Duration,
-    unread_ttl_at_capacity: Duration,
-    read_ttl: Duration,
-    early_removal_count: usize,
+    /// Open an initialized storage directory without cleaning up after
+    /// interrupted writes, which may still be in progress if the directory is
+    /// running.
+    pub async fn open(timeout: Duration, path: PathBuf) -> io::Result<Self> {
+        Self::new(timeout, DiskStorage::open(path).await?).await
+    }
 }
 
 #[derive(Debug)]
-struct DiskStorage {
+pub struct DiskStorage {
     dir: PathBuf,
     xor: Vec<u8>,
+    cipher: Option<Cipher>,
+    secure_delete: bool,
 }
 
 impl DiskStorage {
-    async fn init(dir: PathBuf) -> io::Result<Self> {
-        let tmp_dir = &dir.join("tmp");
+    async fn init(dir: PathBuf, at_rest: AtRest) -> io::Result<Self> {
+        let tmp_dir = &dir.join(TMP_DIR);
         if fs::try_exists(tmp_dir).await? {
             // clear out any tempfiles from uncompleted writes
             fs::remove_dir_all(tmp_dir).await?;
@@ -74,7 +59,7 @@ impl DiskStorage {
         // XOR data with a random pattern to obfuscate v1 requests
         // and writing malicious data such as virus fingerprints
         let xor: Vec<u8>;
-        let xor_file = dir.join("xor.dat");
+        let xor_file = dir.join(XOR_FILE);
         if fs::try_exists(&xor_file).await? {
             xor = fs::read(xor_file).await?;
         } else {
@@ -84,19 +69,114 @@ impl DiskStorage {
             file.sync_all().await?;
         }
 
-        Ok(Self { dir, xor })
+        let cipher = at_rest.key.as_ref().map(|key| key.cipher());
+        let storage = Self { dir, xor, cipher, secure_delete: at_rest.secure_delete };
+        if let Some(cipher) = &storage.cipher {
+            storage.migrate(cipher).await?;
+        }
+        Ok(storage)
+    }
+
+    async fn open(dir: PathBuf) -> io::Result<Self> {
+        let xor = fs::read(dir.join(XOR_FILE)).await?;
+        Ok(Self { dir, xor, cipher: None, secure_delete: false })
+    }
+
+    /// Encrypt XOR-obfuscated mailbox files, and remove those which were
+    /// encrypted with another key and so can't be read anymore.
+    async fn migrate(&self, cipher: &Cipher) -> io::Result<()> {
+        let mut ids = Vec::new();
+        let mut dir_entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir_entries.next_entry().await? {
+            if let Some(Ok(id)) = entry.file_name().to_str().map(ShortId::from_str) {
+                ids.push(id);
+            }
+        }
+
+        let (mut encrypted, mut removed) = (0, 0);
+        for id in ids {
+            let path = self.mailbox_path(&id);
+            let mut contents = fs::read(&path).await?;
+            if at_rest::created_at(&contents).is_some() {
+                if cipher.open(&id, &contents).is_none() {
+                    self.remove(&id).await?;
+                    removed += 1;
+                }
+                continue;
+            }
+
+            // Keep the original creation time, which the encrypted file records
+            let created = fs::metadata(&path).await?.created()?;
+            self.xor_buffer(&mut contents);
+            let sealed = cipher.seal(&id, created, &contents);
+            let tmp_path = self.insert_mailbox_path(&id);
+            let mut file = File::create_new(&tmp_path).await?;
+            file.write_all(&sealed).await?;
+            file.sync_data().await?;
+
+            if self.secure_delete {
+                // Keep the obfuscated file linked until it has been overwritten
+                let old_path = self.dir.join(TMP_DIR).join(format!("{id}.xor"));
+                fs::hard_link(&path, &old_path).await?;
+                fs::rename(&tmp_path, &path).await?;
+                overwrite(&old_path).await?;
+                fs::remove_file(&old_path).await?;
+            } else {
+                fs::rename(&tmp_path, &path).await?;
+            }
+            encrypted += 1;
+        }
+
+        if encrypted > 0 || removed > 0 {
+            File::open(&self.dir).await?.sync_all().await?;
+            info!("Encrypted {encrypted} mailboxes, removed {removed} encrypted with another key");
+        }
+        Ok(())
     }
 
     fn mailbox_path(&self, id: &ShortId) -> PathBuf { self.dir.join(id.to_string()) }
 
     fn insert_mailbox_path(&self, id: &ShortId) -> PathBuf {
-        self.dir.join("tmp").join(id.to_string())
+        self.dir.join(TMP_DIR).join(id.to_string())
     }
 
     async fn contains_key(&self, id: &ShortId) -> io::Result<bool> {
         fs::try_exists(self.mailbox_path(id)).await
     }
 
+    fn xor_buffer(&self, buffer: &mut [u8]) {
+        for (byte, &pattern) in buffer.iter_mut().zip(self.xor.iter().cycle()) {
+            *byte ^= pattern;
+        }
+    }
+}
+
+/// The creation time and payload size of a mailbox file. Encrypted files
+/// record their creation time, since it can't be kept when migrating.
+async fn stat(path: &Path) -> io::Result<(SystemTime, u64)> {
+    let mut file = File::open(path).await?;
+    let metadata = file.metadata().await?;
+    let mut timestamp = [0u8; at_rest::TIMESTAMP_LEN];
+    let sealed_created = match file.read_exact(&mut timestamp).await {
+        Ok(_) => at_rest::created_at(&timestamp),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+        Err(e) => return Err(e),
+    };
+    match sealed_created {
+        Some(created) => Ok((created, metadata.len().saturating_sub(at_rest::OVERHEAD))),
+        None => Ok((metadata.created()?, metadata.len())),
+    }
+}
+
+/// Overwrite a file with zeros, so that its contents don't outlive it on disk.
+async fn overwrite(path: &Path) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).open(path).await?;
+    let len = file.metadata().await?.len();
+    file.write_all(&vec![0u8; len as usize]).await?;
+    file.sync_data().await
+}
+
+impl Storage for DiskStorage {
     async fn get(&self, id: &ShortId) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
         // If the file doesn't exist, it's Ok(None), not Err
         let mut file = match File::open(self.mailbox_path(id)).await {
@@ -105,20 +185,19 @@ impl DiskStorage {
             Err(err) => return Err(err),
         };
 
-        let created = file.metadata().await?.created()?;
-
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer).await?;
+        if let Some(opened) = self.cipher.as_ref().and_then(|cipher| cipher.open(id, &buffer)) {
+            return Ok(Some(opened));
+        }
+
+        let created = file.metadata().await?.created()?;
         self.xor_buffer(&mut buffer);
 
         Ok(Some((created, buffer)))
     }
 
-    async fn try_insert(
-        &self,
-        id: &ShortId,
-        contents: impl AsRef<[u8]>,
-    ) -> io::Result<Option<SystemTime>> {
+    async fn try_insert(&self, id: &ShortId, contents: &[u8]) -> io::Result<Option<SystemTime>> {
         let mailbox_path = self.mailbox_path(id);
 
         // Before attempting to write the file, check if it exists and fail
@@ -129,7 +208,7 @@ impl DiskStorage {
             // Allow idempotent insertion if the contents are identical, in case
             // of OHTTP retries for the same e2e message.
             if let Ok(Some((created, existing_contents))) = self.get(id).await {
-                if &existing_contents[..] == contents.as_ref() {
+                if &existing_contents[..] == contents {
                     return Ok(Some(created));
                 }
             }
@@ -137,10 +216,19 @@ impl DiskStorage {
             return Ok(None);
         }
 
-        // Obfuscate the contents to avoid triggering antiviruses etc due to
-        // malicious content.
-        let mut buffer = contents.as_ref().to_vec();
-        self.xor_buffer(&mut buffer);
+        // Encrypt or at least obfuscate the contents to avoid triggering
+        // antiviruses etc due to malicious content.
+        let (buffer, sealed_created) = match &self.cipher {
+            Some(cipher) => {
+                let created = SystemTime::now();
+                (cipher.seal(id, created, contents), Some(created))
+            }
+            None => {
+                let mut buffer = contents.to_vec();
+                self.xor_buffer(&mut buffer);
+                (buffer, None)
+            }
+        };
 
         // Write the full contents to disk under a temp path
         let tmp_path = self.insert_mailbox_path(id);
@@ -155,20 +243,24 @@ impl DiskStorage {
 
         // Return the creation time upon successful write
         match link_ret {
-            Ok(()) => Ok(Some(file.metadata().await?.created()?)),
+            Ok(()) => match sealed_created {
+                Some(created) => Ok(Some(created)),
+                None => Ok(Some(file.metadata().await?.created()?)),
+            },
             Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(None),
             Err(e) => Err(e),
         }
     }
 
-    fn xor_buffer(&self, buffer: &mut [u8]) {
-        for (byte, &pattern) in buffer.iter_mut().zip(self.xor.iter().cycle()) {
-            *byte ^= pattern;
-        }
-    }
-
     async fn remove(&self, id: &ShortId) -> io::Result<Option<()>> {
-        match fs::remove_file(self.mailbox_path(id)).await {
+        let path = self.mailbox_path(id);
+        if self.secure_delete {
+            match overwrite(&path).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                result => result?,
+            }
+        }
+        match fs::remove_file(path).await {
             Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
             Ok(()) => Ok(Some(())),
             Err(e) => Err(e),
@@ -185,7 +277,7 @@ impl DiskStorage {
         while let Some(entry) = dir_entries.next_entry().await? {
             if let Some(file_name) = entry.file_name().to_str() {
                 if let Ok(id) = ShortId::from_str(file_name) {
-                    let ctime = entry.metadata().await?.created()?;
+                    let (ctime, _size) = stat(&entry.path()).await?;
                     ids.push((ctime, id));
                 }
             }
@@ -195,429 +287,43 @@ impl DiskStorage {
 
         Ok(ids)
     }
-}
-
-impl Mailboxes {
-    async fn init(dir: PathBuf) -> io::Result<Self> {
-        let storage = DiskStorage::init(dir).await?;
-        let insert_order = storage.insert_order().await?.into();
-        Ok(Self {
-            persistent_storage: storage,
-            insert_order,
-            capacity: DEFAULT_CAPACITY,
-            pending_v1: HashMap::default(),
-            pending_v2: HashMap::default(),
-            read_order: VecDeque::default(),
-            read_mailbox_ids: HashSet::default(),
-            unread_ttl_below_capacity: DEFAULT_UNREAD_TTL_BELOW_CAPACITY,
-            unread_ttl_at_capacity: DEFAULT_UNREAD_TTL_AT_CAPACITY,
-            read_ttl: DEFAULT_READ_TTL,
-            early_removal_count: 0,
-        })
-    }
-}
 
-#[derive(Clone, Debug)]
-pub struct Db {
-    timeout: Duration,
-    mailboxes: Arc<Mutex<Mailboxes>>,
-}
-
-impl Db {
-    pub async fn init(timeout: Duration, path: PathBuf) -> io::Result<Self> {
-        Ok(Self { timeout, mailboxes: Arc::new(Mutex::new(Mailboxes::init(path).await?)) })
-    }
-
-    pub async fn prune(&self) -> io::Result<Duration> { self.mailboxes.lock().await.prune().await }
-
-    pub async fn spawn_background_prune(&self) {
-        let this = self.clone();
-        tokio::spawn(async move {
-            loop {
-                // TODO allow cancellation?
-                let sleep_for =
-                    { this.mailboxes.lock().await.prune().await.expect("disk storage failed") };
-                tokio::time::sleep(sleep_for).await;
-            }
-        });
-    }
-}
-
-impl DbTrait for Db {
-    type OperationalError = io::Error;
-    async fn post_v2_payload(
-        &self,
-        id: &ShortId,
-        payload: Vec<u8>,
-    ) -> Result<Option<()>, super::Error<Self::OperationalError>> {
-        let mut guard = self.mailboxes.lock().await;
-        Ok(guard.post_v2(id, payload).await?)
-    }
-
-    async fn wait_for_v2_payload(
-        &self,
-        id: &ShortId,
-    ) -> Result<Arc<Vec<u8>>, super::Error<Self::OperationalError>> {
-        let receiver = {
-            let mut guard = self.mailboxes.lock().await;
-
-            if let Some(payload) = guard.read(id).await? {
-                return Ok(payload);
-            } else {
-                guard.wait_v2(id).await?
-            }
-        };
-
-        let ret = match tokio::time::timeout(self.timeout, receiver).await {
-            Ok(payload) => Ok((payload.expect("receiver must not fail")).clone()),
-            Err(elapsed) => Err(super::Error::Timeout(elapsed)),
-        };
-
-        self.mailboxes.lock().await.maybe_cleanup_v2_waitmap(id);
-
-        ret
-    }
-
-    async fn post_v1_request_and_wait_for_response(
-        &self,
-        id: &ShortId,
-        payload: Vec<u8>,
-    ) -> Result<Arc<Vec<u8>>, super::Error<Self::OperationalError>> {
-        let receiver = {
-            self.mailboxes
-                .lock()
-                .await
-                .post_v1_req_and_wait(id, payload)
-                .await?
-                .ok_or(super::Error::OverCapacity)?
-        };
-
-        trace!("v1 sender waiting for v2 receiver's response");
-
-        let ret = match tokio::time::timeout(self.timeout, receiver).await {
-            Ok(payload) => Ok(Arc::new(payload.expect("receiver must not fail"))),
-            Err(elapsed) => Err(super::Error::Timeout(elapsed)),
-        };
-
-        // unconditionally clear the pending v1 entry. on timeout, the sender
-        // will no longer be available to process any replies so there is no
-        // point delivering the request to the receiver
-        self.mailboxes.lock().await.pending_v1.remove(id);
-
-        ret
-    }
-
-    async fn post_v1_response(
-        &self,
-        id: &ShortId,
-        payload: Vec<u8>,
-    ) -> Result<(), super::Error<Self::OperationalError>> {
-        let mut guard = self.mailboxes.lock().await;
-        Ok(guard.post_v1_res(id, payload).await?)
-    }
-}
-
-// The async methods here generally use &mut self, and therefore assume mutex
-// ownership of the mailbox struct. this means they are supposed to return
-// quickly. however, they will wait for sync() on write, as the implies minimum
-// number of requests per second is only 25, holding the mutex while waiting for
-// disk and thereby serializing all writes should be fine even without an SSD.
-impl Mailboxes {
-    async fn read(&mut self, id: &ShortId) -> io::Result<Option<Arc<Vec<u8>>>> {
-        // V1 POST requests are only stored in memory since they are
-        // unencrypted. Check this hash table first.
-        if let Some(V1WaitMapEntry { payload, .. }) = self.pending_v1.get(id) {
-            return Ok(Some(payload.clone()));
-        }
-
-        // V2 requests are stored on disk
-        if let Some((_created, payload)) = self.persistent_storage.get(id).await? {
-            self.mark_read(id);
-            return Ok(Some(Arc::new(payload)));
-        }
-
-        Ok(None)
-    }
-
-    fn mark_read(&mut self, id: &ShortId) {
-        if self.read_mailbox_ids.insert(*id) {
-            self.read_order.push_back((SystemTime::now(), *id));
-        }
-    }
-
-    async fn has_capacity(&mut self) -> io::Result<bool> {
-        self.maybe_prune().await?;
-        Ok(self.len() < self.capacity)
-    }
-
-    async fn wait_v2(
-        &mut self,
-        id: &ShortId,
-    ) -> Result<future::Shared<oneshot::Receiver<Arc<Vec<u8>>>>, Error> {
-        if !self.has_capacity().await? {
-            return Err(Error::OverCapacity);
-        }
-
-        if self.pending_v1.contains_key(id) {
-            return Err(Error::OverCapacity);
-        }
-
-        let receiver = self
-            .pending_v2
-            .entry(*id)
-            .or_insert_with(|| {
-                let (sender, receiver) = oneshot::channel::<Arc<Vec<u8>>>();
-                let shared_receiver = receiver.shared();
-                V2WaitMapEntry { sender, receiver: shared_receiver.clone() }
-            })
-            .receiver
-            .clone();
-
-        Ok(receiver)
-    }
-
-    fn maybe_cleanup_v2_waitmap(&mut self, id: &ShortId) {
-        if let Some(entry) = self.pending_v2.get(id) {
-            if entry.receiver.strong_count().unwrap_or(0) <= 1 {
-                self.pending_v2.remove(id);
-            }
-        }
-    }
-
-    async fn post_v2(&mut self, id: &ShortId, payload: Vec<u8>) -> Result<Option<()>, Error> {
-        let Some(created) = self.persistent_storage.try_insert(id, &payload).await? else {
-            return Ok(None);
-        };
-
-        self.insert_order.push_back((created, *id));
-
-        // If there are pending readers, satisfy them and mark the payload as read
-        if let Some(pending) = self.pending_v2.remove(id) {
-            trace!("notifying pending readers for {}", id);
-
-            self.mark_read(id);
-
-            pending
-                .sender
-                .send(Arc::new(payload))
-                .expect("sending on oneshot channel must succeed");
-        }
+    async fn payload_sizes(&self) -> io::Result<Vec<(ShortId, u64)>> {
+        let mut sizes = Vec::default();
 
-        Ok(Some(()))
-    }
-
-    async fn post_v1_req_and_wait(
-        &mut self,
-        id: &ShortId,
-        payload: Vec<u8>,
-    ) -> Result<Option<oneshot::Receiver<Vec<u8>>>, Error> {
-        let mut ret = None;
-        let payload = Arc::new(payload);
-
-        // Don't overwrite in flight requests
-        self.pending_v1.entry(*id).or_insert_with(|| {
-            let payload = payload.clone();
-            let (sender, receiver) = oneshot::channel::<Vec<u8>>();
-            ret = Some(receiver);
-            V1WaitMapEntry { payload, sender }
-        });
-
-        // If there are pending readers, satisfy them and mark the payload as read
-        if let Some(pending) = self.pending_v2.remove(id) {
-            trace!("notifying pending readers for {} (v1 fallback)", id);
-            pending.sender.send(payload).expect("sending on oneshot channel must succeed");
-        }
-
-        Ok(ret)
-    }
-
-    async fn remove(&mut self, id: &ShortId) -> io::Result<Option<()>> {
-        self.read_mailbox_ids.remove(id);
-        self.persistent_storage.remove(id).await
-    }
-
-    async fn post_v1_res(&mut self, id: &ShortId, payload: Vec<u8>) -> Result<(), Error> {
-        match self.pending_v1.remove(id) {
-            None => Err(Error::V1SenderUnavailable),
-            Some(V1WaitMapEntry { sender, .. }) =>
-                sender.send(payload).map_err(|_| Error::V1SenderUnavailable),
-        }
-    }
-
-    fn len(&self) -> usize {
-        (self.insert_order.len() - self.early_removal_count)
-            + self.pending_v1.len()
-            + self.pending_v2.len()
-    }
-
-    async fn maybe_prune(&mut self) -> io::Result<Duration> {
-        // TODO make this lazier, once per time interval, or once per n checks
-        // or both
-        self.prune().await
-    }
-
-    /// Clean out the mailboxes.
-    ///
-    /// Since we use a mutex and not a concurrent hashmap, there's currently
-    /// no benefit to putting this in a background task.
-    ///
-    /// Furthermore, to improve privacy and resist mailbox enumeration, we prune
-    /// expired entries eagerly.
-    async fn prune(&mut self) -> io::Result<Duration> {
-        trace!("pruning");
-        let now = SystemTime::now();
-
-        debug_assert!(self.read_ttl < self.unread_ttl_at_capacity);
-        debug_assert!(self.unread_ttl_at_capacity < self.unread_ttl_below_capacity);
-        debug_assert!(self.pending_v1.iter().all(|(_, v)| !v.sender.is_closed()));
-
-        // Prune in flight requests, these can persist in the case of an incomplete session
-        self.pending_v2.retain(|_, v| v.receiver.strong_count().unwrap_or(0) > 1);
-
-        // Prune any fully expired mailboxes, whether read or unread
-        while let Some((created, id)) = self.insert_order.front().cloned() {
-            println!(
-                "checking if {id} elapsed: {:?} < {:?} = {}",
-                (created + self.unread_ttl_below_capacity),
-                now,
-                (created + self.unread_ttl_below_capacity) < now,
-            );
-            if created + self.unread_ttl_below_capacity < now {
-                debug_assert!(self.insert_order.len() >= self.early_removal_count);
-                _ = self.insert_order.pop_front();
-                if self.remove(&id).await?.is_none() {
-                    self.early_removal_count = self
-                        .early_removal_count
-                        .checked_sub(1)
-                        .expect("early removal adjustment should never underflow");
-                }
-                debug_assert!(self.insert_order.len() >= self.early_removal_count);
-                trace!("Pruned old mailbox {id}");
-            } else {
-                break;
-            }
-        }
-
-        // So long as there expired read mailboxes, prune those. Stop when a
-        // mailbox within the TTL is encountered.
-        while let Some((read, id)) = self.read_order.front().cloned() {
-            println!(
-                "checking if {id} elapsed (read ttl): {:?} < {:?} = {}",
-                (read + self.read_ttl),
-                now,
-                (read + self.read_ttl) < now,
-            );
-            if read + self.read_ttl < now {
-                println!("removing");
-                _ = self.read_order.pop_front();
-                if self.remove(&id).await?.is_some() {
-                    self.early_removal_count += 1;
-                    debug_assert!(self.insert_order.len() >= self.early_removal_count);
-                }
-                trace!("Pruned read mailbox {id}");
-            } else {
-                break;
-            }
-        }
-
-        // If no room was created, try to prune the oldest unread mailbox if
-        // it's over the minimum TTL
-        debug_assert!(self.len() <= self.capacity);
-        if self.len() == self.capacity {
-            if let Some((created, id)) = self.insert_order.front().cloned() {
-                if created + self.unread_ttl_at_capacity < now {
-                    _ = self.insert_order.pop_front();
-                    self.remove(&id).await?;
-                    trace!("Pruned unread mailbox {id} to make room");
-                } else {
-                    trace!("Nothing to prune, {} entries remain", self.len());
+        let mut dir_entries = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir_entries.next_entry().await? {
+            if let Some(file_name) = entry.file_name().to_str() {
+                if let Ok(id) = ShortId::from_str(file_name) {
+                    let (_created, size) = stat(&entry.path()).await?;
+                    sizes.push((id, size));
                 }
             }
         }
 
-        Ok(self.next_prune())
-    }
-
-    fn next_prune(&mut self) -> Duration {
-        let earliest_read_prune_opportunity = self
-            .read_order
-            .front()
-            .map(|(read, _id)| {
-                self.read_ttl
-                    .checked_sub(read.elapsed().expect("system clock moved back"))
-                    .unwrap_or(self.read_ttl)
-            })
-            .unwrap_or_else(|| self.read_ttl);
-
-        let earliest_unread_prune_opportunity = self
-            .insert_order
-            .front()
-            .map(|(created, _id)| {
-                self.unread_ttl_at_capacity
-                    .checked_sub(created.elapsed().expect("system clock moved back"))
-                    .unwrap_or(self.unread_ttl_at_capacity)
-            })
-            .unwrap_or_else(|| self.unread_ttl_at_capacity);
-
-        std::cmp::min(earliest_read_prune_opportunity, earliest_unread_prune_opportunity)
-    }
-}
-
-#[derive(Debug)]
-pub enum Error {
-    /// Operation rejected due to lack of capacity
-    OverCapacity,
-
-    /// Indicates the sender that was waiting for the reply is no longer there
-    V1SenderUnavailable,
-
-    IO(io::Error),
-}
-
-impl From<io::Error> for Error {
-    fn from(e: io::Error) -> Self { Self::IO(e) }
-}
-
-// FIXME why isn't this sufficient for ?, necessitating ugly map_err(into)?
-impl From<Error> for super::Error<std::io::Error> {
-    fn from(val: Error) -> super::Error<io::Error> {
-        match val {
-            Error::V1SenderUnavailable => super::Error::V1SenderUnavailable,
-            Error::OverCapacity => super::Error::OverCapacity,
-            Error::IO(e) => super::Error::Operational(e),
-        }
+        Ok(sizes)
     }
-}
 
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            Self::IO(e) => Some(e),
-            _ => None,
+    async fn flush(&self) -> io::Result<()> {
+        // Writes are synced as they happen, but an interrupted write may have
+        // left a temp file behind
+        let mut tmp_entries = fs::read_dir(self.dir.join(TMP_DIR)).await?;
+        while let Some(entry) = tmp_entries.next_entry().await? {
+            fs::remove_file(entry.path()).await?;
         }
-    }
-}
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        use Error::*;
-        match self {
-            OverCapacity => "Database over capacity".fmt(f),
-            V1SenderUnavailable => "Sender no longer connected".fmt(f),
-            IO(e) => write!(f, "Internal Error: {e}"),
-        }
+        // Make the directory entries of linked mailbox files durable
+        File::open(&self.dir).await?.sync_all().await
     }
 }
 
-impl super::SendableError for Error {}
-
 #[tokio::test]
 async fn test_disk_storage_initialization() -> std::io::Result<()> {
     let dir = tempfile::tempdir()?;
     assert!(!dir.path().join("tmp").exists(), "tmp subdirectory should not have been created yet");
 
     let xor_pattern = {
-        let storage = DiskStorage::init(dir.path().to_owned())
+        let storage = DiskStorage::init(dir.path().to_owned(), AtRest::default())
             .await
             .expect("initializing storage directory should succeed");
 
@@ -636,7 +342,7 @@ async fn test_disk_storage_initialization() -> std::io::Result<()> {
         dir.path().join("tmp").join("blah").exists(),
         "temp file should not have been cleared yet"
     );
-    let storage = DiskStorage::init(dir.path().to_owned())
+    let storage = DiskStorage::init(dir.path().to_owned(), AtRest::default())
         .await
         .expect("initializing storage directory should succeed");
 
@@ -651,7 +357,7 @@ async fn test_disk_storage_initialization() -> std::io::Result<()> {
 async fn test_disk_storage_mailboxes() -> std::io::Result<()> {
     let dir = tempfile::tempdir()?;
 
-    let storage = DiskStorage::init(dir.path().to_owned())
+    let storage = DiskStorage::init(dir.path().to_owned(), AtRest::default())
         .await
         .expect("initializing storage directory should succeed");
 
@@ -747,218 +453,49 @@ async fn test_disk_storage_mailboxes() -> std::io::Result<()> {
 }
 
 #[tokio::test]
-async fn test_mailbox_storage() -> std::io::Result<()> {
-    let dir = tempfile::tempdir()?;
-
-    let db = Db::init(Duration::from_millis(10), dir.path().to_owned())
-        .await
-        .expect("initializing mailbox database should succeed");
-
-    let id = ShortId([0u8; 8]);
-    let contents = b"foo bar";
-    db.post_v2_payload(&id, contents.to_vec())
-        .await
-        .expect("posting payload should succeed")
-        .expect("contents should be accepted");
-
-    let res = db.wait_for_v2_payload(&id).await.expect("waiting for payload should succeed");
-    assert_eq!(&res[..], contents, "posted payload should be retrievable");
-
-    Ok(())
-}
-
-#[tokio::test]
-async fn test_v2_wait() -> std::io::Result<()> {
-    let dir = tempfile::tempdir()?;
-
-    let db = Db::init(Duration::from_millis(1), dir.path().to_owned())
-        .await
-        .expect("initializing mailbox database should succeed");
+async fn test_disk_storage_encryption_migration() -> std::io::Result<()> {
+    use super::at_rest::StorageKey;
 
-    let id = ShortId([0u8; 8]);
-    let contents = b"foo bar";
-
-    match db.wait_for_v2_payload(&id).await {
-        Err(super::Error::Timeout(_)) => {}
-        res => panic!("expected timeout, got {:?}", res),
-    }
-
-    let read_task1 = tokio::spawn({
-        let db = db.clone();
-        async move { db.wait_for_v2_payload(&id).await }
-    });
-    let read_task2 = tokio::spawn({
-        let db = db.clone();
-        async move { db.wait_for_v2_payload(&id).await }
-    });
-
-    db.post_v2_payload(&id, contents.to_vec())
-        .await
-        .expect("posting payload should succeed")
-        .expect("contents should be accepted");
-
-    let res = read_task1
-        .await
-        .expect("joining task should succeed")
-        .expect("waiting for payload should succeed");
-    assert_eq!(&res[..], contents, "posted payload should be retrievable");
-
-    let res = read_task2
-        .await
-        .expect("joining task should succeed")
-        .expect("waiting for payload should succeed");
-    assert_eq!(&res[..], contents, "posted payload should be retrievable");
-
-    assert!(
-        db.post_v2_payload(&id, b"something else".to_vec())
-            .await
-            .expect("posting payload should succeed")
-            .is_none(),
-        "duplicate POST should be rejected"
-    );
-
-    let res = db.wait_for_v2_payload(&id).await.expect("reading payload should succeed");
-    assert_eq!(&res[..], contents, "posted payload should be retrievable");
-
-    Ok(())
-}
-
-#[tokio::test]
-async fn test_v1_wait() -> std::io::Result<()> {
     let dir = tempfile::tempdir()?;
+    let id = ShortId::try_from(&(b"12345678")[..]).unwrap();
+    let contents = b"OH HAI";
 
-    let db = Arc::new(
-        Db::init(Duration::from_millis(1), dir.path().to_owned())
-            .await
-            .expect("initializing mailbox database should succeed"),
-    );
-
-    let id = ShortId([0u8; 8]);
-
-    let v1_sender_task = tokio::spawn({
-        let db = db.clone();
-        async move { db.post_v1_request_and_wait_for_response(&id, b"request".to_vec()).await }
-    });
-
-    let res = db.wait_for_v2_payload(&id).await.expect("reading payload should succeed");
-    assert_eq!(&res[..], b"request", "in flight v1 request should be retrievable");
+    let created = {
+        let storage = DiskStorage::init(dir.path().to_owned(), AtRest::default()).await?;
+        storage.try_insert(&id, contents).await?.expect("writing should succeed")
+    };
 
-    assert!(
-        matches!(
-            db.post_v1_request_and_wait_for_response(&id, b"different request".to_vec()).await,
-            Err(super::Error::OverCapacity),
-        ),
-        "second v1 sender with the same shortid should be rejected while request is in flight",
+    let at_rest = AtRest { key: Some(StorageKey::generate()), secure_delete: true };
+    let storage = DiskStorage::init(dir.path().to_owned(), at_rest.clone()).await?;
+    let file_contents = fs::read(storage.mailbox_path(&id)).await?;
+    assert_eq!(file_contents.len() as u64, contents.len() as u64 + at_rest::OVERHEAD);
+    assert_eq!(
+        storage.get(&id).await?,
+        Some((created, contents.to_vec())),
+        "migration should keep the contents and creation time"
     );
-
-    db.post_v1_response(&id, b"response".to_vec()).await.expect("posting payload should succeed");
-
-    let res = v1_sender_task
-        .await
-        .expect("joining task should succeed")
-        .expect("waiting for payload should succeed");
-    assert_eq!(&res[..], b"response", "should be response from v2 receiver");
-
+    assert_eq!(storage.insert_order().await?, vec![(created, id)]);
+    assert_eq!(storage.payload_sizes().await?, vec![(id, contents.len() as u64)]);
     assert!(
-        matches!(
-            db.post_v1_response(&id, b"response".to_vec()).await,
-            Err(super::Error::V1SenderUnavailable)
-        ),
-        "posting without a v1 sender waiting should fail"
+        fs::read_dir(dir.path().join(TMP_DIR)).await?.next_entry().await?.is_none(),
+        "obfuscated originals should be removed"
     );
 
-    Ok(())
-}
-
-// FIXME test is a bit slow and flakey, how to improve?
-// unfortunately tokio::time::pause() can't be used because this uses SystemTime
-// as the underlying clock type, due to timestamps originating from disk
-#[tokio::test]
-async fn test_prune() -> std::io::Result<()> {
-    let dir = tempfile::tempdir()?;
-
-    let db = Db::init(Duration::from_millis(2), dir.path().to_owned())
-        .await
-        .expect("initializing mailbox database should succeed");
-
-    {
-        let mut guard = db.mailboxes.lock().await;
-        guard.capacity = 2;
-        guard.read_ttl = Duration::from_millis(10);
-        guard.unread_ttl_at_capacity = Duration::from_millis(100);
-        guard.unread_ttl_below_capacity = Duration::from_millis(200);
-    }
-
-    assert_eq!(db.mailboxes.lock().await.len(), 0);
-    db.prune().await.expect("pruning should not fail");
-    assert_eq!(db.mailboxes.lock().await.len(), 0);
-
-    let id = ShortId([0u8; 8]);
-    let contents = b"fooo";
-
-    let read_task1 = tokio::spawn({
-        let db = db.clone();
-        async move { db.wait_for_v2_payload(&id).await }
-    });
-
-    tokio::time::sleep(Duration::from_millis(1)).await;
-    assert_eq!(db.mailboxes.lock().await.len(), 1);
-
-    match read_task1.await.expect("joining should succeed") {
-        Err(super::Error::Timeout(_)) => {}
-        res => panic!("expected timeout, got {:?}", res),
-    }
+    // Overwriting reaches other links to the removed file
+    let link = dir.path().join("link");
+    fs::hard_link(storage.mailbox_path(&id), &link).await?;
+    storage.remove(&id).await?;
+    assert!(fs::read(&link).await?.iter().all(|&byte| byte == 0), "contents should be overwritten");
 
-    db.prune().await.expect("pruning should not fail");
-    assert_eq!(db.mailboxes.lock().await.len(), 0);
-
-    db.post_v2_payload(&id, contents.to_vec())
-        .await
-        .expect("posting payload should succeed")
-        .expect("contents should be accepted");
-
-    assert_eq!(db.mailboxes.lock().await.len(), 1);
-    db.prune().await.expect("pruning should not fail");
-    assert_eq!(db.mailboxes.lock().await.len(), 1);
-
-    tokio::time::sleep(Duration::from_millis(200)).await;
-
-    assert_eq!(db.mailboxes.lock().await.len(), 1);
-    db.prune().await.expect("pruning should not fail");
-    assert_eq!(db.mailboxes.lock().await.len(), 0);
-
-    db.post_v2_payload(&id, contents.to_vec())
-        .await
-        .expect("posting payload should succeed")
-        .expect("contents should be accepted");
-
-    assert_eq!(db.mailboxes.lock().await.len(), 1);
-    // FIXME why does this fail?
-    // db.prune().await.expect("pruning should not fail");
-    // assert_eq!(db.mailboxes.lock().await.len(), 1);
-    // mailbox seems to get pruned prematurely
-    // likely cause is it's in both read and insert queue, and two pruning runs
-    // are needed to fully clear it?
-
-    // mark the mailbox as read
-    _ = db.wait_for_v2_payload(&id).await.expect("waiting for payload should succeed");
-
-    assert_eq!(db.mailboxes.lock().await.len(), 1);
-    // FIXME db.prune().await.expect("pruning should not fail");
-    assert_eq!(db.mailboxes.lock().await.len(), 1);
-
-    // allow read TTL to elapse
-    tokio::time::sleep(Duration::from_millis(10)).await;
-
-    assert_eq!(db.mailboxes.lock().await.len(), 1);
-    db.prune().await.expect("pruning should not fail");
-    assert_eq!(db.mailboxes.lock().await.len(), 0);
-
-    tokio::time::sleep(Duration::from_millis(200)).await;
-
-    assert_eq!(db.mailboxes.lock().await.len(), 0);
-    db.prune().await.expect("pruning should not fail");
-    assert_eq!(db.mailboxes.lock().await.len(), 0);
+    let id2 = ShortId::try_from(&(b"87654321")[..]).unwrap();
+    storage.try_insert(&id2, contents).await?.expect("writing should succeed");
+    drop(storage);
+    let storage = DiskStorage::init(dir.path().to_owned(), at_rest).await?;
+    assert_eq!(storage.get(&id2).await?.map(|(_, got)| got), Some(contents.to_vec()));
+
+    let other_key = AtRest { key: Some(StorageKey::generate()), secure_delete: false };
+    let storage = DiskStorage::init(dir.path().to_owned(), other_key).await?;
+    assert!(storage.get(&id2).await?.is_none(), "files encrypted with another key are removed");
 
     Ok(())
 }
@@ -0,0 +1,1151 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future::{self, FutureExt};
+use payjoin::directory::ShortId;
+use tokio::io;
+use tokio::sync::{oneshot, Mutex};
+use tracing::trace;
+
+use super::{Db as DbTrait, Storage};
+use crate::metrics::{self, Metrics};
+use crate::shutdown::Shutdown;
+
+/// The maximum number of pending or populated mailbox entries.
+///
+/// Defaults to around 2e6, for a generous upper bound rounded up from ~2
+/// mailboxes/tx, ~4K txs/block, and ~144 blocks/24h.
+const DEFAULT_CAPACITY: usize = 1 << (1 + 12 + 8);
+
+const DEFAULT_UNREAD_TTL_AT_CAPACITY: Duration = Duration::from_secs(60 * 60 * 24); // 1 day
+const DEFAULT_UNREAD_TTL_BELOW_CAPACITY: Duration = Duration::from_secs(60 * 60 * 24 * 7); // 1 week
+
+/// How long read messages should be kept in mailboxes. Defaults to a 10 minute
+/// grace period from first read attempt, in case of intermittent network or
+/// relay errors.
+const DEFAULT_READ_TTL: Duration = Duration::from_secs(60 * 10); // 10 minutes
+
+/// Upper bound on the time between pruning runs triggered by requests.
+const DEFAULT_PRUNE_INTERVAL: Duration = Duration::from_secs(60); // 1 minute
+
+/// Upper bound on the number of requests between pruning runs.
+const DEFAULT_PRUNE_EVERY: usize = 1024;
+
+/// Limits on mailbox storage and on how eagerly expired mailboxes are pruned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailboxPolicy {
+    /// The maximum number of pending or populated mailbox entries.
+    pub capacity: usize,
+    /// The maximum total size of stored payloads, in bytes.
+    pub max_bytes: Option<u64>,
+    /// How long unread mailboxes are kept once storage is at capacity.
+    pub unread_ttl_at_capacity: Duration,
+    /// How long unread mailboxes are kept while storage is below capacity.
+    pub unread_ttl_below_capacity: Duration,
+    /// How long mailboxes are kept after they were first read.
+    pub read_ttl: Duration,
+    /// Prune at least this often while requests are being served.
+    pub prune_interval: Duration,
+    /// Prune at least once every this many requests.
+    pub prune_every: usize,
+}
+
+impl Default for MailboxPolicy {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CAPACITY,
+            max_bytes: None,
+            unread_ttl_at_capacity: DEFAULT_UNREAD_TTL_AT_CAPACITY,
+            unread_ttl_below_capacity: DEFAULT_UNREAD_TTL_BELOW_CAPACITY,
+            read_ttl: DEFAULT_READ_TTL,
+            prune_interval: DEFAULT_PRUNE_INTERVAL,
+            prune_every: DEFAULT_PRUNE_EVERY,
+        }
+    }
+}
+
+impl MailboxPolicy {
+    /// Check that the TTLs are strictly ordered, so that read mailboxes are
+    /// pruned before unread ones and pruning at capacity frees room sooner.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.capacity == 0 {
+            return Err("mailbox capacity must be greater than zero");
+        }
+        if self.prune_every == 0 {
+            return Err("prune_every must be greater than zero");
+        }
+        if self.read_ttl >= self.unread_ttl_at_capacity {
+            return Err("read TTL must be shorter than the unread TTL at capacity");
+        }
+        if self.unread_ttl_at_capacity >= self.unread_ttl_below_capacity {
+            return Err(
+                "unread TTL at capacity must be shorter than the unread TTL below capacity",
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A stored mailbox, as summarized for operators
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailboxEntry {
+    pub id: ShortId,
+    pub created: SystemTime,
+    /// When the mailbox was first read, if that was recorded
+    pub read: Option<SystemTime>,
+    /// The payload size in bytes
+    pub size: u64,
+}
+
+/// The stored mailboxes, oldest first. Pending long polls and v1 requests
+/// aren't stored and so aren't included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailboxStats {
+    pub entries: Vec<MailboxEntry>,
+}
+
+impl MailboxStats {
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    pub fn read(&self) -> usize { self.entries.iter().filter(|entry| entry.read.is_some()).count() }
+
+    pub fn stored_bytes(&self) -> u64 { self.entries.iter().map(|entry| entry.size).sum() }
+
+    pub fn oldest(&self) -> Option<SystemTime> { self.entries.first().map(|entry| entry.created) }
+}
+
+#[derive(Debug)]
+struct V2WaitMapEntry {
+    receiver: future::Shared<oneshot::Receiver<Arc<Vec<u8>>>>,
+    sender: oneshot::Sender<Arc<Vec<u8>>>, // TODO [u8; 7168]
+}
+
+#[derive(Debug)]
+struct V1WaitMapEntry {
+    payload: Arc<Vec<u8>>,
+    sender: oneshot::Sender<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Mailboxes<S: Storage> {
+    policy: MailboxPolicy,
+    persistent_storage: S,
+    pending_v1: HashMap<ShortId, V1WaitMapEntry>,
+    pending_v2: HashMap<ShortId, V2WaitMapEntry>,
+    insert_order: VecDeque<(SystemTime, ShortId)>,
+    read_order: VecDeque<(SystemTime, ShortId)>,
+    read_mailbox_ids: HashSet<ShortId>,
+    payload_sizes: HashMap<ShortId, u64>,
+    stored_bytes: u64,
+    early_removal_count: usize,
+    next_prune_at: Option<Instant>,
+    ops_since_prune: usize,
+    metrics: Option<Metrics>,
+}
+
+impl<S: Storage> Mailboxes<S> {
+    async fn init(storage: S) -> io::Result<Self> {
+        let insert_order = storage.insert_order().await?.into();
+        let read_order: VecDeque<_> = storage.read_order().await?.into();
+        let read_mailbox_ids = read_order.iter().map(|(_read, id)| *id).collect();
+        let payload_sizes: HashMap<_, _> = storage.payload_sizes().await?.into_iter().collect();
+        let stored_bytes = payload_sizes.values().sum();
+        Ok(Self {
+            persistent_storage: storage,
+            insert_order,
+            policy: MailboxPolicy::default(),
+            pending_v1: HashMap::default(),
+            pending_v2: HashMap::default(),
+            read_order,
+            read_mailbox_ids,
+            payload_sizes,
+            stored_bytes,
+            early_removal_count: 0,
+            next_prune_at: None,
+            ops_since_prune: 0,
+            metrics: None,
+        })
+    }
+}
+
+/// Mailbox bookkeeping shared by all storage backends: long polling, v1 fallback and pruning
+#[derive(Debug)]
+pub struct Db<S: Storage> {
+    timeout: Duration,
+    mailboxes: Arc<Mutex<Mailboxes<S>>>,
+    shutdown: Shutdown,
+}
+
+impl<S: Storage> Clone for Db<S> {
+    fn clone(&self) -> Self {
+        Self {
+            timeout: self.timeout,
+            mailboxes: self.mailboxes.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+}
+
+impl<S: Storage> Db<S> {
+    pub(crate) async fn new(timeout: Duration, storage: S) -> io::Result<Self> {
+        Ok(Self {
+            timeout,
+            mailboxes: Arc::new(Mutex::new(Mailboxes::init(storage).await?)),
+            shutdown: Shutdown::default(),
+        })
+    }
+
+    /// End pending long polls early once `shutdown` is triggered.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Replace the default [`MailboxPolicy`].
+    pub async fn with_policy(self, policy: MailboxPolicy) -> Self {
+        self.mailboxes.lock().await.policy = policy;
+        self
+    }
+
+    /// Report pruning and mailbox occupancy to `metrics`.
+    pub async fn with_metrics(self, metrics: Metrics) -> Self {
+        {
+            let mut guard = self.mailboxes.lock().await;
+            metrics.set_mailboxes(guard.len());
+            guard.metrics = Some(metrics);
+        }
+        self
+    }
+
+    pub async fn prune(&self) -> io::Result<Duration> { self.mailboxes.lock().await.prune().await }
+
+    /// Summarize the stored mailboxes.
+    pub async fn stats(&self) -> MailboxStats { self.mailboxes.lock().await.stats() }
+
+    pub async fn spawn_background_prune(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                // TODO allow cancellation?
+                let sleep_for =
+                    { this.mailboxes.lock().await.prune().await.expect("disk storage failed") };
+                tokio::time::sleep(sleep_for).await;
+            }
+        });
+    }
+}
+
+impl<S: Storage> DbTrait for Db<S> {
+    type OperationalError = io::Error;
+    async fn post_v2_payload(
+        &self,
+        id: &ShortId,
+        payload: Vec<u8>,
+    ) -> Result<Option<()>, super::Error<Self::OperationalError>> {
+        let mut guard = self.mailboxes.lock().await;
+        Ok(guard.post_v2(id, payload).await?)
+    }
+
+    async fn wait_for_v2_payload(
+        &self,
+        id: &ShortId,
+    ) -> Result<Arc<Vec<u8>>, super::Error<Self::OperationalError>> {
+        let receiver = {
+            let mut guard = self.mailboxes.lock().await;
+
+            if let Some(payload) = guard.read(id).await? {
+                return Ok(payload);
+            } else {
+                guard.wait_v2(id).await?
+            }
+        };
+
+        let ret = tokio::select! {
+            res = tokio::time::timeout(self.timeout, receiver) => match res {
+                Ok(payload) => Ok((payload.expect("receiver must not fail")).clone()),
+                Err(elapsed) => Err(super::Error::Timeout(elapsed)),
+            },
+            () = self.shutdown.triggered() => Err(super::Error::ShuttingDown),
+        };
+
+        self.mailboxes.lock().await.maybe_cleanup_v2_waitmap(id);
+
+        ret
+    }
+
+    async fn delete_v2_payload(
+        &self,
+        id: &ShortId,
+    ) -> Result<Option<()>, super::Error<Self::OperationalError>> {
+        Ok(self.mailboxes.lock().await.delete(id).await?)
+    }
+
+    async fn post_v1_request_and_wait_for_response(
+        &self,
+        id: &ShortId,
+        payload: Vec<u8>,
+    ) -> Result<Arc<Vec<u8>>, super::Error<Self::OperationalError>> {
+        let receiver = {
+            self.mailboxes
+                .lock()
+                .await
+                .post_v1_req_and_wait(id, payload)
+                .await?
+                .ok_or(super::Error::OverCapacity)?
+        };
+
+        trace!("v1 sender waiting for v2 receiver's response");
+
+        let ret = tokio::select! {
+            res = tokio::time::timeout(self.timeout, receiver) => match res {
+                Ok(payload) => Ok(Arc::new(payload.expect("receiver must not fail"))),
+                Err(elapsed) => Err(super::Error::Timeout(elapsed)),
+            },
+            () = self.shutdown.triggered() => Err(super::Error::ShuttingDown),
+        };
+
+        // unconditionally clear the pending v1 entry. on timeout, the sender
+        // will no longer be available to process any replies so there is no
+        // point delivering the request to the receiver
+        {
+            let mut guard = self.mailboxes.lock().await;
+            guard.pending_v1.remove(id);
+            guard.record_occupancy();
+        }
+
+        ret
+    }
+
+    async fn post_v1_response(
+        &self,
+        id: &ShortId,
+        payload: Vec<u8>,
+    ) -> Result<(), super::Error<Self::OperationalError>> {
+        let mut guard = self.mailboxes.lock().await;
+        Ok(guard.post_v1_res(id, payload).await?)
+    }
+
+    async fn flush(&self) -> Result<(), super::Error<Self::OperationalError>> {
+        Ok(self.mailboxes.lock().await.persistent_storage.flush().await?)
+    }
+}
+
+// The async methods here generally use &mut self, and therefore assume mutex
+// ownership of the mailbox struct. this means they are supposed to return
+// quickly. however, they will wait for sync() on write, as the implies minimum
+// number of requests per second is only 25, holding the mutex while waiting for
+// disk and thereby serializing all writes should be fine even without an SSD.
+impl<S: Storage> Mailboxes<S> {
+    async fn read(&mut self, id: &ShortId) -> io::Result<Option<Arc<Vec<u8>>>> {
+        // V1 POST requests are only stored in memory since they are
+        // unencrypted. Check this hash table first.
+        if let Some(V1WaitMapEntry { payload, .. }) = self.pending_v1.get(id) {
+            return Ok(Some(payload.clone()));
+        }
+
+        // V2 requests are stored on disk
+        if let Some((_created, payload)) = self.persistent_storage.get(id).await? {
+            self.mark_read(id).await?;
+            return Ok(Some(Arc::new(payload)));
+        }
+
+        Ok(None)
+    }
+
+    async fn mark_read(&mut self, id: &ShortId) -> io::Result<()> {
+        if self.read_mailbox_ids.insert(*id) {
+            let read = SystemTime::now();
+            self.read_order.push_back((read, *id));
+            self.persistent_storage.mark_read(id, read).await?;
+        }
+        Ok(())
+    }
+
+    async fn has_capacity(&mut self) -> io::Result<bool> {
+        self.maybe_prune().await?;
+        Ok(!self.at_capacity(0))
+    }
+
+    async fn wait_v2(
+        &mut self,
+        id: &ShortId,
+    ) -> Result<future::Shared<oneshot::Receiver<Arc<Vec<u8>>>>, Error> {
+        if !self.has_capacity().await? {
+            return Err(Error::OverCapacity);
+        }
+
+        if self.pending_v1.contains_key(id) {
+            return Err(Error::OverCapacity);
+        }
+
+        let receiver = self
+            .pending_v2
+            .entry(*id)
+            .or_insert_with(|| {
+                let (sender, receiver) = oneshot::channel::<Arc<Vec<u8>>>();
+                let shared_receiver = receiver.shared();
+                V2WaitMapEntry { sender, receiver: shared_receiver.clone() }
+            })
+            .receiver
+            .clone();
+
+        self.record_occupancy();
+        Ok(receiver)
+    }
+
+    fn maybe_cleanup_v2_waitmap(&mut self, id: &ShortId) {
+        if let Some(entry) = self.pending_v2.get(id) {
+            if entry.receiver.strong_count().unwrap_or(0) <= 1 {
+                self.pending_v2.remove(id);
+                self.record_occupancy();
+            }
+        }
+    }
+
+    async fn post_v2(&mut self, id: &ShortId, payload: Vec<u8>) -> Result<Option<()>, Error> {
+        self.maybe_prune().await?;
+
+        let size = payload.len() as u64;
+        if !self.payload_sizes.contains_key(id) && self.over_quota(size) {
+            // Evicting old unread mailboxes may free enough room for the new payload
+            self.evict_unread(size, SystemTime::now()).await?;
+            if self.over_quota(size) {
+                return Err(Error::OverCapacity);
+            }
+        }
+
+        let Some(created) = self.persistent_storage.try_insert(id, &payload).await? else {
+            return Ok(None);
+        };
+
+        // Idempotent retries are already accounted for
+        if self.payload_sizes.insert(*id, size).is_none() {
+            self.stored_bytes += size;
+            self.insert_order.push_back((created, *id));
+        }
+
+        // If there are pending readers, satisfy them and mark the payload as read
+        if let Some(pending) = self.pending_v2.remove(id) {
+            trace!("notifying pending readers for {}", id);
+
+            self.mark_read(id).await?;
+
+            pending
+                .sender
+                .send(Arc::new(payload))
+                .expect("sending on oneshot channel must succeed");
+        }
+
+        self.record_occupancy();
+        Ok(Some(()))
+    }
+
+    async fn post_v1_req_and_wait(
+        &mut self,
+        id: &ShortId,
+        payload: Vec<u8>,
+    ) -> Result<Option<oneshot::Receiver<Vec<u8>>>, Error> {
+        let mut ret = None;
+        let payload = Arc::new(payload);
+
+        // Don't overwrite in flight requests
+        self.pending_v1.entry(*id).or_insert_with(|| {
+            let payload = payload.clone();
+            let (sender, receiver) = oneshot::channel::<Vec<u8>>();
+            ret = Some(receiver);
+            V1WaitMapEntry { payload, sender }
+        });
+
+        // If there are pending readers, satisfy them and mark the payload as read
+        if let Some(pending) = self.pending_v2.remove(id) {
+            trace!("notifying pending readers for {} (v1 fallback)", id);
+            pending.sender.send(payload).expect("sending on oneshot channel must succeed");
+        }
+
+        self.record_occupancy();
+        Ok(ret)
+    }
+
+    async fn remove(&mut self, id: &ShortId) -> io::Result<Option<()>> {
+        self.read_mailbox_ids.remove(id);
+        if let Some(size) = self.payload_sizes.remove(id) {
+            self.stored_bytes -= size;
+        }
+        self.persistent_storage.remove(id).await
+    }
+
+    /// Remove a mailbox at its owner's request. Like read mailboxes pruned
+    /// before they expire, it stays in the insert order until then.
+    async fn delete(&mut self, id: &ShortId) -> io::Result<Option<()>> {
+        let removed = self.remove(id).await?;
+        if removed.is_some() {
+            self.early_removal_count += 1;
+            debug_assert!(self.insert_order.len() >= self.early_removal_count);
+        }
+        self.record_occupancy();
+        Ok(removed)
+    }
+
+    async fn post_v1_res(&mut self, id: &ShortId, payload: Vec<u8>) -> Result<(), Error> {
+        let res = match self.pending_v1.remove(id) {
+            None => Err(Error::V1SenderUnavailable),
+            Some(V1WaitMapEntry { sender, .. }) =>
+                sender.send(payload).map_err(|_| Error::V1SenderUnavailable),
+        };
+        self.record_occupancy();
+        res
+    }
+
+    fn stats(&self) -> MailboxStats {
+        let read: HashMap<_, _> = self.read_order.iter().map(|(read, id)| (*id, *read)).collect();
+        // Mailboxes removed after being read stay in the insert order until
+        // they would have expired, so only those with a payload are stored
+        let entries = self
+            .insert_order
+            .iter()
+            .filter_map(|(created, id)| {
+                let size = *self.payload_sizes.get(id)?;
+                Some(MailboxEntry { id: *id, created: *created, read: read.get(id).copied(), size })
+            })
+            .collect();
+        MailboxStats { entries }
+    }
+
+    fn len(&self) -> usize {
+        (self.insert_order.len() - self.early_removal_count)
+            + self.pending_v1.len()
+            + self.pending_v2.len()
+    }
+
+    fn record_occupancy(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set_mailboxes(self.len());
+        }
+    }
+
+    fn record_pruned(&self, reason: &'static str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_pruned(reason);
+        }
+    }
+
+    /// Whether storing `incoming` more bytes would exceed the byte quota.
+    fn over_quota(&self, incoming: u64) -> bool {
+        self.policy.max_bytes.is_some_and(|max| self.stored_bytes + incoming > max)
+    }
+
+    fn at_capacity(&self, incoming: u64) -> bool {
+        self.len() >= self.policy.capacity || self.over_quota(incoming)
+    }
+
+    /// Prune once the scheduled time has passed or enough requests were
+    /// served since the last run, or immediately when at capacity.
+    async fn maybe_prune(&mut self) -> io::Result<()> {
+        self.ops_since_prune += 1;
+        let due = self.ops_since_prune >= self.policy.prune_every
+            || self.next_prune_at.is_none_or(|at| Instant::now() >= at)
+            || self.at_capacity(0);
+        if due {
+            self.prune().await?;
+        }
+        Ok(())
+    }
+
+    /// Clean out the mailboxes.
+    ///
+    /// Since we use a mutex and not a concurrent hashmap, there's currently
+    /// no benefit to putting this in a background task.
+    ///
+    /// Furthermore, to improve privacy and resist mailbox enumeration, we prune
+    /// expired entries eagerly.
+    async fn prune(&mut self) -> io::Result<Duration> {
+        trace!("pruning");
+        let now = SystemTime::now();
+
+        debug_assert!(self.policy.validate().is_ok());
+        debug_assert!(self.pending_v1.iter().all(|(_, v)| !v.sender.is_closed()));
+
+        // Prune in flight requests, these can persist in the case of an incomplete session
+        self.pending_v2.retain(|_, v| v.receiver.strong_count().unwrap_or(0) > 1);
+
+        // Prune any fully expired mailboxes, whether read or unread
+        while let Some((created, id)) = self.insert_order.front().cloned() {
+            trace!(
+                "checking if {id} elapsed: {:?} < {:?} = {}",
+                (created + self.policy.unread_ttl_below_capacity),
+                now,
+                (created + self.policy.unread_ttl_below_capacity) < now,
+            );
+            if created + self.policy.unread_ttl_below_capacity < now {
+                debug_assert!(self.insert_order.len() >= self.early_removal_count);
+                _ = self.insert_order.pop_front();
+                if self.remove(&id).await?.is_some() {
+                    self.record_pruned(metrics::PRUNE_EXPIRED);
+                } else {
+                    self.early_removal_count = self
+                        .early_removal_count
+                        .checked_sub(1)
+                        .expect("early removal adjustment should never underflow");
+                }
+                debug_assert!(self.insert_order.len() >= self.early_removal_count);
+                trace!("Pruned old mailbox {id}");
+            } else {
+                break;
+            }
+        }
+
+        // So long as there expired read mailboxes, prune those. Stop when a
+        // mailbox within the TTL is encountered.
+        while let Some((read, id)) = self.read_order.front().cloned() {
+            trace!(
+                "checking if {id} elapsed (read ttl): {:?} < {:?} = {}",
+                (read + self.policy.read_ttl),
+                now,
+                (read + self.policy.read_ttl) < now,
+            );
+            if read + self.policy.read_ttl < now {
+                _ = self.read_order.pop_front();
+                if self.remove(&id).await?.is_some() {
+                    self.early_removal_count += 1;
+                    debug_assert!(self.insert_order.len() >= self.early_removal_count);
+                    self.record_pruned(metrics::PRUNE_READ);
+                }
+                trace!("Pruned read mailbox {id}");
+            } else {
+                break;
+            }
+        }
+
+        // If no room was created, try to prune the oldest unread mailboxes if
+        // they're over the minimum TTL
+        self.evict_unread(0, now).await?;
+
+        if let Some(metrics) = &self.metrics {
+            metrics.record_prune();
+        }
+        self.record_occupancy();
+        self.ops_since_prune = 0;
+        self.next_prune_at = Some(Instant::now() + self.policy.prune_interval);
+        Ok(self.next_prune())
+    }
+
+    /// Remove the oldest unread mailboxes past the TTL at capacity until
+    /// `incoming` more bytes would fit.
+    async fn evict_unread(&mut self, incoming: u64, now: SystemTime) -> io::Result<()> {
+        while self.at_capacity(incoming) {
+            match self.insert_order.front().cloned() {
+                Some((created, id)) if created + self.policy.unread_ttl_at_capacity < now => {
+                    _ = self.insert_order.pop_front();
+                    if self.remove(&id).await?.is_some() {
+                        self.record_pruned(metrics::PRUNE_CAPACITY);
+                    } else {
+                        self.early_removal_count = self
+                            .early_removal_count
+                            .checked_sub(1)
+                            .expect("early removal adjustment should never underflow");
+                    }
+                    trace!("Pruned unread mailbox {id} to make room");
+                }
+                _ => {
+                    trace!("Nothing to prune, {} entries remain", self.len());
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next_prune(&mut self) -> Duration {
+        let earliest_read_prune_opportunity = self
+            .read_order
+            .front()
+            .map(|(read, _id)| {
+                self.policy
+                    .read_ttl
+                    .checked_sub(read.elapsed().expect("system clock moved back"))
+                    .unwrap_or(self.policy.read_ttl)
+            })
+            .unwrap_or_else(|| self.policy.read_ttl);
+
+        let earliest_unread_prune_opportunity = self
+            .insert_order
+            .front()
+            .map(|(created, _id)| {
+                self.policy
+                    .unread_ttl_at_capacity
+                    .checked_sub(created.elapsed().expect("system clock moved back"))
+                    .unwrap_or(self.policy.unread_ttl_at_capacity)
+            })
+            .unwrap_or_else(|| self.policy.unread_ttl_at_capacity);
+
+        std::cmp::min(earliest_read_prune_opportunity, earliest_unread_prune_opportunity)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Operation rejected due to lack of capacity
+    OverCapacity,
+
+    /// Indicates the sender that was waiting for the reply is no longer there
+    V1SenderUnavailable,
+
+    IO(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self { Self::IO(e) }
+}
+
+// FIXME why isn't this sufficient for ?, necessitating ugly map_err(into)?
+impl From<Error> for super::Error<std::io::Error> {
+    fn from(val: Error) -> super::Error<io::Error> {
+        match val {
+            Error::V1SenderUnavailable => super::Error::V1SenderUnavailable,
+            Error::OverCapacity => super::Error::OverCapacity,
+            Error::IO(e) => super::Error::Operational(e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IO(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Error::*;
+        match self {
+            OverCapacity => "Database over capacity".fmt(f),
+            V1SenderUnavailable => "Sender no longer connected".fmt(f),
+            IO(e) => write!(f, "Internal Error: {e}"),
+        }
+    }
+}
+
+impl super::SendableError for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Error as DbError;
+
+    /// Run a storage-generic test against every backend
+    macro_rules! test_backends {
+        ($test:ident, $timeout:expr) => {
+            mod $test {
+                use std::time::Duration;
+
+                use crate::db::{files, memory, sqlite};
+
+                #[tokio::test]
+                async fn files() -> std::io::Result<()> {
+                    let dir = tempfile::tempdir()?;
+                    super::$test(files::Db::init($timeout, dir.path().to_owned()).await?).await
+                }
+
+                #[tokio::test]
+                async fn memory() -> std::io::Result<()> {
+                    super::$test(memory::Db::init($timeout).await?).await
+                }
+
+                #[tokio::test]
+                async fn sqlite() -> std::io::Result<()> {
+                    let dir = tempfile::tempdir()?;
+                    super::$test(
+                        sqlite::Db::init($timeout, dir.path().join("mailboxes.sqlite")).await?,
+                    )
+                    .await
+                }
+            }
+        };
+    }
+
+    test_backends!(test_mailbox_storage, Duration::from_millis(10));
+    test_backends!(test_v2_wait, Duration::from_millis(1));
+    test_backends!(test_v1_wait, Duration::from_millis(1));
+    test_backends!(test_prune, Duration::from_millis(2));
+    test_backends!(test_byte_quota, Duration::from_millis(1));
+    test_backends!(test_lazy_prune, Duration::from_millis(1));
+    test_backends!(test_prune_metrics, Duration::from_millis(1));
+    test_backends!(test_shutdown, Duration::from_secs(60));
+    test_backends!(test_delete, Duration::from_millis(1));
+
+    async fn test_mailbox_storage<S: Storage>(db: Db<S>) -> std::io::Result<()> {
+        let id = ShortId([0u8; 8]);
+        let contents = b"foo bar";
+        db.post_v2_payload(&id, contents.to_vec())
+            .await
+            .expect("posting payload should succeed")
+            .expect("contents should be accepted");
+
+        let res = db.wait_for_v2_payload(&id).await.expect("waiting for payload should succeed");
+        assert_eq!(&res[..], contents, "posted payload should be retrievable");
+
+        Ok(())
+    }
+
+    async fn test_v2_wait<S: Storage>(db: Db<S>) -> std::io::Result<()> {
+        let id = ShortId([0u8; 8]);
+        let contents = b"foo bar";
+
+        match db.wait_for_v2_payload(&id).await {
+            Err(DbError::Timeout(_)) => {}
+            res => panic!("expected timeout, got {:?}", res),
+        }
+
+        let read_task1 = tokio::spawn({
+            let db = db.clone();
+            async move { db.wait_for_v2_payload(&id).await }
+        });
+        let read_task2 = tokio::spawn({
+            let db = db.clone();
+            async move { db.wait_for_v2_payload(&id).await }
+        });
+
+        db.post_v2_payload(&id, contents.to_vec())
+            .await
+            .expect("posting payload should succeed")
+            .expect("contents should be accepted");
+
+        let res = read_task1
+            .await
+            .expect("joining task should succeed")
+            .expect("waiting for payload should succeed");
+        assert_eq!(&res[..], contents, "posted payload should be retrievable");
+
+        let res = read_task2
+            .await
+            .expect("joining task should succeed")
+            .expect("waiting for payload should succeed");
+        assert_eq!(&res[..], contents, "posted payload should be retrievable");
+
+        assert!(
+            db.post_v2_payload(&id, b"something else".to_vec())
+                .await
+                .expect("posting payload should succeed")
+                .is_none(),
+            "duplicate POST should be rejected"
+        );
+
+        let res = db.wait_for_v2_payload(&id).await.expect("reading payload should succeed");
+        assert_eq!(&res[..], contents, "posted payload should be retrievable");
+
+        Ok(())
+    }
+
+    async fn test_shutdown<S: Storage>(db: Db<S>) -> std::io::Result<()> {
+        let shutdown = Shutdown::default();
+        let db = Arc::new(db.with_shutdown(shutdown.clone()));
+        let v2_id = ShortId([0u8; 8]);
+        let v1_id = ShortId([1u8; 8]);
+
+        let v2_reader_task = tokio::spawn({
+            let db = db.clone();
+            async move { db.wait_for_v2_payload(&v2_id).await }
+        });
+        let v1_sender_task = tokio::spawn({
+            let db = db.clone();
+            async move { db.post_v1_request_and_wait_for_response(&v1_id, b"request".to_vec()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        shutdown.trigger();
+
+        let v2_res = tokio::time::timeout(Duration::from_secs(1), v2_reader_task)
+            .await
+            .expect("pending v2 reads should end on shutdown")
+            .expect("joining task should succeed");
+        assert!(matches!(v2_res, Err(DbError::ShuttingDown)));
+        let v1_res = tokio::time::timeout(Duration::from_secs(1), v1_sender_task)
+            .await
+            .expect("pending v1 requests should end on shutdown")
+            .expect("joining task should succeed");
+        assert!(matches!(v1_res, Err(DbError::ShuttingDown)));
+        assert_eq!(db.mailboxes.lock().await.pending_v1.len(), 0, "v1 request should be cleared");
+
+        db.flush().await.expect("flushing should succeed");
+        Ok(())
+    }
+
+    async fn test_v1_wait<S: Storage>(db: Db<S>) -> std::io::Result<()> {
+        let db = Arc::new(db);
+
+        let id = ShortId([0u8; 8]);
+
+        let v1_sender_task = tokio::spawn({
+            let db = db.clone();
+            async move { db.post_v1_request_and_wait_for_response(&id, b"request".to_vec()).await }
+        });
+
+        let res = db.wait_for_v2_payload(&id).await.expect("reading payload should succeed");
+        assert_eq!(&res[..], b"request", "in flight v1 request should be retrievable");
+
+        assert!(
+            matches!(
+                db.post_v1_request_and_wait_for_response(&id, b"different request".to_vec()).await,
+                Err(DbError::OverCapacity),
+            ),
+            "second v1 sender with the same shortid should be rejected while request is in flight",
+        );
+
+        db.post_v1_response(&id, b"response".to_vec())
+            .await
+            .expect("posting payload should succeed");
+
+        let res = v1_sender_task
+            .await
+            .expect("joining task should succeed")
+            .expect("waiting for payload should succeed");
+        assert_eq!(&res[..], b"response", "should be response from v2 receiver");
+
+        assert!(
+            matches!(
+                db.post_v1_response(&id, b"response".to_vec()).await,
+                Err(DbError::V1SenderUnavailable)
+            ),
+            "posting without a v1 sender waiting should fail"
+        );
+
+        Ok(())
+    }
+
+    // FIXME test is a bit slow and flakey, how to improve?
+    // unfortunately tokio::time::pause() can't be used because this uses SystemTime
+    // as the underlying clock type, due to timestamps originating from storage
+    async fn test_prune<S: Storage>(db: Db<S>) -> std::io::Result<()> {
+        {
+            let mut guard = db.mailboxes.lock().await;
+            guard.policy.capacity = 2;
+            guard.policy.read_ttl = Duration::from_millis(10);
+            guard.policy.unread_ttl_at_capacity = Duration::from_millis(100);
+            guard.policy.unread_ttl_below_capacity = Duration::from_millis(200);
+        }
+
+        assert_eq!(db.mailboxes.lock().await.len(), 0);
+        db.prune().await.expect("pruning should not fail");
+        assert_eq!(db.mailboxes.lock().await.len(), 0);
+
+        let id = ShortId([0u8; 8]);
+        let contents = b"fooo";
+
+        let read_task1 = tokio::spawn({
+            let db = db.clone();
+            async move { db.wait_for_v2_payload(&id).await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(db.mailboxes.lock().await.len(), 1);
+
+        match read_task1.await.expect("joining should succeed") {
+            Err(DbError::Timeout(_)) => {}
+            res => panic!("expected timeout, got {:?}", res),
+        }
+
+        db.prune().await.expect("pruning should not fail");
+        assert_eq!(db.mailboxes.lock().await.len(), 0);
+
+        db.post_v2_payload(&id, contents.to_vec())
+            .await
+            .expect("posting payload should succeed")
+            .expect("contents should be accepted");
+
+        assert_eq!(db.mailboxes.lock().await.len(), 1);
+        db.prune().await.expect("pruning should not fail");
+        assert_eq!(db.mailboxes.lock().await.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(db.mailboxes.lock().await.len(), 1);
+        db.prune().await.expect("pruning should not fail");
+        assert_eq!(db.mailboxes.lock().await.len(), 0);
+
+        db.post_v2_payload(&id, contents.to_vec())
+            .await
+            .expect("posting payload should succeed")
+            .expect("contents should be accepted");
+
+        assert_eq!(db.mailboxes.lock().await.len(), 1);
+        // FIXME why does this fail?
+        // db.prune().await.expect("pruning should not fail");
+        // assert_eq!(db.mailboxes.lock().await.len(), 1);
+        // mailbox seems to get pruned prematurely
+        // likely cause is it's in both read and insert queue, and two pruning runs
+        // are needed to fully clear it?
+
+        // mark the mailbox as read
+        _ = db.wait_for_v2_payload(&id).await.expect("waiting for payload should succeed");
+
+        assert_eq!(db.mailboxes.lock().await.len(), 1);
+        // FIXME db.prune().await.expect("pruning should not fail");
+        assert_eq!(db.mailboxes.lock().await.len(), 1);
+
+        // allow read TTL to elapse
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(db.mailboxes.lock().await.len(), 1);
+        db.prune().await.expect("pruning should not fail");
+        assert_eq!(db.mailboxes.lock().await.len(), 0);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(db.mailboxes.lock().await.len(), 0);
+        db.prune().await.expect("pruning should not fail");
+        assert_eq!(db.mailboxes.lock().await.len(), 0);
+
+        Ok(())
+    }
+
+    async fn test_delete<S: Storage>(db: Db<S>) -> std::io::Result<()> {
+        {
+            let mut guard = db.mailboxes.lock().await;
+            guard.policy.read_ttl = Duration::from_millis(1);
+            guard.policy.unread_ttl_at_capacity = Duration::from_millis(5);
+            guard.policy.unread_ttl_below_capacity = Duration::from_millis(10);
+        }
+
+        let id = ShortId([0u8; 8]);
+        assert!(db.delete_v2_payload(&id).await.expect("deleting should succeed").is_none());
+
+        db.post_v2_payload(&id, b"foo".to_vec())
+            .await
+            .expect("posting payload should succeed")
+            .expect("contents should be accepted");
+        assert!(db.delete_v2_payload(&id).await.expect("deleting should succeed").is_some());
+        assert_eq!(db.mailboxes.lock().await.len(), 0);
+        assert!(db.stats().await.is_empty());
+        assert!(matches!(db.wait_for_v2_payload(&id).await, Err(DbError::Timeout(_))));
+        assert!(db.delete_v2_payload(&id).await.expect("deleting should succeed").is_none());
+
+        // The deleted mailbox's expiry is still accounted for when pruning
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        db.prune().await.expect("pruning should not fail");
+        let guard = db.mailboxes.lock().await;
+        assert_eq!(guard.len(), 0);
+        assert_eq!(guard.early_removal_count, 0);
+        assert_eq!(guard.stored_bytes, 0);
+
+        Ok(())
+    }
+
+    async fn test_byte_quota<S: Storage>(db: Db<S>) -> std::io::Result<()> {
+        let db = db
+            .with_policy(MailboxPolicy {
+                max_bytes: Some(10),
+                read_ttl: Duration::from_millis(1),
+                unread_ttl_at_capacity: Duration::from_millis(5),
+                unread_ttl_below_capacity: Duration::from_secs(60),
+                ..MailboxPolicy::default()
+            })
+            .await;
+
+        let id1 = ShortId([1u8; 8]);
+        let id2 = ShortId([2u8; 8]);
+        let contents = b"8 bytes!";
+
+        db.post_v2_payload(&id1, contents.to_vec())
+            .await
+            .expect("posting payload should succeed")
+            .expect("contents should be accepted");
+        db.post_v2_payload(&id1, contents.to_vec())
+            .await
+            .expect("idempotent post should succeed")
+            .expect("identical contents should be accepted");
+        assert_eq!(db.mailboxes.lock().await.stored_bytes, 8);
+
+        assert!(
+            matches!(db.post_v2_payload(&id2, contents.to_vec()).await, Err(DbError::OverCapacity)),
+            "posting beyond the byte quota should fail"
+        );
+
+        // once the oldest unread mailbox is past the TTL at capacity it is
+        // evicted to make room, allowing for coarse file timestamps
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        db.post_v2_payload(&id2, contents.to_vec())
+            .await
+            .expect("posting payload should succeed")
+            .expect("contents should be accepted");
+
+        let guard = db.mailboxes.lock().await;
+        assert_eq!(guard.stored_bytes, 8);
+        assert!(guard.persistent_storage.get(&id1).await?.is_none());
+        assert!(guard.persistent_storage.get(&id2).await?.is_some());
+
+        Ok(())
+    }
+
+    async fn test_lazy_prune<S: Storage>(db: Db<S>) -> std::io::Result<()> {
+        let db = db
+            .with_policy(MailboxPolicy {
+                read_ttl: Duration::from_millis(1),
+                unread_ttl_at_capacity: Duration::from_secs(60),
+                unread_ttl_below_capacity: Duration::from_secs(120),
+                prune_interval: Duration::from_secs(60),
+                prune_every: 3,
+                ..MailboxPolicy::default()
+            })
+            .await;
+
+        let id = ShortId([0u8; 8]);
+        db.post_v2_payload(&id, b"foo".to_vec())
+            .await
+            .expect("posting payload should succeed")
+            .expect("contents should be accepted");
+        _ = db.wait_for_v2_payload(&id).await.expect("waiting for payload should succeed");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        // the read TTL has elapsed, but pruning is deferred until enough
+        // requests were served
+        let other = ShortId([1u8; 8]);
+        for _ in 0..2 {
+            assert!(matches!(db.wait_for_v2_payload(&other).await, Err(DbError::Timeout(_))));
+            assert!(db.mailboxes.lock().await.persistent_storage.get(&id).await?.is_some());
+        }
+
+        assert!(matches!(db.wait_for_v2_payload(&other).await, Err(DbError::Timeout(_))));
+        assert!(db.mailboxes.lock().await.persistent_storage.get(&id).await?.is_none());
+
+        Ok(())
+    }
+
+    async fn test_prune_metrics<S: Storage>(db: Db<S>) -> std::io::Result<()> {
+        let metrics = Metrics::new();
+        let db = db
+            .with_policy(MailboxPolicy {
+                read_ttl: Duration::from_millis(1),
+                ..MailboxPolicy::default()
+            })
+            .await
+            .with_metrics(metrics.clone())
+            .await;
+
+        let id = ShortId([0u8; 8]);
+        db.post_v2_payload(&id, b"foo".to_vec())
+            .await
+            .expect("posting payload should succeed")
+            .expect("contents should be accepted");
+        assert_eq!(metrics.mailboxes.get(), 1);
+
+        _ = db.wait_for_v2_payload(&id).await.expect("waiting for payload should succeed");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        db.prune().await?;
+
+        assert_eq!(metrics.mailboxes.get(), 0);
+        assert_eq!(
+            metrics.pruned_mailboxes_total.with_label_values(&[metrics::PRUNE_READ]).get(),
+            1
+        );
+        assert!(metrics.prune_runs_total.get() >= 1);
+        let exported = metrics.generate_metrics().expect("Failed to generate metrics");
+        assert!(!exported.contains(&id.to_string()), "mailbox IDs must not be exported");
+
+        Ok(())
+    }
+}
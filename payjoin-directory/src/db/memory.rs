@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use payjoin::directory::ShortId;
+use tokio::io;
+
+use super::{mailboxes, Storage};
+
+/// Mailboxes kept only in memory, for tests and ephemeral deployments
+pub type Db = mailboxes::Db<MemoryStorage>;
+
+impl Db {
+    pub async fn init(timeout: Duration) -> io::Result<Self> {
+        Self::new(timeout, MemoryStorage::default()).await
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    mailboxes: Mutex<HashMap<ShortId, (SystemTime, Vec<u8>)>>,
+}
+
+impl MemoryStorage {
+    fn mailboxes(&self) -> std::sync::MutexGuard<'_, HashMap<ShortId, (SystemTime, Vec<u8>)>> {
+        self.mailboxes.lock().expect("Lock should not be poisoned")
+    }
+}
+
+impl Storage for MemoryStorage {
+    async fn get(&self, id: &ShortId) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+        Ok(self.mailboxes().get(id).cloned())
+    }
+
+    async fn try_insert(&self, id: &ShortId, contents: &[u8]) -> io::Result<Option<SystemTime>> {
+        let mut mailboxes = self.mailboxes();
+        match mailboxes.get(id) {
+            Some((created, existing_contents)) =>
+                Ok((&existing_contents[..] == contents).then_some(*created)),
+            None => {
+                let created = SystemTime::now();
+                mailboxes.insert(*id, (created, contents.to_vec()));
+                Ok(Some(created))
+            }
+        }
+    }
+
+    async fn remove(&self, id: &ShortId) -> io::Result<Option<()>> {
+        Ok(self.mailboxes().remove(id).map(|_| ()))
+    }
+
+    async fn insert_order(&self) -> io::Result<Vec<(SystemTime, ShortId)>> {
+        let mut ids: Vec<_> =
+            self.mailboxes().iter().map(|(id, (created, _))| (*created, *id)).collect();
+        ids.sort_by_key(|&(created, _id)| created);
+        Ok(ids)
+    }
+
+    async fn payload_sizes(&self) -> io::Result<Vec<(ShortId, u64)>> {
+        Ok(self.mailboxes().iter().map(|(id, (_, payload))| (*id, payload.len() as u64)).collect())
+    }
+}
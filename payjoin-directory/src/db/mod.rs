@@ -1,10 +1,15 @@
 use std::future::Future;
 use std::result::Result;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use payjoin::directory::ShortId;
 
+pub(crate) mod at_rest;
 pub(crate) mod files;
+pub(crate) mod mailboxes;
+pub(crate) mod memory;
+pub(crate) mod sqlite;
 
 pub trait SendableError:
     std::error::Error + std::marker::Send + std::marker::Sync + std::convert::Into<anyhow::Error>
@@ -17,6 +22,8 @@ pub enum Error<OperationalError: SendableError> {
     Timeout(tokio::time::error::Elapsed),
     OverCapacity,
     V1SenderUnavailable,
+    /// A wait was cut short because the directory is shutting down
+    ShuttingDown,
 }
 
 impl SendableError for tokio::time::error::Elapsed {}
@@ -34,6 +41,7 @@ impl<E: SendableError> std::fmt::Display for Error<E> {
             Timeout(timeout) => write!(f, "Timeout: {timeout}"),
             OverCapacity => "Database over capacity".fmt(f),
             V1SenderUnavailable => "Sender no longer connected".fmt(f),
+            ShuttingDown => "Directory shutting down".fmt(f),
         }
     }
 }
@@ -66,6 +74,13 @@ pub trait Db: Clone + Send + Sync + 'static {
         mailbox_id: &ShortId,
     ) -> impl Future<Output = Result<Arc<Vec<u8>>, Error<Self::OperationalError>>> + Send;
 
+    /// Remove a v2 payload before it expires, returning `None` if there was
+    /// none. Callers must authorize the removal.
+    fn delete_v2_payload(
+        &self,
+        mailbox_id: &ShortId,
+    ) -> impl Future<Output = Result<Option<()>, Error<Self::OperationalError>>> + Send;
+
     /// Write a v1 response payload.
     fn post_v1_response(
         &self,
@@ -79,4 +94,61 @@ pub trait Db: Clone + Send + Sync + 'static {
         mailbox_id: &ShortId,
         data: Vec<u8>,
     ) -> impl Future<Output = Result<Arc<Vec<u8>>, Error<Self::OperationalError>>> + Send;
+
+    /// Make all stored payloads durable and clean up after interrupted writes,
+    /// before shutting down.
+    fn flush(&self) -> impl Future<Output = Result<(), Error<Self::OperationalError>>> + Send;
+}
+
+/// Persistent mailbox storage underlying [`mailboxes::Db`].
+///
+/// Backends only store payloads and their timestamps. Waiting readers, v1
+/// fallback requests and pruning are handled by [`mailboxes::Db`].
+pub trait Storage: std::fmt::Debug + Send + Sync + 'static {
+    /// Read a mailbox's payload along with its creation time.
+    fn get(
+        &self,
+        id: &ShortId,
+    ) -> impl Future<Output = std::io::Result<Option<(SystemTime, Vec<u8>)>>> + Send;
+
+    /// Insert a payload unless the mailbox is already populated, returning
+    /// the creation time. Inserting identical contents again succeeds with the
+    /// original creation time, in case of OHTTP retries for the same e2e
+    /// message.
+    fn try_insert(
+        &self,
+        id: &ShortId,
+        contents: &[u8],
+    ) -> impl Future<Output = std::io::Result<Option<SystemTime>>> + Send;
+
+    /// Remove a mailbox, returning `None` if it did not exist.
+    fn remove(&self, id: &ShortId) -> impl Future<Output = std::io::Result<Option<()>>> + Send;
+
+    /// Returns the stored mailbox IDs sorted by their creation time.
+    fn insert_order(
+        &self,
+    ) -> impl Future<Output = std::io::Result<Vec<(SystemTime, ShortId)>>> + Send;
+
+    /// Returns the payload size of every stored mailbox, in bytes.
+    fn payload_sizes(&self) -> impl Future<Output = std::io::Result<Vec<(ShortId, u64)>>> + Send;
+
+    /// Record when a mailbox was first read, for backends which persist the
+    /// read TTL across restarts.
+    fn mark_read(
+        &self,
+        _id: &ShortId,
+        _read: SystemTime,
+    ) -> impl Future<Output = std::io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Returns the read mailbox IDs sorted by the time they were first read.
+    fn read_order(
+        &self,
+    ) -> impl Future<Output = std::io::Result<Vec<(SystemTime, ShortId)>>> + Send {
+        async { Ok(Vec::new()) }
+    }
+
+    /// Make all writes durable, for backends which buffer them.
+    fn flush(&self) -> impl Future<Output = std::io::Result<()>> + Send { async { Ok(()) } }
 }
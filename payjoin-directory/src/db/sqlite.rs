@@ -0,0 +1,234 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use payjoin::directory::ShortId;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::io;
+
+use super::{mailboxes, Storage};
+
+/// Mailboxes stored in a SQLite database, along with their creation and read
+/// times so that TTLs survive restarts
+pub type Db = mailboxes::Db<SqliteStorage>;
+
+impl Db {
+    pub async fn init(timeout: Duration, path: PathBuf) -> io::Result<Self> {
+        Self::new(timeout, SqliteStorage::init(path).await?).await
+    }
+}
+
+#[derive(Debug)]
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub(crate) async fn init(path: PathBuf) -> io::Result<Self> {
+        let conn = tokio::task::spawn_blocking(move || -> rusqlite::Result<Connection> {
+            let conn = Connection::open(path)?;
+            conn.execute_batch(
+                "PRAGMA journal_mode = WAL;
+                 PRAGMA synchronous = FULL;
+                 CREATE TABLE IF NOT EXISTS mailboxes (
+                     id TEXT PRIMARY KEY,
+                     payload BLOB NOT NULL,
+                     created_at INTEGER NOT NULL,
+                     read_at INTEGER
+                 );",
+            )?;
+            Ok(conn)
+        })
+        .await
+        .map_err(io::Error::other)?
+        .map_err(io::Error::other)?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    /// Returns the problems found by SQLite's integrity check, if any.
+    pub(crate) async fn integrity_check(&self) -> io::Result<Vec<String>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            let problems = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(problems.into_iter().filter(|problem| problem != "ok").collect())
+        })
+        .await
+    }
+
+    /// Run a query on the blocking thread pool, since rusqlite is synchronous
+    async fn with_conn<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().expect("Lock should not be poisoned");
+            f(&mut conn)
+        })
+        .await
+        .map_err(io::Error::other)?
+        .map_err(io::Error::other)
+    }
+}
+
+fn to_nanos(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).expect("system clock before unix epoch").as_nanos() as i64
+}
+
+fn from_nanos(nanos: i64) -> SystemTime { UNIX_EPOCH + Duration::from_nanos(nanos as u64) }
+
+fn ordered_ids(conn: &Connection, query: &str) -> rusqlite::Result<Vec<(SystemTime, ShortId)>> {
+    let mut stmt = conn.prepare(query)?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+    let mut ids = Vec::new();
+    for row in rows {
+        let (time, id) = row?;
+        // Skip rows which aren't mailboxes, as the files backend skips unknown file names
+        if let Ok(id) = ShortId::from_str(&id) {
+            ids.push((from_nanos(time), id));
+        }
+    }
+    Ok(ids)
+}
+
+impl Storage for SqliteStorage {
+    async fn get(&self, id: &ShortId) -> io::Result<Option<(SystemTime, Vec<u8>)>> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT created_at, payload FROM mailboxes WHERE id = ?1",
+                params![id],
+                |row| Ok((from_nanos(row.get(0)?), row.get(1)?)),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn try_insert(&self, id: &ShortId, contents: &[u8]) -> io::Result<Option<SystemTime>> {
+        let id = id.to_string();
+        let contents = contents.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let existing: Option<(i64, Vec<u8>)> = tx
+                .query_row(
+                    "SELECT created_at, payload FROM mailboxes WHERE id = ?1",
+                    params![id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+            let created = match existing {
+                // Allow idempotent insertion if the contents are identical
+                Some((created, existing_contents)) =>
+                    (existing_contents == contents).then(|| from_nanos(created)),
+                None => {
+                    let created = to_nanos(SystemTime::now());
+                    tx.execute(
+                        "INSERT INTO mailboxes (id, payload, created_at) VALUES (?1, ?2, ?3)",
+                        params![id, contents, created],
+                    )?;
+                    Some(from_nanos(created))
+                }
+            };
+            tx.commit()?;
+            Ok(created)
+        })
+        .await
+    }
+
+    async fn remove(&self, id: &ShortId) -> io::Result<Option<()>> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            let removed = conn.execute("DELETE FROM mailboxes WHERE id = ?1", params![id])?;
+            Ok((removed > 0).then_some(()))
+        })
+        .await
+    }
+
+    async fn insert_order(&self) -> io::Result<Vec<(SystemTime, ShortId)>> {
+        self.with_conn(|conn| {
+            ordered_ids(conn, "SELECT created_at, id FROM mailboxes ORDER BY created_at")
+        })
+        .await
+    }
+
+    async fn payload_sizes(&self) -> io::Result<Vec<(ShortId, u64)>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT id, length(payload) FROM mailboxes")?;
+            let rows =
+                stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+            let mut sizes = Vec::new();
+            for row in rows {
+                let (id, size) = row?;
+                if let Ok(id) = ShortId::from_str(&id) {
+                    sizes.push((id, size as u64));
+                }
+            }
+            Ok(sizes)
+        })
+        .await
+    }
+
+    async fn mark_read(&self, id: &ShortId, read: SystemTime) -> io::Result<()> {
+        let id = id.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE mailboxes SET read_at = ?2 WHERE id = ?1 AND read_at IS NULL",
+                params![id, to_nanos(read)],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn read_order(&self) -> io::Result<Vec<(SystemTime, ShortId)>> {
+        self.with_conn(|conn| {
+            ordered_ids(
+                conn,
+                "SELECT read_at, id FROM mailboxes WHERE read_at IS NOT NULL ORDER BY read_at",
+            )
+        })
+        .await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        // Move the write-ahead log into the database file
+        self.with_conn(|conn| conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(())))
+            .await
+    }
+}
+
+#[tokio::test]
+async fn test_sqlite_storage_persists_ttl_metadata() -> std::io::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("mailboxes.sqlite");
+    let id1 = ShortId([1u8; 8]);
+    let id2 = ShortId([2u8; 8]);
+
+    let (created1, created2, read2) = {
+        let storage = SqliteStorage::init(path.clone()).await?;
+        let created1 = storage.try_insert(&id1, b"foo").await?.expect("insert should succeed");
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let created2 = storage.try_insert(&id2, b"bar").await?.expect("insert should succeed");
+        assert_eq!(storage.try_insert(&id1, b"foo").await?, Some(created1), "idempotent write");
+        assert_eq!(storage.try_insert(&id1, b"baz").await?, None, "conflicting write");
+
+        let read2 = SystemTime::now();
+        storage.mark_read(&id2, read2).await?;
+        storage.mark_read(&id2, SystemTime::now()).await?;
+        (created1, created2, from_nanos(to_nanos(read2)))
+    };
+
+    let storage = SqliteStorage::init(path).await?;
+    assert_eq!(storage.insert_order().await?, vec![(created1, id1), (created2, id2)]);
+    assert_eq!(storage.read_order().await?, vec![(read2, id2)], "first read time is kept");
+    assert_eq!(storage.get(&id1).await?, Some((created1, b"foo".to_vec())));
+
+    assert_eq!(storage.remove(&id1).await?, Some(()));
+    assert_eq!(storage.remove(&id1).await?, None);
+    assert_eq!(storage.insert_order().await?, vec![(created2, id2)]);
+
+    Ok(())
+}
@@ -55,6 +55,29 @@ pub fn persist_new_key_config(ohttp_config: ServerKeyConfig, dir: &Path) -> Resu
     Ok(key_path)
 }
 
+/// Replace the OHTTP Key Configuration at the default path with a new one
+///
+/// Clients holding the previous key configuration get a key rejection until
+/// they fetch the new one.
+pub fn rotate_key_config(ohttp_config: ServerKeyConfig, dir: &Path) -> Result<PathBuf> {
+    use std::io::Write;
+
+    let key_path = key_path(dir);
+    let tmp_path = key_path.with_extension("ikm.tmp");
+
+    let mut file = fs::File::create(&tmp_path)
+        .map_err(|e| anyhow!("Failed to create new OHTTP key file: {}", e))?;
+    file.write_all(&ohttp_config.ikm)
+        .and_then(|()| file.sync_all())
+        .map_err(|e| anyhow!("Failed to write OHTTP keys to file: {}", e))?;
+    // Replace the previous keys atomically
+    fs::rename(&tmp_path, &key_path)
+        .map_err(|e| anyhow!("Failed to replace OHTTP key file: {}", e))?;
+    info!("Rotated OHTTP Key Configuration at {}", &key_path.display());
+
+    Ok(key_path)
+}
+
 /// Read the configured server from the default path
 /// May panic if key exists but is the unexpected format.
 pub fn read_server_config(dir: &Path) -> Result<ServerKeyConfig> {
@@ -74,7 +97,7 @@ pub fn read_server_config(dir: &Path) -> Result<ServerKeyConfig> {
 /// Get the path to the key configuration file
 /// For now, default to [KEY_ID].ikm.
 /// In the future this might be able to save multiple keys named by KeyId.
-fn key_path(dir: &Path) -> PathBuf { dir.join(format!("{KEY_ID}.ikm")) }
+pub fn key_path(dir: &Path) -> PathBuf { dir.join(format!("{KEY_ID}.ikm")) }
 
 #[cfg(test)]
 mod tests {
@@ -90,4 +113,24 @@ mod tests {
             read_server_config(temp_dir.path()).expect("Failed to read server config");
         assert_eq!(ohttp_config.ikm, ohttp_config_again.ikm);
     }
+
+    #[test]
+    fn rotate_server_config() {
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let old_config = gen_ohttp_server_config().expect("Failed to generate server config");
+        persist_new_key_config(old_config.clone(), temp_dir.path())
+            .expect("Failed to persist server config");
+
+        let new_config = gen_ohttp_server_config().expect("Failed to generate server config");
+        assert!(
+            persist_new_key_config(new_config.clone(), temp_dir.path()).is_err(),
+            "existing keys must not be overwritten"
+        );
+        rotate_key_config(new_config.clone(), temp_dir.path())
+            .expect("Failed to rotate server config");
+        let read_config =
+            read_server_config(temp_dir.path()).expect("Failed to read server config");
+        assert_eq!(read_config.ikm, new_config.ikm);
+        assert_ne!(read_config.ikm, old_config.ikm);
+    }
 }
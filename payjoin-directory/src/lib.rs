@@ -1,23 +1,42 @@
+use std::convert::Infallible;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Empty, Full};
 use hyper::body::{Body, Bytes, Incoming};
-use hyper::header::{HeaderValue, ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_TYPE};
-use hyper::server::conn::http1;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_ORIGIN, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER,
+    WWW_AUTHENTICATE,
+};
 use hyper::{Method, Request, Response, StatusCode, Uri};
 use hyper_util::rt::TokioIo;
+use payjoin::directory::deletion::DeleteAuthorization;
+use payjoin::directory::token::{AccessToken, AUTHORIZATION_SCHEME};
 use payjoin::directory::{ShortId, ShortIdError, ENCAPSULATED_MESSAGE_BYTES};
-use tracing::{debug, error, trace, warn};
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::{debug, error, info, trace, warn};
 
+pub use crate::db::at_rest::{AtRest, StorageKey};
 pub use crate::db::files::Db as FilesDb;
+pub use crate::db::mailboxes::{Db as MailboxDb, MailboxEntry, MailboxPolicy, MailboxStats};
+pub use crate::db::memory::Db as MemoryDb;
+pub use crate::db::sqlite::Db as SqliteDb;
 use crate::db::Db;
+pub use crate::db::Storage;
 pub mod key_config;
 pub use crate::key_config::*;
+pub use crate::listener::{ListenAddr, Listener};
 use crate::metrics::Metrics;
+use crate::rate_limit::{ConnectionLimiter, Limiter};
+pub use crate::rate_limit::{RateLimit, RateLimits};
+pub use crate::relay::Relay;
+use crate::shutdown::serve_connection;
+pub use crate::shutdown::Shutdown;
+pub use crate::tokens::TokenIssuer;
 
 const CHACHA20_POLY1305_NONCE_LEN: usize = 32; // chacha20poly1305 n_k
 const POLY1305_TAG_SIZE: usize = 16;
@@ -27,13 +46,23 @@ const V1_MAX_BUFFER_SIZE: usize = 65536;
 
 const V1_REJECT_RES_JSON: &str =
     r#"{{"errorCode": "original-psbt-rejected ", "message": "Body is not a string"}}"#;
+/// The magic string signalling that an OHTTP gateway accepts requests for the BIP 77 purpose
+const BIP77_ALLOWED_PURPOSE: &[u8] = b"BIP77 454403bb-9f7b-4385-b31f-acd2dae20b7e";
+
 const V1_UNAVAILABLE_RES_JSON: &str = r#"{{"errorCode": "unavailable", "message": "V2 receiver offline. V1 sends require synchronous communications."}}"#;
 
 pub(crate) mod db;
 
+pub mod admin;
+
 pub mod cli;
 pub mod config;
+pub mod listener;
 pub mod metrics;
+pub mod rate_limit;
+pub mod relay;
+pub mod shutdown;
+pub mod tokens;
 
 pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -60,6 +89,11 @@ pub struct Service<D: Db> {
     db: D,
     ohttp: ohttp::Server,
     metrics: Metrics,
+    limiter: Arc<Limiter>,
+    connection: Option<Arc<ConnectionLimiter>>,
+    tokens: Option<Arc<TokenIssuer>>,
+    shutdown: Shutdown,
+    h2c: bool,
 }
 
 impl<D: Db> hyper::service::Service<Request<Incoming>> for Service<D> {
@@ -76,56 +110,161 @@ impl<D: Db> hyper::service::Service<Request<Incoming>> for Service<D> {
 
 impl<D: Db> Service<D> {
     pub fn new(db: D, ohttp: ohttp::Server, metrics: Metrics) -> Self {
-        Self { db, ohttp, metrics }
+        Self {
+            db,
+            ohttp,
+            metrics,
+            limiter: Arc::default(),
+            connection: None,
+            tokens: None,
+            shutdown: Shutdown::default(),
+            h2c: false,
+        }
+    }
+
+    /// Stop serving once `shutdown` is triggered. The storage should be given
+    /// the same signal so that its long polls end early.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Also accept HTTP/2 with prior knowledge (h2c) on connections without
+    /// TLS. Over TLS, HTTP/2 is always negotiated with ALPN.
+    pub fn with_h2c(mut self, h2c: bool) -> Self {
+        self.h2c = h2c;
+        self
+    }
+
+    /// Require an access token from `issuer` for every v2 mailbox POST, or
+    /// accept unauthorized writes if `None`.
+    pub fn with_access_tokens(mut self, issuer: Option<TokenIssuer>) -> Self {
+        self.tokens = issuer.map(Arc::new);
+        self
+    }
+
+    /// Enforce `limits`, which are all disabled by default.
+    pub fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.limiter = Arc::new(Limiter::new(limits));
+        self
+    }
+
+    /// Per-address limits can't tell apart clients connecting through a Unix
+    /// socket, so say so rather than silently not enforcing them.
+    fn warn_if_unlimited(&self, listener: &Listener) {
+        if self.limiter.has_per_address_limits() && !listener.has_peer_addresses() {
+            warn!(
+                "Per-address rate limits are not enforced on Unix socket connections, \
+                 limit clients by address in the reverse proxy instead"
+            );
+        }
+    }
+
+    /// A copy of this service for a newly accepted connection from `peer`.
+    fn for_connection(&self, peer: Option<std::net::SocketAddr>) -> Self {
+        let mut service = self.clone();
+        service.connection = Some(Arc::new(self.limiter.connection(peer)));
+        service
     }
 
     #[cfg(feature = "_manual-tls")]
     pub async fn serve_tls(
         self,
-        listener: tokio::net::TcpListener,
+        listener: impl Into<Listener>,
         tls_config: (Vec<u8>, Vec<u8>),
     ) -> Result<(), BoxError> {
         let tls_acceptor = init_tls_acceptor(tls_config)?;
-        // Spawn the connection handling loop in a separate task
-
-        while let Ok((stream, _)) = listener.accept().await {
-            let tls_acceptor = tls_acceptor.clone();
-            let service = self.clone();
-            tokio::spawn(async move {
-                service.metrics.record_connection();
-                let tls_stream = match tls_acceptor.accept(stream).await {
-                    Ok(tls_stream) => tls_stream,
-                    Err(e) => {
-                        error!("TLS accept error: {}", e);
-                        return;
-                    }
-                };
-                if let Err(err) = http1::Builder::new()
-                    .serve_connection(TokioIo::new(tls_stream), service)
-                    .with_upgrades()
-                    .await
-                {
-                    error!("Error serving connection: {:?}", err);
+        let listener = listener.into();
+        self.warn_if_unlimited(&listener);
+        let mut connections = tokio::task::JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let Ok((stream, peer)) = accepted else { break };
+                    let tls_acceptor = tls_acceptor.clone();
+                    let service = self.for_connection(peer);
+                    connections.spawn(async move {
+                        service.metrics.record_connection();
+                        let tls_stream = match tls_acceptor.accept(stream).await {
+                            Ok(tls_stream) => tls_stream,
+                            Err(e) => {
+                                error!("TLS accept error: {}", e);
+                                return;
+                            }
+                        };
+                        let shutdown = service.shutdown.clone();
+                        if let Err(err) =
+                            serve_connection(TokioIo::new(tls_stream), service, true, &shutdown).await
+                        {
+                            error!("Error serving connection: {:?}", err);
+                        }
+                    });
                 }
-            });
+                // Reap finished connections
+                Some(_) = connections.join_next() => {}
+                () = self.shutdown.triggered() => break,
+            }
         }
-        Ok(())
+        self.close(connections).await
     }
 
     pub async fn serve_tcp(self, listener: tokio::net::TcpListener) -> Result<(), BoxError> {
-        while let Ok((stream, _)) = listener.accept().await {
-            let io = TokioIo::new(stream);
-            let service = self.clone();
-            tokio::spawn(async move {
-                service.metrics.record_connection();
-                if let Err(err) =
-                    http1::Builder::new().serve_connection(io, service).with_upgrades().await
-                {
-                    error!("Error serving connection: {:?}", err);
+        self.serve(listener.into()).await
+    }
+
+    /// Serve connections accepted by `listener` until shut down.
+    pub async fn serve(self, listener: Listener) -> Result<(), BoxError> {
+        self.warn_if_unlimited(&listener);
+        let mut connections = tokio::task::JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let Ok((stream, peer)) = accepted else { break };
+                    let service = self.for_connection(peer);
+                    connections.spawn(async move {
+                        service.metrics.record_connection();
+                        let shutdown = service.shutdown.clone();
+                        let h2c = service.h2c;
+                        if let Err(err) =
+                            serve_connection(TokioIo::new(stream), service, h2c, &shutdown).await
+                        {
+                            error!("Error serving connection: {:?}", err);
+                        }
+                    });
                 }
-            });
+                // Reap finished connections
+                Some(_) = connections.join_next() => {}
+                () = self.shutdown.triggered() => break,
+            }
         }
+        self.close(connections).await
+    }
 
+    /// Serve `listener` as with [`Self::serve`], and metrics on `metrics_listener`.
+    pub async fn serve_with_metrics(
+        self,
+        listener: Listener,
+        metrics_listener: Listener,
+    ) -> Result<(), BoxError> {
+        let metrics_service = self.clone();
+        let metrics =
+            tokio::spawn(async move { metrics_service.serve_metrics(metrics_listener).await });
+        let shutdown = self.shutdown.clone();
+        let served = self.serve(listener).await;
+        // Let the metrics connections drain too, unless serving stopped on its own
+        if !shutdown.is_triggered() {
+            metrics.abort();
+        }
+        _ = metrics.await;
+        served
+    }
+
+    /// Drain `connections` and flush storage once no longer accepting
+    async fn close(&self, connections: tokio::task::JoinSet<()>) -> Result<(), BoxError> {
+        info!("Closing {} connections", connections.len());
+        self.shutdown.drain(connections).await;
+        self.db.flush().await?;
+        info!("Storage flushed");
         Ok(())
     }
 
@@ -139,20 +278,28 @@ impl<D: Db> Service<D> {
 
         let path_segments: Vec<&str> = path.split('/').collect();
         debug!("Service::serve_request: {:?}", &path_segments);
-        let mut response = match (parts.method, path_segments.as_slice()) {
+        let start = Instant::now();
+        // Route labels are fixed strings so that mailbox IDs never end up in metrics
+        let (route, response) = match (parts.method, path_segments.as_slice()) {
             (Method::POST, ["", ".well-known", "ohttp-gateway"]) =>
-                self.handle_ohttp_gateway(body).await,
+                ("ohttp_gateway", self.handle_ohttp_gateway(body).await),
             (Method::GET, ["", ".well-known", "ohttp-gateway"]) =>
-                self.handle_ohttp_gateway_get(&query).await,
-            (Method::POST, ["", ""]) => self.handle_ohttp_gateway(body).await,
-            (Method::GET, ["", "ohttp-keys"]) => self.get_ohttp_keys().await,
-            (Method::POST, ["", id]) => self.post_fallback_v1(id, query, body).await,
-            (Method::GET, ["", "health"]) => health_check().await,
-            (Method::GET, ["", ""]) => handle_directory_home_path().await,
-            (Method::GET, ["", "metrics"]) => Ok(self.handle_metrics().await),
-            _ => Ok(not_found()),
-        }
-        .unwrap_or_else(|e| e.to_response());
+                ("ohttp_gateway_get", self.handle_ohttp_gateway_get(&query).await),
+            (Method::POST, ["", ""]) => ("ohttp_gateway", self.handle_ohttp_gateway(body).await),
+            (Method::GET, ["", "ohttp-keys"]) => ("ohttp_keys", self.get_ohttp_keys().await),
+            (Method::GET, ["", ".well-known", "payjoin-token-issuer"]) =>
+                ("token_issuer", self.get_token_issuer_key()),
+            (Method::POST, ["", ".well-known", "payjoin-token-issuer"]) =>
+                ("token_issuance", self.issue_tokens(body).await),
+            (Method::POST, ["", id]) =>
+                ("v1_fallback", self.post_fallback_v1(id, query, body).await),
+            (Method::GET, ["", "health"]) => ("health", health_check().await),
+            (Method::GET, ["", ""]) => ("home", handle_directory_home_path().await),
+            (Method::GET, ["", "metrics"]) => ("metrics", Ok(self.handle_metrics().await)),
+            _ => ("not_found", Ok(not_found())),
+        };
+        let mut response = response.unwrap_or_else(|e| e.to_response());
+        self.metrics.record_request_duration(route, start.elapsed());
 
         // Allow CORS for third-party access
         response.headers_mut().insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
@@ -164,13 +311,20 @@ impl<D: Db> Service<D> {
         &self,
         body: Incoming,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError> {
+        if let Some(connection) = &self.connection {
+            connection.check_ohttp().map_err(|retry_after| {
+                self.metrics.record_rate_limited(metrics::LIMIT_OHTTP_CONNECTION);
+                HandlerError::RateLimited(retry_after)
+            })?;
+        }
+
         // decapsulate
         let ohttp_body =
             body.collect().await.map_err(|e| HandlerError::BadRequest(e.into()))?.to_bytes();
-        let (bhttp_req, res_ctx) = self
-            .ohttp
-            .decapsulate(&ohttp_body)
-            .map_err(|e| HandlerError::OhttpKeyRejection(e.into()))?;
+        let (bhttp_req, res_ctx) = self.ohttp.decapsulate(&ohttp_body).map_err(|e| {
+            self.metrics.record_decapsulation_failure();
+            HandlerError::OhttpKeyRejection(e.into())
+        })?;
         let mut cursor = std::io::Cursor::new(bhttp_req);
         let req = bhttp::Message::read_bhttp(&mut cursor)
             .map_err(|e| HandlerError::BadRequest(e.into()))?;
@@ -224,17 +378,25 @@ impl<D: Db> Service<D> {
 
         let path_segments: Vec<&str> = path.split('/').collect();
         debug!("handle_v2: {:?}", &path_segments);
-        match (parts.method, path_segments.as_slice()) {
-            (Method::POST, &["", id]) => self.post_mailbox(id, body).await,
-            (Method::GET, &["", id]) => self.get_mailbox(id).await,
-            (Method::PUT, &["", id]) => self.put_payjoin_v1(id, body).await,
-            _ => Ok(not_found()),
-        }
+        let start = Instant::now();
+        let (route, response) = match (parts.method, path_segments.as_slice()) {
+            (Method::POST, &["", id]) =>
+                ("mailbox_post", self.post_mailbox(id, &parts.headers, body).await),
+            (Method::GET, &["", id]) => ("mailbox_get", self.get_mailbox(id).await),
+            (Method::PUT, &["", id]) => ("v1_response", self.put_payjoin_v1(id, body).await),
+            (Method::DELETE, &["", id]) => ("mailbox_delete", self.delete_mailbox(id, body).await),
+            _ => ("v2_not_found", Ok(not_found())),
+        };
+        self.metrics.record_request_duration(route, start.elapsed());
+        response
     }
 
+    /// Store a v2 payload, spending the access token in `headers` only once
+    /// the request is otherwise valid, and refunding it if it can't be stored.
     async fn post_mailbox(
         &self,
         id: &str,
+        headers: &HeaderMap,
         body: BoxBody<Bytes, hyper::Error>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError> {
         let none_response = Response::builder().status(StatusCode::OK).body(empty())?;
@@ -251,8 +413,23 @@ impl<D: Db> Service<D> {
             return Err(HandlerError::PayloadTooLarge);
         }
 
-        match self.db.post_v2_payload(&id, req.into()).await {
-            Ok(_) => Ok(none_response),
+        let token = self.redeem_access_token(headers)?;
+        let size = req.len();
+        let posted = self.db.post_v2_payload(&id, req.into()).await;
+        if let (Err(_), Some(token), Some(issuer)) = (&posted, &token, &self.tokens) {
+            issuer.refund(token);
+        }
+        match posted {
+            Ok(_) => {
+                self.metrics.record_post(metrics::V2, size);
+                Ok(none_response)
+            }
+            Err(db::Error::OverCapacity) => {
+                self.metrics.record_over_capacity();
+                Err(HandlerError::ServiceUnavailable(anyhow::Error::msg(
+                    "mailbox storage at capacity",
+                )))
+            }
             Err(e) => Err(HandlerError::InternalServerError(e.into())),
         }
     }
@@ -264,8 +441,39 @@ impl<D: Db> Service<D> {
         trace!("get_mailbox");
         let id = ShortId::from_str(id)?;
         let timeout_response = Response::builder().status(StatusCode::ACCEPTED).body(empty())?;
-        handle_peek(self.db.wait_for_v2_payload(&id).await, timeout_response)
+        let _waiter = self.long_poll_permit()?;
+        self.handle_peek(metrics::V2, self.db.wait_for_v2_payload(&id).await, timeout_response)
+    }
+
+    /// Delete a mailbox before it expires. The body must be a
+    /// [`DeleteAuthorization`] signed by the key the mailbox ID is derived
+    /// from, and deleting a mailbox which is already gone succeeds so that
+    /// the response doesn't reveal whether it existed.
+    async fn delete_mailbox(
+        &self,
+        id: &str,
+        body: BoxBody<Bytes, hyper::Error>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError> {
+        trace!("delete_mailbox");
+        let id = ShortId::from_str(id)?;
+        let req = body
+            .collect()
+            .await
+            .map_err(|e| HandlerError::InternalServerError(e.into()))?
+            .to_bytes();
+        let authorization = DeleteAuthorization::from_bytes(&req)
+            .map_err(|e| HandlerError::BadRequest(e.into()))?;
+        authorization
+            .verify(&id, std::time::SystemTime::now())
+            .map_err(|e| HandlerError::Forbidden(e.into()))?;
+
+        self.db
+            .delete_v2_payload(&id)
+            .await
+            .map_err(|e| HandlerError::InternalServerError(e.into()))?;
+        Ok(Response::builder().status(StatusCode::OK).body(empty())?)
     }
+
     async fn put_payjoin_v1(
         &self,
         id: &str,
@@ -284,9 +492,18 @@ impl<D: Db> Service<D> {
             return Err(HandlerError::PayloadTooLarge);
         }
 
+        let size = req.len();
         match self.db.post_v1_response(&id, req.into()).await {
-            Ok(_) => Ok(ok_response),
-            Err(e) => Err(HandlerError::BadRequest(e.into())),
+            Ok(_) => {
+                self.metrics.record_post(metrics::V1, size);
+                Ok(ok_response)
+            }
+            Err(e) => {
+                if let db::Error::V1SenderUnavailable = e {
+                    self.metrics.record_v1_sender_unavailable();
+                }
+                Err(HandlerError::BadRequest(e.into()))
+            }
         }
     }
 
@@ -303,6 +520,16 @@ impl<D: Db> Service<D> {
         let bad_request_body_res =
             Response::builder().status(StatusCode::BAD_REQUEST).body(full(V1_REJECT_RES_JSON))?;
 
+        if let Some(peer) = self.connection.as_ref().and_then(|connection| connection.peer()) {
+            if let Err(retry_after) = self.limiter.check_v1(peer) {
+                self.metrics.record_rate_limited(metrics::LIMIT_V1_ADDRESS);
+                return Ok(v1_unavailable_response(retry_after)?);
+            }
+        }
+        let Ok(_waiter) = self.long_poll_permit() else {
+            return Ok(v1_unavailable_response(Duration::from_secs(1))?);
+        };
+
         let body_bytes = match body.collect().await {
             Ok(bytes) => bytes.to_bytes(),
             Err(_) => return Ok(bad_request_body_res),
@@ -315,12 +542,67 @@ impl<D: Db> Service<D> {
 
         let v2_compat_body = format!("{body_str}\n{query}");
         let id = ShortId::from_str(id)?;
-        handle_peek(
+        self.metrics.record_post(metrics::V1, v2_compat_body.len());
+        self.handle_peek(
+            metrics::V1,
             self.db.post_v1_request_and_wait_for_response(&id, v2_compat_body.into()).await,
             none_response,
         )
     }
 
+    /// Tokens are only required for mailbox writes, so reads and v1 responses
+    /// need no authorization.
+    fn redeem_access_token(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<Option<AccessToken>, HandlerError> {
+        let Some(issuer) = &self.tokens else { return Ok(None) };
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| AccessToken::from_header_value(value).ok());
+        match token {
+            Some(token) if issuer.redeem(&token) => Ok(Some(token)),
+            _ => {
+                self.metrics.record_access_token_rejection();
+                Err(HandlerError::Unauthorized)
+            }
+        }
+    }
+
+    fn get_token_issuer_key(&self) -> Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError> {
+        let Some(issuer) = &self.tokens else { return Ok(not_found()) };
+        let mut res = Response::new(full(issuer.public_key().to_bytes().to_vec()));
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+        Ok(res)
+    }
+
+    async fn issue_tokens(
+        &self,
+        body: impl Body,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError> {
+        let Some(issuer) = &self.tokens else { return Ok(not_found()) };
+        // Issuance is direct rather than over OHTTP, so it can be limited by address
+        if let Some(peer) = self.connection.as_ref().and_then(|connection| connection.peer()) {
+            self.limiter.check_token_issuance(peer).map_err(|retry_after| {
+                self.metrics.record_rate_limited(metrics::LIMIT_TOKEN_ISSUANCE);
+                HandlerError::RateLimited(retry_after)
+            })?;
+        }
+
+        let request = body
+            .collect()
+            .await
+            .map_err(|_| HandlerError::BadRequest(anyhow::anyhow!("failed to read body")))?
+            .to_bytes();
+        let response = issuer.issue(&request).map_err(|e| HandlerError::BadRequest(e.into()))?;
+        let mut res = Response::new(full(response));
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+        Ok(res)
+    }
+
     async fn handle_ohttp_gateway_get(
         &self,
         query: &str,
@@ -349,9 +631,10 @@ impl<D: Db> Service<D> {
         // The string is just "BIP77" followed by a UUID, that signals to relays
         // that this OHTTP gateway will accept any requests associated with this
         // purpose.
-        let mut res = Response::new(full(Bytes::from_static(
-            b"\x00\x01\x2aBIP77 454403bb-9f7b-4385-b31f-acd2dae20b7e",
-        )));
+        let mut allowed_purposes = b"\x00\x01".to_vec();
+        allowed_purposes.push(BIP77_ALLOWED_PURPOSE.len() as u8);
+        allowed_purposes.extend_from_slice(BIP77_ALLOWED_PURPOSE);
+        let mut res = Response::new(full(allowed_purposes));
 
         res.headers_mut()
             .insert(CONTENT_TYPE, HeaderValue::from_static("application/x-ohttp-allowed-purposes"));
@@ -377,45 +660,101 @@ impl<D: Db> Service<D> {
         }
     }
 
+    /// Reserve one of the limited long poll slots for the rest of the request.
+    fn long_poll_permit(&self) -> Result<Option<OwnedSemaphorePermit>, HandlerError> {
+        self.limiter.long_poll().map_err(|()| {
+            self.metrics.record_rate_limited(metrics::LIMIT_LONG_POLL_WAITERS);
+            HandlerError::RateLimited(Duration::from_secs(1))
+        })
+    }
+
+    fn handle_peek<Error: db::SendableError>(
+        &self,
+        version: &'static str,
+        result: Result<Arc<Vec<u8>>, db::Error<Error>>,
+        timeout_response: Response<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError> {
+        match result {
+            Ok(payload) => {
+                self.metrics.record_read(version);
+                Ok(Response::new(full((*payload).clone()))) // TODO Bytes instead of Arc<Vec<u8>>
+            }
+            Err(e) => match e {
+                db::Error::Operational(err) => {
+                    error!("Storage error: {err}");
+                    Err(HandlerError::InternalServerError(anyhow::Error::msg(
+                        "Internal server error",
+                    )))
+                }
+                db::Error::Timeout(_) => {
+                    self.metrics.record_timeout(version);
+                    Ok(timeout_response)
+                }
+                // Answer as if timed out, so that clients simply retry elsewhere or later
+                db::Error::ShuttingDown => Ok(timeout_response),
+                db::Error::OverCapacity => {
+                    self.metrics.record_over_capacity();
+                    Err(HandlerError::ServiceUnavailable(anyhow::Error::msg(
+                        "mailbox storage at capacity",
+                    )))
+                }
+                db::Error::V1SenderUnavailable => {
+                    self.metrics.record_v1_sender_unavailable();
+                    Err(HandlerError::SenderGone(anyhow::Error::msg(
+                        "Sender is unavailable try a new request",
+                    )))
+                }
+            },
+        }
+    }
+
     pub async fn serve_metrics_tcp(
         &self,
         listener: tokio::net::TcpListener,
     ) -> Result<(), BoxError> {
-        while let Ok((stream, _)) = listener.accept().await {
-            let io = TokioIo::new(stream);
-            let service = self.clone();
-            tokio::spawn(async move {
-                if let Err(err) =
-                    http1::Builder::new().serve_connection(io, service).with_upgrades().await
-                {
-                    error!("Error serving connection: {:?}", err);
+        self.serve_metrics(listener.into()).await
+    }
+
+    /// Serve `GET /metrics` on `listener`, and nothing else of the directory.
+    pub async fn serve_metrics(&self, listener: Listener) -> Result<(), BoxError> {
+        let mut connections = tokio::task::JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let Ok((stream, _)) = accepted else { break };
+                    let service = self.clone();
+                    connections.spawn(async move {
+                        let shutdown = service.shutdown.clone();
+                        // Nothing but the metrics, which aren't rate limited, is served here
+                        let metrics = hyper::service::service_fn(move |req| {
+                            let service = service.clone();
+                            async move {
+                                Ok::<_, Infallible>(service.serve_metrics_request(req).await)
+                            }
+                        });
+                        if let Err(err) =
+                            serve_connection(TokioIo::new(stream), metrics, false, &shutdown).await
+                        {
+                            error!("Error serving connection: {:?}", err);
+                        }
+                    });
                 }
-            });
+                // Reap finished connections
+                Some(_) = connections.join_next() => {}
+                () = self.shutdown.triggered() => break,
+            }
         }
-
-        Ok(())
+        self.close(connections).await
     }
-}
 
-fn handle_peek<Error: db::SendableError>(
-    result: Result<Arc<Vec<u8>>, db::Error<Error>>,
-    timeout_response: Response<BoxBody<Bytes, hyper::Error>>,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError> {
-    match result {
-        Ok(payload) => Ok(Response::new(full((*payload).clone()))), // TODO Bytes instead of Arc<Vec<u8>>
-        Err(e) => match e {
-            db::Error::Operational(err) => {
-                error!("Storage error: {err}");
-                Err(HandlerError::InternalServerError(anyhow::Error::msg("Internal server error")))
-            }
-            db::Error::Timeout(_) => Ok(timeout_response),
-            db::Error::OverCapacity => Err(HandlerError::ServiceUnavailable(anyhow::Error::msg(
-                "mailbox storage at capacity",
-            ))),
-            db::Error::V1SenderUnavailable => Err(HandlerError::SenderGone(anyhow::Error::msg(
-                "Sender is unavailable try a new request",
-            ))),
-        },
+    async fn serve_metrics_request(
+        &self,
+        req: Request<Incoming>,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/metrics") => self.handle_metrics().await,
+            _ => not_found(),
+        }
     }
 }
 
@@ -492,6 +831,12 @@ enum HandlerError {
     SenderGone(anyhow::Error),
     OhttpKeyRejection(anyhow::Error),
     BadRequest(anyhow::Error),
+    Forbidden(anyhow::Error),
+    /// A required access token was missing, invalid or already spent
+    Unauthorized,
+    BadGateway(anyhow::Error),
+    /// A rate limit was exceeded, retry after the given delay
+    RateLimited(Duration),
 }
 
 impl HandlerError {
@@ -524,6 +869,25 @@ impl HandlerError {
                 warn!("Bad request: {}", e);
                 *res.status_mut() = StatusCode::BAD_REQUEST
             }
+            HandlerError::Forbidden(e) => {
+                warn!("Forbidden: {}", e);
+                *res.status_mut() = StatusCode::FORBIDDEN
+            }
+            HandlerError::Unauthorized => {
+                debug!("Unauthorized: access token rejected");
+                *res.status_mut() = StatusCode::UNAUTHORIZED;
+                res.headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static(AUTHORIZATION_SCHEME));
+            }
+            HandlerError::BadGateway(e) => {
+                error!("Bad gateway: {}", e);
+                *res.status_mut() = StatusCode::BAD_GATEWAY
+            }
+            HandlerError::RateLimited(retry_after) => {
+                debug!("Rate limited, retry after {:?}", retry_after);
+                *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                res.headers_mut().insert(RETRY_AFTER, retry_after_header(*retry_after));
+            }
         };
 
         res
@@ -540,6 +904,23 @@ impl From<ShortIdError> for HandlerError {
     }
 }
 
+/// v1 senders expect BIP 78 error JSON rather than an empty 503
+fn v1_unavailable_response(
+    retry_after: Duration,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::http::Error> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(RETRY_AFTER, retry_after_header(retry_after))
+        .body(full(V1_UNAVAILABLE_RES_JSON))
+}
+
+/// Round up to whole seconds, capped at a day for limits which never refill
+fn retry_after_header(retry_after: Duration) -> HeaderValue {
+    const MAX_RETRY_AFTER_SECS: u64 = 60 * 60 * 24;
+    let secs = retry_after.as_secs().saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+    HeaderValue::from(secs.clamp(1, MAX_RETRY_AFTER_SECS))
+}
+
 fn not_found() -> Response<BoxBody<Bytes, hyper::Error>> {
     let mut res = Response::default();
     *res.status_mut() = StatusCode::NOT_FOUND;
@@ -553,3 +934,296 @@ fn empty() -> BoxBody<Bytes, hyper::Error> {
 fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
     Full::new(chunk.into()).map_err(|never| match never {}).boxed()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    async fn service(limits: RateLimits) -> Service<MemoryDb> {
+        let db = MemoryDb::init(Duration::from_millis(10)).await.expect("db");
+        let ohttp = gen_ohttp_server_config().expect("ohttp config");
+        Service::new(db, ohttp.into(), Metrics::new()).with_rate_limits(limits)
+    }
+
+    async fn spawn_directory(limits: RateLimits) -> SocketAddr {
+        spawn_service(service(limits).await).await
+    }
+
+    async fn spawn_service(service: Service<MemoryDb>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        tokio::spawn(service.serve_tcp(listener));
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_v1_rate_limited_per_address() {
+        let directory = spawn_directory(RateLimits {
+            v1_per_address: Some(RateLimit { burst: 1, per_second: 0.0 }),
+            ..RateLimits::default()
+        })
+        .await;
+        let url = format!("http://{directory}/{}?v=1", ShortId([0u8; 8]));
+
+        // Without a receiver the first request times out as unavailable
+        let res = reqwest::Client::new().post(&url).body("psbt").send().await.expect("response");
+        assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.headers().get(RETRY_AFTER).is_none());
+
+        // A new connection from the same address shares its limit
+        let res = reqwest::Client::new().post(&url).body("psbt").send().await.expect("response");
+        assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.headers().get(RETRY_AFTER).is_some());
+        assert!(res.text().await.expect("body").contains("unavailable"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_listener_serves_only_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let service = service(RateLimits::default()).await;
+        tokio::spawn(async move { service.serve_metrics_tcp(listener).await });
+        let client = reqwest::Client::new();
+
+        let res = client.get(format!("http://{addr}/metrics")).send().await.expect("response");
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        for path in ["health", "ohttp-keys", ".well-known/payjoin-token-issuer"] {
+            let res = client.get(format!("http://{addr}/{path}")).send().await.expect("response");
+            assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND, "{path}");
+        }
+        let res = client
+            .post(format!("http://{addr}/.well-known/ohttp-gateway"))
+            .body(vec![0u8; 16])
+            .send()
+            .await
+            .expect("response");
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_ohttp_rate_limited_per_connection() {
+        let directory = spawn_directory(RateLimits {
+            ohttp_per_connection: Some(RateLimit { burst: 1, per_second: 0.0 }),
+            ..RateLimits::default()
+        })
+        .await;
+        let url = format!("http://{directory}/.well-known/ohttp-gateway");
+        let client = reqwest::Client::new();
+
+        // Garbage is rejected by the gateway, but still counts against the limit
+        let res = client.post(&url).body(vec![0u8; 16]).send().await.expect("response");
+        assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let res = client.post(&url).body(vec![0u8; 16]).send().await.expect("response");
+        assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers().get(RETRY_AFTER).map(HeaderValue::as_bytes), Some(&b"86400"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_mailbox_post_requires_access_token() {
+        use payjoin::directory::token::{IssuerPublicKey, TokenRequest};
+
+        let service = service(RateLimits {
+            token_issuance_per_address: Some(RateLimit { burst: 1, per_second: 0.0 }),
+            ..RateLimits::default()
+        })
+        .await
+        .with_access_tokens(Some(TokenIssuer::default()));
+        let directory = spawn_service(service.clone()).await;
+        let url = format!("http://{directory}/.well-known/payjoin-token-issuer");
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.expect("response");
+        let issuer = IssuerPublicKey::from_bytes(&res.bytes().await.expect("body"))
+            .expect("valid issuer key");
+        let request = TokenRequest::new(1).expect("valid batch size");
+        let res = client.post(&url).body(request.body()).send().await.expect("response");
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let token = request
+            .finalize(&res.bytes().await.expect("body"), &issuer)
+            .expect("proof should verify")
+            .remove(0);
+        let res = client.post(&url).body(vec![2u8; 33]).send().await.expect("response");
+        assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+        let post = |token: Option<&AccessToken>| {
+            let mut req =
+                Request::builder().method(Method::POST).uri(format!("/{}", ShortId([0u8; 8])));
+            if let Some(token) = token {
+                req = req.header(AUTHORIZATION, token.to_header_value());
+            }
+            req.body(full("payload")).expect("valid request")
+        };
+        let err = service.handle_v2(post(None)).await.expect_err("token is required");
+        let res = err.to_response();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers().get(WWW_AUTHENTICATE).map(HeaderValue::as_bytes),
+            Some(&b"PrivateToken"[..])
+        );
+
+        let invalid = Request::builder()
+            .method(Method::POST)
+            .uri("/not-a-mailbox-id")
+            .header(AUTHORIZATION, token.to_header_value())
+            .body(full("payload"))
+            .expect("valid request");
+        assert!(service.handle_v2(invalid).await.is_err());
+        let res = service
+            .handle_v2(post(Some(&token)))
+            .await
+            .expect("token is accepted after a rejected write");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(
+            service.handle_v2(post(Some(&token))).await.is_err(),
+            "tokens must not be redeemed twice"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mailbox_delete_requires_signature() {
+        use bitcoin::secp256k1::SecretKey;
+
+        let service = service(RateLimits::default()).await;
+        let owner = DeleteAuthorization::sign(&SecretKey::from_slice(&[7u8; 32]).expect("key"));
+        let other = DeleteAuthorization::sign(&SecretKey::from_slice(&[8u8; 32]).expect("key"));
+        let id = owner.mailbox_id();
+        let request = |method: Method, body: Vec<u8>| {
+            Request::builder()
+                .method(method)
+                .uri(format!("/{id}"))
+                .body(full(body))
+                .expect("valid request")
+        };
+
+        let res = service.handle_v2(request(Method::POST, b"payload".to_vec())).await;
+        assert_eq!(res.expect("post succeeds").status(), StatusCode::OK);
+
+        let err = service
+            .handle_v2(request(Method::DELETE, other.to_bytes().to_vec()))
+            .await
+            .expect_err("another key can't delete the mailbox");
+        assert_eq!(err.to_response().status(), StatusCode::FORBIDDEN);
+        let err = service
+            .handle_v2(request(Method::DELETE, Vec::new()))
+            .await
+            .expect_err("an authorization is required");
+        assert_eq!(err.to_response().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(service.db.stats().await.len(), 1, "mailbox is still stored");
+
+        for _ in 0..2 {
+            let res = service.handle_v2(request(Method::DELETE, owner.to_bytes().to_vec())).await;
+            assert_eq!(res.expect("owner may delete").status(), StatusCode::OK);
+        }
+        assert!(service.db.stats().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ohttp_gateway_over_h2c() {
+        use hyper::client::conn::http2;
+        use hyper_util::rt::TokioExecutor;
+
+        let ohttp: ohttp::Server = gen_ohttp_server_config().expect("ohttp config").into();
+        let key_config = ohttp.config().clone();
+        let db = MemoryDb::init(Duration::from_millis(10)).await.expect("db");
+        let service = Service::new(db, ohttp, Metrics::new()).with_h2c(true);
+        let directory = spawn_service(service).await;
+
+        let stream = TcpStream::connect(directory).await.expect("connect");
+        let (mut sender, connection) =
+            http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await.expect("handshake");
+        tokio::spawn(connection);
+
+        // Post to a mailbox and read it back over the same connection
+        let mailbox = format!("/{}", ShortId([0u8; 8]));
+        for (method, body) in [("POST", &b"payload"[..]), ("GET", &b""[..])] {
+            let mut bhttp_req = bhttp::Message::request(
+                method.as_bytes().to_vec(),
+                b"http".to_vec(),
+                directory.to_string().into_bytes(),
+                mailbox.as_bytes().to_vec(),
+            );
+            bhttp_req.write_content(body);
+            let mut bhttp_bytes = Vec::new();
+            bhttp_req.write_bhttp(bhttp::Mode::KnownLength, &mut bhttp_bytes).expect("bhttp");
+            let (ohttp_req, ohttp_ctx) = ohttp::ClientRequest::from_config(&mut key_config.clone())
+                .expect("client request")
+                .encapsulate(&bhttp_bytes)
+                .expect("encapsulate");
+
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(format!("http://{directory}/.well-known/ohttp-gateway"))
+                .body(full(ohttp_req))
+                .expect("valid request");
+            let res = sender.send_request(req).await.expect("response");
+            assert_eq!(res.version(), hyper::Version::HTTP_2);
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let ohttp_res = res.into_body().collect().await.expect("body").to_bytes();
+            let bhttp_res = ohttp_ctx.decapsulate(&ohttp_res).expect("decapsulate");
+            let res = bhttp::Message::read_bhttp(&mut std::io::Cursor::new(bhttp_res))
+                .expect("bhttp response");
+            assert_eq!(res.control().status().map(|status| status.code()), Some(200));
+            if method == "GET" {
+                assert_eq!(res.content(), b"payload");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_answers_pending_v1_requests() {
+        let shutdown = Shutdown::new(Duration::from_secs(1));
+        let db = MemoryDb::init(Duration::from_secs(60))
+            .await
+            .expect("db")
+            .with_shutdown(shutdown.clone());
+        let ohttp = gen_ohttp_server_config().expect("ohttp config");
+        let service =
+            Service::new(db, ohttp.into(), Metrics::new()).with_shutdown(shutdown.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let directory = listener.local_addr().expect("local addr");
+        let server = tokio::spawn(service.serve_tcp(listener));
+
+        let url = format!("http://{directory}/{}?v=1", ShortId([0u8; 8]));
+        let v1_sender = tokio::spawn(reqwest::Client::new().post(url).body("psbt").send());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.trigger();
+
+        let res = v1_sender.await.expect("task should not panic").expect("response");
+        assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.text().await.expect("body").contains("unavailable"));
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("server should stop within the deadline")
+            .expect("task should not panic")
+            .expect("server should stop cleanly");
+        assert!(TcpStream::connect(directory).await.is_err(), "new connections are refused");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_metrics_connections() {
+        let shutdown = Shutdown::new(Duration::from_secs(1));
+        let service = service(RateLimits::default()).await.with_shutdown(shutdown.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let server = tokio::spawn(async move { service.serve_metrics_tcp(listener).await });
+
+        // Keep a connection open across the shutdown
+        let client = reqwest::Client::new();
+        let res = client.get(format!("http://{addr}/metrics")).send().await.expect("response");
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        shutdown.trigger();
+
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("metrics server should stop within the deadline")
+            .expect("task should not panic")
+            .expect("metrics server should stop cleanly");
+        assert!(TcpStream::connect(addr).await.is_err(), "new connections are refused");
+    }
+}
@@ -0,0 +1,369 @@
+//! Listeners for TCP, Unix domain sockets and systemd socket activation.
+//!
+//! Listen addresses are written as `[::]:8080`, `unix:/run/payjoin/directory.sock`,
+//! `sd-listen` for the next socket passed by systemd, or `sd-listen:NAME` for
+//! the socket with `FileDescriptorName=NAME`.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::{fmt, io};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// Unix sockets are readable and writable by their owner and group, e.g. a
+/// reverse proxy sharing the directory's group, unless configured otherwise.
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+
+/// Where to accept connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// A TCP socket address, with a host name or IP address and a port
+    Tcp(String),
+    /// A Unix domain socket at this path
+    Unix(PathBuf),
+    /// A socket passed by systemd, by `FileDescriptorName=` or else the next unused one
+    Systemd(Option<String>),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("Unix socket path must not be empty".to_string());
+            }
+            Ok(Self::Unix(path.into()))
+        } else if s == "sd-listen" {
+            Ok(Self::Systemd(None))
+        } else if let Some(name) = s.strip_prefix("sd-listen:") {
+            Ok(Self::Systemd(Some(name.to_string())))
+        } else if s.is_empty() {
+            Err("Listen address must not be empty".to_string())
+        } else {
+            Ok(Self::Tcp(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Systemd(None) => "sd-listen".fmt(f),
+            Self::Systemd(Some(name)) => write!(f, "sd-listen:{name}"),
+        }
+    }
+}
+
+/// A bound listener of any supported kind.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The path is removed when the listener is dropped, unless the socket
+    /// was passed by systemd
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// Bind to `addr`. Unix sockets created by the directory get `unix_mode`
+    /// permissions.
+    pub async fn bind(addr: &ListenAddr, unix_mode: u32) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => unix::bind(path, unix_mode),
+            #[cfg(unix)]
+            ListenAddr::Systemd(name) => unix::from_systemd(name.as_deref()),
+            #[cfg(not(unix))]
+            _ => {
+                let _ = unix_mode;
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{addr} is only supported on unix"),
+                ))
+            }
+        }
+    }
+
+    /// Whether accepted connections come with a peer address, which
+    /// per-address rate limits need.
+    pub(crate) fn has_peer_addresses(&self) -> bool {
+        match self {
+            Self::Tcp(_) => true,
+            #[cfg(unix)]
+            Self::Unix(..) => false,
+        }
+    }
+
+    /// Accept a connection, along with the peer address for TCP connections.
+    pub(crate) async fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Self::Tcp(listener) =>
+                listener.accept().await.map(|(stream, peer)| (Stream::Tcp(stream), Some(peer))),
+            #[cfg(unix)]
+            Self::Unix(listener, _) =>
+                listener.accept().await.map(|(stream, _)| (Stream::Unix(stream), None)),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self { Self::Tcp(listener) }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Self::Unix(_, Some(path)) = self {
+            _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A connection accepted by a [`Listener`].
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::collections::BTreeSet;
+    use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// The first file descriptor passed by systemd, see sd_listen_fds(3)
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    pub(super) fn bind(path: &Path, mode: u32) -> io::Result<Listener> {
+        // Replace a socket left behind by an unclean exit, but never a socket
+        // in use or another kind of file
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use", path.display()),
+                    ));
+                }
+                std::fs::remove_file(path)?;
+            }
+            Ok(_) =>
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        // The socket would be created with umask permissions, so bind it in a
+        // directory only we can enter and move it into place once its mode is set
+        let private_dir = path
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(format!(".payjoin-directory-{}", std::process::id()));
+        std::fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+        let bound = bind_in(&private_dir, path, mode);
+        _ = std::fs::remove_dir_all(&private_dir);
+        Ok(Listener::Unix(bound?, Some(path.to_owned())))
+    }
+
+    fn bind_in(private_dir: &Path, path: &Path, mode: u32) -> io::Result<UnixListener> {
+        let private_path = private_dir.join("s");
+        let listener = UnixListener::bind(&private_path)?;
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    }
+
+    pub(super) fn from_systemd(name: Option<&str>) -> io::Result<Listener> {
+        let fd = take_systemd_fd(name)?;
+
+        // Only a Unix socket has a Unix socket address
+        let unix = std::os::unix::net::UnixListener::from(fd);
+        if unix.local_addr().is_ok() {
+            unix.set_nonblocking(true)?;
+            return Ok(Listener::Unix(UnixListener::from_std(unix)?, None));
+        }
+        let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+        tcp.set_nonblocking(true)?;
+        Ok(Listener::Tcp(TcpListener::from_std(tcp)?))
+    }
+
+    /// Take ownership of a listening socket passed by systemd, by name or else
+    /// the first one not taken yet.
+    fn take_systemd_fd(name: Option<&str>) -> io::Result<OwnedFd> {
+        static TAKEN: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+
+        let env = |var| std::env::var(var).ok();
+        let not_activated =
+            || io::Error::new(io::ErrorKind::NotFound, "No sockets were passed by systemd");
+        if env("LISTEN_PID").and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+            return Err(not_activated());
+        }
+        let count: usize =
+            env("LISTEN_FDS").and_then(|count| count.parse().ok()).ok_or_else(not_activated)?;
+        let names = env("LISTEN_FDNAMES").unwrap_or_default();
+        let names: Vec<&str> = names.split(':').collect();
+
+        let mut taken = TAKEN.lock().expect("Lock should not be poisoned");
+        let index = (0..count)
+            .filter(|index| !taken.contains(index))
+            .find(|&index| name.is_none_or(|name| names.get(index) == Some(&name)))
+            .ok_or_else(|| match name {
+                Some(name) => io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No unused socket named {name} was passed by systemd"),
+                ),
+                None => io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "All sockets passed by systemd are already in use",
+                ),
+            })?;
+        taken.insert(index);
+
+        // SAFETY: systemd passes ownership of these descriptors to this
+        // process, and each one is only taken once
+        Ok(unsafe { OwnedFd::from_raw_fd(SD_LISTEN_FDS_START + index as RawFd) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!("[::]:8080".parse(), Ok(ListenAddr::Tcp("[::]:8080".to_string())));
+        assert_eq!(
+            "unix:/run/payjoin/directory.sock".parse(),
+            Ok(ListenAddr::Unix("/run/payjoin/directory.sock".into()))
+        );
+        assert_eq!("sd-listen".parse(), Ok(ListenAddr::Systemd(None)));
+        assert_eq!("sd-listen:metrics".parse(), Ok(ListenAddr::Systemd(Some("metrics".into()))));
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("".parse::<ListenAddr>().is_err());
+
+        for addr in ["localhost:9090", "unix:/tmp/directory.sock", "sd-listen", "sd-listen:x"] {
+            assert_eq!(addr.parse::<ListenAddr>().map(|addr| addr.to_string()), Ok(addr.into()));
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("directory.sock");
+        let addr = ListenAddr::Unix(path.clone());
+
+        let listener = Listener::bind(&addr, 0o600).await?;
+        assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1, "the private directory is removed");
+
+        let mut client = UnixStream::connect(&path).await?;
+        let (mut stream, peer) = listener.accept().await?;
+        assert_eq!(peer, None);
+        client.write_all(b"ping").await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+
+        assert_eq!(
+            Listener::bind(&addr, 0o600).await.map(|_| ()).map_err(|e| e.kind()),
+            Err(io::ErrorKind::AddrInUse),
+            "a socket in use must not be replaced"
+        );
+
+        drop(listener);
+        assert!(!path.exists(), "the socket should be removed with its listener");
+        drop(Listener::bind(&addr, 0o600).await?);
+
+        std::fs::write(&path, "not a socket")?;
+        assert!(Listener::bind(&addr, 0o600).await.is_err(), "other files must not be replaced");
+        Ok(())
+    }
+}
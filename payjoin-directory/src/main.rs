@@ -2,6 +2,7 @@ use clap::Parser;
 use payjoin_directory::metrics::Metrics;
 use payjoin_directory::*;
 use tokio::net::TcpListener;
+use tracing::info;
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::EnvFilter;
 
@@ -10,30 +11,149 @@ async fn main() -> Result<(), BoxError> {
     init_logging();
 
     let cli = cli::Cli::parse();
+    if let Some(cli::Command::Keygen { rotate }) = cli.command {
+        // The key directory is the only configuration needed
+        let path = admin::keygen(&cli.ohttp_keys, rotate)?;
+        println!("Wrote key configuration to {}", path.display());
+        return Ok(());
+    }
     let config = config::Config::new(&cli)?;
 
-    let key_dir = config.ohttp_keys;
-    std::fs::create_dir_all(&key_dir).expect("Failed to create key directory");
+    match cli.command {
+        None => serve(config).await,
+        Some(cli::Command::Keygen { .. }) => unreachable!("handled without a config"),
+        Some(cli::Command::Inspect { show_ids }) => {
+            print!("{}", admin::inspect(&config, show_ids).await?);
+            Ok(())
+        }
+        Some(cli::Command::Prune) => {
+            print!("{}", admin::prune(&config).await?);
+            Ok(())
+        }
+        Some(cli::Command::Verify) => {
+            let verification = admin::verify(&config).await?;
+            print!("{verification}");
+            if !verification.is_ok() {
+                return Err("Storage verification failed".into());
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn serve(config: config::Config) -> Result<(), BoxError> {
+    let key_dir = &config.ohttp_keys;
+    std::fs::create_dir_all(key_dir).expect("Failed to create key directory");
 
-    let ohttp = match key_config::read_server_config(&key_dir) {
+    let ohttp = match key_config::read_server_config(key_dir) {
         Ok(config) => config,
         Err(_) => {
             let ohttp_config = key_config::gen_ohttp_server_config()?;
-            let path = key_config::persist_new_key_config(ohttp_config, &key_dir)?;
+            let path = key_config::persist_new_key_config(ohttp_config, key_dir)?;
             println!("Generated new key configuration at {}", path.display());
-            key_config::read_server_config(&key_dir).expect("Failed to read newly generated config")
+            key_config::read_server_config(key_dir).expect("Failed to read newly generated config")
+        }
+    };
+
+    let shutdown = Shutdown::new(config.shutdown_timeout);
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutting down");
+            shutdown.trigger();
+        }
+    });
+
+    let relay = match &config.relay {
+        Some(relay_config) => {
+            let relay = Relay::new(&relay_config.gateways)?.with_shutdown(shutdown.clone());
+            let relay_listener = TcpListener::bind(&relay_config.listen_addr).await?;
+            Some(tokio::spawn(relay.serve_tcp(relay_listener)))
+        }
+        None => None,
+    };
+
+    let listener = Listener::bind(&config.listen_addr, config.unix_socket_mode).await?;
+    let metrics_listener =
+        Listener::bind(&config.metrics_listen_addr, config.unix_socket_mode).await?;
+    let served = match config.db_backend {
+        config::DbBackend::Files => {
+            let at_rest = AtRest {
+                key: config.storage_key.as_ref().map(|source| source.load()).transpose()?,
+                secure_delete: config.secure_delete,
+            };
+            let db = FilesDb::init_with(config.timeout, config.storage_dir.clone(), at_rest)
+                .await
+                .expect("Failed to initialize persistent storage");
+            run(db, &config, ohttp, shutdown.clone(), listener, metrics_listener).await
+        }
+        config::DbBackend::Memory => {
+            let db = MemoryDb::init(config.timeout)
+                .await
+                .expect("Failed to initialize in-memory storage");
+            run(db, &config, ohttp, shutdown.clone(), listener, metrics_listener).await
+        }
+        config::DbBackend::Sqlite => {
+            std::fs::create_dir_all(&config.storage_dir)
+                .expect("Failed to create storage directory");
+            let db = SqliteDb::init(config.timeout, config.sqlite_path())
+                .await
+                .expect("Failed to initialize SQLite storage");
+            run(db, &config, ohttp, shutdown.clone(), listener, metrics_listener).await
         }
     };
 
+    // The directory may have stopped on an accept error rather than a signal
+    shutdown.trigger();
+    if let Some(relay) = relay {
+        relay.await??;
+    }
+    served
+}
+
+/// Serve the directory on `listener` whichever storage backs its mailboxes,
+/// until `shutdown` is triggered
+async fn run<S: Storage>(
+    db: MailboxDb<S>,
+    config: &config::Config,
+    ohttp: ServerKeyConfig,
+    shutdown: Shutdown,
+    listener: Listener,
+    metrics_listener: Listener,
+) -> Result<(), BoxError> {
     let metrics = Metrics::new();
-    let db = payjoin_directory::FilesDb::init(config.timeout, config.storage_dir)
+    let db = db
+        .with_policy(config.mailbox_policy.clone())
         .await
-        .expect("Failed to initialize persistent storage");
-
-    let service = Service::new(db, ohttp.into(), metrics);
+        .with_metrics(metrics.clone())
+        .await
+        .with_shutdown(shutdown.clone());
+    Service::new(db, ohttp.into(), metrics)
+        .with_rate_limits(config.rate_limits.clone())
+        .with_access_tokens(config.access_token_key_rotation.map(TokenIssuer::new))
+        .with_shutdown(shutdown)
+        .with_h2c(config.h2c)
+        .serve_with_metrics(listener, metrics_listener)
+        .await
+}
 
-    let listener = TcpListener::bind(config.listen_addr).await?;
-    service.serve_tcp(listener).await
+/// Resolves on SIGINT, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm =
+            signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        _ = tokio::signal::ctrl_c().await;
+    }
 }
 
 fn init_logging() {
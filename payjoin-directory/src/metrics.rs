@@ -1,9 +1,57 @@
-use prometheus::{Encoder, IntCounter, Registry, TextEncoder};
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+
+// Labels are restricted to the small fixed sets below. Mailbox IDs must never
+// be used as label values, since that would let anyone scraping the metrics
+// link mailbox activity.
+
+/// Protocol version label values
+pub const V1: &str = "v1";
+pub const V2: &str = "v2";
+
+/// Prune reason label values
+pub const PRUNE_EXPIRED: &str = "expired";
+pub const PRUNE_READ: &str = "read";
+pub const PRUNE_CAPACITY: &str = "capacity";
+
+/// Rate limit label values
+pub const LIMIT_V1_ADDRESS: &str = "v1_address";
+pub const LIMIT_OHTTP_CONNECTION: &str = "ohttp_connection";
+pub const LIMIT_LONG_POLL_WAITERS: &str = "long_poll_waiters";
+pub const LIMIT_TOKEN_ISSUANCE: &str = "token_issuance";
 
 #[derive(Debug, Clone)]
 pub struct Metrics {
     /// Total number of connections accepted by the directory
     pub connections_total: IntCounter,
+    /// OHTTP requests which could not be decapsulated
+    pub gateway_decapsulation_failures_total: IntCounter,
+    /// Mailbox writes by protocol version
+    pub mailbox_posts_total: IntCounterVec,
+    /// Mailbox payloads delivered to a reader by protocol version
+    pub mailbox_reads_total: IntCounterVec,
+    /// Long polls which timed out without a payload by protocol version
+    pub long_poll_timeouts_total: IntCounterVec,
+    /// Requests rejected because mailbox storage was at capacity
+    pub over_capacity_total: IntCounter,
+    /// v1 responses posted after the v1 sender stopped waiting
+    pub v1_sender_unavailable_total: IntCounter,
+    /// Requests rejected by a rate limit by limit
+    pub rate_limited_total: IntCounterVec,
+    /// Mailbox writes rejected for lack of a valid access token
+    pub access_token_rejections_total: IntCounter,
+    /// Size of posted mailbox payloads by protocol version
+    pub payload_size_bytes: HistogramVec,
+    /// Number of times mailboxes were pruned
+    pub prune_runs_total: IntCounter,
+    /// Mailboxes removed while pruning by reason
+    pub pruned_mailboxes_total: IntCounterVec,
+    /// Current number of pending or populated mailboxes
+    pub mailboxes: IntGauge,
+    /// Request handling latency by route
+    pub request_duration_seconds: HistogramVec,
     registry: Registry,
 }
 
@@ -17,17 +65,181 @@ impl Metrics {
         let connections_total =
             IntCounter::new("connections_total", "Total number of tcp connections")
                 .expect("Failed to create connections_total metrics ");
+        let gateway_decapsulation_failures_total = IntCounter::new(
+            "gateway_decapsulation_failures_total",
+            "Total number of OHTTP requests which failed to decapsulate",
+        )
+        .expect("Failed to create gateway_decapsulation_failures_total metrics");
+        let mailbox_posts_total = IntCounterVec::new(
+            Opts::new("mailbox_posts_total", "Total number of mailbox writes"),
+            &["version"],
+        )
+        .expect("Failed to create mailbox_posts_total metrics");
+        let mailbox_reads_total = IntCounterVec::new(
+            Opts::new("mailbox_reads_total", "Total number of mailbox payloads delivered"),
+            &["version"],
+        )
+        .expect("Failed to create mailbox_reads_total metrics");
+        let long_poll_timeouts_total = IntCounterVec::new(
+            Opts::new("long_poll_timeouts_total", "Total number of long polls which timed out"),
+            &["version"],
+        )
+        .expect("Failed to create long_poll_timeouts_total metrics");
+        let over_capacity_total = IntCounter::new(
+            "over_capacity_total",
+            "Total number of requests rejected due to lack of mailbox capacity",
+        )
+        .expect("Failed to create over_capacity_total metrics");
+        let v1_sender_unavailable_total = IntCounter::new(
+            "v1_sender_unavailable_total",
+            "Total number of v1 responses posted after the sender stopped waiting",
+        )
+        .expect("Failed to create v1_sender_unavailable_total metrics");
+        let rate_limited_total = IntCounterVec::new(
+            Opts::new("rate_limited_total", "Total number of requests rejected by a rate limit"),
+            &["limit"],
+        )
+        .expect("Failed to create rate_limited_total metrics");
+        let access_token_rejections_total = IntCounter::new(
+            "access_token_rejections_total",
+            "Total number of mailbox writes rejected for lack of a valid access token",
+        )
+        .expect("Failed to create access_token_rejections_total metrics");
+        let payload_size_bytes = HistogramVec::new(
+            HistogramOpts::new("payload_size_bytes", "Size of posted mailbox payloads")
+                .buckets(exponential_buckets(256.0, 2.0, 10).expect("valid buckets")),
+            &["version"],
+        )
+        .expect("Failed to create payload_size_bytes metrics");
+        let prune_runs_total =
+            IntCounter::new("prune_runs_total", "Total number of mailbox pruning runs")
+                .expect("Failed to create prune_runs_total metrics");
+        let pruned_mailboxes_total = IntCounterVec::new(
+            Opts::new("pruned_mailboxes_total", "Total number of pruned mailboxes"),
+            &["reason"],
+        )
+        .expect("Failed to create pruned_mailboxes_total metrics");
+        let mailboxes =
+            IntGauge::new("mailboxes", "Current number of pending or populated mailboxes")
+                .expect("Failed to create mailboxes metrics");
+        let request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("request_duration_seconds", "Request handling latency"),
+            &["route"],
+        )
+        .expect("Failed to create request_duration_seconds metrics");
 
         registry
             .register(Box::new(connections_total.clone()))
             .expect("Failed to register connections_total");
+        registry
+            .register(Box::new(gateway_decapsulation_failures_total.clone()))
+            .expect("Failed to register gateway_decapsulation_failures_total");
+        registry
+            .register(Box::new(mailbox_posts_total.clone()))
+            .expect("Failed to register mailbox_posts_total");
+        registry
+            .register(Box::new(mailbox_reads_total.clone()))
+            .expect("Failed to register mailbox_reads_total");
+        registry
+            .register(Box::new(long_poll_timeouts_total.clone()))
+            .expect("Failed to register long_poll_timeouts_total");
+        registry
+            .register(Box::new(over_capacity_total.clone()))
+            .expect("Failed to register over_capacity_total");
+        registry
+            .register(Box::new(v1_sender_unavailable_total.clone()))
+            .expect("Failed to register v1_sender_unavailable_total");
+        registry
+            .register(Box::new(rate_limited_total.clone()))
+            .expect("Failed to register rate_limited_total");
+        registry
+            .register(Box::new(access_token_rejections_total.clone()))
+            .expect("Failed to register access_token_rejections_total");
+        registry
+            .register(Box::new(payload_size_bytes.clone()))
+            .expect("Failed to register payload_size_bytes");
+        registry
+            .register(Box::new(prune_runs_total.clone()))
+            .expect("Failed to register prune_runs_total");
+        registry
+            .register(Box::new(pruned_mailboxes_total.clone()))
+            .expect("Failed to register pruned_mailboxes_total");
+        registry.register(Box::new(mailboxes.clone())).expect("Failed to register mailboxes");
+        registry
+            .register(Box::new(request_duration_seconds.clone()))
+            .expect("Failed to register request_duration_seconds");
 
-        Self { connections_total, registry }
+        Self {
+            connections_total,
+            gateway_decapsulation_failures_total,
+            mailbox_posts_total,
+            mailbox_reads_total,
+            long_poll_timeouts_total,
+            over_capacity_total,
+            v1_sender_unavailable_total,
+            rate_limited_total,
+            access_token_rejections_total,
+            payload_size_bytes,
+            prune_runs_total,
+            pruned_mailboxes_total,
+            mailboxes,
+            request_duration_seconds,
+            registry,
+        }
     }
 
     /// Records a new connection
     pub fn record_connection(&self) { self.connections_total.inc(); }
 
+    /// Records an OHTTP request which failed to decapsulate
+    pub fn record_decapsulation_failure(&self) { self.gateway_decapsulation_failures_total.inc(); }
+
+    /// Records a mailbox write and its payload size
+    pub fn record_post(&self, version: &'static str, size: usize) {
+        self.mailbox_posts_total.with_label_values(&[version]).inc();
+        self.payload_size_bytes.with_label_values(&[version]).observe(size as f64);
+    }
+
+    /// Records a payload delivered to a reader
+    pub fn record_read(&self, version: &'static str) {
+        self.mailbox_reads_total.with_label_values(&[version]).inc();
+    }
+
+    /// Records a long poll which timed out
+    pub fn record_timeout(&self, version: &'static str) {
+        self.long_poll_timeouts_total.with_label_values(&[version]).inc();
+    }
+
+    /// Records a request rejected for lack of capacity
+    pub fn record_over_capacity(&self) { self.over_capacity_total.inc(); }
+
+    /// Records a v1 response with no sender left to receive it
+    pub fn record_v1_sender_unavailable(&self) { self.v1_sender_unavailable_total.inc(); }
+
+    /// Records a request rejected by a rate limit
+    pub fn record_rate_limited(&self, limit: &'static str) {
+        self.rate_limited_total.with_label_values(&[limit]).inc();
+    }
+
+    /// Records a mailbox write without a valid access token
+    pub fn record_access_token_rejection(&self) { self.access_token_rejections_total.inc(); }
+
+    /// Records a pruning run
+    pub fn record_prune(&self) { self.prune_runs_total.inc(); }
+
+    /// Records a mailbox removed while pruning
+    pub fn record_pruned(&self, reason: &'static str) {
+        self.pruned_mailboxes_total.with_label_values(&[reason]).inc();
+    }
+
+    /// Records the current number of mailboxes
+    pub fn set_mailboxes(&self, count: usize) { self.mailboxes.set(count as i64); }
+
+    /// Records how long a request to `route` took
+    pub fn record_request_duration(&self, route: &'static str, elapsed: std::time::Duration) {
+        self.request_duration_seconds.with_label_values(&[route]).observe(elapsed.as_secs_f64());
+    }
+
     pub fn generate_metrics(&self) -> Result<String, Box<dyn std::error::Error>> {
         let encoder = TextEncoder::new();
         let all_metrics = self.registry.gather();
@@ -57,4 +269,26 @@ mod tests {
         let metrics_recorded = metrics.generate_metrics().expect("Failed to generate metrics");
         assert!(metrics_recorded.contains("connections_total 0"));
     }
+
+    #[test]
+    fn test_labeled_metrics() {
+        let metrics = Metrics::new();
+        metrics.record_post(V2, 7168);
+        metrics.record_read(V1);
+        metrics.record_timeout(V2);
+        metrics.record_pruned(PRUNE_READ);
+        metrics.set_mailboxes(3);
+        metrics.record_request_duration("mailbox_get", std::time::Duration::from_millis(5));
+
+        let metrics_recorded = metrics.generate_metrics().expect("Failed to generate metrics");
+        assert!(metrics_recorded.contains("mailbox_posts_total{version=\"v2\"} 1"));
+        assert!(metrics_recorded.contains("payload_size_bytes_sum{version=\"v2\"} 7168"));
+        assert!(metrics_recorded.contains("mailbox_reads_total{version=\"v1\"} 1"));
+        assert!(metrics_recorded.contains("long_poll_timeouts_total{version=\"v2\"} 1"));
+        assert!(metrics_recorded.contains("pruned_mailboxes_total{reason=\"read\"} 1"));
+        assert!(metrics_recorded.contains("mailboxes 3"));
+        assert!(
+            metrics_recorded.contains("request_duration_seconds_count{route=\"mailbox_get\"} 1")
+        );
+    }
 }
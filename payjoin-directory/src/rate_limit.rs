@@ -0,0 +1,250 @@
+//! Token bucket rate limits protecting mailbox capacity from a single client.
+//!
+//! v1 fallback requests are limited per source address. OHTTP requests hide
+//! the client's address from the directory, so they are limited per relay
+//! connection instead. Connections accepted on a Unix socket have no source
+//! address, so only per-connection limits apply to them.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Stop tracking clients with full buckets once this many are tracked, so
+/// that the map can't grow without bound.
+const MAX_TRACKED_CLIENTS: usize = 1 << 16;
+
+/// Allows bursts of up to `burst` requests, refilled at `per_second`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+/// Configurable limits, all disabled by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimits {
+    /// Limit on v1 fallback requests per source IP address
+    pub v1_per_address: Option<RateLimit>,
+    /// Limit on OHTTP requests per relay connection
+    pub ohttp_per_connection: Option<RateLimit>,
+    /// Limit on access token issuance requests per source IP address
+    pub token_issuance_per_address: Option<RateLimit>,
+    /// Maximum number of concurrent long polls, whether v2 reads or v1 senders
+    /// waiting for a response
+    pub max_long_poll_waiters: Option<usize>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self { tokens: limit.burst as f64, updated: now }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// Take a token, or return how long until one is available.
+    fn try_take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if limit.per_second > 0.0 {
+            Err(Duration::try_from_secs_f64((1.0 - self.tokens) / limit.per_second)
+                .unwrap_or(Duration::MAX))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+
+    fn is_full(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        self.tokens >= limit.burst as f64
+    }
+}
+
+/// Token buckets for many clients, keyed by e.g. source address.
+#[derive(Debug)]
+struct KeyedRateLimiter<K> {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K: Eq + Hash> KeyedRateLimiter<K> {
+    fn new(limit: RateLimit) -> Self { Self { limit, buckets: Mutex::default() } }
+
+    fn check(&self, key: K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("Lock should not be poisoned");
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            // A full bucket is indistinguishable from an untracked client
+            buckets.retain(|_, bucket| !bucket.is_full(&self.limit, now));
+        }
+        buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(&self.limit, now))
+            .try_take(&self.limit, now)
+    }
+}
+
+/// Limits shared by every connection to a [`crate::Service`].
+#[derive(Debug, Default)]
+pub(crate) struct Limiter {
+    ohttp_per_connection: Option<RateLimit>,
+    v1_per_address: Option<KeyedRateLimiter<IpAddr>>,
+    token_issuance_per_address: Option<KeyedRateLimiter<IpAddr>>,
+    long_poll_waiters: Option<Arc<Semaphore>>,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: RateLimits) -> Self {
+        Self {
+            ohttp_per_connection: limits.ohttp_per_connection,
+            v1_per_address: limits.v1_per_address.map(KeyedRateLimiter::new),
+            token_issuance_per_address: limits
+                .token_issuance_per_address
+                .map(KeyedRateLimiter::new),
+            long_poll_waiters: limits.max_long_poll_waiters.map(|n| Arc::new(Semaphore::new(n))),
+        }
+    }
+
+    /// Whether any limit is kept per source address.
+    pub(crate) fn has_per_address_limits(&self) -> bool {
+        self.v1_per_address.is_some() || self.token_issuance_per_address.is_some()
+    }
+
+    /// Limits for a new connection, from `peer` unless accepted on a Unix socket.
+    pub(crate) fn connection(&self, peer: Option<SocketAddr>) -> ConnectionLimiter {
+        let ohttp = self
+            .ohttp_per_connection
+            .map(|limit| (limit, Mutex::new(TokenBucket::new(&limit, Instant::now()))));
+        ConnectionLimiter { peer, ohttp }
+    }
+
+    /// Take a v1 fallback request token for `peer`.
+    pub(crate) fn check_v1(&self, peer: SocketAddr) -> Result<(), Duration> {
+        match &self.v1_per_address {
+            Some(limiter) => limiter.check(peer.ip()),
+            None => Ok(()),
+        }
+    }
+
+    /// Take a token issuance request token for `peer`.
+    pub(crate) fn check_token_issuance(&self, peer: SocketAddr) -> Result<(), Duration> {
+        match &self.token_issuance_per_address {
+            Some(limiter) => limiter.check(peer.ip()),
+            None => Ok(()),
+        }
+    }
+
+    /// Reserve a long poll slot, held until the returned permit is dropped.
+    ///
+    /// Returns `Err` if all slots are taken, and `Ok(None)` if long polls are
+    /// unlimited.
+    pub(crate) fn long_poll(&self) -> Result<Option<OwnedSemaphorePermit>, ()> {
+        match &self.long_poll_waiters {
+            Some(waiters) => waiters.clone().try_acquire_owned().map(Some).map_err(|_| ()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Limits for a single client connection.
+#[derive(Debug)]
+pub(crate) struct ConnectionLimiter {
+    peer: Option<SocketAddr>,
+    ohttp: Option<(RateLimit, Mutex<TokenBucket>)>,
+}
+
+impl ConnectionLimiter {
+    pub(crate) fn peer(&self) -> Option<SocketAddr> { self.peer }
+
+    /// Take an OHTTP request token for this connection.
+    pub(crate) fn check_ohttp(&self) -> Result<(), Duration> {
+        match &self.ohttp {
+            Some((limit, bucket)) =>
+                bucket.lock().expect("Lock should not be poisoned").try_take(limit, Instant::now()),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit { burst: 2, per_second: 10.0 };
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(&LIMIT, start);
+        assert!(bucket.try_take(&LIMIT, start).is_ok());
+        assert!(bucket.try_take(&LIMIT, start).is_ok());
+        let retry_after = bucket.try_take(&LIMIT, start).expect_err("burst should be exhausted");
+        assert_eq!(retry_after, Duration::from_millis(100));
+
+        let later = start + Duration::from_millis(100);
+        assert!(bucket.try_take(&LIMIT, later).is_ok());
+        assert!(bucket.try_take(&LIMIT, later).is_err());
+
+        let much_later = later + Duration::from_secs(10);
+        assert!(bucket.is_full(&LIMIT, much_later), "refill should be capped at the burst size");
+    }
+
+    #[test]
+    fn test_limits_per_address() {
+        let limiter = Limiter::new(RateLimits {
+            v1_per_address: Some(RateLimit { burst: 1, per_second: 0.0 }),
+            ..RateLimits::default()
+        });
+        let client: SocketAddr = "192.0.2.1:1234".parse().expect("valid address");
+        let same_client_new_port: SocketAddr = "192.0.2.1:5678".parse().expect("valid address");
+        let other_client: SocketAddr = "192.0.2.2:1234".parse().expect("valid address");
+
+        assert!(limiter.check_v1(client).is_ok());
+        assert!(limiter.check_v1(same_client_new_port).is_err());
+        assert!(limiter.check_v1(other_client).is_ok());
+    }
+
+    #[test]
+    fn test_limits_per_connection() {
+        let limiter = Limiter::new(RateLimits {
+            ohttp_per_connection: Some(RateLimit { burst: 1, per_second: 0.0 }),
+            ..RateLimits::default()
+        });
+        let relay: SocketAddr = "192.0.2.1:1234".parse().expect("valid address");
+
+        let connection = limiter.connection(Some(relay));
+        assert!(connection.check_ohttp().is_ok());
+        assert!(connection.check_ohttp().is_err());
+        assert!(
+            limiter.connection(Some(relay)).check_ohttp().is_ok(),
+            "new connections get a new bucket"
+        );
+    }
+
+    #[test]
+    fn test_long_poll_waiters() {
+        let limiter =
+            Limiter::new(RateLimits { max_long_poll_waiters: Some(1), ..RateLimits::default() });
+        let permit = limiter.long_poll().expect("a slot should be free");
+        assert!(limiter.long_poll().is_err());
+        drop(permit);
+        assert!(limiter.long_poll().is_ok());
+
+        assert!(matches!(Limiter::default().long_poll(), Ok(None)));
+    }
+}
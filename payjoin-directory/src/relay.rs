@@ -0,0 +1,509 @@
+//! An OHTTP relay ([Oblivious Relay Resource](https://www.ietf.org/rfc/rfc9458.html#dfn-relay))
+//! for BIP 77 gateways.
+//!
+//! Clients address the gateway in the request path, e.g. `POST /https://payjo.in/`. The relay
+//! only forwards to gateways on its allow-list which also advertise the BIP 77 purpose at
+//! `/.well-known/ohttp-gateway?allowed_purposes`. For OHTTP key bootstrapping the relay also
+//! answers `CONNECT` to those gateways.
+
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Method, Request, Response, Uri};
+use hyper_util::rt::TokioIo;
+use payjoin::directory::ENCAPSULATED_MESSAGE_BYTES;
+use tokio::net::TcpStream;
+use tracing::{debug, error};
+
+use crate::shutdown::{serve_connection, Shutdown};
+use crate::{empty, full, not_found, BoxError, HandlerError, BIP77_ALLOWED_PURPOSE};
+
+/// The largest gateway response body relayed. Encapsulated responses are padded to exactly
+/// this size, OHTTP nonce and tag included, and error responses are smaller.
+const MAX_GATEWAY_RESPONSE_BYTES: usize = ENCAPSULATED_MESSAGE_BYTES;
+
+/// How long a CONNECT tunnel may stay open. Fetching OHTTP keys takes a single request.
+const CONNECT_TUNNEL_LIFETIME: Duration = Duration::from_secs(30);
+
+/// The origin of an allow-listed OHTTP gateway
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct GatewayOrigin {
+    scheme: String,
+    host: String,
+    port: u16,
+}
+
+impl GatewayOrigin {
+    fn from_uri(uri: &Uri) -> Option<Self> {
+        let scheme = uri.scheme_str()?.to_ascii_lowercase();
+        let default_port = match scheme.as_str() {
+            "https" => 443,
+            "http" => 80,
+            _ => return None,
+        };
+        let authority = uri.authority()?;
+        Some(Self {
+            scheme,
+            host: authority.host().to_ascii_lowercase(),
+            port: authority.port_u16().unwrap_or(default_port),
+        })
+    }
+
+    fn url(&self, path_and_query: &str) -> String {
+        format!("{}://{}:{}{}", self.scheme, self.host, self.port, path_and_query)
+    }
+}
+
+#[derive(Clone)]
+pub struct Relay {
+    gateways: Arc<Vec<GatewayOrigin>>,
+    verified_gateways: Arc<Mutex<HashSet<GatewayOrigin>>>,
+    client: reqwest::Client,
+    shutdown: Shutdown,
+}
+
+impl hyper::service::Service<Request<Incoming>> for Relay {
+    type Response = Response<BoxBody<Bytes, hyper::Error>>;
+    type Error = anyhow::Error;
+    type Future =
+        Pin<Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move { this.serve_request(req).await })
+    }
+}
+
+impl Relay {
+    /// Create a relay forwarding to the given gateway origins, e.g. `https://payjo.in`.
+    pub fn new(gateways: &[String]) -> Result<Self> {
+        if gateways.is_empty() {
+            return Err(anyhow!("The relay requires at least one allowed gateway"));
+        }
+        let gateways = gateways
+            .iter()
+            .map(|gateway| {
+                gateway
+                    .parse::<Uri>()
+                    .ok()
+                    .as_ref()
+                    .and_then(GatewayOrigin::from_uri)
+                    .ok_or_else(|| anyhow!("Invalid gateway origin: {gateway}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            gateways: Arc::new(gateways),
+            verified_gateways: Arc::new(Mutex::new(HashSet::new())),
+            client: reqwest::Client::builder().build()?,
+            shutdown: Shutdown::default(),
+        })
+    }
+
+    /// Stop relaying once `shutdown` is triggered.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn serve_tcp(self, listener: tokio::net::TcpListener) -> Result<(), BoxError> {
+        let mut connections = tokio::task::JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let Ok((stream, _)) = accepted else { break };
+                    let relay = self.clone();
+                    connections.spawn(async move {
+                        let shutdown = relay.shutdown.clone();
+                        if let Err(err) =
+                            serve_connection(TokioIo::new(stream), relay, false, &shutdown).await
+                        {
+                            error!("Error serving relay connection: {:?}", err);
+                        }
+                    });
+                }
+                // Reap finished connections
+                Some(_) = connections.join_next() => {}
+                () = self.shutdown.triggered() => break,
+            }
+        }
+        self.shutdown.drain(connections).await;
+        Ok(())
+    }
+
+    async fn serve_request(
+        &self,
+        req: Request<Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+        debug!("Relay::serve_request: {} {}", req.method(), req.uri());
+        let response = match *req.method() {
+            Method::CONNECT => self.handle_connect(req).await,
+            Method::POST => self.handle_ohttp_relay(req).await,
+            Method::GET if req.uri().path() == "/health" => Ok(Response::new(empty())),
+            _ => Ok(not_found()),
+        }
+        .unwrap_or_else(|e| e.to_response());
+
+        Ok(response)
+    }
+
+    /// Forward an encapsulated request to the gateway named in the request path
+    async fn handle_ohttp_relay(
+        &self,
+        req: Request<Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError> {
+        let target = req.uri().path().trim_start_matches('/');
+        let gateway = target
+            .parse::<Uri>()
+            .ok()
+            .as_ref()
+            .and_then(GatewayOrigin::from_uri)
+            .ok_or_else(|| HandlerError::BadRequest(anyhow!("Invalid gateway: {target}")))?;
+        self.check_gateway(&gateway).await?;
+
+        // Stop reading as soon as the body outgrows an encapsulated message
+        let body = Limited::new(req.into_body(), ENCAPSULATED_MESSAGE_BYTES)
+            .collect()
+            .await
+            .map_err(|e| match e.downcast::<LengthLimitError>() {
+                Ok(_) => HandlerError::PayloadTooLarge,
+                Err(e) => HandlerError::BadRequest(anyhow!(e)),
+            })?
+            .to_bytes();
+
+        // Only the body is forwarded so that nothing identifying the client reaches the gateway
+        let gateway_res = self
+            .client
+            .post(gateway.url("/.well-known/ohttp-gateway"))
+            .header(CONTENT_TYPE, "message/ohttp-req")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| HandlerError::BadGateway(e.into()))?;
+        let status = gateway_res.status();
+        let content_type = gateway_res.headers().get(CONTENT_TYPE).cloned();
+        let body = read_gateway_response(gateway_res).await?;
+
+        let mut res = Response::new(full(body));
+        *res.status_mut() = status;
+        if let Some(content_type) = content_type {
+            res.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        Ok(res)
+    }
+
+    /// Tunnel to a gateway so that clients can fetch its OHTTP keys without revealing their IP
+    async fn handle_connect(
+        &self,
+        req: Request<Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, HandlerError> {
+        let authority = req
+            .uri()
+            .authority()
+            .ok_or_else(|| HandlerError::BadRequest(anyhow!("CONNECT requires an authority")))?;
+        let host = authority.host().to_ascii_lowercase();
+        let port = authority.port_u16().unwrap_or(443);
+        let gateway = self
+            .gateways
+            .iter()
+            .find(|gateway| gateway.host == host && gateway.port == port)
+            .cloned()
+            .ok_or_else(|| HandlerError::Forbidden(anyhow!("{host}:{port} is not allowed")))?;
+        self.check_gateway(&gateway).await?;
+
+        let mut upstream = TcpStream::connect((gateway.host.as_str(), gateway.port))
+            .await
+            .map_err(|e| HandlerError::BadGateway(e.into()))?;
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            let mut upgraded = match hyper::upgrade::on(req).await {
+                Ok(upgraded) => TokioIo::new(upgraded),
+                Err(e) => {
+                    error!("CONNECT upgrade failed: {e}");
+                    return;
+                }
+            };
+            tokio::select! {
+                copied = tokio::io::copy_bidirectional(&mut upgraded, &mut upstream) => {
+                    if let Err(e) = copied {
+                        debug!("CONNECT tunnel closed: {e}");
+                    }
+                }
+                () = tokio::time::sleep(CONNECT_TUNNEL_LIFETIME) => {
+                    debug!("CONNECT tunnel to {host}:{port} timed out");
+                }
+                () = shutdown.triggered() => {}
+            }
+        });
+        Ok(Response::new(empty()))
+    }
+
+    /// Only relay to allow-listed gateways which opted into the BIP 77 purpose
+    async fn check_gateway(&self, gateway: &GatewayOrigin) -> Result<(), HandlerError> {
+        if !self.gateways.contains(gateway) {
+            return Err(HandlerError::Forbidden(anyhow!(
+                "{} is not an allowed gateway",
+                gateway.url("")
+            )));
+        }
+        if self.verified_gateways.lock().expect("Lock should not be poisoned").contains(gateway) {
+            return Ok(());
+        }
+
+        let res = self
+            .client
+            .get(gateway.url("/.well-known/ohttp-gateway?allowed_purposes"))
+            .send()
+            .await
+            .map_err(|e| HandlerError::BadGateway(e.into()))?;
+        let body = read_gateway_response(res).await?;
+        if !allows_bip77(&body) {
+            return Err(HandlerError::BadGateway(anyhow!(
+                "{} does not allow the BIP 77 purpose",
+                gateway.url("")
+            )));
+        }
+
+        self.verified_gateways.lock().expect("Lock should not be poisoned").insert(gateway.clone());
+        Ok(())
+    }
+}
+
+/// Read a gateway's response body, giving up once it outgrows any response worth relaying
+async fn read_gateway_response(res: reqwest::Response) -> Result<Bytes, HandlerError> {
+    Limited::new(reqwest::Body::from(res), MAX_GATEWAY_RESPONSE_BYTES)
+        .collect()
+        .await
+        .map(|body| body.to_bytes())
+        .map_err(|e| HandlerError::BadGateway(anyhow!(e)))
+}
+
+/// Look for the BIP 77 magic in an `application/x-ohttp-allowed-purposes` body: a two byte
+/// prefix followed by U8 length encoded strings.
+fn allows_bip77(allowed_purposes: &[u8]) -> bool {
+    let mut purposes = allowed_purposes.get(2..).unwrap_or_default();
+    while let Some((&len, rest)) = purposes.split_first() {
+        let Some(purpose) = rest.get(..len as usize) else {
+            return false;
+        };
+        if purpose == BIP77_ALLOWED_PURPOSE {
+            return true;
+        }
+        purposes = &rest[len as usize..];
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use hyper::header::HeaderValue;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::{gen_ohttp_server_config, FilesDb, Service};
+
+    async fn spawn_directory() -> (SocketAddr, tempfile::TempDir) {
+        let storage = tempfile::tempdir().expect("tempdir");
+        let db =
+            FilesDb::init(Duration::from_millis(100), storage.path().to_owned()).await.expect("db");
+        let ohttp = gen_ohttp_server_config().expect("ohttp config");
+        let service = Service::new(db, ohttp.into(), Metrics::new());
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        tokio::spawn(service.serve_tcp(listener));
+        (addr, storage)
+    }
+
+    async fn spawn_relay(gateways: &[String]) -> SocketAddr {
+        spawn_relay_until(gateways, Shutdown::default()).await
+    }
+
+    async fn spawn_relay_until(gateways: &[String], shutdown: Shutdown) -> SocketAddr {
+        let relay = Relay::new(gateways).expect("relay").with_shutdown(shutdown);
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        tokio::spawn(relay.serve_tcp(listener));
+        addr
+    }
+
+    #[test]
+    fn test_allows_bip77() {
+        assert!(allows_bip77(b"\x00\x01\x2aBIP77 454403bb-9f7b-4385-b31f-acd2dae20b7e"));
+        assert!(!allows_bip77(b"\x00\x01\x05BIP78"));
+        assert!(!allows_bip77(b"\x00\x01\x2aBIP77"));
+        assert!(!allows_bip77(b""));
+    }
+
+    #[tokio::test]
+    async fn test_relay_forwards_to_allowed_gateway() {
+        let (directory, _storage) = spawn_directory().await;
+        let gateway = format!("http://{directory}");
+        let relay = spawn_relay(std::slice::from_ref(&gateway)).await;
+
+        // The directory cannot decapsulate garbage, so its key rejection is relayed back
+        let res = reqwest::Client::new()
+            .post(format!("http://{relay}/{gateway}/"))
+            .header(CONTENT_TYPE, "message/ohttp-req")
+            .body(vec![0u8; ENCAPSULATED_MESSAGE_BYTES])
+            .send()
+            .await
+            .expect("relay response");
+        assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            res.headers().get(CONTENT_TYPE).map(HeaderValue::as_bytes),
+            Some(&b"application/problem+json"[..])
+        );
+
+        let res = reqwest::Client::new()
+            .post(format!("http://{relay}/http://127.0.0.1:1/"))
+            .body(vec![0u8; ENCAPSULATED_MESSAGE_BYTES])
+            .send()
+            .await
+            .expect("relay response");
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_relay_rejects_oversized_body_early() {
+        let (directory, _storage) = spawn_directory().await;
+        let gateway = format!("http://{directory}");
+        let relay = spawn_relay(std::slice::from_ref(&gateway)).await;
+
+        // Announce far more than is ever sent, so a relay buffering the whole body would hang
+        let mut stream = TcpStream::connect(relay).await.expect("connect to relay");
+        stream
+            .write_all(
+                format!(
+                    "POST /{gateway}/ HTTP/1.1\r\nHost: {relay}\r\nContent-Length: 1000000000\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .expect("write head");
+        stream.write_all(&[0u8; ENCAPSULATED_MESSAGE_BYTES + 1]).await.expect("write body");
+        let mut buf = [0u8; 1024];
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("relay should answer before the body is complete")
+            .expect("read response");
+        assert!(buf[..n].starts_with(b"HTTP/1.1 413"), "oversized body should be rejected");
+    }
+
+    /// A gateway allowing BIP 77 whose OHTTP responses are `response_len` bytes long
+    async fn spawn_gateway(response_len: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 2 * ENCAPSULATED_MESSAGE_BYTES];
+                    let n = stream.read(&mut buf).await.expect("read request");
+                    let body = if buf[..n].starts_with(b"GET") {
+                        let mut purposes = vec![0u8, 1, BIP77_ALLOWED_PURPOSE.len() as u8];
+                        purposes.extend_from_slice(BIP77_ALLOWED_PURPOSE);
+                        purposes
+                    } else {
+                        vec![0u8; response_len]
+                    };
+                    let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                    _ = stream.write_all(head.as_bytes()).await;
+                    _ = stream.write_all(&body).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_relay_rejects_oversized_gateway_response() {
+        for (response_len, status) in [
+            (ENCAPSULATED_MESSAGE_BYTES, reqwest::StatusCode::OK),
+            (ENCAPSULATED_MESSAGE_BYTES + 1, reqwest::StatusCode::BAD_GATEWAY),
+        ] {
+            let gateway = format!("http://{}", spawn_gateway(response_len).await);
+            let relay = spawn_relay(std::slice::from_ref(&gateway)).await;
+            let res = reqwest::Client::new()
+                .post(format!("http://{relay}/{gateway}/"))
+                .body(vec![0u8; ENCAPSULATED_MESSAGE_BYTES])
+                .send()
+                .await
+                .expect("relay response");
+            assert_eq!(res.status(), status, "{response_len} byte response");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_connect_to_allowed_gateway() {
+        let (directory, _storage) = spawn_directory().await;
+        let relay = spawn_relay(&[format!("http://{directory}")]).await;
+
+        let mut stream = TcpStream::connect(relay).await.expect("connect to relay");
+        stream
+            .write_all(
+                format!("CONNECT {directory} HTTP/1.1\r\nHost: {directory}\r\n\r\n").as_bytes(),
+            )
+            .await
+            .expect("write CONNECT");
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.expect("read CONNECT response");
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200"), "CONNECT should be accepted");
+
+        stream
+            .write_all(
+                format!(
+                    "GET /.well-known/ohttp-gateway HTTP/1.1\r\nHost: {directory}\r\nConnection: close\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await
+            .expect("write GET");
+        let mut res = Vec::new();
+        stream.read_to_end(&mut res).await.expect("read tunneled response");
+        let res = String::from_utf8_lossy(&res);
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.contains("application/ohttp-keys"));
+
+        let mut stream = TcpStream::connect(relay).await.expect("connect to relay");
+        stream
+            .write_all(b"CONNECT 127.0.0.1:1 HTTP/1.1\r\nHost: 127.0.0.1:1\r\n\r\n")
+            .await
+            .expect("write CONNECT");
+        let n = stream.read(&mut buf).await.expect("read CONNECT response");
+        assert!(buf[..n].starts_with(b"HTTP/1.1 403"), "CONNECT should be forbidden");
+    }
+
+    #[tokio::test]
+    async fn test_relay_closes_connect_tunnels_on_shutdown() {
+        let (directory, _storage) = spawn_directory().await;
+        let shutdown = Shutdown::new(Duration::from_secs(1));
+        let relay = spawn_relay_until(&[format!("http://{directory}")], shutdown.clone()).await;
+
+        let mut stream = TcpStream::connect(relay).await.expect("connect to relay");
+        stream
+            .write_all(
+                format!("CONNECT {directory} HTTP/1.1\r\nHost: {directory}\r\n\r\n").as_bytes(),
+            )
+            .await
+            .expect("write CONNECT");
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.expect("read CONNECT response");
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200"), "CONNECT should be accepted");
+
+        shutdown.trigger();
+        let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+            .await
+            .expect("the tunnel should close on shutdown")
+            .expect("read after shutdown");
+        assert_eq!(n, 0, "the tunnel should be closed");
+    }
+}
@@ -0,0 +1,123 @@
+//! Graceful shutdown.
+//!
+//! Once triggered, servers stop accepting connections and finish serving the
+//! requests in flight. Pending long polls are answered right away as if they
+//! had timed out, so that draining doesn't take the full long poll timeout.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Bytes, Incoming};
+use hyper::{Request, Response};
+use hyper_util::rt::TokioExecutor;
+use hyper_util::server::conn::auto;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::warn;
+
+use crate::BoxError;
+
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(10);
+
+/// A shutdown signal shared by servers and storage.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    triggered: Arc<watch::Sender<bool>>,
+    deadline: Duration,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self { Self::new(DEFAULT_DEADLINE) }
+}
+
+impl Shutdown {
+    /// Connections still open `deadline` after shutdown was triggered are
+    /// closed abruptly.
+    pub fn new(deadline: Duration) -> Self {
+        Self { triggered: Arc::new(watch::Sender::new(false)), deadline }
+    }
+
+    /// Begin shutting down. Triggering more than once has no further effect.
+    pub fn trigger(&self) { self.triggered.send_replace(true); }
+
+    pub fn is_triggered(&self) -> bool { *self.triggered.borrow() }
+
+    /// Resolves once shutdown was triggered.
+    pub async fn triggered(&self) {
+        let mut receiver = self.triggered.subscribe();
+        // The sender is kept alive by self, so this can't fail
+        _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Wait for `connections` to close, aborting any left after the deadline.
+    pub(crate) async fn drain(&self, mut connections: JoinSet<()>) {
+        let closed = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(self.deadline, closed).await.is_err() {
+            warn!("Shutdown deadline elapsed, closing {} connections", connections.len());
+            connections.shutdown().await;
+        }
+    }
+}
+
+/// Serve an HTTP/1 connection, or also HTTP/2 if `http2`, closing it
+/// gracefully once `shutdown` is triggered.
+pub(crate) async fn serve_connection<I, S>(
+    io: I,
+    service: S,
+    http2: bool,
+    shutdown: &Shutdown,
+) -> Result<(), BoxError>
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+    S: hyper::service::Service<
+            Request<Incoming>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+        > + Send
+        + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    if !http2 {
+        // Skip sniffing for the HTTP/2 preface
+        builder = builder.http1_only();
+    }
+    let connection = builder.serve_connection_with_upgrades(io, service);
+    tokio::pin!(connection);
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            res = connection.as_mut() => return res,
+            () = shutdown.triggered(), if !closing => {
+                // Finish the request in flight, if any, then close
+                connection.as_mut().graceful_shutdown();
+                closing = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trigger() {
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.is_triggered());
+        let waiter = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.triggered().await }
+        });
+
+        shutdown.trigger();
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("waiters should be woken")
+            .expect("waiter should not panic");
+        assert!(shutdown.is_triggered());
+        // Already triggered, so this resolves immediately
+        shutdown.triggered().await;
+    }
+}
@@ -0,0 +1,157 @@
+//! Issuance and redemption of anonymous access tokens for mailbox writes.
+//!
+//! See [`payjoin::directory::token`] for the token scheme. The issuer key is
+//! generated at startup and rotated periodically. Tokens issued under the
+//! previous key remain redeemable for one more rotation period, so that spent
+//! nonces only need to be remembered for two periods.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use payjoin::directory::token::{AccessToken, IssuerPublicKey, IssuerSecretKey, TokenError};
+use rand::RngCore;
+
+pub const DEFAULT_KEY_ROTATION_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+
+#[derive(Debug)]
+struct Epoch {
+    key: IssuerSecretKey,
+    spent: HashSet<[u8; 32]>,
+}
+
+impl Epoch {
+    fn generate() -> Self {
+        let mut rng = rand::rngs::OsRng;
+        loop {
+            let mut bytes = [0u8; 32];
+            rng.fill_bytes(&mut bytes);
+            // Fails only for bytes outside the curve order, which is negligibly likely
+            if let Ok(key) = IssuerSecretKey::from_bytes(bytes) {
+                return Self { key, spent: HashSet::new() };
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Epochs {
+    current: Epoch,
+    previous: Option<Epoch>,
+    rotated_at: Instant,
+}
+
+/// Issues tokens and checks that each is redeemed at most once.
+#[derive(Debug)]
+pub struct TokenIssuer {
+    rotation_interval: Duration,
+    epochs: Mutex<Epochs>,
+}
+
+impl TokenIssuer {
+    pub fn new(rotation_interval: Duration) -> Self {
+        let epochs =
+            Epochs { current: Epoch::generate(), previous: None, rotated_at: Instant::now() };
+        Self { rotation_interval, epochs: Mutex::new(epochs) }
+    }
+
+    /// The public key clients should verify issuance against.
+    pub fn public_key(&self) -> IssuerPublicKey { self.epochs().current.key.public_key() }
+
+    /// Evaluate a client's blinded token request under the current key.
+    pub fn issue(&self, request: &[u8]) -> Result<Vec<u8>, TokenError> {
+        let key = self.epochs().current.key.clone();
+        key.issue(request)
+    }
+
+    /// Redeem `token`, returning false if it is invalid, expired or already spent.
+    pub fn redeem(&self, token: &AccessToken) -> bool {
+        let mut epochs = self.epochs();
+        let Epochs { current, previous, .. } = &mut *epochs;
+        match std::iter::once(current).chain(previous).find(|epoch| epoch.key.verify(token)) {
+            Some(epoch) => epoch.spent.insert(*token.nonce()),
+            None => false,
+        }
+    }
+
+    /// Make a redeemed `token` redeemable again, because the write it paid for failed.
+    pub fn refund(&self, token: &AccessToken) {
+        let mut epochs = self.epochs();
+        let Epochs { current, previous, .. } = &mut *epochs;
+        if let Some(epoch) =
+            std::iter::once(current).chain(previous).find(|epoch| epoch.key.verify(token))
+        {
+            epoch.spent.remove(token.nonce());
+        }
+    }
+
+    /// Lock the epochs, rotating the key first if it is due.
+    fn epochs(&self) -> std::sync::MutexGuard<'_, Epochs> {
+        let mut epochs = self.epochs.lock().expect("Lock should not be poisoned");
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(epochs.rotated_at);
+        if elapsed >= self.rotation_interval {
+            let previous = std::mem::replace(&mut epochs.current, Epoch::generate());
+            // Expire the old key too if it went unused for a whole period
+            epochs.previous = (elapsed < self.rotation_interval * 2).then_some(previous);
+            epochs.rotated_at = now;
+        }
+        epochs
+    }
+}
+
+impl Default for TokenIssuer {
+    fn default() -> Self { Self::new(DEFAULT_KEY_ROTATION_INTERVAL) }
+}
+
+#[cfg(test)]
+mod tests {
+    use payjoin::directory::token::{TokenRequest, MAX_BATCH_SIZE};
+
+    use super::*;
+
+    fn tokens(issuer: &TokenIssuer, count: usize) -> Vec<AccessToken> {
+        let request = TokenRequest::new(count).expect("valid batch size");
+        let response = issuer.issue(&request.body()).expect("issuance should succeed");
+        request.finalize(&response, &issuer.public_key()).expect("proofs should verify")
+    }
+
+    #[test]
+    fn test_tokens_redeem_once() {
+        let issuer = TokenIssuer::default();
+        let tokens = tokens(&issuer, 2);
+        assert!(issuer.redeem(&tokens[0]));
+        assert!(!issuer.redeem(&tokens[0]), "double spend must be rejected");
+        assert!(issuer.redeem(&tokens[1]));
+
+        let unspent = self::tokens(&issuer, 1);
+        assert!(!TokenIssuer::default().redeem(&unspent[0]), "tokens are bound to their issuer");
+    }
+
+    #[test]
+    fn test_refunded_token_redeems_again() {
+        let issuer = TokenIssuer::default();
+        let token = tokens(&issuer, 1).remove(0);
+        assert!(issuer.redeem(&token));
+        issuer.refund(&token);
+        assert!(issuer.redeem(&token), "a refunded token can be spent once more");
+        assert!(!issuer.redeem(&token));
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        // Long enough for issuance to finish within one period in debug builds
+        let period = Duration::from_millis(500);
+        let issuer = TokenIssuer::new(period);
+        let old = tokens(&issuer, MAX_BATCH_SIZE);
+        let old_key = issuer.public_key();
+
+        std::thread::sleep(period);
+        assert_ne!(issuer.public_key(), old_key);
+        assert!(issuer.redeem(&old[0]), "tokens from the previous key remain valid");
+        assert!(!issuer.redeem(&old[0]));
+
+        std::thread::sleep(period);
+        assert!(!issuer.redeem(&old[1]), "tokens expire after two rotations");
+    }
+}
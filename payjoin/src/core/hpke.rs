@@ -89,6 +89,15 @@ impl Deref for HpkeSecretKey {
     fn deref(&self) -> &Self::Target { &self.0 }
 }
 
+impl HpkeSecretKey {
+    /// The same scalar as a secp256k1 key, for signing on behalf of the
+    /// mailbox whose ID is derived from the corresponding public key.
+    pub(crate) fn to_secp(&self) -> secp256k1::SecretKey {
+        secp256k1::SecretKey::from_slice(&self.0.to_bytes())
+            .expect("HPKE secret keys are valid secp256k1 scalars")
+    }
+}
+
 impl fmt::Debug for HpkeSecretKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "SecpHpkeSecretKey([REDACTED])")
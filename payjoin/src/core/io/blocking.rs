@@ -0,0 +1,280 @@
+//! Synchronous versions of the [`crate::io`] functions for callers without an async runtime.
+//!
+//! Requests are made with [`ureq`], a small blocking HTTP client.
+use std::io::Read;
+use std::time::Duration;
+
+use url::Url;
+
+use super::{Error, InternalErrorInner};
+use crate::into_url::IntoUrl;
+use crate::{OhttpKeys, Request};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetch the ohttp keys from the specified payjoin directory via proxy.
+///
+/// * `ohttp_relay`: The http CONNECT method proxy to request the ohttp keys from a payjoin
+///   directory.  Proxying requests for ohttp keys ensures a client IP address is never revealed to
+///   the payjoin directory. Only `http://` relays are supported.
+///
+/// * `payjoin_directory`: The payjoin directory from which to fetch the ohttp keys.  This
+///   directory stores and forwards payjoin client payloads.
+pub fn fetch_ohttp_keys(
+    ohttp_relay: impl IntoUrl,
+    payjoin_directory: impl IntoUrl,
+) -> Result<OhttpKeys, Error> {
+    let agent = agent_builder(Some(connect_proxy(ohttp_relay)?)).build();
+    get_ohttp_keys(&agent, payjoin_directory)
+}
+
+/// Fetch the ohttp keys from the specified payjoin directory through a SOCKS5 proxy.
+///
+/// See [`crate::io::fetch_ohttp_keys_via_socks_proxy`].
+pub fn fetch_ohttp_keys_via_socks_proxy(
+    socks_proxy: impl IntoUrl,
+    payjoin_directory: impl IntoUrl,
+) -> Result<OhttpKeys, Error> {
+    let agent = agent_builder(Some(socks_proxy_from_url(socks_proxy)?)).build();
+    get_ohttp_keys(&agent, payjoin_directory)
+}
+
+/// Fetch the ohttp keys from the specified payjoin directory via proxy.
+///
+/// See [`fetch_ohttp_keys`].
+///
+/// * `cert_der`: The DER-encoded certificate to use for local HTTPS connections.
+#[cfg(feature = "_manual-tls")]
+pub fn fetch_ohttp_keys_with_cert(
+    ohttp_relay: impl IntoUrl,
+    payjoin_directory: impl IntoUrl,
+    cert_der: Vec<u8>,
+) -> Result<OhttpKeys, Error> {
+    let agent =
+        with_root_certificate(agent_builder(Some(connect_proxy(ohttp_relay)?)), cert_der)?.build();
+    get_ohttp_keys(&agent, payjoin_directory)
+}
+
+/// Fetch the ohttp keys from the specified payjoin directory through a SOCKS5 proxy.
+///
+/// See [`fetch_ohttp_keys_via_socks_proxy`].
+///
+/// * `cert_der`: The DER-encoded certificate to use for local HTTPS connections.
+#[cfg(feature = "_manual-tls")]
+pub fn fetch_ohttp_keys_via_socks_proxy_with_cert(
+    socks_proxy: impl IntoUrl,
+    payjoin_directory: impl IntoUrl,
+    cert_der: Vec<u8>,
+) -> Result<OhttpKeys, Error> {
+    let builder = agent_builder(Some(socks_proxy_from_url(socks_proxy)?));
+    let agent = with_root_certificate(builder, cert_der)?.build();
+    get_ohttp_keys(&agent, payjoin_directory)
+}
+
+/// Send a [`Request`] produced by a sender or receiver and return the response body.
+///
+/// The body is returned whatever the HTTP status, since the payjoin state machines interpret
+/// error responses themselves.
+pub fn send_request(req: &Request) -> Result<Vec<u8>, Error> {
+    post(&agent_builder(None).build(), req)
+}
+
+/// Send a [`Request`] through a SOCKS5 proxy and return the response body.
+///
+/// See [`send_request`].
+pub fn send_request_via_socks_proxy(
+    socks_proxy: impl IntoUrl,
+    req: &Request,
+) -> Result<Vec<u8>, Error> {
+    post(&agent_builder(Some(socks_proxy_from_url(socks_proxy)?)).build(), req)
+}
+
+/// Send a [`Request`] and return the response body.
+///
+/// See [`send_request`].
+///
+/// * `cert_der`: The DER-encoded certificate to use for local HTTPS connections.
+#[cfg(feature = "_manual-tls")]
+pub fn send_request_with_cert(req: &Request, cert_der: Vec<u8>) -> Result<Vec<u8>, Error> {
+    post(&with_root_certificate(agent_builder(None), cert_der)?.build(), req)
+}
+
+fn agent_builder(proxy: Option<ureq::Proxy>) -> ureq::AgentBuilder {
+    let builder = ureq::AgentBuilder::new().timeout(TIMEOUT);
+    match proxy {
+        Some(proxy) => builder.proxy(proxy),
+        None => builder,
+    }
+}
+
+#[cfg(feature = "_manual-tls")]
+fn with_root_certificate(
+    builder: ureq::AgentBuilder,
+    cert_der: Vec<u8>,
+) -> Result<ureq::AgentBuilder, Error> {
+    use std::sync::Arc;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(rustls::pki_types::CertificateDer::from(cert_der))?;
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(builder.tls_config(Arc::new(config)))
+}
+
+/// The relay is used as an http CONNECT proxy. ureq needs an explicit proxy port, so the relay's
+/// default port is spelled out.
+///
+/// ureq can't reach a proxy over TLS, so only `http://` relays are accepted. Fetch keys through
+/// an `https://` relay with the async [`crate::io::fetch_ohttp_keys`] or through a SOCKS5 proxy.
+fn connect_proxy(ohttp_relay: impl IntoUrl) -> Result<ureq::Proxy, Error> {
+    let ohttp_relay = ohttp_relay.into_url()?;
+    match ohttp_relay.scheme() {
+        "http" => proxy(&ohttp_relay, "http"),
+        scheme => Err(InternalErrorInner::UnsupportedProxyScheme(scheme.to_string()).into()),
+    }
+}
+
+/// Only accept SOCKS5 proxies so that a misconfigured HTTP proxy is never silently used in place
+/// of the intended anonymizing proxy. ureq always lets the SOCKS5 proxy resolve host names, so
+/// `socks5h` maps onto its `socks5`.
+fn socks_proxy_from_url(socks_proxy: impl IntoUrl) -> Result<ureq::Proxy, Error> {
+    let socks_proxy = socks_proxy.into_url()?;
+    match socks_proxy.scheme() {
+        "socks5" | "socks5h" => proxy(&socks_proxy, "socks5"),
+        scheme => Err(InternalErrorInner::UnsupportedProxyScheme(scheme.to_string()).into()),
+    }
+}
+
+fn proxy(url: &Url, scheme: &str) -> Result<ureq::Proxy, Error> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(1080);
+    let credentials = match (url.username(), url.password()) {
+        ("", None) => String::new(),
+        (user, Some(password)) => format!("{user}:{password}@"),
+        (user, None) => format!("{user}@"),
+    };
+    Ok(ureq::Proxy::new(format!("{scheme}://{credentials}{host}:{port}"))?)
+}
+
+fn get_ohttp_keys(
+    agent: &ureq::Agent,
+    payjoin_directory: impl IntoUrl,
+) -> Result<OhttpKeys, Error> {
+    let ohttp_keys_url = payjoin_directory.into_url()?.join("/.well-known/ohttp-gateway")?;
+    let res =
+        match agent.get(ohttp_keys_url.as_str()).set("Accept", "application/ohttp-keys").call() {
+            Ok(res) => res,
+            Err(ureq::Error::Status(code, _)) => return Err(unexpected_status_code(code)),
+            Err(e) => return Err(e.into()),
+        };
+    if !(200..300).contains(&res.status()) {
+        return Err(unexpected_status_code(res.status()));
+    }
+
+    let body = read_body(res)?;
+    OhttpKeys::decode(&body).map_err(|e| InternalErrorInner::InvalidOhttpKeys(e.to_string()).into())
+}
+
+fn post(agent: &ureq::Agent, req: &Request) -> Result<Vec<u8>, Error> {
+    let res = match agent.post(&req.url).set("Content-Type", req.content_type).send_bytes(&req.body)
+    {
+        Ok(res) => res,
+        Err(ureq::Error::Status(_, res)) => res,
+        Err(e) => return Err(e.into()),
+    };
+    read_body(res)
+}
+
+fn read_body(res: ureq::Response) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    res.into_reader().read_to_end(&mut body)?;
+    Ok(body)
+}
+
+fn unexpected_status_code(code: u16) -> Error {
+    Error::UnexpectedStatusCode(
+        http::StatusCode::from_u16(code).unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::super::test_servers::{spawn_ohttp_keys_server, spawn_socks5_stand_in};
+    use super::super::InternalError;
+    use super::*;
+
+    fn ohttp_keys() -> OhttpKeys {
+        OhttpKeys::from_str("OH1QYPM5JXYNS754Y4R45QWE336QFX6ZR8DQGVQCULVZTV20TFVEYDMFQC")
+            .expect("valid keys")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fetch_ohttp_keys_via_socks_proxy() {
+        let keys = ohttp_keys();
+        let directory = spawn_ohttp_keys_server(keys.encode().expect("encode keys")).await;
+        let (socks_proxy, mut requested_hosts) = spawn_socks5_stand_in(directory).await;
+
+        let onion_host = "payjoindirectory2payjoindirectory3payjoindirectory4payjo.onion";
+        let fetched = tokio::task::spawn_blocking(move || {
+            fetch_ohttp_keys_via_socks_proxy(
+                format!("socks5h://{socks_proxy}"),
+                format!("http://{onion_host}"),
+            )
+        })
+        .await
+        .expect("blocking task should not panic")
+        .expect("keys should be fetched through the SOCKS proxy");
+
+        assert_eq!(fetched, keys);
+        assert_eq!(requested_hosts.recv().await.as_deref(), Some(onion_host));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_send_request() {
+        let response = ohttp_keys().encode().expect("encode keys");
+        let server = spawn_ohttp_keys_server(response.clone()).await;
+        let req = Request {
+            url: format!("http://{server}/"),
+            content_type: "text/plain",
+            body: b"original psbt".to_vec(),
+        };
+
+        let body = tokio::task::spawn_blocking(move || send_request(&req))
+            .await
+            .expect("blocking task should not panic")
+            .expect("request should succeed");
+
+        assert_eq!(body, response);
+    }
+
+    #[test]
+    fn test_https_relay_rejected() {
+        let result = fetch_ohttp_keys("https://relay.example.com", "https://example.com");
+        assert!(
+            matches!(
+                result,
+                Err(Error::Internal(InternalError(InternalErrorInner::UnsupportedProxyScheme(_))))
+            ),
+            "expected UnsupportedProxyScheme error, got {result:?}"
+        );
+    }
+
+    #[test]
+    fn test_non_socks_proxy_rejected() {
+        let result =
+            fetch_ohttp_keys_via_socks_proxy("http://127.0.0.1:9050", "https://example.com");
+        assert!(
+            matches!(
+                result,
+                Err(Error::Internal(InternalError(InternalErrorInner::UnsupportedProxyScheme(_))))
+            ),
+            "expected UnsupportedProxyScheme error, got {result:?}"
+        );
+    }
+}
@@ -0,0 +1,505 @@
+//! IO-related types and functions. Specifically, fetching OHTTP keys from a payjoin directory.
+//!
+//! The functions in this module are async and run on reqwest with the `io` feature. The
+//! `blocking-io` feature provides synchronous equivalents in [`blocking`] which need no async
+//! runtime.
+#[cfg(feature = "io")]
+use std::time::Duration;
+
+#[cfg(feature = "io")]
+use http::header::ACCEPT;
+#[cfg(feature = "io")]
+use reqwest::{Client, Proxy};
+
+#[cfg(feature = "io")]
+use crate::into_url::IntoUrl;
+#[cfg(feature = "io")]
+use crate::{OhttpKeys, OhttpKeysCache};
+
+#[cfg(feature = "blocking-io")]
+#[cfg_attr(docsrs, doc(cfg(feature = "blocking-io")))]
+pub mod blocking;
+
+/// Fetch the ohttp keys from the specified payjoin directory via proxy.
+///
+/// * `ohttp_relay`: The http CONNECT method proxy to request the ohttp keys from a payjoin
+///   directory.  Proxying requests for ohttp keys ensures a client IP address is never revealed to
+///   the payjoin directory.
+///
+/// * `payjoin_directory`: The payjoin directory from which to fetch the ohttp keys.  This
+///   directory stores and forwards payjoin client payloads.
+#[cfg(feature = "io")]
+pub async fn fetch_ohttp_keys(
+    ohttp_relay: impl IntoUrl,
+    payjoin_directory: impl IntoUrl,
+) -> Result<OhttpKeys, Error> {
+    let ohttp_keys_url = payjoin_directory.into_url()?.join("/.well-known/ohttp-gateway")?;
+    let proxy = Proxy::all(ohttp_relay.into_url()?.as_str())?;
+    let client = Client::builder().proxy(proxy).build()?;
+    let res = client
+        .get(ohttp_keys_url)
+        .timeout(Duration::from_secs(10))
+        .header(ACCEPT, "application/ohttp-keys")
+        .send()
+        .await?;
+    parse_ohttp_keys_response(res).await
+}
+
+/// Fetch the ohttp keys from the specified payjoin directory via proxy, unless unexpired keys
+/// for that directory are already present in `cache`.
+///
+/// Freshly fetched keys are inserted into `cache`. Invalidate the directory's entry with
+/// [`OhttpKeysCache::invalidate`] when its gateway rejects the cached key configuration.
+#[cfg(feature = "io")]
+pub async fn fetch_ohttp_keys_cached(
+    cache: &OhttpKeysCache,
+    ohttp_relay: impl IntoUrl,
+    payjoin_directory: impl IntoUrl,
+) -> Result<OhttpKeys, Error> {
+    let payjoin_directory = payjoin_directory.into_url()?;
+    if let Some(ohttp_keys) = cache.get(&payjoin_directory) {
+        return Ok(ohttp_keys);
+    }
+    let ohttp_keys = fetch_ohttp_keys(ohttp_relay, payjoin_directory.as_str()).await?;
+    cache.insert(&payjoin_directory, ohttp_keys.clone());
+    Ok(ohttp_keys)
+}
+
+/// Fetch the ohttp keys from the specified payjoin directory through a SOCKS5 proxy, such as the
+/// SOCKS port of a local Tor daemon.
+///
+/// * `socks_proxy`: The `socks5://` or `socks5h://` proxy URL, e.g. `socks5h://127.0.0.1:9050`.
+///   With `socks5h` host names are resolved by the proxy, which is required to reach `.onion`
+///   directories and avoids leaking DNS queries.
+///
+/// * `payjoin_directory`: The payjoin directory from which to fetch the ohttp keys.  This
+///   directory stores and forwards payjoin client payloads.
+///
+/// The SOCKS proxy takes the place of the OHTTP relay's CONNECT tunnel for this request: it is
+/// the proxy rather than the relay that keeps the client IP address from the payjoin directory.
+#[cfg(feature = "io")]
+pub async fn fetch_ohttp_keys_via_socks_proxy(
+    socks_proxy: impl IntoUrl,
+    payjoin_directory: impl IntoUrl,
+) -> Result<OhttpKeys, Error> {
+    let ohttp_keys_url = payjoin_directory.into_url()?.join("/.well-known/ohttp-gateway")?;
+    let proxy = socks_proxy_from_url(socks_proxy)?;
+    let client = Client::builder().proxy(proxy).build()?;
+    let res = client
+        .get(ohttp_keys_url)
+        .timeout(Duration::from_secs(10))
+        .header(ACCEPT, "application/ohttp-keys")
+        .send()
+        .await?;
+    parse_ohttp_keys_response(res).await
+}
+
+/// Fetch the ohttp keys from the specified payjoin directory via proxy.
+///
+/// * `ohttp_relay`: The http CONNECT method proxy to request the ohttp keys from a payjoin
+///   directory.  Proxying requests for ohttp keys ensures a client IP address is never revealed to
+///   the payjoin directory.
+///
+/// * `payjoin_directory`: The payjoin directory from which to fetch the ohttp keys.  This
+///   directory stores and forwards payjoin client payloads.
+///
+/// * `cert_der`: The DER-encoded certificate to use for local HTTPS connections.
+#[cfg(all(feature = "io", feature = "_manual-tls"))]
+pub async fn fetch_ohttp_keys_with_cert(
+    ohttp_relay: impl IntoUrl,
+    payjoin_directory: impl IntoUrl,
+    cert_der: Vec<u8>,
+) -> Result<OhttpKeys, Error> {
+    let ohttp_keys_url = payjoin_directory.into_url()?.join("/.well-known/ohttp-gateway")?;
+    let proxy = Proxy::all(ohttp_relay.into_url()?.as_str())?;
+    let client = Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(reqwest::tls::Certificate::from_der(&cert_der)?)
+        .proxy(proxy)
+        .build()?;
+    let res = client
+        .get(ohttp_keys_url)
+        .timeout(Duration::from_secs(10))
+        .header(ACCEPT, "application/ohttp-keys")
+        .send()
+        .await?;
+    parse_ohttp_keys_response(res).await
+}
+
+/// Fetch the ohttp keys from the specified payjoin directory through a SOCKS5 proxy.
+///
+/// See [`fetch_ohttp_keys_via_socks_proxy`].
+///
+/// * `cert_der`: The DER-encoded certificate to use for local HTTPS connections.
+#[cfg(all(feature = "io", feature = "_manual-tls"))]
+pub async fn fetch_ohttp_keys_via_socks_proxy_with_cert(
+    socks_proxy: impl IntoUrl,
+    payjoin_directory: impl IntoUrl,
+    cert_der: Vec<u8>,
+) -> Result<OhttpKeys, Error> {
+    let ohttp_keys_url = payjoin_directory.into_url()?.join("/.well-known/ohttp-gateway")?;
+    let proxy = socks_proxy_from_url(socks_proxy)?;
+    let client = Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(reqwest::tls::Certificate::from_der(&cert_der)?)
+        .proxy(proxy)
+        .build()?;
+    let res = client
+        .get(ohttp_keys_url)
+        .timeout(Duration::from_secs(10))
+        .header(ACCEPT, "application/ohttp-keys")
+        .send()
+        .await?;
+    parse_ohttp_keys_response(res).await
+}
+
+/// Only accept SOCKS5 proxies so that a misconfigured HTTP proxy is never silently used in place
+/// of the intended anonymizing proxy.
+#[cfg(feature = "io")]
+fn socks_proxy_from_url(socks_proxy: impl IntoUrl) -> Result<Proxy, Error> {
+    let socks_proxy = socks_proxy.into_url()?;
+    match socks_proxy.scheme() {
+        "socks5" | "socks5h" => Ok(Proxy::all(socks_proxy.as_str())?),
+        scheme => Err(InternalErrorInner::UnsupportedProxyScheme(scheme.to_string()).into()),
+    }
+}
+
+#[cfg(feature = "io")]
+async fn parse_ohttp_keys_response(res: reqwest::Response) -> Result<OhttpKeys, Error> {
+    if !res.status().is_success() {
+        return Err(Error::UnexpectedStatusCode(res.status()));
+    }
+
+    let body = res.bytes().await?.to_vec();
+    OhttpKeys::decode(&body).map_err(|e| {
+        Error::Internal(InternalError(InternalErrorInner::InvalidOhttpKeys(e.to_string())))
+    })
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// When the payjoin directory returns an unexpected status code
+    UnexpectedStatusCode(http::StatusCode),
+    /// Internal errors that should not be pattern matched by users
+    #[doc(hidden)]
+    Internal(InternalError),
+}
+
+#[derive(Debug)]
+pub struct InternalError(InternalErrorInner);
+
+#[derive(Debug)]
+enum InternalErrorInner {
+    ParseUrl(crate::into_url::Error),
+    #[cfg(feature = "io")]
+    Reqwest(reqwest::Error),
+    #[cfg(feature = "blocking-io")]
+    Ureq(Box<ureq::Error>),
+    Io(std::io::Error),
+    #[cfg(feature = "_manual-tls")]
+    Rustls(rustls::Error),
+    InvalidOhttpKeys(String),
+    UnsupportedProxyScheme(String),
+}
+
+impl From<url::ParseError> for Error {
+    fn from(value: url::ParseError) -> Self {
+        Self::Internal(InternalError(InternalErrorInner::ParseUrl(value.into())))
+    }
+}
+
+macro_rules! impl_from_error {
+    ($from:ty, $to:ident) => {
+        impl From<$from> for Error {
+            fn from(value: $from) -> Self {
+                Self::Internal(InternalError(InternalErrorInner::$to(value)))
+            }
+        }
+    };
+}
+
+impl_from_error!(crate::into_url::Error, ParseUrl);
+#[cfg(feature = "io")]
+impl_from_error!(reqwest::Error, Reqwest);
+#[cfg(feature = "blocking-io")]
+impl From<ureq::Error> for Error {
+    fn from(value: ureq::Error) -> Self {
+        Self::Internal(InternalError(InternalErrorInner::Ureq(Box::new(value))))
+    }
+}
+impl_from_error!(std::io::Error, Io);
+#[cfg(feature = "_manual-tls")]
+impl_from_error!(rustls::Error, Rustls);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UnexpectedStatusCode(code) => {
+                write!(f, "Unexpected status code from payjoin directory: {code}")
+            }
+            Self::Internal(InternalError(e)) => e.fmt(f),
+        }
+    }
+}
+
+impl std::fmt::Display for InternalErrorInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        use InternalErrorInner::*;
+
+        match &self {
+            #[cfg(feature = "io")]
+            Reqwest(e) => e.fmt(f),
+            #[cfg(feature = "blocking-io")]
+            Ureq(e) => e.fmt(f),
+            ParseUrl(e) => e.fmt(f),
+            Io(e) => e.fmt(f),
+            InvalidOhttpKeys(e) => {
+                write!(f, "Invalid ohttp keys returned from payjoin directory: {e}")
+            }
+            UnsupportedProxyScheme(scheme) => {
+                write!(f, "Unsupported proxy scheme {scheme}")
+            }
+            #[cfg(feature = "_manual-tls")]
+            Rustls(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Internal(InternalError(e)) => e.source(),
+            Self::UnexpectedStatusCode(_) => None,
+        }
+    }
+}
+
+impl std::error::Error for InternalErrorInner {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use InternalErrorInner::*;
+
+        match self {
+            #[cfg(feature = "io")]
+            Reqwest(e) => Some(e),
+            #[cfg(feature = "blocking-io")]
+            Ureq(e) => Some(e.as_ref()),
+            ParseUrl(e) => Some(e),
+            Io(e) => Some(e),
+            InvalidOhttpKeys(_) => None,
+            UnsupportedProxyScheme(_) => None,
+            #[cfg(feature = "_manual-tls")]
+            Rustls(e) => Some(e),
+        }
+    }
+}
+
+impl From<InternalError> for Error {
+    fn from(value: InternalError) -> Self { Self::Internal(value) }
+}
+
+impl From<InternalErrorInner> for Error {
+    fn from(value: InternalErrorInner) -> Self { Self::Internal(InternalError(value)) }
+}
+
+#[cfg(all(test, feature = "io"))]
+mod tests {
+    use std::str::FromStr;
+
+    use http::StatusCode;
+    use reqwest::Response;
+
+    use super::test_servers::{spawn_ohttp_keys_server, spawn_socks5_stand_in};
+    use super::*;
+
+    fn mock_response(status: StatusCode, body: Vec<u8>) -> Response {
+        Response::from(http::response::Response::builder().status(status).body(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_parse_success_response() {
+        let valid_keys =
+            OhttpKeys::from_str("OH1QYPM5JXYNS754Y4R45QWE336QFX6ZR8DQGVQCULVZTV20TFVEYDMFQC")
+                .expect("valid keys")
+                .encode()
+                .expect("encodevalid keys");
+
+        let response = mock_response(StatusCode::OK, valid_keys);
+        assert!(parse_ohttp_keys_response(response).await.is_ok(), "expected valid keys response");
+    }
+
+    #[tokio::test]
+    async fn test_parse_error_status_codes() {
+        let error_codes = [
+            StatusCode::BAD_REQUEST,
+            StatusCode::NOT_FOUND,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::SERVICE_UNAVAILABLE,
+        ];
+
+        for status in error_codes {
+            let response = mock_response(status, vec![]);
+            match parse_ohttp_keys_response(response).await {
+                Err(Error::UnexpectedStatusCode(code)) => assert_eq!(code, status),
+                result => panic!(
+                    "Expected UnexpectedStatusCode error for status code: {status}, got: {result:?}"
+                ),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parse_invalid_keys() {
+        // Invalid OHTTP keys (not properly encoded)
+        let invalid_keys = vec![1, 2, 3, 4];
+
+        let response = mock_response(StatusCode::OK, invalid_keys);
+
+        assert!(
+            matches!(
+                parse_ohttp_keys_response(response).await,
+                Err(Error::Internal(InternalError(InternalErrorInner::InvalidOhttpKeys(_))))
+            ),
+            "expected InvalidOhttpKeys error"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_ohttp_keys_via_socks_proxy() {
+        let keys =
+            OhttpKeys::from_str("OH1QYPM5JXYNS754Y4R45QWE336QFX6ZR8DQGVQCULVZTV20TFVEYDMFQC")
+                .expect("valid keys");
+        let directory = spawn_ohttp_keys_server(keys.encode().expect("encode keys")).await;
+        let (socks_proxy, mut requested_hosts) = spawn_socks5_stand_in(directory).await;
+
+        let onion_host = "payjoindirectory2payjoindirectory3payjoindirectory4payjo.onion";
+        let fetched = fetch_ohttp_keys_via_socks_proxy(
+            format!("socks5h://{socks_proxy}"),
+            format!("http://{onion_host}"),
+        )
+        .await
+        .expect("keys should be fetched through the SOCKS proxy");
+
+        assert_eq!(fetched, keys);
+        // socks5h must leave name resolution of the onion host to the proxy
+        assert_eq!(requested_hosts.recv().await.as_deref(), Some(onion_host));
+    }
+
+    #[tokio::test]
+    async fn test_non_socks_proxy_rejected() {
+        let result =
+            fetch_ohttp_keys_via_socks_proxy("http://127.0.0.1:9050", "https://example.com").await;
+        assert!(
+            matches!(
+                result,
+                Err(Error::Internal(InternalError(InternalErrorInner::UnsupportedProxyScheme(_))))
+            ),
+            "expected UnsupportedProxyScheme error, got {result:?}"
+        );
+    }
+}
+
+/// Local stand-ins for a payjoin directory and a SOCKS5 proxy shared by the async and blocking
+/// tests.
+#[cfg(test)]
+pub(crate) mod test_servers {
+    /// Serve `body` as `application/ohttp-keys` to every HTTP request, standing in for a
+    /// directory's `/.well-known/ohttp-gateway` endpoint.
+    pub(crate) async fn spawn_ohttp_keys_server(body: Vec<u8>) -> std::net::SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    let head_len = loop {
+                        if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            break i + 4;
+                        }
+                        let n = stream.read(&mut buf).await?;
+                        if n == 0 {
+                            return Ok(());
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                    };
+                    // Drain any request body before answering
+                    let head = String::from_utf8_lossy(&request[..head_len]).to_ascii_lowercase();
+                    let content_length = head
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .and_then(|len| len.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    while request.len() < head_len + content_length {
+                        let n = stream.read(&mut buf).await?;
+                        if n == 0 {
+                            break;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/ohttp-keys\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    stream.write_all(head.as_bytes()).await?;
+                    stream.write_all(&body).await?;
+                    stream.shutdown().await?;
+                    Ok::<(), std::io::Error>(())
+                });
+            }
+        });
+        addr
+    }
+
+    /// A minimal SOCKS5 proxy which tunnels every CONNECT to `target` regardless of the
+    /// requested destination, and reports each requested host.
+    pub(crate) async fn spawn_socks5_stand_in(
+        target: std::net::SocketAddr,
+    ) -> (std::net::SocketAddr, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    // Greeting: VER NMETHODS METHODS, answered with "no authentication"
+                    let mut greeting = [0u8; 2];
+                    client.read_exact(&mut greeting).await?;
+                    let mut methods = vec![0u8; greeting[1] as usize];
+                    client.read_exact(&mut methods).await?;
+                    client.write_all(&[0x05, 0x00]).await?;
+                    // Request: VER CMD RSV ATYP DST.ADDR DST.PORT
+                    let mut request = [0u8; 4];
+                    client.read_exact(&mut request).await?;
+                    let host = match request[3] {
+                        0x01 => {
+                            let mut ip = [0u8; 4];
+                            client.read_exact(&mut ip).await?;
+                            std::net::Ipv4Addr::from(ip).to_string()
+                        }
+                        0x03 => {
+                            let len = client.read_u8().await?;
+                            let mut name = vec![0u8; len as usize];
+                            client.read_exact(&mut name).await?;
+                            String::from_utf8_lossy(&name).into_owned()
+                        }
+                        _ => return Ok(()),
+                    };
+                    let _port = client.read_u16().await?;
+                    let _ = tx.send(host);
+                    let mut upstream = tokio::net::TcpStream::connect(target).await?;
+                    client.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+                    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+                    Ok::<(), std::io::Error>(())
+                });
+            }
+        });
+        (addr, rx)
+    }
+}
@@ -23,7 +23,6 @@ pub use uri::{PjParam, PjParseError, PjUri, Uri, UriExt};
 pub(crate) mod error_codes;
 
 pub(crate) mod output_substitution;
-#[cfg(feature = "v1")]
 pub use output_substitution::OutputSubstitution;
 
 #[cfg(feature = "v2")]
@@ -35,10 +34,10 @@ pub use crate::hpke::{HpkeKeyPair, HpkePublicKey};
 #[cfg(feature = "v2")]
 pub(crate) mod ohttp;
 #[cfg(feature = "v2")]
-pub use crate::ohttp::OhttpKeys;
+pub use crate::ohttp::{OhttpKeys, OhttpKeysCache};
 
-#[cfg(feature = "io")]
-#[cfg_attr(docsrs, doc(cfg(feature = "io")))]
+#[cfg(any(feature = "io", feature = "blocking-io"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "io", feature = "blocking-io"))))]
 pub mod io;
 
 /// 4M block size limit with base64 encoding overhead => maximum reasonable size of content-length
@@ -1,10 +1,14 @@
+use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::{error, fmt};
 
 use bitcoin::bech32::{self, EncodeError};
 use bitcoin::key::constants::UNCOMPRESSED_PUBLIC_KEY_SIZE;
 use hpke::rand_core::{OsRng, RngCore};
 
+use crate::directory::token::AccessToken;
 use crate::directory::ENCAPSULATED_MESSAGE_BYTES;
 
 const N_ENC: usize = UNCOMPRESSED_PUBLIC_KEY_SIZE;
@@ -13,11 +17,16 @@ const OHTTP_REQ_HEADER_BYTES: usize = 7;
 pub const PADDED_BHTTP_REQ_BYTES: usize =
     ENCAPSULATED_MESSAGE_BYTES - (N_ENC + N_T + OHTTP_REQ_HEADER_BYTES);
 
+/// The problem type a gateway returns when it does not recognize the key configuration used to
+/// encapsulate a request. See RFC 9458 Section 5.3.
+const OHTTP_KEY_PROBLEM_TYPE: &str = "https://iana.org/assignments/http-problem-types#ohttp-key";
+
 pub(crate) fn ohttp_encapsulate(
     ohttp_keys: &ohttp::KeyConfig,
     method: &str,
     target_resource: &str,
     body: Option<&[u8]>,
+    access_token: Option<&AccessToken>,
 ) -> Result<([u8; ENCAPSULATED_MESSAGE_BYTES], ohttp::ClientResponse), OhttpEncapsulationError> {
     use std::fmt::Write;
     let mut ohttp_keys = ohttp_keys.clone();
@@ -37,7 +46,10 @@ pub(crate) fn ohttp_encapsulate(
         authority_bytes,
         url.path().as_bytes().to_vec(),
     );
-    // None of our messages include headers, so we don't add them
+    // The only header our messages may include is a directory access token
+    if let Some(access_token) = access_token {
+        bhttp_message.put_header("authorization", access_token.to_header_value());
+    }
     if let Some(body) = body {
         bhttp_message.write_content(body);
     }
@@ -57,6 +69,9 @@ pub(crate) fn ohttp_encapsulate(
 pub enum DirectoryResponseError {
     InvalidSize(usize),
     OhttpDecapsulation(OhttpEncapsulationError),
+    /// The gateway rejected the OHTTP key configuration, most likely because the directory
+    /// rotated its keys. Fresh keys must be fetched before retrying.
+    OhttpKeyRejection,
     UnexpectedStatusCode(http::StatusCode),
 }
 
@@ -67,9 +82,14 @@ impl DirectoryResponseError {
         match self {
             OhttpDecapsulation(_) => true,
             InvalidSize(_) => false,
+            OhttpKeyRejection => false,
             UnexpectedStatusCode(status_code) => status_code.is_client_error(),
         }
     }
+
+    pub(crate) fn is_ohttp_key_rejection(&self) -> bool {
+        matches!(self, DirectoryResponseError::OhttpKeyRejection)
+    }
 }
 
 impl fmt::Display for DirectoryResponseError {
@@ -78,6 +98,8 @@ impl fmt::Display for DirectoryResponseError {
 
         match self {
             OhttpDecapsulation(e) => write!(f, "OHTTP Decapsulation Error: {e}"),
+            OhttpKeyRejection =>
+                write!(f, "OHTTP key configuration rejected by the gateway, refresh OHTTP keys"),
             InvalidSize(size) => write!(
                 f,
                 "Unexpected response size {}, expected {} bytes",
@@ -96,6 +118,7 @@ impl error::Error for DirectoryResponseError {
         match self {
             OhttpDecapsulation(e) => Some(e),
             InvalidSize(_) => None,
+            OhttpKeyRejection => None,
             UnexpectedStatusCode(_) => None,
         }
     }
@@ -128,6 +151,9 @@ fn process_ohttp_res(
     res: &[u8],
     ohttp_context: ohttp::ClientResponse,
 ) -> Result<http::Response<Vec<u8>>, DirectoryResponseError> {
+    if is_ohttp_key_rejection(res) {
+        return Err(DirectoryResponseError::OhttpKeyRejection);
+    }
     let response_array: &[u8; crate::directory::ENCAPSULATED_MESSAGE_BYTES] =
         res.try_into().map_err(|_| DirectoryResponseError::InvalidSize(res.len()))?;
     tracing::trace!("decapsulating directory response");
@@ -136,6 +162,17 @@ fn process_ohttp_res(
     Ok(res)
 }
 
+/// Check whether an unencapsulated response body is the `application/problem+json` key rejection
+/// a gateway returns when it cannot decapsulate a request with the client's key configuration.
+fn is_ohttp_key_rejection(res: &[u8]) -> bool {
+    if res.len() == ENCAPSULATED_MESSAGE_BYTES {
+        return false;
+    }
+    serde_json::from_slice::<serde_json::Value>(res).is_ok_and(|problem| {
+        problem.get("type").and_then(|t| t.as_str()) == Some(OHTTP_KEY_PROBLEM_TYPE)
+    })
+}
+
 /// decapsulate ohttp, bhttp response and return http response body and status code
 pub(crate) fn ohttp_decapsulate(
     res_ctx: ohttp::ClientResponse,
@@ -339,6 +376,64 @@ impl serde::Serialize for OhttpKeys {
     }
 }
 
+/// A cache of [`OhttpKeys`] keyed by payjoin directory.
+///
+/// Directories may rotate their OHTTP keys at any time, so cached entries expire after a
+/// configurable time-to-live. When a directory rejects a key configuration the entry should be
+/// [`invalidated`] so that fresh keys are fetched on the next lookup.
+///
+/// [`invalidated`]: OhttpKeysCache::invalidate
+#[derive(Debug)]
+pub struct OhttpKeysCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<url::Url, CachedOhttpKeys>>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedOhttpKeys {
+    ohttp_keys: OhttpKeys,
+    fetched_at: Instant,
+}
+
+impl OhttpKeysCache {
+    /// Create an empty cache whose entries are considered stale after `ttl`.
+    pub fn new(ttl: Duration) -> Self { Self { ttl, entries: Mutex::new(HashMap::new()) } }
+
+    /// Get the cached keys for `directory` if they have not expired.
+    pub fn get(&self, directory: &url::Url) -> Option<OhttpKeys> {
+        let key = Self::cache_key(directory)?;
+        let mut entries = self.entries.lock().expect("Lock should not be poisoned");
+        match entries.get(&key) {
+            Some(entry) if entry.fetched_at.elapsed() < self.ttl => Some(entry.ohttp_keys.clone()),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Cache freshly fetched keys for `directory`, replacing any previous entry.
+    pub fn insert(&self, directory: &url::Url, ohttp_keys: OhttpKeys) {
+        if let Some(key) = Self::cache_key(directory) {
+            self.entries
+                .lock()
+                .expect("Lock should not be poisoned")
+                .insert(key, CachedOhttpKeys { ohttp_keys, fetched_at: Instant::now() });
+        }
+    }
+
+    /// Forget the keys cached for `directory`, e.g. after they were rejected by its gateway.
+    pub fn invalidate(&self, directory: &url::Url) {
+        if let Some(key) = Self::cache_key(directory) {
+            self.entries.lock().expect("Lock should not be poisoned").remove(&key);
+        }
+    }
+
+    /// OHTTP keys are served from the directory origin, so paths are not part of the key.
+    fn cache_key(directory: &url::Url) -> Option<url::Url> { directory.join("/").ok() }
+}
+
 #[derive(Debug)]
 pub enum ParseOhttpKeysError {
     IncorrectLength(usize),
@@ -406,4 +501,42 @@ mod test {
         assert!(!keys_one.eq(&deserialized_two));
         assert!(!keys_two.eq(&deserialized_one));
     }
+
+    #[test]
+    fn test_ohttp_key_rejection_detected() {
+        let rejection = br#"{"type":"https://iana.org/assignments/http-problem-types#ohttp-key", "title": "key identifier unknown"}"#;
+        assert!(is_ohttp_key_rejection(rejection));
+        assert!(!is_ohttp_key_rejection(br#"{"type":"about:blank"}"#));
+        assert!(!is_ohttp_key_rejection(&[0u8; ENCAPSULATED_MESSAGE_BYTES]));
+
+        let keys = OhttpKeys(ohttp::KeyConfig::new(KEY_ID, KEM, Vec::from(SYMMETRIC)).unwrap());
+        let (_, ctx) = ohttp_encapsulate(&keys, "GET", "https://example.com", None, None).unwrap();
+        match process_get_res(rejection, ctx) {
+            Err(e @ DirectoryResponseError::OhttpKeyRejection) => {
+                assert!(e.is_ohttp_key_rejection());
+                assert!(!e.is_fatal());
+            }
+            res => panic!("expected OhttpKeyRejection, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_ohttp_keys_cache() {
+        let keys = OhttpKeys(ohttp::KeyConfig::new(KEY_ID, KEM, Vec::from(SYMMETRIC)).unwrap());
+        let directory = url::Url::parse("https://directory.example/some/path").unwrap();
+        let same_origin = url::Url::parse("https://directory.example").unwrap();
+        let other = url::Url::parse("https://other.example").unwrap();
+
+        let cache = OhttpKeysCache::new(Duration::from_secs(60));
+        assert_eq!(cache.get(&directory), None);
+        cache.insert(&directory, keys.clone());
+        assert_eq!(cache.get(&same_origin), Some(keys.clone()));
+        assert_eq!(cache.get(&other), None);
+        cache.invalidate(&same_origin);
+        assert_eq!(cache.get(&directory), None);
+
+        let expired = OhttpKeysCache::new(Duration::ZERO);
+        expired.insert(&directory, keys);
+        assert_eq!(expired.get(&directory), None);
+    }
 }
@@ -18,6 +18,16 @@ impl From<InternalSessionError> for SessionError {
     fn from(value: InternalSessionError) -> Self { SessionError(value) }
 }
 
+impl SessionError {
+    /// Whether the directory rejected the session's OHTTP keys, e.g. after a key rotation.
+    ///
+    /// The session can continue once fresh keys are applied with
+    /// [`super::ReceiveSession::refresh_ohttp_keys`].
+    pub fn is_ohttp_key_rejection(&self) -> bool {
+        matches!(&self.0, InternalSessionError::DirectoryResponse(e) if e.is_ohttp_key_rejection())
+    }
+}
+
 impl From<InternalSessionError> for Error {
     fn from(e: InternalSessionError) -> Self { Error::Protocol(ProtocolError::V2(e.into())) }
 }
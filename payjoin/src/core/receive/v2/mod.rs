@@ -41,6 +41,8 @@ use super::error::{Error, InputContributionError};
 use super::{
     common, InternalPayloadError, JsonReply, OutputSubstitutionError, ProtocolError, SelectionError,
 };
+use crate::directory::deletion::DeleteAuthorization;
+use crate::directory::token::AccessToken;
 use crate::error::{InternalReplayError, ReplayError};
 use crate::hpke::{decrypt_message_a, encrypt_message_b, HpkeKeyPair, HpkePublicKey};
 use crate::ohttp::{
@@ -195,6 +197,11 @@ impl ReceiveSession {
             (_, SessionEvent::Closed(session_outcome)) =>
                 Ok(ReceiveSession::Closed(session_outcome)),
 
+            (mut session, SessionEvent::RefreshedOhttpKeys(ohttp_keys)) => {
+                session.apply_refreshed_ohttp_keys(ohttp_keys);
+                Ok(session)
+            }
+
             (session, SessionEvent::GotReplyableError(error)) =>
                 Ok(ReceiveSession::HasReplyableError(Receiver {
                     state: HasReplyableError { error_reply: error.clone() },
@@ -223,6 +230,46 @@ impl ReceiveSession {
             .into()),
         }
     }
+
+    /// Replace the OHTTP keys used to reach the directory, e.g. after the directory rotated its
+    /// keys and rejected a request (see [`SessionError::is_ohttp_key_rejection`]). This works in
+    /// any state, and has no effect once the session is closed.
+    ///
+    /// The mailbox and receiver key are unchanged, so the Payjoin URI already shared with the
+    /// sender stays valid. A sender holding the old keys must fetch the new ones from the
+    /// directory itself.
+    pub fn refresh_ohttp_keys(
+        mut self,
+        ohttp_keys: OhttpKeys,
+    ) -> NextStateTransition<SessionEvent, ReceiveSession> {
+        self.apply_refreshed_ohttp_keys(ohttp_keys.clone());
+        NextStateTransition::success(SessionEvent::RefreshedOhttpKeys(ohttp_keys), self)
+    }
+
+    fn apply_refreshed_ohttp_keys(&mut self, ohttp_keys: OhttpKeys) {
+        if let Some(session_context) = self.session_context_mut() {
+            session_context.ohttp_keys = ohttp_keys;
+        }
+    }
+
+    /// The context shared by every state, or `None` once the session is closed
+    fn session_context_mut(&mut self) -> Option<&mut SessionContext> {
+        match self {
+            ReceiveSession::Initialized(r) => Some(&mut r.session_context),
+            ReceiveSession::UncheckedOriginalPayload(r) => Some(&mut r.session_context),
+            ReceiveSession::MaybeInputsOwned(r) => Some(&mut r.session_context),
+            ReceiveSession::MaybeInputsSeen(r) => Some(&mut r.session_context),
+            ReceiveSession::OutputsUnknown(r) => Some(&mut r.session_context),
+            ReceiveSession::WantsOutputs(r) => Some(&mut r.session_context),
+            ReceiveSession::WantsInputs(r) => Some(&mut r.session_context),
+            ReceiveSession::WantsFeeRange(r) => Some(&mut r.session_context),
+            ReceiveSession::ProvisionalProposal(r) => Some(&mut r.session_context),
+            ReceiveSession::PayjoinProposal(r) => Some(&mut r.session_context),
+            ReceiveSession::HasReplyableError(r) => Some(&mut r.session_context),
+            ReceiveSession::Monitor(r) => Some(&mut r.session_context),
+            ReceiveSession::Closed(_) => None,
+        }
+    }
 }
 
 mod sealed {
@@ -279,6 +326,45 @@ impl<State> core::ops::DerefMut for Receiver<State> {
     fn deref_mut(&mut self) -> &mut Self::Target { &mut self.state }
 }
 
+impl<State> Receiver<State> {
+    /// Construct an OHTTP Encapsulated HTTP DELETE request for the mailbox the sender posts the
+    /// Original PSBT to, so a finished or cancelled session doesn't linger on the directory
+    /// until it expires.
+    ///
+    /// The request is authorized by a signature from the receiver key the mailbox ID is derived
+    /// from. Deleting a mailbox that is already gone succeeds.
+    pub fn create_delete_request(
+        &self,
+        ohttp_relay: impl IntoUrl,
+    ) -> Result<(Request, ohttp::ClientResponse), Error> {
+        let target = mailbox_endpoint(
+            &self.session_context.directory,
+            &self.session_context.proposal_mailbox_id(),
+        );
+        let authorization =
+            DeleteAuthorization::sign(&self.session_context.receiver_key.secret_key().to_secp());
+        let (body, ohttp_ctx) = ohttp_encapsulate(
+            &self.session_context.ohttp_keys,
+            "DELETE",
+            target.as_str(),
+            Some(&authorization.to_bytes()),
+            None,
+        )?;
+        let req = Request::new_v2(&self.session_context.full_relay_url(ohttp_relay)?, &body);
+        Ok((req, ohttp_ctx))
+    }
+
+    /// Process the directory's response to [`Self::create_delete_request`].
+    pub fn process_delete_response(
+        &self,
+        body: &[u8],
+        context: ohttp::ClientResponse,
+    ) -> Result<(), SessionError> {
+        process_post_res(body, context)
+            .map_err(|e| InternalSessionError::DirectoryResponse(e).into())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ReceiverBuilder(SessionContext);
 
@@ -441,7 +527,13 @@ impl Receiver<Initialized> {
             &self.session_context.directory,
             &self.session_context.proposal_mailbox_id(),
         );
-        ohttp_encapsulate(&self.session_context.ohttp_keys, "GET", fallback_target.as_str(), None)
+        ohttp_encapsulate(
+            &self.session_context.ohttp_keys,
+            "GET",
+            fallback_target.as_str(),
+            None,
+            None,
+        )
     }
 
     fn extract_proposal_from_v1(self, response: &str) -> Result<OriginalPayload, ProtocolError> {
@@ -1073,6 +1165,24 @@ impl Receiver<PayjoinProposal> {
     pub fn create_post_request(
         &self,
         ohttp_relay: impl IntoUrl,
+    ) -> Result<(Request, ohttp::ClientResponse), Error> {
+        self.create_post_request_inner(ohttp_relay, None)
+    }
+
+    /// Like [`Self::create_post_request`], presenting a single-use
+    /// [`AccessToken`] to a directory which requires one for mailbox writes.
+    pub fn create_post_request_with_token(
+        &self,
+        ohttp_relay: impl IntoUrl,
+        access_token: &AccessToken,
+    ) -> Result<(Request, ohttp::ClientResponse), Error> {
+        self.create_post_request_inner(ohttp_relay, Some(access_token))
+    }
+
+    fn create_post_request_inner(
+        &self,
+        ohttp_relay: impl IntoUrl,
+        access_token: Option<&AccessToken>,
     ) -> Result<(Request, ohttp::ClientResponse), Error> {
         let target_resource: Url;
         let body: Vec<u8>;
@@ -1099,6 +1209,7 @@ impl Receiver<PayjoinProposal> {
             method,
             target_resource.as_str(),
             Some(&body),
+            access_token,
         )?;
 
         let req = Request::new_v2(&self.session_context.full_relay_url(ohttp_relay)?, &body);
@@ -1158,6 +1269,24 @@ impl Receiver<HasReplyableError> {
     pub fn create_error_request(
         &self,
         ohttp_relay: impl IntoUrl,
+    ) -> Result<(Request, ohttp::ClientResponse), SessionError> {
+        self.create_error_request_inner(ohttp_relay, None)
+    }
+
+    /// Like [`Self::create_error_request`], presenting a single-use
+    /// [`AccessToken`] to a directory which requires one for mailbox writes.
+    pub fn create_error_request_with_token(
+        &self,
+        ohttp_relay: impl IntoUrl,
+        access_token: &AccessToken,
+    ) -> Result<(Request, ohttp::ClientResponse), SessionError> {
+        self.create_error_request_inner(ohttp_relay, Some(access_token))
+    }
+
+    fn create_error_request_inner(
+        &self,
+        ohttp_relay: impl IntoUrl,
+        access_token: Option<&AccessToken>,
     ) -> Result<(Request, ohttp::ClientResponse), SessionError> {
         let session_context = &self.session_context;
         if session_context.expiration.elapsed() {
@@ -1180,9 +1309,14 @@ impl Receiver<HasReplyableError> {
                 err.to_json().to_string().as_bytes().to_vec()
             }
         };
-        let (body, ohttp_ctx) =
-            ohttp_encapsulate(&session_context.ohttp_keys.0, "POST", mailbox.as_str(), Some(&body))
-                .map_err(InternalSessionError::OhttpEncapsulation)?;
+        let (body, ohttp_ctx) = ohttp_encapsulate(
+            &session_context.ohttp_keys.0,
+            "POST",
+            mailbox.as_str(),
+            Some(&body),
+            access_token,
+        )
+        .map_err(InternalSessionError::OhttpEncapsulation)?;
         let req = Request::new_v2(&session_context.full_relay_url(ohttp_relay)?, &body);
         Ok((req, ohttp_ctx))
     }
@@ -1505,6 +1639,43 @@ pub mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_refresh_ohttp_keys_in_any_state() -> Result<(), BoxError> {
+        let refreshed_keys =
+            OhttpKeys(ohttp::KeyConfig::new(KEY_ID + 1, KEM, Vec::from(SYMMETRIC))?);
+        let session = ReceiveSession::MaybeInputsOwned(Receiver {
+            state: maybe_inputs_owned_v2_from_test_vector(),
+            session_context: SHARED_CONTEXT.clone(),
+        });
+
+        let persister = InMemoryTestPersister::default();
+        let refreshed = session
+            .refresh_ohttp_keys(refreshed_keys.clone())
+            .save(&persister)
+            .expect("InMemoryTestPersister shouldn't fail");
+        assert_eq!(
+            refreshed,
+            ReceiveSession::MaybeInputsOwned(Receiver {
+                state: maybe_inputs_owned_v2_from_test_vector(),
+                session_context: SessionContext {
+                    ohttp_keys: refreshed_keys.clone(),
+                    ..SHARED_CONTEXT.clone()
+                },
+            })
+        );
+        assert_eq!(
+            *persister.inner.read().expect("Shouldn't be poisoned").events,
+            vec![SessionEvent::RefreshedOhttpKeys(refreshed_keys.clone())]
+        );
+
+        let closed = ReceiveSession::Closed(SessionOutcome::Cancel)
+            .refresh_ohttp_keys(refreshed_keys)
+            .save(&persister)
+            .expect("InMemoryTestPersister shouldn't fail");
+        assert_eq!(closed, ReceiveSession::Closed(SessionOutcome::Cancel));
+        Ok(())
+    }
+
     #[test]
     fn test_v2_mutable_receiver_state_closures() {
         let persister = NoopSessionPersister::default();
@@ -1778,6 +1949,20 @@ pub mod test {
         );
     }
 
+    #[test]
+    fn test_create_delete_request() -> Result<(), BoxError> {
+        let now = crate::time::Time::now();
+        let context = SessionContext { expiration: now, ..SHARED_CONTEXT.clone() };
+        let authorization = DeleteAuthorization::sign(&context.receiver_key.secret_key().to_secp());
+        assert_eq!(authorization.mailbox_id(), context.proposal_mailbox_id());
+
+        // Expired sessions may still clean up their mailbox
+        let receiver = Receiver { state: Initialized {}, session_context: context };
+        let (_req, _ctx) = receiver.create_delete_request(EXAMPLE_URL)?;
+
+        Ok(())
+    }
+
     #[test]
     fn test_v2_pj_uri() {
         let uri =
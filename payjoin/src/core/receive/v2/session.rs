@@ -74,6 +74,9 @@ impl SessionHistory {
             .expect("Session event log must contain at least one event with pj_uri")
     }
 
+    /// The Payjoin directory hosting the session's mailboxes
+    pub fn directory(&self) -> url::Url { self.session_context().directory }
+
     fn get_unchecked_proposal(&self) -> Option<OriginalPayload> {
         self.events.iter().find_map(|event| match event {
             SessionEvent::RetrievedOriginalPayload { original, .. } => Some(original.clone()),
@@ -109,6 +112,13 @@ impl SessionHistory {
             _ => None,
         });
 
+        if let Some(ohttp_keys) = self.events.iter().rev().find_map(|event| match event {
+            SessionEvent::RefreshedOhttpKeys(ohttp_keys) => Some(ohttp_keys.clone()),
+            _ => None,
+        }) {
+            initial_session_context.ohttp_keys = ohttp_keys;
+        }
+
         initial_session_context
     }
 
@@ -155,6 +165,9 @@ pub enum SessionEvent {
     FinalizedProposal(bitcoin::Psbt),
     GotReplyableError(JsonReply),
     PostedPayjoinProposal(),
+    /// The directory's OHTTP keys were replaced, e.g. after the directory rotated them.
+    /// The session's mailbox and the already-shared Payjoin URI remain valid.
+    RefreshedOhttpKeys(crate::OhttpKeys),
     Closed(SessionOutcome),
 }
 
@@ -175,7 +188,7 @@ pub enum SessionOutcome {
 mod tests {
     use std::time::{Duration, SystemTime};
 
-    use payjoin_test_utils::{BoxError, EXAMPLE_URL};
+    use payjoin_test_utils::{BoxError, EXAMPLE_URL, KEM, KEY_ID, SYMMETRIC};
 
     use super::*;
     use crate::persist::test_utils::InMemoryTestPersister;
@@ -370,6 +383,51 @@ mod tests {
         run_session_history_test(test)
     }
 
+    #[test]
+    fn test_replaying_refreshed_ohttp_keys() -> Result<(), BoxError> {
+        let session_context = SHARED_CONTEXT.clone();
+        let original = original_from_test_vector();
+        let refreshed_keys = crate::OhttpKeys(
+            ohttp::KeyConfig::new(KEY_ID + 1, KEM, Vec::from(SYMMETRIC)).expect("valid key config"),
+        );
+
+        let test = SessionHistoryTest {
+            events: vec![
+                SessionEvent::Created(session_context.clone()),
+                SessionEvent::RefreshedOhttpKeys(refreshed_keys.clone()),
+                SessionEvent::RetrievedOriginalPayload {
+                    original: original.clone(),
+                    reply_key: None,
+                },
+            ],
+            expected_session_history: SessionHistoryExpectedOutcome {
+                fallback_tx: None,
+                expected_status: SessionStatus::Active,
+            },
+            expected_receiver_state: ReceiveSession::UncheckedOriginalPayload(Receiver {
+                state: UncheckedOriginalPayload { original },
+                session_context: SessionContext {
+                    ohttp_keys: refreshed_keys.clone(),
+                    ..session_context.clone()
+                },
+            }),
+        };
+        run_session_history_test(test)?;
+
+        // The already-shared URI is unaffected by the refresh
+        let history = SessionHistory::new(vec![
+            SessionEvent::Created(session_context.clone()),
+            SessionEvent::RefreshedOhttpKeys(refreshed_keys.clone()),
+        ]);
+        assert_eq!(
+            history.pj_uri().to_string(),
+            SessionHistory::new(vec![SessionEvent::Created(session_context)]).pj_uri().to_string()
+        );
+        assert_eq!(history.session_context().ohttp_keys, refreshed_keys);
+        assert_eq!(history.directory(), SHARED_CONTEXT.directory);
+        Ok(())
+    }
+
     #[test]
     fn getting_fallback_tx() -> Result<(), BoxError> {
         let persister = NoopSessionPersister::<SessionEvent>::default();
@@ -39,6 +39,8 @@ use url::Url;
 
 use super::error::BuildSenderError;
 use super::*;
+use crate::directory::deletion::DeleteAuthorization;
+use crate::directory::token::AccessToken;
 use crate::error::{InternalReplayError, ReplayError};
 use crate::hpke::{decrypt_message_b, encrypt_message_a, HpkeSecretKey};
 use crate::ohttp::{ohttp_encapsulate, process_get_res, process_post_res};
@@ -219,6 +221,57 @@ impl<State> core::ops::DerefMut for Sender<State> {
 impl<State> Sender<State> {
     /// The endpoint in the Payjoin URI
     pub fn endpoint(&self) -> String { self.session_context.pj_param.endpoint().to_string() }
+
+    /// Construct an OHTTP Encapsulated HTTP DELETE request for the mailbox the receiver posts
+    /// the Proposal PSBT to, so a finished or cancelled session doesn't linger on the directory
+    /// until it expires.
+    ///
+    /// The request is authorized by a signature from the reply key the mailbox ID is derived
+    /// from. Deleting a mailbox that is already gone succeeds.
+    pub fn create_delete_request(
+        &self,
+        ohttp_relay: impl IntoUrl,
+    ) -> Result<(Request, ohttp::ClientResponse), CreateRequestError> {
+        let url = self.session_context.reply_mailbox_url()?;
+        let authorization = DeleteAuthorization::sign(&self.session_context.reply_key.to_secp());
+        let ohttp_keys = self.session_context.pj_param.ohttp_keys();
+        let (body, ohttp_ctx) = ohttp_encapsulate(
+            ohttp_keys,
+            "DELETE",
+            url.as_str(),
+            Some(&authorization.to_bytes()),
+            None,
+        )
+        .map_err(InternalCreateRequestError::OhttpEncapsulation)?;
+
+        let url = ohttp_relay.into_url().map_err(InternalCreateRequestError::Url)?;
+        Ok((Request::new_v2(&url, &body), ohttp_ctx))
+    }
+
+    /// Process the directory's response to [`Self::create_delete_request`].
+    pub fn process_delete_response(
+        &self,
+        response: &[u8],
+        ohttp_ctx: ohttp::ClientResponse,
+    ) -> Result<(), EncapsulationError> {
+        process_post_res(response, ohttp_ctx)
+            .map_err(|e| InternalEncapsulationError::DirectoryResponse(e).into())
+    }
+}
+
+impl SessionContext {
+    /// The mailbox where the receiver posts its reply, derived from the reply key.
+    fn reply_mailbox_url(&self) -> Result<Url, InternalCreateRequestError> {
+        // TODO unify with receiver's fn short_id_from_pubkey
+        let hash = sha256::Hash::hash(
+            &HpkeKeyPair::from_secret_key(&self.reply_key).public_key().to_compressed_bytes(),
+        );
+        let mailbox: ShortId = hash.into();
+        Url::parse(self.pj_param.endpoint().as_str())
+            .expect("Could not parse url")
+            .join(&mailbox.to_string())
+            .map_err(|e| InternalCreateRequestError::Url(e.into()))
+    }
 }
 
 /// Represents the various states of a Payjoin send session during the protocol flow.
@@ -288,6 +341,24 @@ impl Sender<WithReplyKey> {
     pub fn create_v2_post_request(
         &self,
         ohttp_relay: impl IntoUrl,
+    ) -> Result<(Request, ClientResponse), CreateRequestError> {
+        self.create_v2_post_request_inner(ohttp_relay, None)
+    }
+
+    /// Like [`Self::create_v2_post_request`], presenting a single-use
+    /// [`AccessToken`] to a directory which requires one for mailbox writes.
+    pub fn create_v2_post_request_with_token(
+        &self,
+        ohttp_relay: impl IntoUrl,
+        access_token: &AccessToken,
+    ) -> Result<(Request, ClientResponse), CreateRequestError> {
+        self.create_v2_post_request_inner(ohttp_relay, Some(access_token))
+    }
+
+    fn create_v2_post_request_inner(
+        &self,
+        ohttp_relay: impl IntoUrl,
+        access_token: Option<&AccessToken>,
     ) -> Result<(Request, ClientResponse), CreateRequestError> {
         if self.session_context.pj_param.expiration().elapsed() {
             return Err(InternalCreateRequestError::Expired(
@@ -313,6 +384,7 @@ impl Sender<WithReplyKey> {
             base_url,
             self.session_context.pj_param.receiver_pubkey().clone(),
             ohttp_keys,
+            access_token,
         )?;
         Ok((request, ohttp_ctx))
     }
@@ -366,6 +438,7 @@ pub(crate) fn extract_request(
     url: Url,
     receiver_pubkey: HpkePublicKey,
     ohttp_keys: &OhttpKeys,
+    access_token: Option<&AccessToken>,
 ) -> Result<(Request, ClientResponse), CreateRequestError> {
     let ohttp_relay = ohttp_relay.into_url()?;
     let body = encrypt_message_a(
@@ -375,8 +448,9 @@ pub(crate) fn extract_request(
     )
     .map_err(InternalCreateRequestError::Hpke)?;
 
-    let (body, ohttp_ctx) = ohttp_encapsulate(ohttp_keys, "POST", url.as_str(), Some(&body))
-        .map_err(InternalCreateRequestError::OhttpEncapsulation)?;
+    let (body, ohttp_ctx) =
+        ohttp_encapsulate(ohttp_keys, "POST", url.as_str(), Some(&body), access_token)
+            .map_err(InternalCreateRequestError::OhttpEncapsulation)?;
     tracing::debug!("ohttp_relay_url: {ohttp_relay:?}");
     let directory_base = url.join("/").map_err(|e| InternalCreateRequestError::Url(e.into()))?;
     let full_ohttp_relay = ohttp_relay
@@ -423,17 +497,7 @@ impl Sender<PollingForProposal> {
         &self,
         ohttp_relay: impl IntoUrl,
     ) -> Result<(Request, ohttp::ClientResponse), CreateRequestError> {
-        // TODO unify with receiver's fn short_id_from_pubkey
-        let hash = sha256::Hash::hash(
-            &HpkeKeyPair::from_secret_key(&self.session_context.reply_key)
-                .public_key()
-                .to_compressed_bytes(),
-        );
-        let mailbox: ShortId = hash.into();
-        let url = Url::parse(self.session_context.pj_param.endpoint().as_str())
-            .expect("Could not parse url")
-            .join(&mailbox.to_string())
-            .map_err(|e| InternalCreateRequestError::Url(e.into()))?;
+        let url = self.session_context.reply_mailbox_url()?;
         let body = encrypt_message_a(
             Vec::new(),
             HpkeKeyPair::from_secret_key(&self.session_context.reply_key).public_key(),
@@ -441,8 +505,9 @@ impl Sender<PollingForProposal> {
         )
         .map_err(InternalCreateRequestError::Hpke)?;
         let ohttp_keys = self.session_context.pj_param.ohttp_keys();
-        let (body, ohttp_ctx) = ohttp_encapsulate(ohttp_keys, "GET", url.as_str(), Some(&body))
-            .map_err(InternalCreateRequestError::OhttpEncapsulation)?;
+        let (body, ohttp_ctx) =
+            ohttp_encapsulate(ohttp_keys, "GET", url.as_str(), Some(&body), None)
+                .map_err(InternalCreateRequestError::OhttpEncapsulation)?;
 
         let url = ohttp_relay.into_url().map_err(InternalCreateRequestError::Url)?;
         Ok((Request::new_v2(&url, &body), ohttp_ctx))
@@ -624,6 +689,21 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_create_delete_request() -> Result<(), BoxError> {
+        let expiration =
+            Time::from_now(Duration::from_secs(60)).expect("expiration should be valid");
+        let sender = create_sender_context(expiration)?;
+        let authorization = DeleteAuthorization::sign(&sender.session_context.reply_key.to_secp());
+        let reply_mailbox =
+            sender.session_context.reply_mailbox_url().map_err(CreateRequestError::from)?;
+        assert!(reply_mailbox.path().ends_with(&authorization.mailbox_id().to_string()));
+
+        let (request, _) = sender.create_delete_request(EXAMPLE_URL)?;
+        assert_eq!(request.url, Url::parse(EXAMPLE_URL)?.to_string());
+        Ok(())
+    }
+
     #[test]
     fn test_v2_sender_builder() {
         let address = Address::from_str("2N47mmrWXsNBvQR6k78hWJoTji57zXwNcU7")
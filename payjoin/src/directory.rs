@@ -1,5 +1,8 @@
 //! Types relevant to the Payjoin Directory as defined in BIP 77.
 
+pub mod deletion;
+pub mod token;
+
 pub const ENCAPSULATED_MESSAGE_BYTES: usize = 8192;
 
 /// A 64-bit identifier used to identify Payjoin Directory entries.
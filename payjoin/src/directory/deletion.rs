@@ -0,0 +1,207 @@
+//! Authorization for deleting Payjoin Directory mailboxes.
+//!
+//! A mailbox ID is the truncated hash of the public key whose holder reads
+//! from that mailbox: the receiver's key for the Original PSBT, and the
+//! sender's reply key for the Proposal PSBT. Once a session is finished or
+//! cancelled, the key holder may ask the directory to delete the mailbox
+//! right away instead of waiting for it to expire.
+//!
+//! The `DELETE` request body is the compressed public key, an expiry time and
+//! a BIP 340 Schnorr signature over both, so that only the key holder can
+//! delete the mailbox and a captured request can't be replayed indefinitely.
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{schnorr, Keypair, Message, PublicKey, Secp256k1, SecretKey};
+
+use super::ShortId;
+
+/// How long an authorization is valid for once signed.
+pub const DEFAULT_VALIDITY: Duration = Duration::from_secs(60 * 5);
+
+/// The furthest in the future a directory accepts an expiry, which bounds
+/// how long a captured authorization remains usable despite clock skew.
+pub const MAX_VALIDITY: Duration = Duration::from_secs(60 * 60);
+
+const PUBLIC_KEY_SIZE: usize = 33;
+const EXPIRY_SIZE: usize = 8;
+const SIGNATURE_SIZE: usize = 64;
+const AUTHORIZATION_SIZE: usize = PUBLIC_KEY_SIZE + EXPIRY_SIZE + SIGNATURE_SIZE;
+
+const SIGNATURE_TAG: &[u8] = b"payjoin-directory-delete/v1";
+
+/// A signed request to delete the mailbox of [`DeleteAuthorization::mailbox_id`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeleteAuthorization {
+    public_key: PublicKey,
+    expiry: u64,
+    signature: schnorr::Signature,
+}
+
+impl DeleteAuthorization {
+    /// Authorize deletion of the mailbox belonging to `secret_key`, valid
+    /// for [`DEFAULT_VALIDITY`].
+    pub fn sign(secret_key: &SecretKey) -> Self {
+        let expiry = unix_seconds(SystemTime::now() + DEFAULT_VALIDITY);
+        Self::sign_with_expiry(secret_key, expiry)
+    }
+
+    fn sign_with_expiry(secret_key: &SecretKey, expiry: u64) -> Self {
+        let secp = Secp256k1::new();
+        let public_key = PublicKey::from_secret_key(&secp, secret_key);
+        let keypair = Keypair::from_secret_key(&secp, secret_key);
+        let signature = secp.sign_schnorr_no_aux_rand(&message(&public_key, expiry), &keypair);
+        Self { public_key, expiry, signature }
+    }
+
+    /// The mailbox this authorization applies to.
+    pub fn mailbox_id(&self) -> ShortId { sha256::Hash::hash(&self.public_key.serialize()).into() }
+
+    /// Check that this authorizes deleting mailbox `id` at time `now`.
+    pub fn verify(&self, id: &ShortId, now: SystemTime) -> Result<(), DeletionError> {
+        if self.mailbox_id() != *id {
+            return Err(InternalDeletionError::WrongMailbox.into());
+        }
+        let now = unix_seconds(now);
+        if self.expiry < now {
+            return Err(InternalDeletionError::Expired.into());
+        }
+        if self.expiry > now + MAX_VALIDITY.as_secs() {
+            return Err(InternalDeletionError::ExpiryTooFar.into());
+        }
+        let (x_only, _parity) = self.public_key.x_only_public_key();
+        Secp256k1::verification_only()
+            .verify_schnorr(&self.signature, &message(&self.public_key, self.expiry), &x_only)
+            .map_err(|_| InternalDeletionError::InvalidSignature.into())
+    }
+
+    pub fn to_bytes(&self) -> [u8; AUTHORIZATION_SIZE] {
+        let mut bytes = [0u8; AUTHORIZATION_SIZE];
+        bytes[..PUBLIC_KEY_SIZE].copy_from_slice(&self.public_key.serialize());
+        bytes[PUBLIC_KEY_SIZE..PUBLIC_KEY_SIZE + EXPIRY_SIZE]
+            .copy_from_slice(&self.expiry.to_be_bytes());
+        bytes[PUBLIC_KEY_SIZE + EXPIRY_SIZE..].copy_from_slice(&self.signature.serialize());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DeletionError> {
+        if bytes.len() != AUTHORIZATION_SIZE {
+            return Err(InternalDeletionError::InvalidLength(bytes.len()).into());
+        }
+        let (public_key, rest) = bytes.split_at(PUBLIC_KEY_SIZE);
+        let (expiry, signature) = rest.split_at(EXPIRY_SIZE);
+        Ok(Self {
+            public_key: PublicKey::from_slice(public_key)
+                .map_err(|_| InternalDeletionError::InvalidKey)?,
+            expiry: u64::from_be_bytes(expiry.try_into().expect("expiry length is fixed")),
+            signature: schnorr::Signature::from_slice(signature)
+                .map_err(|_| InternalDeletionError::InvalidSignature)?,
+        })
+    }
+}
+
+fn message(public_key: &PublicKey, expiry: u64) -> Message {
+    let mut engine = sha256::Hash::engine();
+    engine.input(SIGNATURE_TAG);
+    engine.input(&public_key.serialize());
+    engine.input(&expiry.to_be_bytes());
+    Message::from_digest(sha256::Hash::from_engine(engine).to_byte_array())
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Error parsing or verifying a mailbox deletion authorization.
+#[derive(Debug)]
+pub struct DeletionError(InternalDeletionError);
+
+#[derive(Debug)]
+enum InternalDeletionError {
+    InvalidLength(usize),
+    InvalidKey,
+    InvalidSignature,
+    WrongMailbox,
+    Expired,
+    ExpiryTooFar,
+}
+
+impl From<InternalDeletionError> for DeletionError {
+    fn from(value: InternalDeletionError) -> Self { DeletionError(value) }
+}
+
+impl fmt::Display for DeletionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalDeletionError::*;
+
+        match &self.0 {
+            InvalidLength(len) => write!(f, "Unexpected deletion authorization length {len}"),
+            InvalidKey => write!(f, "Invalid public key in deletion authorization"),
+            InvalidSignature => write!(f, "Deletion authorization signature is invalid"),
+            WrongMailbox => write!(f, "Deletion authorization is for a different mailbox"),
+            Expired => write!(f, "Deletion authorization has expired"),
+            ExpiryTooFar => write!(
+                f,
+                "Deletion authorization expires more than {}s in the future",
+                MAX_VALIDITY.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeletionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret_key() -> SecretKey { SecretKey::from_slice(&[7u8; 32]).expect("valid key") }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let authorization = DeleteAuthorization::sign(&secret_key());
+        let id = authorization.mailbox_id();
+        let public_key = PublicKey::from_secret_key(&Secp256k1::new(), &secret_key());
+        assert_eq!(id, sha256::Hash::hash(&public_key.serialize()).into());
+
+        let parsed = DeleteAuthorization::from_bytes(&authorization.to_bytes())
+            .expect("authorization should round trip");
+        assert_eq!(parsed, authorization);
+        assert!(parsed.verify(&id, SystemTime::now()).is_ok());
+        assert!(parsed.verify(&ShortId([0u8; 8]), SystemTime::now()).is_err());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let now = SystemTime::now();
+        let authorization = DeleteAuthorization::sign(&secret_key());
+        let id = authorization.mailbox_id();
+        assert!(authorization.verify(&id, now + DEFAULT_VALIDITY * 2).is_err(), "expired");
+
+        let too_far = unix_seconds(now + MAX_VALIDITY * 2);
+        let authorization = DeleteAuthorization::sign_with_expiry(&secret_key(), too_far);
+        assert!(authorization.verify(&id, now).is_err(), "expiry beyond the maximum validity");
+    }
+
+    #[test]
+    fn test_tampered_authorization_rejected() {
+        let authorization = DeleteAuthorization::sign(&secret_key());
+        let id = authorization.mailbox_id();
+
+        let mut bytes = authorization.to_bytes();
+        bytes[PUBLIC_KEY_SIZE + EXPIRY_SIZE - 1] ^= 1;
+        let extended = DeleteAuthorization::from_bytes(&bytes).expect("well formed");
+        assert!(extended.verify(&id, SystemTime::now()).is_err(), "the expiry is signed");
+
+        let other = SecretKey::from_slice(&[8u8; 32]).expect("valid key");
+        let mut bytes = authorization.to_bytes();
+        bytes[..PUBLIC_KEY_SIZE]
+            .copy_from_slice(&PublicKey::from_secret_key(&Secp256k1::new(), &other).serialize());
+        let forged = DeleteAuthorization::from_bytes(&bytes).expect("well formed");
+        assert!(forged.verify(&forged.mailbox_id(), SystemTime::now()).is_err());
+
+        assert!(DeleteAuthorization::from_bytes(&[0u8; AUTHORIZATION_SIZE - 1]).is_err());
+    }
+}
@@ -0,0 +1,459 @@
+//! Anonymous access tokens for Payjoin Directory mailbox writes.
+//!
+//! Clients behind an OHTTP relay all share the relay's address, so a directory
+//! can't rate limit them by address. Instead, a directory may require mailbox
+//! POST requests to carry a single-use access token, in the style of Privacy
+//! Pass.
+//!
+//! Tokens are issued using a verifiable oblivious pseudorandom function
+//! (VOPRF) over secp256k1:
+//!
+//! 1. The client picks a random nonce `t`, hashes it to a curve point `P`, and
+//!    sends the blinded element `B = r·P` for a random scalar `r`.
+//! 2. The directory returns `Z = k·B` for its issuer key `k`, along with a
+//!    proof that `Z` and its public key `K = k·G` share the same discrete log.
+//!    The proof only binds `Z` to the `K` the client fetched. Nothing checks
+//!    that every client fetched the same `K`, so a directory handing out
+//!    distinct keys could still tell groups of clients apart.
+//! 3. The client unblinds `N = r⁻¹·Z = k·P`. The token `(t, N)` can be
+//!    verified by the directory but not linked to its issuance, since the
+//!    directory never saw `t` or `P`.
+
+use std::fmt;
+
+use bitcoin::base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use bitcoin::base64::Engine;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+
+/// The `Authorization` scheme used to present a token.
+pub const AUTHORIZATION_SCHEME: &str = "PrivateToken";
+
+/// The maximum number of tokens issued per request.
+pub const MAX_BATCH_SIZE: usize = 32;
+
+const NONCE_SIZE: usize = 32;
+const ELEMENT_SIZE: usize = 33;
+const PROOF_SIZE: usize = 64;
+const TOKEN_SIZE: usize = NONCE_SIZE + ELEMENT_SIZE;
+const EVALUATION_SIZE: usize = ELEMENT_SIZE + PROOF_SIZE;
+
+const HASH_TO_CURVE_TAG: &[u8] = b"payjoin-directory-token/v1/hash-to-curve";
+const CHALLENGE_TAG: &[u8] = b"payjoin-directory-token/v1/challenge";
+const PROOF_NONCE_TAG: &[u8] = b"payjoin-directory-token/v1/proof-nonce";
+
+/// The secp256k1 group order minus two, for inversion by Fermat's little theorem.
+#[cfg(feature = "v2")]
+const ORDER_MINUS_TWO: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x3f,
+];
+
+/// A directory's secret key for issuing and verifying tokens.
+#[derive(Clone)]
+pub struct IssuerSecretKey(SecretKey);
+
+impl fmt::Debug for IssuerSecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("IssuerSecretKey").field(&"[redacted]").finish()
+    }
+}
+
+impl IssuerSecretKey {
+    /// Use 32 uniformly random bytes as the issuer key.
+    pub fn from_bytes(bytes: [u8; 32]) -> Result<Self, TokenError> {
+        SecretKey::from_slice(&bytes).map(Self).map_err(|_| InternalTokenError::InvalidKey.into())
+    }
+
+    pub fn public_key(&self) -> IssuerPublicKey {
+        IssuerPublicKey(PublicKey::from_secret_key(&Secp256k1::new(), &self.0))
+    }
+
+    /// Evaluate a batch of blinded elements produced by a client's
+    /// `TokenRequest`, returning each evaluation along with its proof.
+    pub fn issue(&self, request: &[u8]) -> Result<Vec<u8>, TokenError> {
+        if request.is_empty()
+            || request.len() % ELEMENT_SIZE != 0
+            || request.len() / ELEMENT_SIZE > MAX_BATCH_SIZE
+        {
+            return Err(InternalTokenError::UnexpectedLength(request.len()).into());
+        }
+
+        let secp = Secp256k1::new();
+        let public_key = PublicKey::from_secret_key(&secp, &self.0);
+        let mut response = Vec::with_capacity(request.len() / ELEMENT_SIZE * EVALUATION_SIZE);
+        for blinded in request.chunks_exact(ELEMENT_SIZE) {
+            let blinded =
+                PublicKey::from_slice(blinded).map_err(|_| InternalTokenError::InvalidElement)?;
+            let evaluated = blinded
+                .mul_tweak(&secp, &Scalar::from(self.0))
+                .map_err(|_| InternalTokenError::InvalidElement)?;
+
+            // Chaum-Pedersen proof that log_G(K) == log_B(Z), with a
+            // deterministic nonce so no randomness is needed
+            let nonce = hash_to_scalar(
+                PROOF_NONCE_TAG,
+                &[&self.0.secret_bytes()[..], &blinded.serialize()[..]],
+            );
+            let a1 = PublicKey::from_secret_key(&secp, &nonce);
+            let a2 = blinded
+                .mul_tweak(&secp, &Scalar::from(nonce))
+                .map_err(|_| InternalTokenError::InvalidElement)?;
+            let challenge = challenge(&public_key, &blinded, &evaluated, &a1, &a2);
+            // s = w - c·k
+            let s = self
+                .0
+                .mul_tweak(&Scalar::from(challenge))
+                .expect("product of nonzero scalars is nonzero")
+                .negate()
+                .add_tweak(&Scalar::from(nonce))
+                .map_err(|_| InternalTokenError::InvalidElement)?;
+
+            response.extend_from_slice(&evaluated.serialize());
+            response.extend_from_slice(&challenge.secret_bytes());
+            response.extend_from_slice(&s.secret_bytes());
+        }
+        Ok(response)
+    }
+
+    /// Check that `token` was issued with this key. Callers must also reject
+    /// tokens whose [`AccessToken::nonce`] was already redeemed.
+    pub fn verify(&self, token: &AccessToken) -> bool {
+        let secp = Secp256k1::new();
+        hash_to_curve(&token.nonce)
+            .mul_tweak(&secp, &Scalar::from(self.0))
+            .is_ok_and(|expected| expected == token.element)
+    }
+}
+
+/// The public key clients use to verify token issuance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IssuerPublicKey(PublicKey);
+
+impl IssuerPublicKey {
+    pub fn to_bytes(&self) -> [u8; ELEMENT_SIZE] { self.0.serialize() }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TokenError> {
+        PublicKey::from_slice(bytes).map(Self).map_err(|_| InternalTokenError::InvalidKey.into())
+    }
+}
+
+/// A single-use token authorizing one mailbox write.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessToken {
+    nonce: [u8; NONCE_SIZE],
+    element: PublicKey,
+}
+
+impl AccessToken {
+    /// The random nonce identifying this token, for double spend checks.
+    pub fn nonce(&self) -> &[u8; NONCE_SIZE] { &self.nonce }
+
+    pub fn to_bytes(&self) -> [u8; TOKEN_SIZE] {
+        let mut bytes = [0u8; TOKEN_SIZE];
+        bytes[..NONCE_SIZE].copy_from_slice(&self.nonce);
+        bytes[NONCE_SIZE..].copy_from_slice(&self.element.serialize());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TokenError> {
+        if bytes.len() != TOKEN_SIZE {
+            return Err(InternalTokenError::UnexpectedLength(bytes.len()).into());
+        }
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&bytes[..NONCE_SIZE]);
+        let element = PublicKey::from_slice(&bytes[NONCE_SIZE..])
+            .map_err(|_| InternalTokenError::InvalidElement)?;
+        Ok(Self { nonce, element })
+    }
+
+    /// The value of an `Authorization` header presenting this token.
+    pub fn to_header_value(&self) -> String {
+        format!("{AUTHORIZATION_SCHEME} token={}", URL_SAFE_NO_PAD.encode(self.to_bytes()))
+    }
+
+    /// Parse the value of an `Authorization` header presenting a token.
+    pub fn from_header_value(value: &str) -> Result<Self, TokenError> {
+        let encoded = value
+            .strip_prefix(AUTHORIZATION_SCHEME)
+            .and_then(|params| params.trim_start().strip_prefix("token="))
+            .ok_or(InternalTokenError::InvalidHeader)?;
+        let bytes = URL_SAFE_NO_PAD
+            .decode(encoded.trim())
+            .map_err(|_| InternalTokenError::InvalidHeader)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// A client's pending request for a batch of tokens.
+///
+/// Send [`TokenRequest::body`] to the directory's token issuance endpoint and
+/// pass its response to [`TokenRequest::finalize`].
+#[cfg(feature = "v2")]
+#[derive(Debug)]
+pub struct TokenRequest {
+    blinds: Vec<Blind>,
+}
+
+#[cfg(feature = "v2")]
+#[derive(Debug)]
+struct Blind {
+    nonce: [u8; NONCE_SIZE],
+    factor: SecretKey,
+    element: PublicKey,
+}
+
+#[cfg(feature = "v2")]
+impl TokenRequest {
+    /// Blind `count` fresh token nonces, up to [`MAX_BATCH_SIZE`].
+    pub fn new(count: usize) -> Result<Self, TokenError> {
+        use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+
+        if count == 0 || count > MAX_BATCH_SIZE {
+            return Err(InternalTokenError::InvalidBatchSize(count).into());
+        }
+        let secp = Secp256k1::new();
+        let mut rng = thread_rng();
+        let blinds = (0..count)
+            .map(|_| {
+                let mut nonce = [0u8; NONCE_SIZE];
+                rng.fill_bytes(&mut nonce);
+                let factor = SecretKey::new(&mut rng);
+                let element = hash_to_curve(&nonce)
+                    .mul_tweak(&secp, &Scalar::from(factor))
+                    .expect("blinding by a nonzero scalar cannot yield infinity");
+                Blind { nonce, factor, element }
+            })
+            .collect();
+        Ok(Self { blinds })
+    }
+
+    /// The request body for the directory's token issuance endpoint.
+    pub fn body(&self) -> Vec<u8> {
+        self.blinds.iter().flat_map(|blind| blind.element.serialize()).collect()
+    }
+
+    /// Verify the directory's response against its public key and unblind the tokens.
+    pub fn finalize(
+        self,
+        response: &[u8],
+        issuer: &IssuerPublicKey,
+    ) -> Result<Vec<AccessToken>, TokenError> {
+        if response.len() != self.blinds.len() * EVALUATION_SIZE {
+            return Err(InternalTokenError::UnexpectedLength(response.len()).into());
+        }
+
+        let secp = Secp256k1::new();
+        self.blinds
+            .into_iter()
+            .zip(response.chunks_exact(EVALUATION_SIZE))
+            .map(|(blind, evaluation)| {
+                let evaluated = PublicKey::from_slice(&evaluation[..ELEMENT_SIZE])
+                    .map_err(|_| InternalTokenError::InvalidElement)?;
+                let challenge = SecretKey::from_slice(&evaluation[ELEMENT_SIZE..][..32])
+                    .map_err(|_| InternalTokenError::InvalidProof)?;
+                let s = SecretKey::from_slice(&evaluation[ELEMENT_SIZE + 32..])
+                    .map_err(|_| InternalTokenError::InvalidProof)?;
+
+                // A1 = s·G + c·K and A2 = s·B + c·Z
+                let a1 = PublicKey::from_secret_key(&secp, &s)
+                    .combine(
+                        &issuer
+                            .0
+                            .mul_tweak(&secp, &Scalar::from(challenge))
+                            .map_err(|_| InternalTokenError::InvalidProof)?,
+                    )
+                    .map_err(|_| InternalTokenError::InvalidProof)?;
+                let a2 = blind
+                    .element
+                    .mul_tweak(&secp, &Scalar::from(s))
+                    .map_err(|_| InternalTokenError::InvalidProof)?
+                    .combine(
+                        &evaluated
+                            .mul_tweak(&secp, &Scalar::from(challenge))
+                            .map_err(|_| InternalTokenError::InvalidProof)?,
+                    )
+                    .map_err(|_| InternalTokenError::InvalidProof)?;
+                if challenge != self::challenge(&issuer.0, &blind.element, &evaluated, &a1, &a2) {
+                    return Err(InternalTokenError::InvalidProof.into());
+                }
+
+                let element = evaluated
+                    .mul_tweak(&secp, &Scalar::from(invert(&blind.factor)))
+                    .map_err(|_| InternalTokenError::InvalidElement)?;
+                Ok(AccessToken { nonce: blind.nonce, element })
+            })
+            .collect()
+    }
+}
+
+fn tagged_hash(tag: &[u8], counter: u8, data: &[&[u8]]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag);
+    engine.input(&[counter]);
+    for data in data {
+        engine.input(data);
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// Hash to a curve point by try-and-increment. This isn't constant time, which
+/// is fine since only random token nonces are hashed.
+fn hash_to_curve(nonce: &[u8; NONCE_SIZE]) -> PublicKey {
+    let mut compressed = [0u8; ELEMENT_SIZE];
+    compressed[0] = 0x02;
+    (0..=u8::MAX)
+        .find_map(|counter| {
+            compressed[1..].copy_from_slice(&tagged_hash(HASH_TO_CURVE_TAG, counter, &[nonce]));
+            PublicKey::from_slice(&compressed).ok()
+        })
+        .expect("a valid x coordinate is found with overwhelming probability")
+}
+
+fn hash_to_scalar(tag: &[u8], data: &[&[u8]]) -> SecretKey {
+    (0..=u8::MAX)
+        .find_map(|counter| SecretKey::from_slice(&tagged_hash(tag, counter, data)).ok())
+        .expect("a valid scalar is found with overwhelming probability")
+}
+
+fn challenge(
+    public_key: &PublicKey,
+    blinded: &PublicKey,
+    evaluated: &PublicKey,
+    a1: &PublicKey,
+    a2: &PublicKey,
+) -> SecretKey {
+    hash_to_scalar(
+        CHALLENGE_TAG,
+        &[
+            &public_key.serialize(),
+            &blinded.serialize(),
+            &evaluated.serialize(),
+            &a1.serialize(),
+            &a2.serialize(),
+        ],
+    )
+}
+
+/// Invert a scalar modulo the group order by raising it to the power n - 2.
+#[cfg(feature = "v2")]
+fn invert(x: &SecretKey) -> SecretKey {
+    let mut result: Option<SecretKey> = None;
+    for byte in ORDER_MINUS_TWO {
+        for bit in (0..8).rev() {
+            if let Some(r) = result {
+                result = Some(r.mul_tweak(&Scalar::from(r)).expect("nonzero"));
+            }
+            if (byte >> bit) & 1 == 1 {
+                result = Some(match result {
+                    None => *x,
+                    Some(r) => r.mul_tweak(&Scalar::from(*x)).expect("nonzero"),
+                });
+            }
+        }
+    }
+    result.expect("exponent is nonzero")
+}
+
+/// Error issuing, finalizing or parsing access tokens.
+#[derive(Debug)]
+pub struct TokenError(InternalTokenError);
+
+#[derive(Debug)]
+enum InternalTokenError {
+    InvalidKey,
+    UnexpectedLength(usize),
+    InvalidElement,
+    #[cfg(feature = "v2")]
+    InvalidProof,
+    InvalidHeader,
+    #[cfg(feature = "v2")]
+    InvalidBatchSize(usize),
+}
+
+impl From<InternalTokenError> for TokenError {
+    fn from(value: InternalTokenError) -> Self { TokenError(value) }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalTokenError::*;
+
+        match &self.0 {
+            InvalidKey => write!(f, "Invalid token issuer key"),
+            UnexpectedLength(len) => write!(f, "Unexpected token message length {len}"),
+            InvalidElement => write!(f, "Invalid curve point in token message"),
+            #[cfg(feature = "v2")]
+            InvalidProof => write!(f, "Token issuance proof is invalid"),
+            InvalidHeader => write!(f, "Malformed {AUTHORIZATION_SCHEME} authorization header"),
+            #[cfg(feature = "v2")]
+            InvalidBatchSize(count) =>
+                write!(f, "Can't request {count} tokens, the maximum is {MAX_BATCH_SIZE}"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+#[cfg(all(test, feature = "v2"))]
+mod tests {
+    use super::*;
+
+    fn issuer() -> IssuerSecretKey { IssuerSecretKey::from_bytes([7u8; 32]).expect("valid key") }
+
+    #[test]
+    fn test_invert() {
+        let x = SecretKey::from_slice(&[3u8; 32]).expect("valid scalar");
+        let one = x.mul_tweak(&Scalar::from(invert(&x))).expect("nonzero");
+        assert_eq!(one.secret_bytes(), Scalar::ONE.to_be_bytes());
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let issuer = issuer();
+        let request = TokenRequest::new(3).expect("valid batch size");
+        let response = issuer.issue(&request.body()).expect("issuance should succeed");
+        let tokens =
+            request.finalize(&response, &issuer.public_key()).expect("proofs should verify");
+
+        assert_eq!(tokens.len(), 3);
+        for token in &tokens {
+            assert!(issuer.verify(token));
+            let parsed = AccessToken::from_header_value(&token.to_header_value())
+                .expect("header should round trip");
+            assert_eq!(&parsed, token);
+        }
+
+        let other = IssuerSecretKey::from_bytes([8u8; 32]).expect("valid key");
+        assert!(!other.verify(&tokens[0]), "tokens are bound to the issuer key");
+    }
+
+    #[test]
+    fn test_proof_rejects_other_key() {
+        let request = TokenRequest::new(1).expect("valid batch size");
+        let response = issuer().issue(&request.body()).expect("issuance should succeed");
+        let other = IssuerSecretKey::from_bytes([8u8; 32]).expect("valid key");
+
+        assert!(
+            request.finalize(&response, &other.public_key()).is_err(),
+            "evaluation with a different key than advertised must be rejected"
+        );
+    }
+
+    #[test]
+    fn test_forged_token_rejected() {
+        let mut bytes = [2u8; TOKEN_SIZE];
+        bytes[NONCE_SIZE..].copy_from_slice(&issuer().public_key().to_bytes());
+        let forged = AccessToken::from_bytes(&bytes).expect("well formed token");
+        assert!(!issuer().verify(&forged));
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        assert!(TokenRequest::new(0).is_err());
+        assert!(TokenRequest::new(MAX_BATCH_SIZE + 1).is_err());
+        assert!(issuer().issue(&[]).is_err());
+        assert!(issuer().issue(&[2u8; ELEMENT_SIZE - 1]).is_err());
+        assert!(AccessToken::from_header_value("Bearer abc").is_err());
+    }
+}
@@ -8,7 +8,7 @@
 //!
 //! Only the latest BIP 77 Payjoin V2 is enabled by default. To use BIP 78 Payjoin V1, enable the `v1` feature.
 //!
-//! The library is perfectly IO-agnostic — in fact, it does no IO by default without the `io` feature.
+//! The library is perfectly IO-agnostic — in fact, it does no IO by default without the `io` or `blocking-io` features.
 //!
 //! Types relevant to a Payjoin Directory as defined in BIP 77 are available in the [`directory`] module enabled by
 //!  the `directory` feature.
@@ -259,6 +259,38 @@ mod integration {
             Ok(())
         }
 
+        #[cfg(feature = "blocking-io")]
+        #[tokio::test(flavor = "multi_thread")]
+        async fn test_blocking_fetch_ohttp_keys_through_relay() -> Result<(), BoxSendSyncError> {
+            let mut services = TestServices::initialize().await?;
+            let result = tokio::select!(
+            err = services.take_directory_handle() => panic!("Directory server exited early: {:?}", err),
+            res = fetch_through_relay(&services) => res
+            );
+            assert!(result.is_ok(), "{result:?}");
+
+            async fn fetch_through_relay(services: &TestServices) -> Result<(), BoxSendSyncError> {
+                services.wait_for_services_ready().await?;
+                let expected = services.fetch_ohttp_keys().await?;
+                let relay = services.ohttp_relay_url();
+                let directory = services.directory_url();
+                let cert = services.cert();
+                // The relay is an http:// CONNECT proxy to the https:// directory
+                let fetched = tokio::task::spawn_blocking(move || {
+                    payjoin::io::blocking::fetch_ohttp_keys_with_cert(
+                        relay.as_str(),
+                        directory.as_str(),
+                        cert,
+                    )
+                })
+                .await??;
+                assert_eq!(fetched, expected);
+                Ok(())
+            }
+
+            Ok(())
+        }
+
         #[tokio::test]
         async fn test_session_expiration() -> Result<(), BoxSendSyncError> {
             init_tracing();